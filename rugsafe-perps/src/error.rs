@@ -0,0 +1,108 @@
+use num_derive::FromPrimitive;
+use solana_program::{
+    decode_error::DecodeError,
+    msg,
+    program_error::{PrintProgramError, ProgramError},
+};
+use thiserror::Error;
+
+#[derive(Clone, Copy, Debug, Eq, Error, FromPrimitive, PartialEq)]
+pub enum PerpsError {
+    #[error("Account does not match its derived address")]
+    InvalidPda,
+    #[error("User positions data is invalid")]
+    InvalidUserPositionsData,
+    #[error("Position data is invalid")]
+    InvalidPositionData,
+    #[error("Position not found")]
+    PositionNotFound,
+    #[error("Position account is already in use")]
+    PositionAlreadyExists,
+    #[error("Signer is not authorized for this account")]
+    Unauthorized,
+    #[error("Maximum number of open positions reached")]
+    MaxPositionsReached,
+    #[error("Collateral is insufficient for the required margin")]
+    InsufficientMargin,
+    #[error("Amount exceeds the position's collateral")]
+    InsufficientCollateral,
+    #[error("Leverage exceeds the market maximum")]
+    MaxLeverageExceeded,
+    #[error("Oracle price is stale")]
+    StaleOracle,
+    #[error("Oracle price is invalid")]
+    InvalidOraclePrice,
+    #[error("Position is not liquidatable")]
+    NotLiquidatable,
+    #[error("Token account does not match the position or signer")]
+    TokenAccountMismatch,
+    #[error("Amount must be greater than zero")]
+    ZeroAmount,
+    #[error("Arithmetic overflow")]
+    MathOverflow,
+    #[error("Oracle price is worse than the acceptable price")]
+    SlippageExceeded,
+    #[error("Pool data is invalid")]
+    InvalidPoolData,
+    #[error("Pool parameters are out of range")]
+    InvalidPoolParams,
+    #[error("Pool has no room for another market")]
+    PoolFull,
+    #[error("Custody data is invalid")]
+    InvalidCustodyData,
+    #[error("Custody does not belong to this pool or position")]
+    CustodyMismatch,
+    #[error("Oracle account does not match the custody")]
+    OracleMismatch,
+    #[error("Oracle confidence interval is too wide")]
+    OracleConfidenceTooWide,
+    #[error("Invalid oracle parameters")]
+    InvalidOracleParams,
+    #[error("Invalid borrow rate parameters")]
+    InvalidBorrowRateParams,
+    #[error("Invalid order account data")]
+    InvalidOrderData,
+    #[error("Oracle price has not reached the trigger price")]
+    TriggerNotReached,
+    #[error("Oracle price has not reached the limit price")]
+    LimitPriceNotReached,
+    #[error("Not enough unreserved liquidity in the pool")]
+    InsufficientLiquidity,
+    #[error("Invalid fee parameters")]
+    InvalidFeeParams,
+    #[error("Invalid open interest parameters")]
+    InvalidOpenInterestParams,
+    #[error("Position would exceed the market's open interest cap")]
+    MaxOpenInterestExceeded,
+    #[error("Position would exceed the market's long/short skew limit")]
+    MaxSkewExceeded,
+    #[error("Pool has LP tokens outstanding but no assets")]
+    InsolventPool,
+    #[error("Max leverage is below 1x or leaves no room above the maintenance margin")]
+    InvalidMaxLeverage,
+}
+
+impl From<PerpsError> for ProgramError {
+    fn from(e: PerpsError) -> Self {
+        ProgramError::Custom(e as u32)
+    }
+}
+
+impl<T> DecodeError<T> for PerpsError {
+    fn type_of() -> &'static str {
+        "PerpsError"
+    }
+}
+
+impl PrintProgramError for PerpsError {
+    fn print<E>(&self)
+    where
+        E: 'static
+            + std::error::Error
+            + DecodeError<E>
+            + PrintProgramError
+            + num_traits::FromPrimitive,
+    {
+        msg!("PerpsError: {}", self);
+    }
+}
@@ -1,5 +1,7 @@
 pub mod perpetuals;
+pub mod pool;
 pub mod processor;
 
 // pub use {perpetuals::*, vaults::*};
 pub use perpetuals::instruction::PerpetualsInstruction;
+pub use pool::instruction::PoolInstruction;
@@ -1,14 +1,53 @@
+use crate::state::order::TriggerKind;
 use crate::state::perpetuals::Side;
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::program_error::ProgramError;
 
 #[derive(Clone, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
 pub enum PerpetualsInstruction {
-    OpenPosition { side: Side, amount: u64 },
-    ClosePosition { position_id: u64 },
-    AddCollateral { position_id: u64, amount: u64 },
-    RemoveCollateral { position_id: u64, amount: u64 },
-    LiquidatePosition { position_id: u64 },
+    OpenPosition {
+        side: Side,
+        amount: u64,   // Collateral deposited, in base units of the collateral mint
+        size_usd: u64, // Notional size, scaled by PRICE_SCALE
+        acceptable_price: u64, // Worst entry price accepted: a maximum for longs, a minimum for shorts
+    },
+    ClosePosition {
+        position_id: u64,
+    },
+    AddCollateral {
+        position_id: u64,
+        amount: u64,
+    },
+    RemoveCollateral {
+        position_id: u64,
+        amount: u64,
+    },
+    LiquidatePosition {
+        position_id: u64,
+    },
+    InitInsuranceFund,
+    DecreasePosition {
+        position_id: u64,
+        size_usd: u64, // Size to close, scaled by PRICE_SCALE. The whole position closes at or above its size
+        withdraw_collateral: bool, // Release the closed share of collateral, or keep it all to lower leverage
+    },
+    PlaceTriggerOrder {
+        position_id: u64,
+        kind: TriggerKind,
+        trigger_price: u64, // Oracle price that makes the order executable, scaled by PRICE_SCALE
+        size_usd: u64, // Size to close, scaled by PRICE_SCALE. The whole position closes at or above its size
+    },
+    CancelTriggerOrder,
+    ExecuteTriggerOrder,
+    PlaceLimitOrder {
+        order_id: u64, // Chosen by the owner, one open order per id
+        side: Side,
+        amount: u64,      // Collateral escrowed, in base units of the collateral mint
+        size_usd: u64,    // Notional size, scaled by PRICE_SCALE
+        limit_price: u64, // Worst entry price: a maximum for longs, a minimum for shorts
+    },
+    CancelLimitOrder,
+    ExecuteLimitOrder,
 }
 
 impl PerpetualsInstruction {
@@ -28,7 +67,14 @@ impl PerpetualsInstruction {
                     _ => return Err(ProgramError::InvalidInstructionData),
                 };
                 let amount = Self::unpack_amount(rest)?;
-                Self::OpenPosition { side, amount }
+                let size_usd = Self::unpack_u64(rest.get(8..).unwrap_or_default())?;
+                let acceptable_price = Self::unpack_u64(rest.get(16..).unwrap_or_default())?;
+                Self::OpenPosition {
+                    side,
+                    amount,
+                    size_usd,
+                    acceptable_price,
+                }
             }
             1 => {
                 let position_id = Self::unpack_u64(rest)?;
@@ -54,6 +100,59 @@ impl PerpetualsInstruction {
                 let position_id = Self::unpack_u64(rest)?;
                 Self::LiquidatePosition { position_id }
             }
+            5 => Self::InitInsuranceFund,
+            6 => {
+                let position_id = Self::unpack_u64(rest)?;
+                let size_usd = Self::unpack_u64(rest.get(8..).unwrap_or_default())?;
+                let withdraw_collateral = match rest.get(16) {
+                    Some(0) => false,
+                    Some(1) => true,
+                    _ => return Err(ProgramError::InvalidInstructionData),
+                };
+                Self::DecreasePosition {
+                    position_id,
+                    size_usd,
+                    withdraw_collateral,
+                }
+            }
+            7 => {
+                let position_id = Self::unpack_u64(rest)?;
+                let kind = match rest.get(8) {
+                    Some(0) => TriggerKind::TakeProfit,
+                    Some(1) => TriggerKind::StopLoss,
+                    _ => return Err(ProgramError::InvalidInstructionData),
+                };
+                let trigger_price = Self::unpack_u64(rest.get(9..).unwrap_or_default())?;
+                let size_usd = Self::unpack_u64(rest.get(17..).unwrap_or_default())?;
+                Self::PlaceTriggerOrder {
+                    position_id,
+                    kind,
+                    trigger_price,
+                    size_usd,
+                }
+            }
+            8 => Self::CancelTriggerOrder,
+            9 => Self::ExecuteTriggerOrder,
+            10 => {
+                let order_id = Self::unpack_u64(rest)?;
+                let side = match rest.get(8) {
+                    Some(1) => Side::Long,
+                    Some(2) => Side::Short,
+                    _ => return Err(ProgramError::InvalidInstructionData),
+                };
+                let amount = Self::unpack_amount(rest.get(9..).unwrap_or_default())?;
+                let size_usd = Self::unpack_u64(rest.get(17..).unwrap_or_default())?;
+                let limit_price = Self::unpack_u64(rest.get(25..).unwrap_or_default())?;
+                Self::PlaceLimitOrder {
+                    order_id,
+                    side,
+                    amount,
+                    size_usd,
+                    limit_price,
+                }
+            }
+            11 => Self::CancelLimitOrder,
+            12 => Self::ExecuteLimitOrder,
             _ => return Err(ProgramError::InvalidInstructionData),
         })
     }
@@ -1,5 +1,13 @@
+use crate::error::PerpsError;
 use crate::instructions::perpetuals::PerpetualsInstruction;
+use crate::math;
+use crate::oracle::get_oracle_price;
+use crate::state::order::{
+    LimitOrder, TriggerKind, TriggerOrder, LIMIT_ORDER_ESCROW_SEED, LIMIT_ORDER_SEED,
+    TRIGGER_ORDER_SEED,
+};
 use crate::state::perpetuals::{Position, Side, UserPositions};
+use crate::state::pool::{Custody, Pool};
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
@@ -15,9 +23,7 @@ use solana_program::{
 };
 // use solana_sdk::program_pack::Pack;
 use solana_program::program_pack::Pack;
-
-use spl_associated_token_account::get_associated_token_address;
-use spl_token::state::Mint;
+use spl_token::state::Account as TokenAccount;
 
 pub struct Processor;
 
@@ -30,9 +36,19 @@ impl Processor {
         let instruction = PerpetualsInstruction::unpack(instruction_data)?;
 
         match instruction {
-            PerpetualsInstruction::OpenPosition { side, amount } => {
-                Self::process_open_position(program_id, accounts, side, amount)
-            }
+            PerpetualsInstruction::OpenPosition {
+                side,
+                amount,
+                size_usd,
+                acceptable_price,
+            } => Self::process_open_position(
+                program_id,
+                accounts,
+                side,
+                amount,
+                size_usd,
+                acceptable_price,
+            ),
             PerpetualsInstruction::ClosePosition { position_id } => {
                 Self::process_close_position(program_id, accounts, position_id)
             }
@@ -47,6 +63,60 @@ impl Processor {
             PerpetualsInstruction::LiquidatePosition { position_id } => {
                 Self::process_liquidate_position(program_id, accounts, position_id)
             }
+            PerpetualsInstruction::InitInsuranceFund => {
+                Self::process_init_insurance_fund(program_id, accounts)
+            }
+            PerpetualsInstruction::DecreasePosition {
+                position_id,
+                size_usd,
+                withdraw_collateral,
+            } => Self::process_decrease_position(
+                program_id,
+                accounts,
+                position_id,
+                size_usd,
+                withdraw_collateral,
+            ),
+            PerpetualsInstruction::PlaceTriggerOrder {
+                position_id,
+                kind,
+                trigger_price,
+                size_usd,
+            } => Self::process_place_trigger_order(
+                program_id,
+                accounts,
+                position_id,
+                kind,
+                trigger_price,
+                size_usd,
+            ),
+            PerpetualsInstruction::CancelTriggerOrder => {
+                Self::process_cancel_trigger_order(program_id, accounts)
+            }
+            PerpetualsInstruction::ExecuteTriggerOrder => {
+                Self::process_execute_trigger_order(program_id, accounts)
+            }
+            PerpetualsInstruction::PlaceLimitOrder {
+                order_id,
+                side,
+                amount,
+                size_usd,
+                limit_price,
+            } => Self::process_place_limit_order(
+                program_id,
+                accounts,
+                order_id,
+                side,
+                amount,
+                size_usd,
+                limit_price,
+            ),
+            PerpetualsInstruction::CancelLimitOrder => {
+                Self::process_cancel_limit_order(program_id, accounts)
+            }
+            PerpetualsInstruction::ExecuteLimitOrder => {
+                Self::process_execute_limit_order(program_id, accounts)
+            }
         }
     }
 
@@ -55,20 +125,22 @@ impl Processor {
         accounts: &[AccountInfo],
         side: Side,
         amount: u64,
+        size_usd: u64,
+        acceptable_price: u64,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
 
         let payer_account = next_account_info(account_info_iter)?; // User who opens the position
         let user_positions_account = next_account_info(account_info_iter)?; // User's positions account (PDA)
         let user_collateral_account = next_account_info(account_info_iter)?; // User's collateral token account
-        let collateral_mint_account = next_account_info(account_info_iter)?; // Collateral mint account
-        let custody_account = next_account_info(account_info_iter)?; // Custody account (associated token account)
+        let pool_account = next_account_info(account_info_iter)?; // Pool account (PDA)
+        let custody_account = next_account_info(account_info_iter)?; // Market custody account (PDA)
+        let custody_token_account = next_account_info(account_info_iter)?; // Custody token account (PDA)
+        let position_account = next_account_info(account_info_iter)?; // Position account (PDA)
+        let oracle_account = next_account_info(account_info_iter)?; // Price oracle
         let spl_account = next_account_info(account_info_iter)?; // Token program
         let system_program = next_account_info(account_info_iter)?; // System program
         let rent_account = next_account_info(account_info_iter)?; // Rent sysvar
-        let associated_token_program = next_account_info(account_info_iter)?; // Associated token account program
-        let program_account = next_account_info(account_info_iter)?; // Program's AccountInfo
-        let position_account = next_account_info(account_info_iter)?; // **Position account (PDA) should be last**
 
         msg!("OpenPosition Account: Payer: {:?}", payer_account.key);
         msg!(
@@ -79,258 +151,87 @@ impl Processor {
             "OpenPosition Account: User Collateral: {:?}",
             user_collateral_account.key
         );
+        msg!("OpenPosition Account: Pool: {:?}", pool_account.key);
+        msg!("OpenPosition Account: Custody: {:?}", custody_account.key);
         msg!(
-            "OpenPosition Account: Collateral Mint: {:?}",
-            collateral_mint_account.key
-        );
-        msg!(
-            "OpenPosition Account: Custody ATA: {:?}",
-            custody_account.key
-        );
-        msg!(
-            "OpenPosition Account: Passed Custody Account: {:?}",
-            custody_account.key
+            "OpenPosition Account: Custody Token Account: {:?}",
+            custody_token_account.key
         );
         msg!(
-            "OpenPosition Account: Collateral Mint Account: {:?}",
-            collateral_mint_account.key
+            "OpenPosition Account: Position PDA: {:?}",
+            position_account.key
         );
+        msg!("OpenPosition Account: Oracle: {:?}", oracle_account.key);
         msg!("OpenPosition Account: SPL Token: {:?}", spl_account.key);
         msg!(
             "OpenPosition Account: System Program: {:?}",
             system_program.key
         );
         msg!("OpenPosition Account: Rent: {:?}", rent_account.key);
-        msg!(
-            "OpenPosition Account: Associated Token Program: {:?}",
-            associated_token_program.key
-        );
-        msg!(
-            "OpenPosition Account: Program Accout: {:?}",
-            program_account.key
-        );
-
-        msg!(
-            "OpenPosition Account: Position PDA: {:?}",
-            position_account.key
-        );
 
         // Ensure the payer is a signer
         if !payer_account.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
-        // Derive the user_positions_account PDA
-        let (user_positions_pda, user_positions_bump) = Pubkey::find_program_address(
-            &[b"user_positions", payer_account.key.as_ref()],
-            program_id,
-        );
-
-        // Check that the provided user_positions_account matches the derived PDA
-        if user_positions_account.key != &user_positions_pda {
-            return Err(ProgramError::InvalidArgument);
-        }
-
-        // If the user_positions_account data is empty, create it
-        if user_positions_account.data_is_empty() {
-            let rent = &Rent::from_account_info(rent_account)?;
-            // msg!("rent: {:?}", rent);
-            let required_lamports = rent.minimum_balance(UserPositions::LEN as usize);
-            // msg!("required_lamports: {:?}", required_lamports);
-            // msg!(
-            //     "Required lamports for rent exemption: {}",
-            //     required_lamports
-            // );
-
-            let seeds = &[
-                b"user_positions",
-                payer_account.key.as_ref(),
-                &[user_positions_bump],
-            ];
-
-            // msg!("PDA seeds: {:?}", seeds);
-
-            invoke_signed(
-                &solana_program::system_instruction::create_account(
-                    payer_account.key,
-                    user_positions_account.key,
-                    required_lamports,
-                    UserPositions::LEN as u64,
-                    program_id,
-                ),
-                &[
-                    payer_account.clone(),
-                    user_positions_account.clone(),
-                    system_program.clone(),
-                ],
-                // &[seeds],
-                // &[&[b"user_positions".as_ref(), &[bump_seed]]],
-                &[&[
-                    b"user_positions",
-                    payer_account.key.as_ref(),
-                    &[user_positions_bump],
-                ]],
-            )?;
-        }
-
-        // Derive the program-owned PDA for custody
-        // Derive the custody PDA
-        let (custody_pda, custody_bump) =
-            Pubkey::find_program_address(&[b"custody", payer_account.key.as_ref()], program_id);
-
-        // Verify that the provided custody_account matches the derived PDA
-        if custody_account.key != &custody_pda {
-            msg!("Invalid custody account: {:?}", custody_pda);
-            return Err(ProgramError::InvalidArgument);
-        }
-
-        if custody_account.lamports() == 0 {
-            // msg!("Creating custody account...");
-
-            // Calculate the required lamports for rent exemption
-            let rent = &Rent::from_account_info(rent_account)?;
-            let required_lamports = rent.minimum_balance(spl_token::state::Account::LEN);
-
-            // Create the custody account using invoke_signed with create_account
-            invoke_signed(
-                &solana_program::system_instruction::create_account(
-                    payer_account.key,
-                    custody_account.key,
-                    required_lamports,
-                    spl_token::state::Account::LEN as u64,
-                    &spl_token::id(), // Owner is the SPL Token program
-                ),
-                &[
-                    payer_account.clone(),
-                    custody_account.clone(),
-                    system_program.clone(),
-                ],
-                &[&[b"custody", payer_account.key.as_ref(), &[custody_bump]]],
-            )?;
-
-            // msg!("Initializing custody account...");
-
-            // Initialize the token account
-            invoke(
-                &spl_token::instruction::initialize_account(
-                    &spl_token::id(),
-                    custody_account.key,
-                    collateral_mint_account.key,
-                    &custody_pda, // Authority is the program's PDA
-                )?,
-                &[
-                    custody_account.clone(),
-                    collateral_mint_account.clone(),
-                    spl_account.clone(),
-                    rent_account.clone(),
-                ],
-            )?;
+        if amount == 0 || size_usd == 0 {
+            return Err(PerpsError::ZeroAmount.into());
         }
 
-        ///////////////////////////////////
-        ///
-        // Deserialize the UserPositions account data
-        let mut user_positions_data = user_positions_account.try_borrow_mut_data()?; // Access data via AccountInfo
-        let mut data_slice: &[u8] = &user_positions_data;
-
-        // msg!("user_positions_data: {:?}", user_positions_data);
-
-        let mut user_positions = if data_slice.iter().all(|&x| x == 0) {
-            // Account data is uninitialized, initialize UserPositions
-            UserPositions {
-                owner: *payer_account.key,
-                next_position_idx: 0, // Initialize index to 0
-            }
-        } else {
-            // Account data is initialized, deserialize
-            let user_positions = UserPositions::deserialize(&mut data_slice)?;
-
-            // Check if the owner matches
-            if user_positions.owner != *payer_account.key {
-                return Err(ProgramError::InvalidAccountData);
-            }
-            user_positions
-        };
-
-        // msg!("user_positions: {:?}", user_positions);
-
-        // Derive the PDA for the new position based on the user and the position index
-        let (position_pda, position_bump) = Pubkey::find_program_address(
-            &[
-                b"position",
-                payer_account.key.as_ref(),
-                &user_positions.next_position_idx.to_le_bytes(),
-            ],
+        let (mut pool, mut custody) = Self::load_market(
             program_id,
-        );
-
-        // Check if the new position PDA needs to be created
-        if position_account.data_is_empty() {
-            // msg!("position_account 1: {:?}", position_account);
-            // This checks the account info, not Pubkey
-            let rent = &Rent::from_account_info(rent_account)?;
-            let required_lamports = rent.minimum_balance(Position::LEN);
-
-            let seeds = &[
-                b"position",
-                payer_account.key.as_ref(),
-                &user_positions.next_position_idx.to_le_bytes(),
-                &[position_bump],
-            ];
-
-            msg!("Creating position account...");
+            pool_account,
+            custody_account,
+            custody_token_account,
+            oracle_account,
+        )?;
+        Self::check_token_account(user_collateral_account, &custody.mint, payer_account.key)?;
 
-            invoke_signed(
-                &solana_program::system_instruction::create_account(
-                    payer_account.key,
-                    position_account.key, // Use AccountInfo's key here
-                    required_lamports,
-                    Position::LEN as u64,
-                    program_id,
-                    // payer_account.key,
-                ),
-                &[
-                    payer_account.clone(),
-                    position_account.clone(),
-                    system_program.clone(),
-                ],
-                // &[seeds],
-                &[&[
-                    b"position",
-                    payer_account.key.as_ref(),
-                    &user_positions.next_position_idx.to_le_bytes(),
-                    &[position_bump],
-                ]],
-            )?;
+        // Enter at the oracle price, within the trader's slippage limit
+        let price = get_oracle_price(program_id, oracle_account, &custody.oracle)?;
+        let slipped = match side {
+            Side::Long => price > acceptable_price,
+            Side::Short => price < acceptable_price,
+            Side::None => return Err(PerpsError::InvalidPositionData.into()),
+        };
+        if slipped {
+            msg!(
+                "OpenPosition: price {} is worse than acceptable price {}",
+                price,
+                acceptable_price
+            );
+            return Err(PerpsError::SlippageExceeded.into());
         }
 
-        ////////////////////////////////////////////
-        /// ///////////////////////////////////////
-        /// /////////////////////////////////////
-        // Create a new Position
-        let position = Position {
-            owner: *payer_account.key,
+        let custody_balance =
+            TokenAccount::unpack(&custody_token_account.try_borrow_data()?)?.amount;
+        Self::create_position(
+            program_id,
+            payer_account,
+            payer_account.key,
+            user_positions_account,
+            position_account,
+            pool_account,
+            custody_account,
+            system_program,
+            rent_account,
+            &mut pool,
+            &mut custody,
             side,
-            size_usd: amount,
-            open_time: Clock::get()?.unix_timestamp,
-            update_time: Clock::get()?.unix_timestamp,
-            ..Position::default()
-        };
-
-        // Serialize the new position and store it in the position PDA
-        let mut position_data = position_account.try_borrow_mut_data()?; // Use AccountInfo for data access
-        position.serialize(&mut *position_data)?;
-
-        // Update the UserPositions account's next_position_idx
-        user_positions.next_position_idx += 1;
-        user_positions.serialize(&mut *user_positions_data)?;
+            amount,
+            size_usd,
+            price,
+            custody_balance,
+        )?;
+        pool.store(pool_account)?;
+        custody.store(custody_account)?;
 
         // msg!("Position added successfully");
-        // Transfer collateral from user's account to custody account (associated token account)
+        // Transfer collateral from user's account to the market custody
         let transfer_ix = spl_token::instruction::transfer(
             spl_account.key,
             user_collateral_account.key,
-            custody_account.key,
+            custody_token_account.key,
             payer_account.key,
             &[],
             amount,
@@ -340,7 +241,7 @@ impl Processor {
             &transfer_ix,
             &[
                 user_collateral_account.clone(),
-                custody_account.clone(),
+                custody_token_account.clone(),
                 payer_account.clone(),
                 spl_account.clone(),
             ],
@@ -352,44 +253,1855 @@ impl Processor {
     }
 
     fn process_close_position(
-        _program_id: &Pubkey,
-        _accounts: &[AccountInfo],
-        _position_id: u64,
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        position_id: u64,
     ) -> ProgramResult {
-        // Implement logic to close a position
-        msg!("Processing ClosePosition instruction");
-        Ok(())
+        let account_info_iter = &mut accounts.iter();
+
+        let owner_account = next_account_info(account_info_iter)?; // Position owner, receives the rent refund
+        let position_account = next_account_info(account_info_iter)?; // Position account (PDA)
+        let user_collateral_account = next_account_info(account_info_iter)?; // Owner's collateral token account
+        let pool_account = next_account_info(account_info_iter)?; // Pool account (PDA)
+        let custody_account = next_account_info(account_info_iter)?; // Market custody account (PDA)
+        let custody_token_account = next_account_info(account_info_iter)?; // Custody token account (PDA)
+        let oracle_account = next_account_info(account_info_iter)?; // Price oracle
+        let spl_account = next_account_info(account_info_iter)?; // Token program
+        let user_positions_account = next_account_info(account_info_iter)?; // Owner's positions account (PDA)
+
+        msg!("ClosePosition: position {}", position_id);
+
+        if !owner_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let (mut pool, mut custody) = Self::load_market(
+            program_id,
+            pool_account,
+            custody_account,
+            custody_token_account,
+            oracle_account,
+        )?;
+        let mut position = Self::load_position(
+            program_id,
+            owner_account,
+            position_account,
+            position_id,
+            pool_account,
+            custody_account,
+        )?;
+        Self::check_token_account(user_collateral_account, &custody.mint, owner_account.key)?;
+
+        let price = get_oracle_price(program_id, oracle_account, &custody.oracle)?;
+        let size_usd = position.size_usd;
+        let payout = Self::reduce_position(
+            &mut position,
+            &mut pool,
+            &mut custody,
+            price,
+            size_usd,
+            true,
+        )?;
+        pool.store(pool_account)?;
+        custody.store(custody_account)?;
+
+        Self::transfer_from_custody(
+            &custody,
+            custody_account,
+            custody_token_account,
+            user_collateral_account,
+            spl_account,
+            payout,
+        )?;
+
+        Self::finish_position(
+            program_id,
+            owner_account,
+            position_account,
+            user_positions_account,
+            position_id,
+            &position,
+        )
     }
 
     fn process_add_collateral(
-        _program_id: &Pubkey,
-        _accounts: &[AccountInfo],
-        _position_id: u64,
-        _amount: u64,
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        position_id: u64,
+        amount: u64,
     ) -> ProgramResult {
-        // Implement logic to add collateral to a position
-        msg!("Processing AddCollateral instruction");
-        Ok(())
+        let account_info_iter = &mut accounts.iter();
+
+        let owner_account = next_account_info(account_info_iter)?; // Position owner
+        let position_account = next_account_info(account_info_iter)?; // Position account (PDA)
+        let user_collateral_account = next_account_info(account_info_iter)?; // Owner's collateral token account
+        let pool_account = next_account_info(account_info_iter)?; // Pool account (PDA)
+        let custody_account = next_account_info(account_info_iter)?; // Market custody account (PDA)
+        let custody_token_account = next_account_info(account_info_iter)?; // Custody token account (PDA)
+        let oracle_account = next_account_info(account_info_iter)?; // Price oracle
+        let spl_account = next_account_info(account_info_iter)?; // Token program
+
+        msg!("AddCollateral: position {}, amount {}", position_id, amount);
+
+        if !owner_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if amount == 0 {
+            return Err(PerpsError::ZeroAmount.into());
+        }
+
+        let (_pool, mut custody) = Self::load_market(
+            program_id,
+            pool_account,
+            custody_account,
+            custody_token_account,
+            oracle_account,
+        )?;
+        let mut position = Self::load_position(
+            program_id,
+            owner_account,
+            position_account,
+            position_id,
+            pool_account,
+            custody_account,
+        )?;
+        Self::check_token_account(user_collateral_account, &custody.mint, owner_account.key)?;
+
+        let price = get_oracle_price(program_id, oracle_account, &custody.oracle)?;
+        let collateral_before = position.collateral_amount;
+        Self::settle_funding(&mut position, &custody, price)?;
+        Self::settle_interest(&mut position, &custody, price)?;
+
+        position.collateral_amount = position
+            .collateral_amount
+            .checked_add(amount)
+            .ok_or(PerpsError::MathOverflow)?;
+        custody.update_collateral(collateral_before, position.collateral_amount)?;
+        position.collateral_usd =
+            math::token_to_usd(position.collateral_amount, custody.decimals, price)
+                .ok_or(PerpsError::MathOverflow)?;
+        position.borrow_size_usd = position.size_usd.saturating_sub(position.collateral_usd);
+        position.update_time = Clock::get()?.unix_timestamp;
+        Self::store_position(position_account, &position)?;
+        custody.store(custody_account)?;
+
+        invoke(
+            &spl_token::instruction::transfer(
+                spl_account.key,
+                user_collateral_account.key,
+                custody_token_account.key,
+                owner_account.key,
+                &[],
+                amount,
+            )?,
+            &[
+                user_collateral_account.clone(),
+                custody_token_account.clone(),
+                owner_account.clone(),
+                spl_account.clone(),
+            ],
+        )
     }
 
     fn process_remove_collateral(
-        _program_id: &Pubkey,
-        _accounts: &[AccountInfo],
-        _position_id: u64,
-        _amount: u64,
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        position_id: u64,
+        amount: u64,
     ) -> ProgramResult {
-        // Implement logic to remove collateral from a position
-        msg!("Processing RemoveCollateral instruction");
-        Ok(())
+        let account_info_iter = &mut accounts.iter();
+
+        let owner_account = next_account_info(account_info_iter)?; // Position owner
+        let position_account = next_account_info(account_info_iter)?; // Position account (PDA)
+        let user_collateral_account = next_account_info(account_info_iter)?; // Owner's collateral token account
+        let pool_account = next_account_info(account_info_iter)?; // Pool account (PDA)
+        let custody_account = next_account_info(account_info_iter)?; // Market custody account (PDA)
+        let custody_token_account = next_account_info(account_info_iter)?; // Custody token account (PDA)
+        let oracle_account = next_account_info(account_info_iter)?; // Price oracle
+        let spl_account = next_account_info(account_info_iter)?; // Token program
+
+        msg!(
+            "RemoveCollateral: position {}, amount {}",
+            position_id,
+            amount
+        );
+
+        if !owner_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if amount == 0 {
+            return Err(PerpsError::ZeroAmount.into());
+        }
+
+        let (_pool, mut custody) = Self::load_market(
+            program_id,
+            pool_account,
+            custody_account,
+            custody_token_account,
+            oracle_account,
+        )?;
+        let mut position = Self::load_position(
+            program_id,
+            owner_account,
+            position_account,
+            position_id,
+            pool_account,
+            custody_account,
+        )?;
+        Self::check_token_account(user_collateral_account, &custody.mint, owner_account.key)?;
+
+        let price = get_oracle_price(program_id, oracle_account, &custody.oracle)?;
+        let collateral_before = position.collateral_amount;
+        Self::settle_funding(&mut position, &custody, price)?;
+        Self::settle_interest(&mut position, &custody, price)?;
+
+        position.collateral_amount = position
+            .collateral_amount
+            .checked_sub(amount)
+            .ok_or(PerpsError::InsufficientCollateral)?;
+        custody.update_collateral(collateral_before, position.collateral_amount)?;
+        position.collateral_usd =
+            math::token_to_usd(position.collateral_amount, custody.decimals, price)
+                .ok_or(PerpsError::MathOverflow)?;
+        position.borrow_size_usd = position.size_usd.saturating_sub(position.collateral_usd);
+
+        // The remaining equity must still cover the initial margin
+        let (profit_usd, loss_usd) = Self::position_pnl(&position, &custody, price)?;
+        let equity_usd = math::equity_usd(position.collateral_usd, profit_usd, loss_usd);
+        if math::exceeds_leverage(position.size_usd, equity_usd, custody.max_leverage_bps) {
+            msg!(
+                "RemoveCollateral: size_usd {} over equity_usd {} exceeds max leverage",
+                position.size_usd,
+                equity_usd
+            );
+            return Err(PerpsError::InsufficientMargin.into());
+        }
+
+        position.update_time = Clock::get()?.unix_timestamp;
+        Self::store_position(position_account, &position)?;
+        custody.store(custody_account)?;
+
+        Self::transfer_from_custody(
+            &custody,
+            custody_account,
+            custody_token_account,
+            user_collateral_account,
+            spl_account,
+            amount,
+        )
     }
 
     fn process_liquidate_position(
-        _program_id: &Pubkey,
-        _accounts: &[AccountInfo],
-        _position_id: u64,
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        position_id: u64,
     ) -> ProgramResult {
-        // Implement logic to liquidate a position
-        msg!("Processing LiquidatePosition instruction");
+        let account_info_iter = &mut accounts.iter();
+
+        let liquidator_account = next_account_info(account_info_iter)?; // Any signer
+        let owner_account = next_account_info(account_info_iter)?; // Position owner, receives the rent refund
+        let position_account = next_account_info(account_info_iter)?; // Position account (PDA)
+        let liquidator_collateral_account = next_account_info(account_info_iter)?; // Liquidator's collateral token account
+        let pool_account = next_account_info(account_info_iter)?; // Pool account (PDA)
+        let custody_account = next_account_info(account_info_iter)?; // Market custody account (PDA)
+        let custody_token_account = next_account_info(account_info_iter)?; // Custody token account (PDA)
+        let insurance_fund_account = next_account_info(account_info_iter)?; // Insurance fund token account (PDA)
+        let oracle_account = next_account_info(account_info_iter)?; // Price oracle
+        let spl_account = next_account_info(account_info_iter)?; // Token program
+        let user_positions_account = next_account_info(account_info_iter)?; // Owner's positions account (PDA)
+
+        msg!("LiquidatePosition: position {}", position_id);
+
+        if !liquidator_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let (mut pool, mut custody) = Self::load_market(
+            program_id,
+            pool_account,
+            custody_account,
+            custody_token_account,
+            oracle_account,
+        )?;
+        let position = Self::load_position(
+            program_id,
+            owner_account,
+            position_account,
+            position_id,
+            pool_account,
+            custody_account,
+        )?;
+        Self::check_token_account(
+            liquidator_collateral_account,
+            &custody.mint,
+            liquidator_account.key,
+        )?;
+        let insurance_fund_bump =
+            Self::check_insurance_fund(program_id, insurance_fund_account, &custody.mint)?;
+        let mut user_positions =
+            Self::load_user_positions(program_id, owner_account, user_positions_account)?;
+
+        let price = get_oracle_price(program_id, oracle_account, &custody.oracle)?;
+
+        let collateral_usd =
+            math::token_to_usd(position.collateral_amount, custody.decimals, price)
+                .ok_or(PerpsError::MathOverflow)?;
+        let (profit_usd, loss_usd) = Self::position_pnl(&position, &custody, price)?;
+        let equity_usd = math::equity_usd(collateral_usd, profit_usd, loss_usd);
+
+        if !math::is_liquidatable(
+            position.size_usd,
+            equity_usd,
+            pool.params.maintenance_margin_bps,
+        ) {
+            return Err(PerpsError::NotLiquidatable.into());
+        }
+
+        // The liquidator is paid out of whatever equity is left
+        let reward_usd = math::bps_of(position.size_usd, pool.params.liquidation_fee_bps)
+            .ok_or(PerpsError::MathOverflow)?
+            .min(equity_usd);
+        let custody_balance =
+            TokenAccount::unpack(&custody_token_account.try_borrow_data()?)?.amount;
+        let reward = math::usd_to_token(reward_usd, custody.decimals, price)
+            .ok_or(PerpsError::MathOverflow)?
+            .min(custody_balance);
+
+        // Losses beyond the collateral are covered by the insurance fund as far as it can
+        let shortfall_usd = loss_usd.saturating_sub(collateral_usd.saturating_add(profit_usd));
+        let shortfall = math::usd_to_token_ceil(shortfall_usd, custody.decimals, price)
+            .ok_or(PerpsError::MathOverflow)?;
+        let insurance_balance = if insurance_fund_account.data_is_empty() {
+            0
+        } else {
+            TokenAccount::unpack(&insurance_fund_account.try_borrow_data()?)?.amount
+        };
+        let covered = shortfall.min(insurance_balance);
+
+        msg!(
+            "LiquidatePosition: price {}, equity_usd {}, reward {}, shortfall {}, covered {}",
+            price,
+            equity_usd,
+            reward,
+            shortfall,
+            covered
+        );
+
+        pool.remove_open_interest(position.side, position.size_usd)?;
+        pool.store(pool_account)?;
+        custody.remove_open_interest(position.side, position.size_usd)?;
+        custody.unlock(position.side, position.locked_amount)?;
+        custody.update_collateral(position.collateral_amount, 0)?;
+        custody.store(custody_account)?;
+        user_positions.remove(position_id)?;
+        Self::store_user_positions(user_positions_account, &user_positions)?;
+
+        Self::transfer_from_custody(
+            &custody,
+            custody_account,
+            custody_token_account,
+            liquidator_collateral_account,
+            spl_account,
+            reward,
+        )?;
+
+        if covered > 0 {
+            invoke_signed(
+                &spl_token::instruction::transfer(
+                    spl_account.key,
+                    insurance_fund_account.key,
+                    custody_token_account.key,
+                    insurance_fund_account.key,
+                    &[],
+                    covered,
+                )?,
+                &[
+                    insurance_fund_account.clone(),
+                    custody_token_account.clone(),
+                    spl_account.clone(),
+                ],
+                &[&[
+                    b"insurance_fund",
+                    custody.mint.as_ref(),
+                    &[insurance_fund_bump],
+                ]],
+            )?;
+        }
+
+        // The rest of the collateral stays in the pool's custody
+        Self::close_account(position_account, owner_account)
+    }
+
+    fn process_decrease_position(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        position_id: u64,
+        size_usd: u64,
+        withdraw_collateral: bool,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let owner_account = next_account_info(account_info_iter)?; // Position owner
+        let position_account = next_account_info(account_info_iter)?; // Position account (PDA)
+        let user_collateral_account = next_account_info(account_info_iter)?; // Owner's collateral token account
+        let pool_account = next_account_info(account_info_iter)?; // Pool account (PDA)
+        let custody_account = next_account_info(account_info_iter)?; // Market custody account (PDA)
+        let custody_token_account = next_account_info(account_info_iter)?; // Custody token account (PDA)
+        let oracle_account = next_account_info(account_info_iter)?; // Price oracle
+        let spl_account = next_account_info(account_info_iter)?; // Token program
+        let user_positions_account = next_account_info(account_info_iter)?; // Owner's positions account (PDA), updated when the whole position closes
+
+        msg!(
+            "DecreasePosition: position {}, size_usd {}, withdraw_collateral {}",
+            position_id,
+            size_usd,
+            withdraw_collateral
+        );
+
+        if !owner_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if size_usd == 0 {
+            return Err(PerpsError::ZeroAmount.into());
+        }
+
+        let (mut pool, mut custody) = Self::load_market(
+            program_id,
+            pool_account,
+            custody_account,
+            custody_token_account,
+            oracle_account,
+        )?;
+        let mut position = Self::load_position(
+            program_id,
+            owner_account,
+            position_account,
+            position_id,
+            pool_account,
+            custody_account,
+        )?;
+
+        Self::check_token_account(user_collateral_account, &custody.mint, owner_account.key)?;
+
+        let price = get_oracle_price(program_id, oracle_account, &custody.oracle)?;
+        let payout = Self::reduce_position(
+            &mut position,
+            &mut pool,
+            &mut custody,
+            price,
+            size_usd,
+            withdraw_collateral,
+        )?;
+        pool.store(pool_account)?;
+        custody.store(custody_account)?;
+
+        Self::transfer_from_custody(
+            &custody,
+            custody_account,
+            custody_token_account,
+            user_collateral_account,
+            spl_account,
+            payout,
+        )?;
+
+        Self::finish_position(
+            program_id,
+            owner_account,
+            position_account,
+            user_positions_account,
+            position_id,
+            &position,
+        )
+    }
+
+    fn process_init_insurance_fund(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let payer_account = next_account_info(account_info_iter)?; // Pays for the account
+        let insurance_fund_account = next_account_info(account_info_iter)?; // Insurance fund token account (PDA)
+        let collateral_mint_account = next_account_info(account_info_iter)?; // Collateral mint account
+        let spl_account = next_account_info(account_info_iter)?; // Token program
+        let system_program = next_account_info(account_info_iter)?; // System program
+        let rent_account = next_account_info(account_info_iter)?; // Rent sysvar
+
+        if !payer_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let insurance_fund_bump = Self::check_insurance_fund(
+            program_id,
+            insurance_fund_account,
+            collateral_mint_account.key,
+        )?;
+        if !insurance_fund_account.data_is_empty() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        msg!(
+            "InitInsuranceFund: mint {:?}, fund {:?}",
+            collateral_mint_account.key,
+            insurance_fund_account.key
+        );
+
+        let rent = &Rent::from_account_info(rent_account)?;
+        invoke_signed(
+            &solana_program::system_instruction::create_account(
+                payer_account.key,
+                insurance_fund_account.key,
+                rent.minimum_balance(TokenAccount::LEN),
+                TokenAccount::LEN as u64,
+                &spl_token::id(),
+            ),
+            &[
+                payer_account.clone(),
+                insurance_fund_account.clone(),
+                system_program.clone(),
+            ],
+            &[&[
+                b"insurance_fund",
+                collateral_mint_account.key.as_ref(),
+                &[insurance_fund_bump],
+            ]],
+        )?;
+
+        // The fund is its own authority, so only this program can spend from it
+        invoke(
+            &spl_token::instruction::initialize_account(
+                spl_account.key,
+                insurance_fund_account.key,
+                collateral_mint_account.key,
+                insurance_fund_account.key,
+            )?,
+            &[
+                insurance_fund_account.clone(),
+                collateral_mint_account.clone(),
+                spl_account.clone(),
+                rent_account.clone(),
+            ],
+        )
+    }
+
+    fn process_place_trigger_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        position_id: u64,
+        kind: TriggerKind,
+        trigger_price: u64,
+        size_usd: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let owner_account = next_account_info(account_info_iter)?; // Position owner, pays for the order account
+        let position_account = next_account_info(account_info_iter)?; // Position account (PDA)
+        let order_account = next_account_info(account_info_iter)?; // Trigger order account (PDA)
+        let system_program = next_account_info(account_info_iter)?; // System program
+        let rent_account = next_account_info(account_info_iter)?; // Rent sysvar
+
+        msg!(
+            "PlaceTriggerOrder: position {}, kind {:?}, trigger_price {}, size_usd {}",
+            position_id,
+            kind,
+            trigger_price,
+            size_usd
+        );
+
+        if !owner_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if trigger_price == 0 || size_usd == 0 {
+            return Err(PerpsError::ZeroAmount.into());
+        }
+
+        Self::load_owned_position(program_id, owner_account, position_account, position_id)?;
+
+        let (order_pda, order_bump) = Pubkey::find_program_address(
+            &[
+                TRIGGER_ORDER_SEED,
+                position_account.key.as_ref(),
+                &[kind as u8],
+            ],
+            program_id,
+        );
+        if order_account.key != &order_pda {
+            return Err(PerpsError::InvalidPda.into());
+        }
+        if !order_account.data_is_empty() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        let rent = &Rent::from_account_info(rent_account)?;
+        invoke_signed(
+            &solana_program::system_instruction::create_account(
+                owner_account.key,
+                order_account.key,
+                rent.minimum_balance(TriggerOrder::LEN),
+                TriggerOrder::LEN as u64,
+                program_id,
+            ),
+            &[
+                owner_account.clone(),
+                order_account.clone(),
+                system_program.clone(),
+            ],
+            &[&TriggerOrder::seeds(
+                position_account.key,
+                &[kind as u8],
+                &[order_bump],
+            )],
+        )?;
+
+        TriggerOrder {
+            owner: *owner_account.key,
+            position: *position_account.key,
+            position_id,
+            kind,
+            trigger_price,
+            size_usd,
+            bump: order_bump,
+        }
+        .store(order_account)
+    }
+
+    fn process_cancel_trigger_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let owner_account = next_account_info(account_info_iter)?; // Order owner, receives the rent refund
+        let order_account = next_account_info(account_info_iter)?; // Trigger order account (PDA)
+
+        msg!("CancelTriggerOrder: order {:?}", order_account.key);
+
+        if !owner_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // The position may already be closed, the order can still be reclaimed
+        let order = TriggerOrder::load(program_id, order_account)?;
+        if order.owner != *owner_account.key {
+            return Err(PerpsError::Unauthorized.into());
+        }
+
+        Self::close_account(order_account, owner_account)
+    }
+
+    fn process_execute_trigger_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let keeper_account = next_account_info(account_info_iter)?; // Keeper executing the order
+        let keeper_collateral_account = next_account_info(account_info_iter)?; // Keeper's collateral token account, receives the fee
+        let owner_account = next_account_info(account_info_iter)?; // Position owner, receives the rent refunds
+        let order_account = next_account_info(account_info_iter)?; // Trigger order account (PDA)
+        let position_account = next_account_info(account_info_iter)?; // Position account (PDA)
+        let user_collateral_account = next_account_info(account_info_iter)?; // Owner's collateral token account
+        let pool_account = next_account_info(account_info_iter)?; // Pool account (PDA)
+        let custody_account = next_account_info(account_info_iter)?; // Market custody account (PDA)
+        let custody_token_account = next_account_info(account_info_iter)?; // Custody token account (PDA)
+        let oracle_account = next_account_info(account_info_iter)?; // Price oracle
+        let spl_account = next_account_info(account_info_iter)?; // Token program
+        let user_positions_account = next_account_info(account_info_iter)?; // Owner's positions account (PDA), updated when the whole position closes
+
+        msg!("ExecuteTriggerOrder: order {:?}", order_account.key);
+
+        if !keeper_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let order = TriggerOrder::load(program_id, order_account)?;
+        if order.owner != *owner_account.key {
+            return Err(PerpsError::Unauthorized.into());
+        }
+        if order.position != *position_account.key {
+            return Err(PerpsError::InvalidOrderData.into());
+        }
+
+        let (mut pool, mut custody) = Self::load_market(
+            program_id,
+            pool_account,
+            custody_account,
+            custody_token_account,
+            oracle_account,
+        )?;
+        let mut position = Self::load_position(
+            program_id,
+            owner_account,
+            position_account,
+            order.position_id,
+            pool_account,
+            custody_account,
+        )?;
+        Self::check_token_account(user_collateral_account, &custody.mint, owner_account.key)?;
+        Self::check_token_account(keeper_collateral_account, &custody.mint, keeper_account.key)?;
+
+        let price = get_oracle_price(program_id, oracle_account, &custody.oracle)?;
+        if !order.is_triggered(position.side, price)? {
+            return Err(PerpsError::TriggerNotReached.into());
+        }
+
+        let size_usd = order.size_usd.min(position.size_usd);
+        let payout = Self::reduce_position(
+            &mut position,
+            &mut pool,
+            &mut custody,
+            price,
+            size_usd,
+            true,
+        )?;
+
+        // The keeper is paid out of what the owner receives
+        let fee_usd =
+            math::bps_of(size_usd, pool.params.keeper_fee_bps).ok_or(PerpsError::MathOverflow)?;
+        let fee = math::usd_to_token(fee_usd, custody.decimals, price)
+            .ok_or(PerpsError::MathOverflow)?
+            .min(payout);
+
+        msg!(
+            "ExecuteTriggerOrder: price {}, size_usd {}, payout {}, keeper fee {}",
+            price,
+            size_usd,
+            payout,
+            fee
+        );
+
+        pool.store(pool_account)?;
+        custody.store(custody_account)?;
+
+        Self::transfer_from_custody(
+            &custody,
+            custody_account,
+            custody_token_account,
+            user_collateral_account,
+            spl_account,
+            payout - fee,
+        )?;
+        Self::transfer_from_custody(
+            &custody,
+            custody_account,
+            custody_token_account,
+            keeper_collateral_account,
+            spl_account,
+            fee,
+        )?;
+
+        Self::finish_position(
+            program_id,
+            owner_account,
+            position_account,
+            user_positions_account,
+            order.position_id,
+            &position,
+        )?;
+        Self::close_account(order_account, owner_account)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn process_place_limit_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        order_id: u64,
+        side: Side,
+        amount: u64,
+        size_usd: u64,
+        limit_price: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let owner_account = next_account_info(account_info_iter)?; // Trader placing the order, pays for its accounts
+        let order_account = next_account_info(account_info_iter)?; // Limit order account (PDA)
+        let escrow_account = next_account_info(account_info_iter)?; // Escrow token account of the order (PDA)
+        let user_collateral_account = next_account_info(account_info_iter)?; // Owner's collateral token account
+        let pool_account = next_account_info(account_info_iter)?; // Pool account (PDA)
+        let custody_account = next_account_info(account_info_iter)?; // Market custody account (PDA)
+        let mint_account = next_account_info(account_info_iter)?; // Collateral mint of the market
+        let spl_account = next_account_info(account_info_iter)?; // Token program
+        let system_program = next_account_info(account_info_iter)?; // System program
+        let rent_account = next_account_info(account_info_iter)?; // Rent sysvar
+
+        msg!(
+            "PlaceLimitOrder: order {}, side {:?}, amount {}, size_usd {}, limit_price {}",
+            order_id,
+            side,
+            amount,
+            size_usd,
+            limit_price
+        );
+
+        if !owner_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if amount == 0 || size_usd == 0 || limit_price == 0 {
+            return Err(PerpsError::ZeroAmount.into());
+        }
+
+        Pool::load(program_id, pool_account)?;
+        let custody = Custody::load(program_id, pool_account.key, custody_account)?;
+        if mint_account.key != &custody.mint {
+            return Err(PerpsError::CustodyMismatch.into());
+        }
+        Self::check_token_account(user_collateral_account, &custody.mint, owner_account.key)?;
+
+        let (order_pda, order_bump) = Pubkey::find_program_address(
+            &[
+                LIMIT_ORDER_SEED,
+                owner_account.key.as_ref(),
+                &order_id.to_le_bytes(),
+            ],
+            program_id,
+        );
+        if order_account.key != &order_pda {
+            return Err(PerpsError::InvalidPda.into());
+        }
+        if !order_account.data_is_empty() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+        let (escrow_pda, escrow_bump) = Pubkey::find_program_address(
+            &[LIMIT_ORDER_ESCROW_SEED, order_account.key.as_ref()],
+            program_id,
+        );
+        if escrow_account.key != &escrow_pda {
+            return Err(PerpsError::InvalidPda.into());
+        }
+
+        let rent = &Rent::from_account_info(rent_account)?;
+        invoke_signed(
+            &solana_program::system_instruction::create_account(
+                owner_account.key,
+                order_account.key,
+                rent.minimum_balance(LimitOrder::LEN),
+                LimitOrder::LEN as u64,
+                program_id,
+            ),
+            &[
+                owner_account.clone(),
+                order_account.clone(),
+                system_program.clone(),
+            ],
+            &[&LimitOrder::seeds(
+                owner_account.key,
+                &order_id.to_le_bytes(),
+                &[order_bump],
+            )],
+        )?;
+        invoke_signed(
+            &solana_program::system_instruction::create_account(
+                owner_account.key,
+                escrow_account.key,
+                rent.minimum_balance(TokenAccount::LEN),
+                TokenAccount::LEN as u64,
+                &spl_token::id(),
+            ),
+            &[
+                owner_account.clone(),
+                escrow_account.clone(),
+                system_program.clone(),
+            ],
+            &[&[
+                LIMIT_ORDER_ESCROW_SEED,
+                order_account.key.as_ref(),
+                &[escrow_bump],
+            ]],
+        )?;
+
+        // The order PDA is the escrow authority, so only this program can release it
+        invoke(
+            &spl_token::instruction::initialize_account(
+                spl_account.key,
+                escrow_account.key,
+                mint_account.key,
+                order_account.key,
+            )?,
+            &[
+                escrow_account.clone(),
+                mint_account.clone(),
+                order_account.clone(),
+                spl_account.clone(),
+                rent_account.clone(),
+            ],
+        )?;
+        invoke(
+            &spl_token::instruction::transfer(
+                spl_account.key,
+                user_collateral_account.key,
+                escrow_account.key,
+                owner_account.key,
+                &[],
+                amount,
+            )?,
+            &[
+                user_collateral_account.clone(),
+                escrow_account.clone(),
+                owner_account.clone(),
+                spl_account.clone(),
+            ],
+        )?;
+
+        LimitOrder {
+            owner: *owner_account.key,
+            pool: *pool_account.key,
+            custody: *custody_account.key,
+            order_id,
+            side,
+            collateral_amount: amount,
+            size_usd,
+            limit_price,
+            bump: order_bump,
+            escrow_bump,
+        }
+        .store(order_account)
+    }
+
+    fn process_cancel_limit_order(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let owner_account = next_account_info(account_info_iter)?; // Order owner, receives the rent refunds
+        let order_account = next_account_info(account_info_iter)?; // Limit order account (PDA)
+        let escrow_account = next_account_info(account_info_iter)?; // Escrow token account of the order (PDA)
+        let user_collateral_account = next_account_info(account_info_iter)?; // Owner's collateral token account, receives the escrow
+        let spl_account = next_account_info(account_info_iter)?; // Token program
+
+        msg!("CancelLimitOrder: order {:?}", order_account.key);
+
+        if !owner_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let order = LimitOrder::load(program_id, order_account)?;
+        if order.owner != *owner_account.key {
+            return Err(PerpsError::Unauthorized.into());
+        }
+        order.check_escrow(program_id, order_account, escrow_account)?;
+
+        Self::release_escrow(
+            &order,
+            order_account,
+            escrow_account,
+            user_collateral_account,
+            owner_account,
+            spl_account,
+        )?;
+        Self::close_account(order_account, owner_account)
+    }
+
+    fn process_execute_limit_order(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let keeper_account = next_account_info(account_info_iter)?; // Keeper filling the order, pays for the position
+        let owner_account = next_account_info(account_info_iter)?; // Order owner the position opens for
+        let order_account = next_account_info(account_info_iter)?; // Limit order account (PDA)
+        let escrow_account = next_account_info(account_info_iter)?; // Escrow token account of the order (PDA)
+        let user_positions_account = next_account_info(account_info_iter)?; // Owner's positions account (PDA)
+        let position_account = next_account_info(account_info_iter)?; // Owner's next position account (PDA)
+        let pool_account = next_account_info(account_info_iter)?; // Pool account (PDA)
+        let custody_account = next_account_info(account_info_iter)?; // Market custody account (PDA)
+        let custody_token_account = next_account_info(account_info_iter)?; // Custody token account (PDA)
+        let oracle_account = next_account_info(account_info_iter)?; // Price oracle
+        let spl_account = next_account_info(account_info_iter)?; // Token program
+        let system_program = next_account_info(account_info_iter)?; // System program
+        let rent_account = next_account_info(account_info_iter)?; // Rent sysvar
+
+        msg!("ExecuteLimitOrder: order {:?}", order_account.key);
+
+        if !keeper_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let order = LimitOrder::load(program_id, order_account)?;
+        if order.owner != *owner_account.key {
+            return Err(PerpsError::Unauthorized.into());
+        }
+        if order.pool != *pool_account.key || order.custody != *custody_account.key {
+            return Err(PerpsError::CustodyMismatch.into());
+        }
+        order.check_escrow(program_id, order_account, escrow_account)?;
+
+        let (mut pool, mut custody) = Self::load_market(
+            program_id,
+            pool_account,
+            custody_account,
+            custody_token_account,
+            oracle_account,
+        )?;
+
+        let price = get_oracle_price(program_id, oracle_account, &custody.oracle)?;
+        if !order.is_fillable(price)? {
+            msg!(
+                "ExecuteLimitOrder: price {} has not reached limit price {}",
+                price,
+                order.limit_price
+            );
+            return Err(PerpsError::LimitPriceNotReached.into());
+        }
+
+        let custody_balance =
+            TokenAccount::unpack(&custody_token_account.try_borrow_data()?)?.amount;
+        Self::create_position(
+            program_id,
+            keeper_account,
+            owner_account.key,
+            user_positions_account,
+            position_account,
+            pool_account,
+            custody_account,
+            system_program,
+            rent_account,
+            &mut pool,
+            &mut custody,
+            order.side,
+            order.collateral_amount,
+            order.size_usd,
+            price,
+            custody_balance,
+        )?;
+        pool.store(pool_account)?;
+        custody.store(custody_account)?;
+
+        // The keeper funded the position, so it takes back the order's rent
+        Self::release_escrow(
+            &order,
+            order_account,
+            escrow_account,
+            custody_token_account,
+            keeper_account,
+            spl_account,
+        )?;
+        Self::close_account(order_account, keeper_account)
+    }
+
+    /*
+    @name create_position
+    @description Creates the owner's next position PDA at the given price, creating their UserPositions first if needed, and books its open interest.
+    @param program_id - The perpetuals program.
+    @param payer_account - Pays the rent of the new accounts.
+    @param owner - The position owner the PDAs are seeded by.
+    @param user_positions_account - The owner's UserPositions PDA.
+    @param position_account - The PDA of the owner's next position.
+    @param pool_account - The pool the position opens in.
+    @param custody_account - The market custody the position opens in.
+    @param system_program - The system program.
+    @param rent_account - The rent sysvar.
+    @param pool - The loaded pool, updated with the open interest.
+    @param custody - The loaded custody, updated with the open interest and locked tokens.
+    @param side - Long or short.
+    @param amount - Collateral backing the position, in tokens. The caller moves it into custody.
+    @param size_usd - Notional size, scaled by PRICE_SCALE.
+    @param price - Entry price, scaled by PRICE_SCALE.
+    @param custody_balance - Tokens held by the custody token account before the collateral is moved in.
+    */
+    #[allow(clippy::too_many_arguments)]
+    fn create_position<'a>(
+        program_id: &Pubkey,
+        payer_account: &AccountInfo<'a>,
+        owner: &Pubkey,
+        user_positions_account: &AccountInfo<'a>,
+        position_account: &AccountInfo<'a>,
+        pool_account: &AccountInfo<'a>,
+        custody_account: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        rent_account: &AccountInfo<'a>,
+        pool: &mut Pool,
+        custody: &mut Custody,
+        side: Side,
+        amount: u64,
+        size_usd: u64,
+        price: u64,
+        custody_balance: u64,
+    ) -> ProgramResult {
+        // Derive the user_positions_account PDA
+        let (user_positions_pda, user_positions_bump) =
+            Pubkey::find_program_address(&[b"user_positions", owner.as_ref()], program_id);
+
+        // Check that the provided user_positions_account matches the derived PDA
+        if user_positions_account.key != &user_positions_pda {
+            return Err(PerpsError::InvalidPda.into());
+        }
+
+        // If the user_positions_account data is empty, create it
+        if user_positions_account.data_is_empty() {
+            let rent = &Rent::from_account_info(rent_account)?;
+            // msg!("rent: {:?}", rent);
+            let required_lamports = rent.minimum_balance(UserPositions::LEN);
+            // msg!("required_lamports: {:?}", required_lamports);
+            // msg!(
+            //     "Required lamports for rent exemption: {}",
+            //     required_lamports
+            // );
+
+            invoke_signed(
+                &solana_program::system_instruction::create_account(
+                    payer_account.key,
+                    user_positions_account.key,
+                    required_lamports,
+                    UserPositions::LEN as u64,
+                    program_id,
+                ),
+                &[
+                    payer_account.clone(),
+                    user_positions_account.clone(),
+                    system_program.clone(),
+                ],
+                // &[seeds],
+                // &[&[b"user_positions".as_ref(), &[bump_seed]]],
+                &[&[b"user_positions", owner.as_ref(), &[user_positions_bump]]],
+            )?;
+        }
+
+        // Deserialize the UserPositions account data
+        let mut user_positions_data = user_positions_account.try_borrow_mut_data()?; // Access data via AccountInfo
+        let mut data_slice: &[u8] = &user_positions_data;
+
+        // msg!("user_positions_data: {:?}", user_positions_data);
+
+        let mut user_positions = if data_slice.iter().all(|&x| x == 0) {
+            // Account data is uninitialized, initialize UserPositions
+            UserPositions {
+                owner: *owner,
+                next_position_idx: 0, // Initialize index to 0
+                active_positions: Vec::new(),
+            }
+        } else {
+            // Account data is initialized, deserialize
+            let user_positions = UserPositions::deserialize(&mut data_slice)
+                .map_err(|_| PerpsError::InvalidUserPositionsData)?;
+
+            // Check if the owner matches
+            if user_positions.owner != *owner {
+                return Err(PerpsError::Unauthorized.into());
+            }
+            user_positions
+        };
+
+        // msg!("user_positions: {:?}", user_positions);
+
+        // Derive the PDA for the new position based on the user and the position index
+        let (position_pda, position_bump) = Pubkey::find_program_address(
+            &[
+                b"position",
+                owner.as_ref(),
+                &user_positions.next_position_idx.to_le_bytes(),
+            ],
+            program_id,
+        );
+
+        if position_account.key != &position_pda {
+            return Err(PerpsError::InvalidPda.into());
+        }
+
+        // A position PDA is only ever created once per index
+        if !position_account.data_is_empty() {
+            return Err(PerpsError::PositionAlreadyExists.into());
+        }
+
+        // Create the new position PDA
+        let rent = &Rent::from_account_info(rent_account)?;
+        let required_lamports = rent.minimum_balance(Position::LEN);
+
+        msg!("Creating position account...");
+
+        invoke_signed(
+            &solana_program::system_instruction::create_account(
+                payer_account.key,
+                position_account.key, // Use AccountInfo's key here
+                required_lamports,
+                Position::LEN as u64,
+                program_id,
+                // payer_account.key,
+            ),
+            &[
+                payer_account.clone(),
+                position_account.clone(),
+                system_program.clone(),
+            ],
+            // &[seeds],
+            &[&[
+                b"position",
+                owner.as_ref(),
+                &user_positions.next_position_idx.to_le_bytes(),
+                &[position_bump],
+            ]],
+        )?;
+
+        // The open fee comes out of the deposited collateral
+        let fee = math::fee_amount(
+            size_usd,
+            custody.fees.open_position_bps,
+            custody.decimals,
+            price,
+        )
+        .ok_or(PerpsError::MathOverflow)?;
+        let collateral_amount = amount
+            .checked_sub(fee)
+            .ok_or(PerpsError::InsufficientCollateral)?;
+        msg!("OpenPosition: open fee {}", fee);
+
+        let collateral_usd = math::token_to_usd(collateral_amount, custody.decimals, price)
+            .ok_or(PerpsError::MathOverflow)?;
+        if math::exceeds_leverage(size_usd, collateral_usd, custody.max_leverage_bps) {
+            msg!(
+                "OpenPosition: size_usd {} on collateral_usd {} exceeds max leverage",
+                size_usd,
+                collateral_usd
+            );
+            return Err(PerpsError::MaxLeverageExceeded.into());
+        }
+        if let Err(error) = custody.check_open_interest(side, size_usd) {
+            msg!(
+                "OpenPosition: size_usd {} exceeds the market's open interest limits, long {} short {}",
+                size_usd,
+                custody.long_open_interest_usd,
+                custody.short_open_interest_usd
+            );
+            return Err(error.into());
+        }
+        let locked_amount = math::usd_to_token(size_usd, custody.decimals, price)
+            .ok_or(PerpsError::MathOverflow)?;
+        if let Err(error) = custody.check_liquidity(custody_balance, locked_amount) {
+            msg!(
+                "OpenPosition: locking {} more exceeds the pool's {} tokens, {} already locked",
+                locked_amount,
+                custody.owned_amount(custody_balance),
+                custody.locked_amount
+            );
+            return Err(error.into());
+        }
+
+        // Create a new Position
+        let now = Clock::get()?.unix_timestamp;
+        let position = Position {
+            owner: *owner,
+            pool: *pool_account.key,
+            custody: *custody_account.key,
+            collateral_custody: *custody_account.key,
+            side,
+            price,
+            size_usd,
+            collateral_usd,
+            collateral_amount,
+            borrow_size_usd: size_usd.saturating_sub(collateral_usd),
+            locked_amount,
+            open_time: now,
+            update_time: now,
+            cumulative_interest_snapshot: custody.cumulative_interest,
+            cumulative_funding_snapshot: custody.funding_index(side)?,
+            ..Position::default()
+        };
+
+        // Serialize the new position and store it in the position PDA
+        let mut position_data = position_account.try_borrow_mut_data()?; // Use AccountInfo for data access
+        position.serialize(&mut &mut position_data[..])?;
+
+        // Record the position as open and update the UserPositions account's next_position_idx
+        user_positions.add(user_positions.next_position_idx)?;
+        user_positions.next_position_idx = user_positions
+            .next_position_idx
+            .checked_add(1)
+            .ok_or(PerpsError::MathOverflow)?;
+        user_positions.serialize(&mut &mut user_positions_data[..])?;
+
+        pool.add_open_interest(side, size_usd)?;
+        custody.add_open_interest(side, size_usd)?;
+        custody.lock(side, position.locked_amount)?;
+        custody.update_collateral(0, collateral_amount)?;
+        custody.charge_fee(fee)?;
+
+        Ok(())
+    }
+
+    /*
+    @name load_market
+    @description Loads a market's pool and custody, checks the custody token account and oracle, and accrues funding and borrow interest up to now.
+    @param program_id - The perpetuals program.
+    @param pool_account - The pool PDA.
+    @param custody_account - The market custody PDA, which must belong to the pool.
+    @param custody_token_account - The token account recorded on the custody.
+    @param oracle_account - The oracle recorded on the custody.
+    */
+    fn load_market(
+        program_id: &Pubkey,
+        pool_account: &AccountInfo,
+        custody_account: &AccountInfo,
+        custody_token_account: &AccountInfo,
+        oracle_account: &AccountInfo,
+    ) -> Result<(Pool, Custody), ProgramError> {
+        let pool = Pool::load(program_id, pool_account)?;
+        let mut custody = Custody::load(program_id, pool_account.key, custody_account)?;
+
+        if custody_token_account.key != &custody.token_account {
+            return Err(PerpsError::CustodyMismatch.into());
+        }
+        if oracle_account.key != &custody.oracle.oracle_account {
+            return Err(PerpsError::OracleMismatch.into());
+        }
+
+        // Funding and interest must be accrued at the old open interest and utilization
+        // before any position changes them
+        let now = Clock::get()?.unix_timestamp;
+        custody.update_funding(pool.params.max_funding_rate_bps, now)?;
+        let token_balance = TokenAccount::unpack(&custody_token_account.try_borrow_data()?)?.amount;
+        custody.update_interest(custody.owned_amount(token_balance), now)?;
+
+        Ok((pool, custody))
+    }
+
+    /*
+    @name load_position
+    @description Loads an open position PDA and checks that it belongs to the owner and market.
+    @param program_id - The perpetuals program.
+    @param owner_account - The expected position owner.
+    @param position_account - The position PDA for `position_id`.
+    @param position_id - Index the position was opened under.
+    @param pool_account - The pool the position was opened in.
+    @param custody_account - The market custody the position was opened in.
+    */
+    fn load_position(
+        program_id: &Pubkey,
+        owner_account: &AccountInfo,
+        position_account: &AccountInfo,
+        position_id: u64,
+        pool_account: &AccountInfo,
+        custody_account: &AccountInfo,
+    ) -> Result<Position, ProgramError> {
+        let position =
+            Self::load_owned_position(program_id, owner_account, position_account, position_id)?;
+        if position.pool != *pool_account.key || position.custody != *custody_account.key {
+            return Err(PerpsError::CustodyMismatch.into());
+        }
+
+        Ok(position)
+    }
+
+    /*
+    @name load_owned_position
+    @description Loads an open position PDA and checks that it belongs to the owner, whatever its market.
+    @param program_id - The perpetuals program.
+    @param owner_account - The expected position owner.
+    @param position_account - The position PDA for `position_id`.
+    @param position_id - Index the position was opened under.
+    */
+    fn load_owned_position(
+        program_id: &Pubkey,
+        owner_account: &AccountInfo,
+        position_account: &AccountInfo,
+        position_id: u64,
+    ) -> Result<Position, ProgramError> {
+        let (position_pda, _) = Pubkey::find_program_address(
+            &[
+                b"position",
+                owner_account.key.as_ref(),
+                &position_id.to_le_bytes(),
+            ],
+            program_id,
+        );
+        if position_account.key != &position_pda {
+            return Err(PerpsError::InvalidPda.into());
+        }
+
+        if position_account.owner != program_id || position_account.data_is_empty() {
+            return Err(PerpsError::PositionNotFound.into());
+        }
+
+        let data = position_account.try_borrow_data()?;
+        let position =
+            Position::deserialize(&mut &data[..]).map_err(|_| PerpsError::InvalidPositionData)?;
+
+        if position.owner != *owner_account.key {
+            return Err(PerpsError::Unauthorized.into());
+        }
+        if position.side == Side::None {
+            return Err(PerpsError::PositionNotFound.into());
+        }
+
+        Ok(position)
+    }
+
+    /*
+    @name reduce_position
+    @description Settles a decrease of a position at the given price: the whole size closes it, a partial decrease realizes PnL in proportion and must leave the rest within max leverage.
+    @param position - The open position, updated in place.
+    @param pool - The position's pool, whose open interest is reduced.
+    @param custody - The position's market custody, with funding and interest accrued.
+    @param price - Current oracle price, scaled by PRICE_SCALE.
+    @param size_usd - Size to close. Anything above the position's size closes it.
+    @param withdraw_collateral - Whether a partial decrease releases its share of the collateral.
+    @return Tokens owed to the owner, at most the position's collateral and the tokens it unlocks.
+    */
+    fn reduce_position(
+        position: &mut Position,
+        pool: &mut Pool,
+        custody: &mut Custody,
+        price: u64,
+        size_usd: u64,
+        withdraw_collateral: bool,
+    ) -> Result<u64, ProgramError> {
+        let size_usd = size_usd.min(position.size_usd);
+        let closes = size_usd == position.size_usd;
+        let collateral_before = position.collateral_amount;
+
+        let (payout, unlocked) = if closes {
+            let (profit_usd, loss_usd) = Self::position_pnl(position, custody, price)?;
+            let profit = math::usd_to_token(profit_usd, custody.decimals, price)
+                .ok_or(PerpsError::MathOverflow)?;
+            let loss = math::usd_to_token_ceil(loss_usd, custody.decimals, price)
+                .ok_or(PerpsError::MathOverflow)?;
+
+            // Losses beyond the collateral stay with the custody
+            let payout = position
+                .collateral_amount
+                .checked_add(profit)
+                .ok_or(PerpsError::MathOverflow)?
+                .saturating_sub(loss);
+            msg!(
+                "ClosePosition: price {}, profit_usd {}, loss_usd {}, payout {}",
+                price,
+                profit_usd,
+                loss_usd,
+                payout
+            );
+
+            position.collateral_amount = 0;
+            (payout, position.locked_amount)
+        } else {
+            Self::settle_funding(position, custody, price)?;
+            Self::settle_interest(position, custody, price)?;
+
+            // Realize the decreased share of the price PnL
+            let (profit_usd, loss_usd) = Self::position_pnl(position, custody, price)?;
+            let realized_profit_usd = math::pro_rata(profit_usd, size_usd, position.size_usd)
+                .ok_or(PerpsError::MathOverflow)?;
+            let realized_loss_usd = math::pro_rata_ceil(loss_usd, size_usd, position.size_usd)
+                .ok_or(PerpsError::MathOverflow)?;
+            let profit = math::usd_to_token(realized_profit_usd, custody.decimals, price)
+                .ok_or(PerpsError::MathOverflow)?;
+            let loss = math::usd_to_token_ceil(realized_loss_usd, custody.decimals, price)
+                .ok_or(PerpsError::MathOverflow)?;
+
+            let released = if withdraw_collateral {
+                math::pro_rata(position.collateral_amount, size_usd, position.size_usd)
+                    .ok_or(PerpsError::MathOverflow)?
+            } else {
+                0
+            };
+
+            // The realized loss is paid from the released collateral and profit first, then from what stays
+            let gross = released
+                .checked_add(profit)
+                .ok_or(PerpsError::MathOverflow)?;
+            let payout = gross.saturating_sub(loss);
+            position.collateral_amount =
+                (position.collateral_amount - released).saturating_sub(loss.saturating_sub(gross));
+            msg!(
+                "DecreasePosition: price {}, realized profit_usd {}, loss_usd {}, released {}, payout {}",
+                price,
+                realized_profit_usd,
+                realized_loss_usd,
+                released,
+                payout
+            );
+
+            let unlocked = math::pro_rata(position.locked_amount, size_usd, position.size_usd)
+                .ok_or(PerpsError::MathOverflow)?;
+            (payout, unlocked)
+        };
+
+        // The close fee comes out of what the owner receives
+        let fee = math::fee_amount(
+            size_usd,
+            custody.fees.close_position_bps,
+            custody.decimals,
+            price,
+        )
+        .ok_or(PerpsError::MathOverflow)?
+        .min(payout);
+        custody.charge_fee(fee)?;
+        let payout = payout - fee;
+        msg!("Close fee {}, payout after fee {}", fee, payout);
+
+        pool.remove_open_interest(position.side, size_usd)?;
+        custody.remove_open_interest(position.side, size_usd)?;
+        custody.unlock(position.side, unlocked)?;
+        custody.update_collateral(collateral_before, position.collateral_amount)?;
+
+        position.size_usd -= size_usd;
+        position.locked_amount -= unlocked;
+        position.collateral_usd =
+            math::token_to_usd(position.collateral_amount, custody.decimals, price)
+                .ok_or(PerpsError::MathOverflow)?;
+        position.borrow_size_usd = position.size_usd.saturating_sub(position.collateral_usd);
+        position.update_time = Clock::get()?.unix_timestamp;
+
+        // What stays open must still cover the initial margin, as after RemoveCollateral
+        if position.size_usd > 0 {
+            let (profit_usd, loss_usd) = Self::position_pnl(position, custody, price)?;
+            let equity_usd = math::equity_usd(position.collateral_usd, profit_usd, loss_usd);
+            if math::exceeds_leverage(position.size_usd, equity_usd, custody.max_leverage_bps) {
+                msg!(
+                    "DecreasePosition: remaining size_usd {} over equity_usd {} exceeds max leverage",
+                    position.size_usd,
+                    equity_usd
+                );
+                return Err(PerpsError::InsufficientMargin.into());
+            }
+        }
+
+        // Only the position's own collateral and locked tokens back its payout
+        let backing = collateral_before
+            .checked_add(unlocked)
+            .ok_or(PerpsError::MathOverflow)?;
+        if payout > backing {
+            msg!(
+                "Error: payout {} exceeds the position's collateral and locked tokens {}",
+                payout,
+                backing
+            );
+            return Err(PerpsError::InsufficientLiquidity.into());
+        }
+
+        Ok(payout)
+    }
+
+    /*
+    @name finish_position
+    @description Stores a position that is still open, or closes a fully decreased one and frees its UserPositions slot.
+    @param program_id - The perpetuals program.
+    @param owner_account - The position owner, receives the rent refund.
+    @param position_account - The position PDA.
+    @param user_positions_account - The owner's UserPositions PDA.
+    @param position_id - Id the position was opened under.
+    @param position - The position after the decrease.
+    */
+    fn finish_position(
+        program_id: &Pubkey,
+        owner_account: &AccountInfo,
+        position_account: &AccountInfo,
+        user_positions_account: &AccountInfo,
+        position_id: u64,
+        position: &Position,
+    ) -> ProgramResult {
+        if position.size_usd > 0 {
+            return Self::store_position(position_account, position);
+        }
+
+        let mut user_positions =
+            Self::load_user_positions(program_id, owner_account, user_positions_account)?;
+        user_positions.remove(position_id)?;
+        Self::store_user_positions(user_positions_account, &user_positions)?;
+        Self::close_account(position_account, owner_account)
+    }
+
+    /*
+    @name load_user_positions
+    @description Loads the owner's UserPositions PDA.
+    @param program_id - The perpetuals program.
+    @param owner_account - The owner the account is seeded by.
+    @param user_positions_account - The UserPositions PDA.
+    */
+    fn load_user_positions(
+        program_id: &Pubkey,
+        owner_account: &AccountInfo,
+        user_positions_account: &AccountInfo,
+    ) -> Result<UserPositions, ProgramError> {
+        let (user_positions_pda, _) = Pubkey::find_program_address(
+            &[b"user_positions", owner_account.key.as_ref()],
+            program_id,
+        );
+        if user_positions_account.key != &user_positions_pda {
+            return Err(PerpsError::InvalidPda.into());
+        }
+        if user_positions_account.owner != program_id || user_positions_account.data_is_empty() {
+            return Err(PerpsError::InvalidUserPositionsData.into());
+        }
+
+        let data = user_positions_account.try_borrow_data()?;
+        let user_positions = UserPositions::deserialize(&mut &data[..])
+            .map_err(|_| PerpsError::InvalidUserPositionsData)?;
+        if user_positions.owner != *owner_account.key {
+            return Err(PerpsError::Unauthorized.into());
+        }
+        Ok(user_positions)
+    }
+
+    /*
+    @name store_user_positions
+    @description Writes a UserPositions account back into its PDA.
+    @param user_positions_account - The UserPositions PDA.
+    @param user_positions - The updated account.
+    */
+    fn store_user_positions(
+        user_positions_account: &AccountInfo,
+        user_positions: &UserPositions,
+    ) -> ProgramResult {
+        let mut data = user_positions_account.try_borrow_mut_data()?;
+        user_positions.serialize(&mut &mut data[..])?;
+        Ok(())
+    }
+
+    /*
+    @name position_pnl
+    @description Unrealized (profit_usd, loss_usd) of a position at the given price, net of unsettled funding and borrow interest.
+    @param position - The open position.
+    @param custody - The position's market custody, with funding and interest accrued.
+    @param price - Current oracle price, scaled by PRICE_SCALE.
+    */
+    fn position_pnl(
+        position: &Position,
+        custody: &Custody,
+        price: u64,
+    ) -> Result<(u64, u64), ProgramError> {
+        let (profit_usd, loss_usd) =
+            math::calculate_pnl(position.side, position.size_usd, position.price, price)
+                .ok_or(PerpsError::InvalidPositionData)?;
+        let funding_usd = Self::position_funding(position, custody)?;
+        let interest_usd = Self::position_interest(position, custody)?;
+        let owed_usd = funding_usd
+            .checked_add(interest_usd as i128)
+            .ok_or(PerpsError::MathOverflow)?;
+        Ok(math::apply_funding(profit_usd, loss_usd, owed_usd).ok_or(PerpsError::MathOverflow)?)
+    }
+
+    /*
+    @name position_funding
+    @description Funding a position owes (positive) or is owed (negative) since it last settled.
+    @param position - The open position.
+    @param custody - The position's market custody, with funding accrued.
+    */
+    fn position_funding(position: &Position, custody: &Custody) -> Result<i128, ProgramError> {
+        let funding_index = custody.funding_index(position.side)?;
+        Ok(math::funding_usd(
+            position.size_usd,
+            position.cumulative_funding_snapshot,
+            funding_index,
+        )
+        .ok_or(PerpsError::MathOverflow)?)
+    }
+
+    /*
+    @name settle_funding
+    @description Realizes a position's accrued funding against its collateral and moves its snapshot to the current index.
+    @param position - The open position.
+    @param custody - The position's market custody, with funding accrued.
+    @param price - Current oracle price, scaled by PRICE_SCALE.
+    */
+    fn settle_funding(position: &mut Position, custody: &Custody, price: u64) -> ProgramResult {
+        let funding_usd = Self::position_funding(position, custody)?;
+        let funding_abs =
+            u64::try_from(funding_usd.unsigned_abs()).map_err(|_| PerpsError::MathOverflow)?;
+        if funding_usd > 0 {
+            let owed = math::usd_to_token_ceil(funding_abs, custody.decimals, price)
+                .ok_or(PerpsError::MathOverflow)?;
+            position.collateral_amount = position.collateral_amount.saturating_sub(owed);
+        } else if funding_usd < 0 {
+            let received = math::usd_to_token(funding_abs, custody.decimals, price)
+                .ok_or(PerpsError::MathOverflow)?;
+            position.collateral_amount = position
+                .collateral_amount
+                .checked_add(received)
+                .ok_or(PerpsError::MathOverflow)?;
+        }
+
+        position.cumulative_funding_snapshot = custody.funding_index(position.side)?;
+        Ok(())
+    }
+
+    /*
+    @name position_interest
+    @description Borrow interest a position owes since it last settled.
+    @param position - The open position.
+    @param custody - The position's market custody, with interest accrued.
+    */
+    fn position_interest(position: &Position, custody: &Custody) -> Result<u64, ProgramError> {
+        Ok(math::interest_usd(
+            position.borrow_size_usd,
+            position.cumulative_interest_snapshot,
+            custody.cumulative_interest,
+        )
+        .ok_or(PerpsError::MathOverflow)?)
+    }
+
+    /*
+    @name settle_interest
+    @description Charges a position's accrued borrow interest to its collateral and moves its snapshot to the current index.
+    @param position - The open position.
+    @param custody - The position's market custody, with interest accrued.
+    @param price - Current oracle price, scaled by PRICE_SCALE.
+    */
+    fn settle_interest(position: &mut Position, custody: &Custody, price: u64) -> ProgramResult {
+        let interest_usd = Self::position_interest(position, custody)?;
+        let interest = math::usd_to_token_ceil(interest_usd, custody.decimals, price)
+            .ok_or(PerpsError::MathOverflow)?;
+        position.collateral_amount = position.collateral_amount.saturating_sub(interest);
+        position.cumulative_interest_snapshot = custody.cumulative_interest;
+        Ok(())
+    }
+
+    /*
+    @name store_position
+    @description Writes a position back into its PDA.
+    @param position_account - The position PDA.
+    @param position - The updated position.
+    */
+    fn store_position(position_account: &AccountInfo, position: &Position) -> ProgramResult {
+        let mut data = position_account.try_borrow_mut_data()?;
+        position.serialize(&mut &mut data[..])?;
+        Ok(())
+    }
+
+    /*
+    @name transfer_from_custody
+    @description Transfers tokens out of a market's custody, signed by the custody PDA.
+    @param custody - The loaded custody.
+    @param custody_account - The custody PDA, authority of the token account.
+    @param custody_token_account - The custody token account.
+    @param destination - The token account receiving the tokens.
+    @param spl_account - The token program.
+    @param amount - The amount to transfer. Nothing is sent for zero.
+    */
+    fn transfer_from_custody<'a>(
+        custody: &Custody,
+        custody_account: &AccountInfo<'a>,
+        custody_token_account: &AccountInfo<'a>,
+        destination: &AccountInfo<'a>,
+        spl_account: &AccountInfo<'a>,
+        amount: u64,
+    ) -> ProgramResult {
+        if amount == 0 {
+            return Ok(());
+        }
+
+        invoke_signed(
+            &spl_token::instruction::transfer(
+                spl_account.key,
+                custody_token_account.key,
+                destination.key,
+                custody_account.key,
+                &[],
+                amount,
+            )?,
+            &[
+                custody_token_account.clone(),
+                destination.clone(),
+                custody_account.clone(),
+                spl_account.clone(),
+            ],
+            &[&custody.seeds(&[custody.bump])],
+        )
+    }
+
+    /*
+    @name release_escrow
+    @description Moves a limit order's escrowed collateral out and closes the escrow token account, signed by the order PDA.
+    @param order - The loaded limit order.
+    @param order_account - The limit order PDA, authority of the escrow.
+    @param escrow_account - The escrow token account.
+    @param destination - The token account receiving the collateral.
+    @param rent_destination - The account receiving the escrow's rent.
+    @param spl_account - The token program.
+    */
+    fn release_escrow<'a>(
+        order: &LimitOrder,
+        order_account: &AccountInfo<'a>,
+        escrow_account: &AccountInfo<'a>,
+        destination: &AccountInfo<'a>,
+        rent_destination: &AccountInfo<'a>,
+        spl_account: &AccountInfo<'a>,
+    ) -> ProgramResult {
+        let order_id = order.order_id.to_le_bytes();
+        let bump = [order.bump];
+        let seeds = LimitOrder::seeds(&order.owner, &order_id, &bump);
+
+        invoke_signed(
+            &spl_token::instruction::transfer(
+                spl_account.key,
+                escrow_account.key,
+                destination.key,
+                order_account.key,
+                &[],
+                order.collateral_amount,
+            )?,
+            &[
+                escrow_account.clone(),
+                destination.clone(),
+                order_account.clone(),
+                spl_account.clone(),
+            ],
+            &[&seeds],
+        )?;
+        invoke_signed(
+            &spl_token::instruction::close_account(
+                spl_account.key,
+                escrow_account.key,
+                rent_destination.key,
+                order_account.key,
+                &[],
+            )?,
+            &[
+                escrow_account.clone(),
+                rent_destination.clone(),
+                order_account.clone(),
+                spl_account.clone(),
+            ],
+            &[&seeds],
+        )
+    }
+
+    /*
+    @name check_insurance_fund
+    @description Verifies the insurance fund PDA for the collateral mint. The fund may not exist yet.
+    @param program_id - The perpetuals program.
+    @param insurance_fund_account - The insurance fund token account.
+    @param mint - The collateral mint the fund is seeded by.
+    @return The insurance fund PDA bump, used to sign transfers out of the fund.
+    */
+    fn check_insurance_fund(
+        program_id: &Pubkey,
+        insurance_fund_account: &AccountInfo,
+        mint: &Pubkey,
+    ) -> Result<u8, ProgramError> {
+        let (insurance_fund_pda, insurance_fund_bump) =
+            Pubkey::find_program_address(&[b"insurance_fund", mint.as_ref()], program_id);
+        if insurance_fund_account.key != &insurance_fund_pda {
+            return Err(PerpsError::InvalidPda.into());
+        }
+        Ok(insurance_fund_bump)
+    }
+
+    /*
+    @name check_token_account
+    @description Verifies that a token account holds the market's mint and belongs to the expected owner.
+    @param token_account - The token account to check.
+    @param mint - The market mint.
+    @param owner - The expected token account owner.
+    */
+    fn check_token_account(
+        token_account: &AccountInfo,
+        mint: &Pubkey,
+        owner: &Pubkey,
+    ) -> ProgramResult {
+        let account = TokenAccount::unpack(&token_account.try_borrow_data()?)?;
+        if account.mint != *mint || account.owner != *owner {
+            return Err(PerpsError::TokenAccountMismatch.into());
+        }
+        Ok(())
+    }
+
+    /*
+    @name close_account
+    @description Closes a program-owned account, refunding its rent to the destination.
+    @param account - The account to close.
+    @param destination - The account receiving the lamports.
+    */
+    fn close_account(account: &AccountInfo, destination: &AccountInfo) -> ProgramResult {
+        let lamports = account.lamports();
+        **destination.try_borrow_mut_lamports()? = destination
+            .lamports()
+            .checked_add(lamports)
+            .ok_or(PerpsError::MathOverflow)?;
+        **account.try_borrow_mut_lamports()? = 0;
+
+        account.realloc(0, false)?;
+        account.assign(&solana_program::system_program::id());
         Ok(())
     }
 }
@@ -0,0 +1,109 @@
+use crate::oracle::{OracleType, TestOracle};
+use crate::state::pool::{BorrowRateParams, FeeParams, OpenInterestParams, PoolParams};
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::program_error::ProgramError;
+
+#[derive(Clone, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
+pub enum PoolInstruction {
+    InitPool {
+        params: PoolParams,
+    },
+    AddMarket {
+        oracle_type: OracleType,
+        max_price_age_sec: u64,
+        max_price_error_bps: u64,
+        borrow_rate: BorrowRateParams,
+        fees: FeeParams,
+        open_interest: OpenInterestParams,
+        max_leverage_bps: u64,
+    },
+    SetTestOracle {
+        oracle: TestOracle,
+    },
+    UpdateFunding,
+    AddLiquidity {
+        amount: u64,        // Tokens deposited, in base units of the market mint
+        min_lp_amount: u64, // Fewest LP tokens accepted
+    },
+    RemoveLiquidity {
+        lp_amount: u64,      // LP tokens burned
+        min_amount_out: u64, // Fewest tokens accepted
+    },
+    CollectFees,
+}
+
+impl PoolInstruction {
+    pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
+        let (&tag, rest) = input
+            .split_first()
+            .ok_or(ProgramError::InvalidInstructionData)?;
+
+        Ok(match tag {
+            0 => {
+                let params = PoolParams {
+                    maintenance_margin_bps: Self::unpack_u64(rest)?,
+                    liquidation_fee_bps: Self::unpack_u64(rest.get(8..).unwrap_or_default())?,
+                    max_funding_rate_bps: Self::unpack_u64(rest.get(16..).unwrap_or_default())?,
+                    keeper_fee_bps: Self::unpack_u64(rest.get(24..).unwrap_or_default())?,
+                };
+                Self::InitPool { params }
+            }
+            1 => {
+                let oracle_type = match rest.first() {
+                    Some(0) => OracleType::Test,
+                    Some(1) => OracleType::Pyth,
+                    _ => return Err(ProgramError::InvalidInstructionData),
+                };
+                Self::AddMarket {
+                    oracle_type,
+                    max_price_age_sec: Self::unpack_u64(rest.get(1..).unwrap_or_default())?,
+                    max_price_error_bps: Self::unpack_u64(rest.get(9..).unwrap_or_default())?,
+                    borrow_rate: BorrowRateParams {
+                        base_rate_bps: Self::unpack_u64(rest.get(17..).unwrap_or_default())?,
+                        slope1_bps: Self::unpack_u64(rest.get(25..).unwrap_or_default())?,
+                        slope2_bps: Self::unpack_u64(rest.get(33..).unwrap_or_default())?,
+                        optimal_utilization_bps: Self::unpack_u64(
+                            rest.get(41..).unwrap_or_default(),
+                        )?,
+                    },
+                    fees: FeeParams {
+                        open_position_bps: Self::unpack_u64(rest.get(49..).unwrap_or_default())?,
+                        close_position_bps: Self::unpack_u64(rest.get(57..).unwrap_or_default())?,
+                        protocol_share_bps: Self::unpack_u64(rest.get(65..).unwrap_or_default())?,
+                    },
+                    open_interest: OpenInterestParams {
+                        max_open_interest_usd: Self::unpack_u64(
+                            rest.get(73..).unwrap_or_default(),
+                        )?,
+                        max_skew_bps: Self::unpack_u64(rest.get(81..).unwrap_or_default())?,
+                    },
+                    max_leverage_bps: Self::unpack_u64(rest.get(89..).unwrap_or_default())?,
+                }
+            }
+            2 => {
+                let oracle = TestOracle::try_from_slice(rest)
+                    .map_err(|_| ProgramError::InvalidInstructionData)?;
+                Self::SetTestOracle { oracle }
+            }
+            3 => Self::UpdateFunding,
+            4 => Self::AddLiquidity {
+                amount: Self::unpack_u64(rest)?,
+                min_lp_amount: Self::unpack_u64(rest.get(8..).unwrap_or_default())?,
+            },
+            5 => Self::RemoveLiquidity {
+                lp_amount: Self::unpack_u64(rest)?,
+                min_amount_out: Self::unpack_u64(rest.get(8..).unwrap_or_default())?,
+            },
+            6 => Self::CollectFees,
+            _ => return Err(ProgramError::InvalidInstructionData),
+        })
+    }
+
+    fn unpack_u64(input: &[u8]) -> Result<u64, ProgramError> {
+        input
+            .get(..8)
+            .and_then(|slice| slice.try_into().ok())
+            .map(u64::from_le_bytes)
+            .ok_or(ProgramError::InvalidInstructionData)
+    }
+}
@@ -0,0 +1,3 @@
+pub mod instruction;
+pub mod processor;
+pub use {instruction::*, processor::*};
@@ -0,0 +1,840 @@
+use crate::error::PerpsError;
+use crate::instructions::pool::PoolInstruction;
+use crate::math::{self, BPS_POWER};
+use crate::oracle::{get_oracle_price, OracleParams, OracleType, TestOracle, TEST_ORACLE_SEED};
+use crate::state::pool::{
+    BorrowRateParams, Custody, FeeParams, OpenInterestParams, Pool, PoolParams, CUSTODY_SEED,
+    CUSTODY_TOKEN_ACCOUNT_SEED, LP_DECIMALS, LP_MINT_SEED, MAX_CUSTODIES, POOL_SEED,
+};
+use borsh::BorshSerialize;
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    clock::Clock,
+    entrypoint::ProgramResult,
+    msg,
+    program::{invoke, invoke_signed},
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction,
+    sysvar::Sysvar,
+};
+use spl_token::state::{Account as TokenAccount, Mint};
+
+pub struct Processor;
+
+impl Processor {
+    pub fn process(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        instruction_data: &[u8],
+    ) -> ProgramResult {
+        let instruction = PoolInstruction::unpack(instruction_data)?;
+
+        match instruction {
+            PoolInstruction::InitPool { params } => {
+                Self::process_init_pool(program_id, accounts, params)
+            }
+            PoolInstruction::AddMarket {
+                oracle_type,
+                max_price_age_sec,
+                max_price_error_bps,
+                borrow_rate,
+                fees,
+                open_interest,
+                max_leverage_bps,
+            } => Self::process_add_market(
+                program_id,
+                accounts,
+                oracle_type,
+                max_price_age_sec,
+                max_price_error_bps,
+                borrow_rate,
+                fees,
+                open_interest,
+                max_leverage_bps,
+            ),
+            PoolInstruction::SetTestOracle { oracle } => {
+                Self::process_set_test_oracle(program_id, accounts, oracle)
+            }
+            PoolInstruction::UpdateFunding => Self::process_update_funding(program_id, accounts),
+            PoolInstruction::AddLiquidity {
+                amount,
+                min_lp_amount,
+            } => Self::process_add_liquidity(program_id, accounts, amount, min_lp_amount),
+            PoolInstruction::RemoveLiquidity {
+                lp_amount,
+                min_amount_out,
+            } => Self::process_remove_liquidity(program_id, accounts, lp_amount, min_amount_out),
+            PoolInstruction::CollectFees => Self::process_collect_fees(program_id, accounts),
+        }
+    }
+
+    fn process_init_pool(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        params: PoolParams,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let admin_account = next_account_info(account_info_iter)?; // Pool admin, pays for the pool
+        let pool_account = next_account_info(account_info_iter)?; // Pool account (PDA)
+        let lp_mint_account = next_account_info(account_info_iter)?; // LP token mint (PDA)
+        let spl_account = next_account_info(account_info_iter)?; // Token program
+        let system_program = next_account_info(account_info_iter)?; // System program
+        let rent_account = next_account_info(account_info_iter)?; // Rent sysvar
+
+        if !admin_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if !params.is_valid() {
+            return Err(PerpsError::InvalidPoolParams.into());
+        }
+
+        let (pool_pda, pool_bump) =
+            Pubkey::find_program_address(&[POOL_SEED, admin_account.key.as_ref()], program_id);
+        let (lp_mint_pda, lp_mint_bump) =
+            Pubkey::find_program_address(&[LP_MINT_SEED, pool_account.key.as_ref()], program_id);
+        if pool_account.key != &pool_pda || lp_mint_account.key != &lp_mint_pda {
+            return Err(PerpsError::InvalidPda.into());
+        }
+        if !pool_account.data_is_empty() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        msg!(
+            "InitPool: admin {:?}, params {:?}",
+            admin_account.key,
+            params
+        );
+
+        let rent = &Rent::from_account_info(rent_account)?;
+        invoke_signed(
+            &system_instruction::create_account(
+                admin_account.key,
+                pool_account.key,
+                rent.minimum_balance(Pool::LEN),
+                Pool::LEN as u64,
+                program_id,
+            ),
+            &[
+                admin_account.clone(),
+                pool_account.clone(),
+                system_program.clone(),
+            ],
+            &[&[POOL_SEED, admin_account.key.as_ref(), &[pool_bump]]],
+        )?;
+
+        invoke_signed(
+            &system_instruction::create_account(
+                admin_account.key,
+                lp_mint_account.key,
+                rent.minimum_balance(Mint::LEN),
+                Mint::LEN as u64,
+                &spl_token::id(),
+            ),
+            &[
+                admin_account.clone(),
+                lp_mint_account.clone(),
+                system_program.clone(),
+            ],
+            &[&[LP_MINT_SEED, pool_account.key.as_ref(), &[lp_mint_bump]]],
+        )?;
+
+        // The pool PDA is the mint authority, so LP tokens are only minted against deposits
+        invoke(
+            &spl_token::instruction::initialize_mint(
+                spl_account.key,
+                lp_mint_account.key,
+                pool_account.key,
+                None,
+                LP_DECIMALS,
+            )?,
+            &[
+                lp_mint_account.clone(),
+                spl_account.clone(),
+                rent_account.clone(),
+            ],
+        )?;
+
+        Pool {
+            admin: *admin_account.key,
+            bump: pool_bump,
+            params,
+            lp_mint: *lp_mint_account.key,
+            lp_mint_bump,
+            treasury: *admin_account.key,
+            ..Pool::default()
+        }
+        .store(pool_account)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn process_add_market(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        oracle_type: OracleType,
+        max_price_age_sec: u64,
+        max_price_error_bps: u64,
+        borrow_rate: BorrowRateParams,
+        fees: FeeParams,
+        open_interest: OpenInterestParams,
+        max_leverage_bps: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let admin_account = next_account_info(account_info_iter)?; // Pool admin, pays for the custody
+        let pool_account = next_account_info(account_info_iter)?; // Pool account (PDA)
+        let custody_account = next_account_info(account_info_iter)?; // Custody account (PDA)
+        let custody_token_account = next_account_info(account_info_iter)?; // Custody token account (PDA)
+        let mint_account = next_account_info(account_info_iter)?; // Mint traded in the market
+        let oracle_account = next_account_info(account_info_iter)?; // Price oracle for the mint
+        let spl_account = next_account_info(account_info_iter)?; // Token program
+        let system_program = next_account_info(account_info_iter)?; // System program
+        let rent_account = next_account_info(account_info_iter)?; // Rent sysvar
+
+        if !admin_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut pool = Pool::load(program_id, pool_account)?;
+        if pool.admin != *admin_account.key {
+            return Err(PerpsError::Unauthorized.into());
+        }
+        if pool.custodies.len() >= MAX_CUSTODIES {
+            return Err(PerpsError::PoolFull.into());
+        }
+
+        let (custody_pda, custody_bump) = Pubkey::find_program_address(
+            &[
+                CUSTODY_SEED,
+                pool_account.key.as_ref(),
+                mint_account.key.as_ref(),
+            ],
+            program_id,
+        );
+        let (custody_token_pda, custody_token_bump) = Pubkey::find_program_address(
+            &[
+                CUSTODY_TOKEN_ACCOUNT_SEED,
+                pool_account.key.as_ref(),
+                mint_account.key.as_ref(),
+            ],
+            program_id,
+        );
+        if custody_account.key != &custody_pda || custody_token_account.key != &custody_token_pda {
+            return Err(PerpsError::InvalidPda.into());
+        }
+        // One market per mint
+        if !custody_account.data_is_empty() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        if max_price_age_sec == 0 || max_price_error_bps == 0 || max_price_error_bps > BPS_POWER {
+            return Err(PerpsError::InvalidOracleParams.into());
+        }
+        if !borrow_rate.is_valid() {
+            return Err(PerpsError::InvalidBorrowRateParams.into());
+        }
+        if !fees.is_valid() {
+            return Err(PerpsError::InvalidFeeParams.into());
+        }
+        if !open_interest.is_valid() {
+            return Err(PerpsError::InvalidOpenInterestParams.into());
+        }
+        if !pool.params.is_valid_max_leverage(max_leverage_bps) {
+            return Err(PerpsError::InvalidMaxLeverage.into());
+        }
+        // Test markets read the oracle the admin sets through SetTestOracle
+        if oracle_type == OracleType::Test {
+            let (test_oracle_pda, _) = Pubkey::find_program_address(
+                &[
+                    TEST_ORACLE_SEED,
+                    pool_account.key.as_ref(),
+                    mint_account.key.as_ref(),
+                ],
+                program_id,
+            );
+            if oracle_account.key != &test_oracle_pda {
+                return Err(PerpsError::InvalidPda.into());
+            }
+        }
+
+        let decimals = Mint::unpack(&mint_account.try_borrow_data()?)?.decimals;
+
+        msg!(
+            "AddMarket: pool {:?}, mint {:?}, oracle {:?}, borrow rate {:?}, fees {:?}, open interest {:?}, max leverage {}",
+            pool_account.key,
+            mint_account.key,
+            oracle_account.key,
+            borrow_rate,
+            fees,
+            open_interest,
+            max_leverage_bps
+        );
+
+        let now = Clock::get()?.unix_timestamp;
+        let rent = &Rent::from_account_info(rent_account)?;
+        invoke_signed(
+            &system_instruction::create_account(
+                admin_account.key,
+                custody_account.key,
+                rent.minimum_balance(Custody::LEN),
+                Custody::LEN as u64,
+                program_id,
+            ),
+            &[
+                admin_account.clone(),
+                custody_account.clone(),
+                system_program.clone(),
+            ],
+            &[&[
+                CUSTODY_SEED,
+                pool_account.key.as_ref(),
+                mint_account.key.as_ref(),
+                &[custody_bump],
+            ]],
+        )?;
+
+        invoke_signed(
+            &system_instruction::create_account(
+                admin_account.key,
+                custody_token_account.key,
+                rent.minimum_balance(TokenAccount::LEN),
+                TokenAccount::LEN as u64,
+                &spl_token::id(),
+            ),
+            &[
+                admin_account.clone(),
+                custody_token_account.clone(),
+                system_program.clone(),
+            ],
+            &[&[
+                CUSTODY_TOKEN_ACCOUNT_SEED,
+                pool_account.key.as_ref(),
+                mint_account.key.as_ref(),
+                &[custody_token_bump],
+            ]],
+        )?;
+
+        // The custody PDA owns the token account, so only this program can move collateral
+        invoke(
+            &spl_token::instruction::initialize_account(
+                spl_account.key,
+                custody_token_account.key,
+                mint_account.key,
+                custody_account.key,
+            )?,
+            &[
+                custody_token_account.clone(),
+                mint_account.clone(),
+                custody_account.clone(),
+                spl_account.clone(),
+                rent_account.clone(),
+            ],
+        )?;
+
+        let custody = Custody {
+            pool: *pool_account.key,
+            mint: *mint_account.key,
+            token_account: *custody_token_account.key,
+            oracle: OracleParams {
+                oracle_account: *oracle_account.key,
+                oracle_type,
+                max_price_age_sec,
+                max_price_error_bps,
+            },
+            decimals,
+            bump: custody_bump,
+            token_account_bump: custody_token_bump,
+            last_funding_update: now,
+            borrow_rate,
+            last_interest_update: now,
+            fees,
+            open_interest,
+            max_leverage_bps,
+            ..Custody::default()
+        };
+        custody.store(custody_account)?;
+
+        pool.custodies.push(*custody_account.key);
+        pool.store(pool_account)
+    }
+
+    fn process_set_test_oracle(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        oracle: TestOracle,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let admin_account = next_account_info(account_info_iter)?; // Pool admin, pays for the oracle
+        let pool_account = next_account_info(account_info_iter)?; // Pool account (PDA)
+        let custody_account = next_account_info(account_info_iter)?; // Custody account (PDA)
+        let oracle_account = next_account_info(account_info_iter)?; // Test oracle account (PDA)
+        let system_program = next_account_info(account_info_iter)?; // System program
+        let rent_account = next_account_info(account_info_iter)?; // Rent sysvar
+
+        if !admin_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let pool = Pool::load(program_id, pool_account)?;
+        if pool.admin != *admin_account.key {
+            return Err(PerpsError::Unauthorized.into());
+        }
+        let custody = Custody::load(program_id, pool_account.key, custody_account)?;
+        if custody.oracle.oracle_type != OracleType::Test
+            || oracle_account.key != &custody.oracle.oracle_account
+        {
+            return Err(PerpsError::OracleMismatch.into());
+        }
+
+        msg!(
+            "SetTestOracle: custody {:?}, price {}, expo {}, conf {}, publish_time {}",
+            custody_account.key,
+            oracle.price,
+            oracle.expo,
+            oracle.conf,
+            oracle.publish_time
+        );
+
+        if oracle_account.data_is_empty() {
+            let (_, oracle_bump) = Pubkey::find_program_address(
+                &[
+                    TEST_ORACLE_SEED,
+                    pool_account.key.as_ref(),
+                    custody.mint.as_ref(),
+                ],
+                program_id,
+            );
+            let rent = &Rent::from_account_info(rent_account)?;
+            invoke_signed(
+                &system_instruction::create_account(
+                    admin_account.key,
+                    oracle_account.key,
+                    rent.minimum_balance(TestOracle::LEN),
+                    TestOracle::LEN as u64,
+                    program_id,
+                ),
+                &[
+                    admin_account.clone(),
+                    oracle_account.clone(),
+                    system_program.clone(),
+                ],
+                &[&[
+                    TEST_ORACLE_SEED,
+                    pool_account.key.as_ref(),
+                    custody.mint.as_ref(),
+                    &[oracle_bump],
+                ]],
+            )?;
+        }
+
+        let mut oracle_data = oracle_account.try_borrow_mut_data()?;
+        oracle.serialize(&mut &mut oracle_data[..])?;
+        Ok(())
+    }
+
+    fn process_update_funding(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let pool_account = next_account_info(account_info_iter)?; // Pool account (PDA)
+        let custody_account = next_account_info(account_info_iter)?; // Custody account (PDA)
+
+        let pool = Pool::load(program_id, pool_account)?;
+        let mut custody = Custody::load(program_id, pool_account.key, custody_account)?;
+
+        custody.update_funding(
+            pool.params.max_funding_rate_bps,
+            Clock::get()?.unix_timestamp,
+        )?;
+
+        msg!(
+            "UpdateFunding: custody {:?}, long OI {}, short OI {}, long index {}, short index {}",
+            custody_account.key,
+            custody.long_open_interest_usd,
+            custody.short_open_interest_usd,
+            custody.cumulative_funding_long,
+            custody.cumulative_funding_short
+        );
+
+        custody.store(custody_account)
+    }
+
+    fn process_add_liquidity(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        amount: u64,
+        min_lp_amount: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let provider_account = next_account_info(account_info_iter)?; // Liquidity provider
+        let provider_token_account = next_account_info(account_info_iter)?; // Provider's token account for the market mint
+        let provider_lp_account = next_account_info(account_info_iter)?; // Provider's LP token account
+        let pool_account = next_account_info(account_info_iter)?; // Pool account (PDA)
+        let custody_account = next_account_info(account_info_iter)?; // Custody account (PDA) receiving the deposit
+        let custody_token_account = next_account_info(account_info_iter)?; // Custody token account (PDA)
+        let lp_mint_account = next_account_info(account_info_iter)?; // LP token mint (PDA)
+        let spl_account = next_account_info(account_info_iter)?; // Token program
+        let market_accounts = account_info_iter.as_slice(); // Custody, custody token account and oracle of every market in the pool
+
+        msg!(
+            "AddLiquidity: custody {:?}, amount {}, min_lp_amount {}",
+            custody_account.key,
+            amount,
+            min_lp_amount
+        );
+
+        if !provider_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if amount == 0 {
+            return Err(PerpsError::ZeroAmount.into());
+        }
+
+        let pool = Pool::load(program_id, pool_account)?;
+        let custody = Custody::load(program_id, pool_account.key, custody_account)?;
+        Self::check_liquidity_accounts(
+            &pool,
+            &custody,
+            provider_account,
+            provider_token_account,
+            provider_lp_account,
+            custody_token_account,
+            lp_mint_account,
+        )?;
+
+        let (aum_usd, price) = Self::assets_under_management(
+            program_id,
+            pool_account,
+            &pool,
+            market_accounts,
+            custody_account.key,
+        )?;
+        let lp_supply = Mint::unpack(&lp_mint_account.try_borrow_data()?)?.supply;
+        let deposit_usd =
+            math::token_to_usd(amount, custody.decimals, price).ok_or(PerpsError::MathOverflow)?;
+        // Minting at 1:1 here would hand the new deposit to the existing LPs
+        if lp_supply > 0 && aum_usd == 0 {
+            msg!("Error: {} LP tokens are backed by no assets", lp_supply);
+            return Err(PerpsError::InsolventPool.into());
+        }
+        let lp_amount = math::lp_amount_for_deposit(deposit_usd, aum_usd, lp_supply)
+            .ok_or(PerpsError::MathOverflow)?;
+
+        msg!(
+            "AddLiquidity: aum_usd {}, lp_supply {}, deposit_usd {}, lp_amount {}",
+            aum_usd,
+            lp_supply,
+            deposit_usd,
+            lp_amount
+        );
+
+        if lp_amount == 0 {
+            return Err(PerpsError::ZeroAmount.into());
+        }
+        if lp_amount < min_lp_amount {
+            return Err(PerpsError::SlippageExceeded.into());
+        }
+
+        invoke(
+            &spl_token::instruction::transfer(
+                spl_account.key,
+                provider_token_account.key,
+                custody_token_account.key,
+                provider_account.key,
+                &[],
+                amount,
+            )?,
+            &[
+                provider_token_account.clone(),
+                custody_token_account.clone(),
+                provider_account.clone(),
+                spl_account.clone(),
+            ],
+        )?;
+        invoke_signed(
+            &spl_token::instruction::mint_to(
+                spl_account.key,
+                lp_mint_account.key,
+                provider_lp_account.key,
+                pool_account.key,
+                &[],
+                lp_amount,
+            )?,
+            &[
+                lp_mint_account.clone(),
+                provider_lp_account.clone(),
+                pool_account.clone(),
+                spl_account.clone(),
+            ],
+            &[&pool.seeds(&[pool.bump])],
+        )
+    }
+
+    fn process_remove_liquidity(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        lp_amount: u64,
+        min_amount_out: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let provider_account = next_account_info(account_info_iter)?; // Liquidity provider
+        let provider_token_account = next_account_info(account_info_iter)?; // Provider's token account for the market mint
+        let provider_lp_account = next_account_info(account_info_iter)?; // Provider's LP token account
+        let pool_account = next_account_info(account_info_iter)?; // Pool account (PDA)
+        let custody_account = next_account_info(account_info_iter)?; // Custody account (PDA) paying the withdrawal
+        let custody_token_account = next_account_info(account_info_iter)?; // Custody token account (PDA)
+        let lp_mint_account = next_account_info(account_info_iter)?; // LP token mint (PDA)
+        let spl_account = next_account_info(account_info_iter)?; // Token program
+        let market_accounts = account_info_iter.as_slice(); // Custody, custody token account and oracle of every market in the pool
+
+        msg!(
+            "RemoveLiquidity: custody {:?}, lp_amount {}, min_amount_out {}",
+            custody_account.key,
+            lp_amount,
+            min_amount_out
+        );
+
+        if !provider_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if lp_amount == 0 {
+            return Err(PerpsError::ZeroAmount.into());
+        }
+
+        let pool = Pool::load(program_id, pool_account)?;
+        let custody = Custody::load(program_id, pool_account.key, custody_account)?;
+        Self::check_liquidity_accounts(
+            &pool,
+            &custody,
+            provider_account,
+            provider_token_account,
+            provider_lp_account,
+            custody_token_account,
+            lp_mint_account,
+        )?;
+
+        let (aum_usd, price) = Self::assets_under_management(
+            program_id,
+            pool_account,
+            &pool,
+            market_accounts,
+            custody_account.key,
+        )?;
+        let lp_supply = Mint::unpack(&lp_mint_account.try_borrow_data()?)?.supply;
+        if lp_amount > lp_supply {
+            return Err(PerpsError::InsufficientLiquidity.into());
+        }
+        let withdrawal_usd = math::lp_redemption_usd(lp_amount, aum_usd, lp_supply)
+            .ok_or(PerpsError::MathOverflow)?;
+        let amount_out = math::usd_to_token(withdrawal_usd, custody.decimals, price)
+            .ok_or(PerpsError::MathOverflow)?;
+
+        // Traders' collateral, protocol fees and tokens locked for open positions stay in the custody
+        let token_balance = TokenAccount::unpack(&custody_token_account.try_borrow_data()?)?.amount;
+        let available = custody
+            .owned_amount(token_balance)
+            .saturating_sub(custody.locked_amount);
+
+        msg!(
+            "RemoveLiquidity: aum_usd {}, lp_supply {}, withdrawal_usd {}, amount_out {}, available {}",
+            aum_usd,
+            lp_supply,
+            withdrawal_usd,
+            amount_out,
+            available
+        );
+
+        if amount_out > available {
+            return Err(PerpsError::InsufficientLiquidity.into());
+        }
+        if amount_out < min_amount_out {
+            return Err(PerpsError::SlippageExceeded.into());
+        }
+
+        invoke(
+            &spl_token::instruction::burn(
+                spl_account.key,
+                provider_lp_account.key,
+                lp_mint_account.key,
+                provider_account.key,
+                &[],
+                lp_amount,
+            )?,
+            &[
+                provider_lp_account.clone(),
+                lp_mint_account.clone(),
+                provider_account.clone(),
+                spl_account.clone(),
+            ],
+        )?;
+        if amount_out == 0 {
+            return Ok(());
+        }
+        invoke_signed(
+            &spl_token::instruction::transfer(
+                spl_account.key,
+                custody_token_account.key,
+                provider_token_account.key,
+                custody_account.key,
+                &[],
+                amount_out,
+            )?,
+            &[
+                custody_token_account.clone(),
+                provider_token_account.clone(),
+                custody_account.clone(),
+                spl_account.clone(),
+            ],
+            &[&custody.seeds(&[custody.bump])],
+        )
+    }
+
+    fn process_collect_fees(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let pool_account = next_account_info(account_info_iter)?; // Pool account (PDA)
+        let custody_account = next_account_info(account_info_iter)?; // Custody account (PDA)
+        let custody_token_account = next_account_info(account_info_iter)?; // Custody token account (PDA)
+        let treasury_token_account = next_account_info(account_info_iter)?; // Treasury's token account for the market mint
+        let spl_account = next_account_info(account_info_iter)?; // Token program
+
+        // Anyone can sweep, the fees only ever go to the pool's treasury
+        let pool = Pool::load(program_id, pool_account)?;
+        let mut custody = Custody::load(program_id, pool_account.key, custody_account)?;
+        if custody_token_account.key != &custody.token_account {
+            return Err(PerpsError::CustodyMismatch.into());
+        }
+        let treasury_token = TokenAccount::unpack(&treasury_token_account.try_borrow_data()?)?;
+        if treasury_token.mint != custody.mint || treasury_token.owner != pool.treasury {
+            return Err(PerpsError::TokenAccountMismatch.into());
+        }
+
+        let amount = custody.protocol_fee_amount;
+        msg!(
+            "CollectFees: custody {:?}, amount {}",
+            custody_account.key,
+            amount
+        );
+        if amount == 0 {
+            return Ok(());
+        }
+
+        custody.protocol_fee_amount = 0;
+        custody.store(custody_account)?;
+
+        invoke_signed(
+            &spl_token::instruction::transfer(
+                spl_account.key,
+                custody_token_account.key,
+                treasury_token_account.key,
+                custody_account.key,
+                &[],
+                amount,
+            )?,
+            &[
+                custody_token_account.clone(),
+                treasury_token_account.clone(),
+                custody_account.clone(),
+                spl_account.clone(),
+            ],
+            &[&custody.seeds(&[custody.bump])],
+        )
+    }
+
+    /*
+    @name check_liquidity_accounts
+    @description Verifies the custody token account and LP mint of a pool, and that the provider's token accounts hold the right mints.
+    @param pool - The loaded pool.
+    @param custody - The loaded custody of the market deposited into or withdrawn from.
+    @param provider_account - The liquidity provider.
+    @param provider_token_account - The provider's token account for the market mint.
+    @param provider_lp_account - The provider's LP token account.
+    @param custody_token_account - The custody token account.
+    @param lp_mint_account - The LP mint.
+    */
+    fn check_liquidity_accounts(
+        pool: &Pool,
+        custody: &Custody,
+        provider_account: &AccountInfo,
+        provider_token_account: &AccountInfo,
+        provider_lp_account: &AccountInfo,
+        custody_token_account: &AccountInfo,
+        lp_mint_account: &AccountInfo,
+    ) -> ProgramResult {
+        if custody_token_account.key != &custody.token_account {
+            return Err(PerpsError::CustodyMismatch.into());
+        }
+        if lp_mint_account.key != &pool.lp_mint {
+            return Err(PerpsError::InvalidPda.into());
+        }
+
+        let provider_token = TokenAccount::unpack(&provider_token_account.try_borrow_data()?)?;
+        let provider_lp = TokenAccount::unpack(&provider_lp_account.try_borrow_data()?)?;
+        if provider_token.mint != custody.mint
+            || provider_token.owner != *provider_account.key
+            || provider_lp.mint != pool.lp_mint
+            || provider_lp.owner != *provider_account.key
+        {
+            return Err(PerpsError::TokenAccountMismatch.into());
+        }
+        Ok(())
+    }
+
+    /*
+    @name assets_under_management
+    @description Values every market of a pool at its oracle price, net of traders' unrealized PnL.
+    @param program_id - The perpetuals program.
+    @param pool_account - The pool PDA.
+    @param pool - The loaded pool.
+    @param market_accounts - Custody, custody token account and oracle of each market, in the pool's order.
+    @param custody_key - The custody whose price is returned.
+    @return The pool's AUM in USD and the price of `custody_key`, both scaled by PRICE_SCALE.
+    */
+    fn assets_under_management(
+        program_id: &Pubkey,
+        pool_account: &AccountInfo,
+        pool: &Pool,
+        market_accounts: &[AccountInfo],
+        custody_key: &Pubkey,
+    ) -> Result<(u64, u64), ProgramError> {
+        if market_accounts.len() != pool.custodies.len() * 3 {
+            return Err(PerpsError::CustodyMismatch.into());
+        }
+
+        let mut aum_usd: u64 = 0;
+        let mut custody_price = None;
+        for (pool_custody, accounts) in pool.custodies.iter().zip(market_accounts.chunks_exact(3)) {
+            let [custody_account, custody_token_account, oracle_account] = accounts else {
+                return Err(PerpsError::CustodyMismatch.into());
+            };
+            if custody_account.key != pool_custody {
+                return Err(PerpsError::CustodyMismatch.into());
+            }
+            let custody = Custody::load(program_id, pool_account.key, custody_account)?;
+            if custody_token_account.key != &custody.token_account {
+                return Err(PerpsError::CustodyMismatch.into());
+            }
+            if oracle_account.key != &custody.oracle.oracle_account {
+                return Err(PerpsError::OracleMismatch.into());
+            }
+
+            let price = get_oracle_price(program_id, oracle_account, &custody.oracle)?;
+            let token_balance =
+                TokenAccount::unpack(&custody_token_account.try_borrow_data()?)?.amount;
+            aum_usd = aum_usd
+                .checked_add(custody.assets_under_management_usd(token_balance, price)?)
+                .ok_or(PerpsError::MathOverflow)?;
+            if custody_account.key == custody_key {
+                custody_price = Some(price);
+            }
+        }
+
+        let price = custody_price.ok_or(PerpsError::CustodyMismatch)?;
+        Ok((aum_usd, price))
+    }
+}
@@ -22,6 +22,10 @@ impl Processor {
                     program_id, accounts, rest,
                 )
             }
+            1 => {
+                // Pool module
+                crate::instructions::pool::processor::Processor::process(program_id, accounts, rest)
+            }
             _ => Err(solana_program::program_error::ProgramError::InvalidInstructionData),
         }
     }
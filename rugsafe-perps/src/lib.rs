@@ -1,5 +1,8 @@
+pub mod error;
 // pub mod instruction;
 pub mod instructions;
+pub mod math;
+pub mod oracle;
 // pub mod processor;
 // pub mod instructions::
 pub mod state;
@@ -7,8 +10,10 @@ pub mod state;
 // deterministically designate program ID
 // declare_id!("FobNvbQsK5BAniZC2oJhXakjcPiArpsthTGDnX9eHDVY");
 
+use error::PerpsError;
 use solana_program::{
-    account_info::AccountInfo, entrypoint, entrypoint::ProgramResult, pubkey::Pubkey,
+    account_info::AccountInfo, entrypoint, entrypoint::ProgramResult,
+    program_error::PrintProgramError, pubkey::Pubkey,
 };
 
 entrypoint!(process_instruction);
@@ -19,5 +24,11 @@ pub fn process_instruction(
     instruction_data: &[u8],
 ) -> ProgramResult {
     // instructions::processor::Processor::process(program_id, accounts, instruction_data)
-    crate::instructions::processor::Processor::process(program_id, accounts, instruction_data)
+    if let Err(error) =
+        crate::instructions::processor::Processor::process(program_id, accounts, instruction_data)
+    {
+        error.print::<PerpsError>();
+        return Err(error);
+    }
+    Ok(())
 }
@@ -0,0 +1,410 @@
+// Fixed-point helpers for position accounting.
+//
+// USD amounts and prices carry 6 decimals (PRICE_SCALE). Token amounts are in base units of
+// the collateral mint. Intermediate products are computed in u128 so they cannot overflow.
+
+use crate::state::perpetuals::Side;
+
+pub const PRICE_SCALE: u64 = 1_000_000; // Prices and USD amounts carry 6 decimals
+pub const BPS_POWER: u64 = 10_000; // Ratios such as leverage and margins are in basis points
+
+/*
+@name token_to_usd
+@description USD value of a token amount at the given price, rounded down.
+@param amount - The token amount in base units.
+@param decimals - Decimals of the token mint.
+@param price - Token price scaled by PRICE_SCALE.
+*/
+pub fn token_to_usd(amount: u64, decimals: u8, price: u64) -> Option<u64> {
+    let value =
+        (amount as u128).checked_mul(price as u128)? / 10u128.checked_pow(decimals as u32)?;
+    u64::try_from(value).ok()
+}
+
+/*
+@name usd_to_token
+@description Token amount worth a USD value at the given price, rounded down.
+@param usd - The USD amount scaled by PRICE_SCALE.
+@param decimals - Decimals of the token mint.
+@param price - Token price scaled by PRICE_SCALE. Must be greater than zero.
+*/
+pub fn usd_to_token(usd: u64, decimals: u8, price: u64) -> Option<u64> {
+    if price == 0 {
+        return None;
+    }
+    let amount = (usd as u128).checked_mul(10u128.checked_pow(decimals as u32)?)? / price as u128;
+    u64::try_from(amount).ok()
+}
+
+/*
+@name usd_to_token_ceil
+@description Token amount worth a USD value at the given price, rounded up. Used for amounts owed to the pool.
+@param usd - The USD amount scaled by PRICE_SCALE.
+@param decimals - Decimals of the token mint.
+@param price - Token price scaled by PRICE_SCALE. Must be greater than zero.
+*/
+pub fn usd_to_token_ceil(usd: u64, decimals: u8, price: u64) -> Option<u64> {
+    if price == 0 {
+        return None;
+    }
+    let amount = (usd as u128)
+        .checked_mul(10u128.checked_pow(decimals as u32)?)?
+        .div_ceil(price as u128);
+    u64::try_from(amount).ok()
+}
+
+/*
+@name calculate_pnl
+@description Profit and loss of a position between its entry price and the exit price.
+@param side - Whether the position is long or short.
+@param size_usd - Notional size of the position scaled by PRICE_SCALE.
+@param entry_price - Price the position was opened at, scaled by PRICE_SCALE.
+@param exit_price - Current price, scaled by PRICE_SCALE.
+@return (profit_usd, loss_usd), at most one of which is non-zero, or None for an invalid side or price.
+*/
+pub fn calculate_pnl(
+    side: Side,
+    size_usd: u64,
+    entry_price: u64,
+    exit_price: u64,
+) -> Option<(u64, u64)> {
+    if entry_price == 0 || exit_price == 0 {
+        return None;
+    }
+
+    let price_delta = exit_price.abs_diff(entry_price) as u128;
+    let delta_usd = u64::try_from((size_usd as u128) * price_delta / entry_price as u128).ok()?;
+    let price_rose = exit_price > entry_price;
+
+    match side {
+        Side::Long if price_rose => Some((delta_usd, 0)),
+        Side::Long => Some((0, delta_usd)),
+        Side::Short if price_rose => Some((0, delta_usd)),
+        Side::Short => Some((delta_usd, 0)),
+        Side::None => None,
+    }
+}
+
+/*
+@name equity_usd
+@description Collateral value plus unrealized profit minus unrealized loss, floored at zero.
+@param collateral_usd - USD value of the position's collateral.
+@param profit_usd - Unrealized profit of the position.
+@param loss_usd - Unrealized loss of the position.
+*/
+pub fn equity_usd(collateral_usd: u64, profit_usd: u64, loss_usd: u64) -> u64 {
+    collateral_usd
+        .saturating_add(profit_usd)
+        .saturating_sub(loss_usd)
+}
+
+/*
+@name exceeds_leverage
+@description Whether a position's size is more than max_leverage_bps times its equity.
+@param size_usd - Notional size of the position.
+@param equity_usd - Equity backing the position.
+@param max_leverage_bps - Maximum leverage in basis points (10x = 100_000).
+*/
+pub fn exceeds_leverage(size_usd: u64, equity_usd: u64, max_leverage_bps: u64) -> bool {
+    if equity_usd == 0 {
+        return size_usd > 0;
+    }
+    (size_usd as u128) * (BPS_POWER as u128) > (equity_usd as u128) * (max_leverage_bps as u128)
+}
+
+/*
+@name exceeds_skew
+@description Whether the difference between long and short open interest is more than max_skew_bps of their total.
+@param skew_usd - Absolute difference between long and short open interest.
+@param open_interest_usd - Long plus short open interest.
+@param max_skew_bps - Largest skew allowed, as a share of the total.
+*/
+pub fn exceeds_skew(skew_usd: u64, open_interest_usd: u64, max_skew_bps: u64) -> bool {
+    (skew_usd as u128) * (BPS_POWER as u128) > (open_interest_usd as u128) * (max_skew_bps as u128)
+}
+
+/*
+@name bps_of
+@description Applies a basis-point ratio to an amount, rounded down.
+@param amount - The amount to take a share of.
+@param bps - The share in basis points.
+*/
+pub fn bps_of(amount: u64, bps: u64) -> Option<u64> {
+    u64::try_from((amount as u128) * (bps as u128) / BPS_POWER as u128).ok()
+}
+
+/*
+@name pro_rata
+@description Share of an amount in proportion to part / whole, rounded down.
+@param amount - The amount to split.
+@param part - The share's numerator.
+@param whole - The share's denominator. Must be greater than zero.
+*/
+pub fn pro_rata(amount: u64, part: u64, whole: u64) -> Option<u64> {
+    if whole == 0 {
+        return None;
+    }
+    u64::try_from((amount as u128) * (part as u128) / whole as u128).ok()
+}
+
+/*
+@name pro_rata_ceil
+@description Share of an amount in proportion to part / whole, rounded up. Used for amounts owed to the pool.
+@param amount - The amount to split.
+@param part - The share's numerator.
+@param whole - The share's denominator. Must be greater than zero.
+*/
+pub fn pro_rata_ceil(amount: u64, part: u64, whole: u64) -> Option<u64> {
+    if whole == 0 {
+        return None;
+    }
+    u64::try_from(((amount as u128) * (part as u128)).div_ceil(whole as u128)).ok()
+}
+
+/*
+@name is_liquidatable
+@description Whether a position's equity has fallen below its maintenance margin.
+@param size_usd - Notional size of the position.
+@param equity_usd - Equity backing the position.
+@param maintenance_margin_bps - Maintenance margin as a share of size, in basis points.
+*/
+pub fn is_liquidatable(size_usd: u64, equity_usd: u64, maintenance_margin_bps: u64) -> bool {
+    (equity_usd as u128) * (BPS_POWER as u128)
+        < (size_usd as u128) * (maintenance_margin_bps as u128)
+}
+
+pub const FUNDING_PRECISION: u128 = 1_000_000_000_000; // Funding indices are USD per USD of size, with 12 decimals
+pub const SECONDS_PER_HOUR: u64 = 3_600;
+
+/*
+@name funding_index_deltas
+@description Funding accrued per unit of size on each side: the heavier side pays the skew-scaled hourly rate, the lighter side shares the same total.
+@param long_open_interest_usd - Open long size in the market.
+@param short_open_interest_usd - Open short size in the market.
+@param max_funding_rate_bps - Hourly rate paid when open interest is entirely on one side.
+@param elapsed_sec - Seconds since funding was last accrued.
+@return (long_delta, short_delta) scaled by FUNDING_PRECISION. Positive deltas are owed by the side.
+*/
+pub fn funding_index_deltas(
+    long_open_interest_usd: u64,
+    short_open_interest_usd: u64,
+    max_funding_rate_bps: u64,
+    elapsed_sec: u64,
+) -> Option<(i128, i128)> {
+    // Funding moves between longs and shorts, so there is nothing to accrue without both sides
+    if long_open_interest_usd == 0 || short_open_interest_usd == 0 {
+        return Some((0, 0));
+    }
+
+    let (heavy, light) = if long_open_interest_usd >= short_open_interest_usd {
+        (long_open_interest_usd, short_open_interest_usd)
+    } else {
+        (short_open_interest_usd, long_open_interest_usd)
+    };
+    let total = (heavy as u128).checked_add(light as u128)?;
+
+    let paid = (max_funding_rate_bps as u128)
+        .checked_mul((heavy - light) as u128)?
+        .checked_mul(elapsed_sec as u128)?
+        .checked_mul(FUNDING_PRECISION)?
+        / total.checked_mul((BPS_POWER as u128) * (SECONDS_PER_HOUR as u128))?;
+    let received = paid.checked_mul(heavy as u128)? / light as u128;
+
+    let paid = i128::try_from(paid).ok()?;
+    let received = i128::try_from(received).ok()?;
+    if long_open_interest_usd >= short_open_interest_usd {
+        Some((paid, -received))
+    } else {
+        Some((-received, paid))
+    }
+}
+
+/*
+@name funding_usd
+@description Funding a position has accrued since its snapshot. Amounts owed round up, amounts received round down.
+@param size_usd - Notional size of the position.
+@param funding_snapshot - The side's funding index when the position last settled.
+@param funding_index - The side's current funding index.
+@return Funding in USD, positive when owed by the position and negative when received.
+*/
+pub fn funding_usd(size_usd: u64, funding_snapshot: i128, funding_index: i128) -> Option<i128> {
+    let delta = funding_index.checked_sub(funding_snapshot)?;
+    let scaled = (size_usd as i128).checked_mul(delta)?;
+    let precision = FUNDING_PRECISION as i128;
+    if scaled >= 0 {
+        Some(scaled.checked_add(precision - 1)? / precision)
+    } else {
+        Some(scaled / precision)
+    }
+}
+
+/*
+@name apply_funding
+@description Nets accrued funding against a position's profit and loss.
+@param profit_usd - Unrealized profit of the position.
+@param loss_usd - Unrealized loss of the position.
+@param funding_usd - Funding owed (positive) or received (negative) by the position.
+@return (profit_usd, loss_usd), at most one of which is non-zero.
+*/
+pub fn apply_funding(profit_usd: u64, loss_usd: u64, funding_usd: i128) -> Option<(u64, u64)> {
+    let net = (profit_usd as i128)
+        .checked_sub(loss_usd as i128)?
+        .checked_sub(funding_usd)?;
+    if net >= 0 {
+        Some((u64::try_from(net).ok()?, 0))
+    } else {
+        Some((0, u64::try_from(net.checked_neg()?).ok()?))
+    }
+}
+
+pub const INTEREST_PRECISION: u128 = 1_000_000_000_000; // Interest indices are USD per USD borrowed, with 12 decimals
+pub const SECONDS_PER_YEAR: u64 = 31_536_000;
+
+/*
+@name utilization_bps
+@description Share of a market's tokens locked by open positions, capped at 100%.
+@param locked_amount - Tokens reserved for open position sizes.
+@param owned_amount - Tokens held by the market's custody.
+*/
+pub fn utilization_bps(locked_amount: u64, owned_amount: u64) -> u64 {
+    if locked_amount == 0 {
+        return 0;
+    }
+    if locked_amount >= owned_amount {
+        return BPS_POWER;
+    }
+    ((locked_amount as u128) * (BPS_POWER as u128) / owned_amount as u128) as u64
+}
+
+/*
+@name borrow_rate_bps
+@description Yearly borrow rate at a utilization: base plus slope1 up to the optimal utilization, plus slope2 beyond it.
+@param base_rate_bps - Rate at zero utilization.
+@param slope1_bps - Rate added between zero and the optimal utilization.
+@param slope2_bps - Rate added between the optimal utilization and 100%.
+@param optimal_utilization_bps - Utilization at which slope2 takes over.
+@param utilization_bps - Current utilization, at most BPS_POWER.
+*/
+pub fn borrow_rate_bps(
+    base_rate_bps: u64,
+    slope1_bps: u64,
+    slope2_bps: u64,
+    optimal_utilization_bps: u64,
+    utilization_bps: u64,
+) -> Option<u64> {
+    let rate = if utilization_bps <= optimal_utilization_bps {
+        if optimal_utilization_bps == 0 {
+            0
+        } else {
+            (slope1_bps as u128) * (utilization_bps as u128) / optimal_utilization_bps as u128
+        }
+    } else {
+        let excess = (utilization_bps - optimal_utilization_bps) as u128;
+        let range = BPS_POWER.checked_sub(optimal_utilization_bps)? as u128;
+        (slope1_bps as u128).checked_add((slope2_bps as u128) * excess / range)?
+    };
+    u64::try_from((base_rate_bps as u128).checked_add(rate)?).ok()
+}
+
+/*
+@name interest_index_delta
+@description Interest accrued per unit of borrowed size at a yearly rate.
+@param borrow_rate_bps - Yearly borrow rate.
+@param elapsed_sec - Seconds since interest was last accrued.
+@return The delta scaled by INTEREST_PRECISION.
+*/
+pub fn interest_index_delta(borrow_rate_bps: u64, elapsed_sec: u64) -> Option<u128> {
+    Some(
+        (borrow_rate_bps as u128)
+            .checked_mul(elapsed_sec as u128)?
+            .checked_mul(INTEREST_PRECISION)?
+            / ((BPS_POWER as u128) * (SECONDS_PER_YEAR as u128)),
+    )
+}
+
+/*
+@name interest_usd
+@description Borrow interest a position owes since its snapshot, rounded up.
+@param borrow_size_usd - The position's size not backed by its collateral.
+@param interest_snapshot - The market's interest index when the position last settled.
+@param interest_index - The market's current interest index.
+*/
+pub fn interest_usd(
+    borrow_size_usd: u64,
+    interest_snapshot: u128,
+    interest_index: u128,
+) -> Option<u64> {
+    let delta = interest_index.checked_sub(interest_snapshot)?;
+    let interest = (borrow_size_usd as u128)
+        .checked_mul(delta)?
+        .div_ceil(INTEREST_PRECISION);
+    u64::try_from(interest).ok()
+}
+
+/*
+@name traders_pnl_usd
+@description Unrealized price PnL of all positions in a market, from their sizes and the tokens locked for them at entry.
+@param long_open_interest_usd - Total size of open longs.
+@param long_locked_usd - Current value of the tokens locked for longs.
+@param short_open_interest_usd - Total size of open shorts.
+@param short_locked_usd - Current value of the tokens locked for shorts.
+@return Positive when traders are in profit, which the pool owes them.
+*/
+pub fn traders_pnl_usd(
+    long_open_interest_usd: u64,
+    long_locked_usd: u64,
+    short_open_interest_usd: u64,
+    short_locked_usd: u64,
+) -> i128 {
+    (long_locked_usd as i128 - long_open_interest_usd as i128)
+        + (short_open_interest_usd as i128 - short_locked_usd as i128)
+}
+
+/*
+@name lp_amount_for_deposit
+@description LP tokens minted for a deposit: one per micro-dollar while no LP tokens exist, otherwise the deposit's share of the pool's AUM. None if LP tokens exist but the AUM is zero, since any amount minted would be backed by the new deposit alone.
+@param deposit_usd - Value of the deposit, scaled by PRICE_SCALE.
+@param aum_usd - Pool assets under management before the deposit.
+@param lp_supply - LP tokens in circulation before the deposit.
+*/
+pub fn lp_amount_for_deposit(deposit_usd: u64, aum_usd: u64, lp_supply: u64) -> Option<u64> {
+    if lp_supply == 0 {
+        return Some(deposit_usd);
+    }
+    pro_rata(deposit_usd, lp_supply, aum_usd)
+}
+
+/*
+@name lp_redemption_usd
+@description Value paid out for burning LP tokens, their share of the pool's AUM rounded down.
+@param lp_amount - LP tokens burned.
+@param aum_usd - Pool assets under management before the withdrawal.
+@param lp_supply - LP tokens in circulation before the withdrawal.
+*/
+pub fn lp_redemption_usd(lp_amount: u64, aum_usd: u64, lp_supply: u64) -> Option<u64> {
+    pro_rata(aum_usd, lp_amount, lp_supply)
+}
+
+/*
+@name fee_amount
+@description Fee in tokens for a share of a size, rounded up in the pool's favour.
+@param size_usd - The size the fee is charged on.
+@param fee_bps - The fee rate.
+@param decimals - Decimals of the collateral mint.
+@param price - Collateral price, scaled by PRICE_SCALE.
+*/
+pub fn fee_amount(size_usd: u64, fee_bps: u64, decimals: u8, price: u64) -> Option<u64> {
+    usd_to_token_ceil(bps_of(size_usd, fee_bps)?, decimals, price)
+}
+
+/*
+@name split_fee
+@description Splits a fee into the protocol's share, rounded down, and the rest kept by LPs.
+@param fee_amount - The fee, in tokens.
+@param protocol_share_bps - The protocol's share of fees.
+@return (protocol_fee, lp_fee)
+*/
+pub fn split_fee(fee_amount: u64, protocol_share_bps: u64) -> Option<(u64, u64)> {
+    let protocol_fee = bps_of(fee_amount, protocol_share_bps)?;
+    Some((protocol_fee, fee_amount.checked_sub(protocol_fee)?))
+}
@@ -0,0 +1,193 @@
+// Price source for the perpetuals program.
+//
+// Each market reads its price either from a Pyth-style price account or from a
+// program-owned test oracle set by the pool admin. Prices carry an exponent and a
+// confidence interval, and are normalized to PRICE_SCALE (6 decimals), the same
+// scale used for every USD amount stored on a Position.
+
+use crate::error::PerpsError;
+use crate::math::BPS_POWER;
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{
+    account_info::AccountInfo, clock::Clock, program_error::ProgramError, pubkey::Pubkey,
+    sysvar::Sysvar,
+};
+
+pub const TEST_ORACLE_SEED: &[u8] = b"test_oracle";
+
+const PRICE_EXPO: i32 = -6; // PRICE_SCALE is 10^6
+
+// Layout of a Pyth v2 price account
+const PYTH_MAGIC: u32 = 0xa1b2_c3d4;
+const PYTH_VERSION: u32 = 2;
+const PYTH_PRICE_ACCOUNT_TYPE: u32 = 3;
+const PYTH_STATUS_TRADING: u32 = 1;
+const PYTH_EXPO_OFFSET: usize = 20;
+const PYTH_TIMESTAMP_OFFSET: usize = 96;
+const PYTH_AGG_PRICE_OFFSET: usize = 208;
+const PYTH_AGG_CONF_OFFSET: usize = 216;
+const PYTH_AGG_STATUS_OFFSET: usize = 224;
+pub const PYTH_PRICE_ACCOUNT_LEN: usize = 240;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, BorshSerialize, BorshDeserialize)]
+pub enum OracleType {
+    #[default]
+    Test,
+    Pyth,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct OracleParams {
+    pub oracle_account: Pubkey,   // Price account read for the market
+    pub oracle_type: OracleType,  // How the price account is parsed
+    pub max_price_age_sec: u64,   // Prices published longer ago than this are rejected
+    pub max_price_error_bps: u64, // Widest accepted confidence interval, as a share of price
+}
+
+impl OracleParams {
+    pub const LEN: usize = 32 + 1 + 8 + 8;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct TestOracle {
+    pub price: i64,        // Price in units of 10^expo
+    pub expo: i32,         // Price exponent
+    pub conf: u64,         // Confidence interval in units of 10^expo
+    pub publish_time: i64, // Unix timestamp the price was set for
+}
+
+impl TestOracle {
+    pub const LEN: usize = 8 + 4 + 8 + 8;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OraclePrice {
+    pub price: i64,        // Price in units of 10^expo
+    pub expo: i32,         // Price exponent
+    pub conf: u64,         // Confidence interval in units of 10^expo
+    pub publish_time: i64, // Unix timestamp the price was published at
+}
+
+impl OraclePrice {
+    /*
+    @name from_pyth
+    @description Parses the aggregate price of a Pyth v2 price account. Only trading prices are accepted.
+    @param data - The price account data.
+    */
+    pub fn from_pyth(data: &[u8]) -> Result<Self, ProgramError> {
+        if data.len() < PYTH_PRICE_ACCOUNT_LEN
+            || read_u32(data, 0) != PYTH_MAGIC
+            || read_u32(data, 4) != PYTH_VERSION
+            || read_u32(data, 8) != PYTH_PRICE_ACCOUNT_TYPE
+        {
+            return Err(PerpsError::InvalidOraclePrice.into());
+        }
+        if read_u32(data, PYTH_AGG_STATUS_OFFSET) != PYTH_STATUS_TRADING {
+            return Err(PerpsError::InvalidOraclePrice.into());
+        }
+
+        Ok(Self {
+            price: read_u64(data, PYTH_AGG_PRICE_OFFSET) as i64,
+            expo: read_u32(data, PYTH_EXPO_OFFSET) as i32,
+            conf: read_u64(data, PYTH_AGG_CONF_OFFSET),
+            publish_time: read_u64(data, PYTH_TIMESTAMP_OFFSET) as i64,
+        })
+    }
+
+    /*
+    @name from_test_oracle
+    @description Reads a test oracle account.
+    @param data - The test oracle account data.
+    */
+    pub fn from_test_oracle(data: &[u8]) -> Result<Self, ProgramError> {
+        let oracle =
+            TestOracle::deserialize(&mut &data[..]).map_err(|_| PerpsError::InvalidOraclePrice)?;
+        Ok(Self {
+            price: oracle.price,
+            expo: oracle.expo,
+            conf: oracle.conf,
+            publish_time: oracle.publish_time,
+        })
+    }
+
+    /*
+    @name checked_price
+    @description Validates the price against the market's oracle settings and scales it to PRICE_SCALE.
+    @param params - The market's oracle settings.
+    @param now - Current unix timestamp.
+    @return The price scaled by PRICE_SCALE.
+    */
+    pub fn checked_price(&self, params: &OracleParams, now: i64) -> Result<u64, ProgramError> {
+        if self.price <= 0 {
+            return Err(PerpsError::InvalidOraclePrice.into());
+        }
+        if now.saturating_sub(self.publish_time) > params.max_price_age_sec as i64 {
+            return Err(PerpsError::StaleOracle.into());
+        }
+
+        // Both sides share the exponent, so the ratio can be checked before scaling
+        let price = self.price as u64;
+        if (self.conf as u128) * (BPS_POWER as u128)
+            > (price as u128) * (params.max_price_error_bps as u128)
+        {
+            return Err(PerpsError::OracleConfidenceTooWide.into());
+        }
+
+        let scaled = scale_to_price_expo(price, self.expo).ok_or(PerpsError::MathOverflow)?;
+        if scaled == 0 {
+            return Err(PerpsError::InvalidOraclePrice.into());
+        }
+        Ok(scaled)
+    }
+}
+
+/*
+@name get_oracle_price
+@description Reads the current price of a market from its oracle account.
+@param program_id - The perpetuals program, which must own test oracles.
+@param oracle_account - The oracle account to read.
+@param params - The market's oracle settings.
+@return The price scaled by PRICE_SCALE.
+*/
+pub fn get_oracle_price(
+    program_id: &Pubkey,
+    oracle_account: &AccountInfo,
+    params: &OracleParams,
+) -> Result<u64, ProgramError> {
+    let data = oracle_account.try_borrow_data()?;
+    let price = match params.oracle_type {
+        OracleType::Test => {
+            if oracle_account.owner != program_id {
+                return Err(PerpsError::InvalidOraclePrice.into());
+            }
+            OraclePrice::from_test_oracle(&data)?
+        }
+        OracleType::Pyth => OraclePrice::from_pyth(&data)?,
+    };
+
+    price.checked_price(params, Clock::get()?.unix_timestamp)
+}
+
+/*
+@name scale_to_price_expo
+@description Rescales an amount in units of 10^expo to units of 10^-6. Digits below PRICE_SCALE are dropped.
+@param amount - The amount to rescale.
+@param expo - The amount's exponent.
+*/
+pub fn scale_to_price_expo(amount: u64, expo: i32) -> Option<u64> {
+    let shift = expo.checked_sub(PRICE_EXPO)?;
+    let factor = 10u64.checked_pow(shift.unsigned_abs());
+    if shift >= 0 {
+        amount.checked_mul(factor?)
+    } else {
+        Some(factor.map_or(0, |factor| amount / factor))
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
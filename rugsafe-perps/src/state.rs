@@ -1 +1,3 @@
+pub mod order;
 pub mod perpetuals;
+pub mod pool;
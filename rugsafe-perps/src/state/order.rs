@@ -0,0 +1,208 @@
+use crate::error::PerpsError;
+use crate::state::perpetuals::Side;
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{
+    account_info::AccountInfo, entrypoint::ProgramResult, program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+pub const TRIGGER_ORDER_SEED: &[u8] = b"trigger_order";
+pub const LIMIT_ORDER_SEED: &[u8] = b"limit_order";
+pub const LIMIT_ORDER_ESCROW_SEED: &[u8] = b"limit_order_escrow";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, BorshSerialize, BorshDeserialize)]
+pub enum TriggerKind {
+    #[default]
+    TakeProfit,
+    StopLoss,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct TriggerOrder {
+    pub owner: Pubkey,      // Owner of the position, receives the rent refund
+    pub position: Pubkey,   // Position PDA the order exits
+    pub position_id: u64,   // Id the position was opened under
+    pub kind: TriggerKind,  // Take-profit or stop-loss, one of each per position
+    pub trigger_price: u64, // Oracle price that makes the order executable, scaled by PRICE_SCALE
+    pub size_usd: u64, // Size closed on execution. The whole position closes at or above its size
+    pub bump: u8,      // Bump of the order PDA, seeded by the position and kind
+}
+
+impl TriggerOrder {
+    pub const LEN: usize = 32 * 2 + 8 + 1 + 8 + 8 + 1;
+
+    /*
+    @name seeds
+    @description Signer seeds of a trigger order PDA.
+    @param position - The position PDA the order is attached to.
+    @param kind - Slice holding the order kind, kept alive by the caller.
+    @param bump - Slice holding the order bump, kept alive by the caller.
+    */
+    pub fn seeds<'a>(position: &'a Pubkey, kind: &'a [u8], bump: &'a [u8]) -> [&'a [u8]; 4] {
+        [TRIGGER_ORDER_SEED, position.as_ref(), kind, bump]
+    }
+
+    /*
+    @name load
+    @description Loads a trigger order account, checking its owner and address.
+    @param program_id - The perpetuals program.
+    @param order_account - The trigger order PDA.
+    */
+    pub fn load(program_id: &Pubkey, order_account: &AccountInfo) -> Result<Self, ProgramError> {
+        if order_account.owner != program_id || order_account.data_is_empty() {
+            return Err(PerpsError::InvalidOrderData.into());
+        }
+
+        let data = order_account.try_borrow_data()?;
+        let order =
+            TriggerOrder::deserialize(&mut &data[..]).map_err(|_| PerpsError::InvalidOrderData)?;
+
+        let order_pda = Pubkey::create_program_address(
+            &Self::seeds(&order.position, &[order.kind as u8], &[order.bump]),
+            program_id,
+        )
+        .map_err(|_| PerpsError::InvalidPda)?;
+        if order_account.key != &order_pda {
+            return Err(PerpsError::InvalidPda.into());
+        }
+
+        Ok(order)
+    }
+
+    /*
+    @name store
+    @description Writes the order into its account.
+    @param order_account - The trigger order PDA.
+    */
+    pub fn store(&self, order_account: &AccountInfo) -> ProgramResult {
+        let mut data = order_account.try_borrow_mut_data()?;
+        self.serialize(&mut &mut data[..])?;
+        Ok(())
+    }
+
+    /*
+    @name is_triggered
+    @description Whether the price has crossed the trigger: take-profits fire in the position's favour, stop-losses against it.
+    @param side - Side of the position the order exits.
+    @param price - Current oracle price, scaled by PRICE_SCALE.
+    */
+    pub fn is_triggered(&self, side: Side, price: u64) -> Result<bool, PerpsError> {
+        match (side, self.kind) {
+            (Side::Long, TriggerKind::TakeProfit) | (Side::Short, TriggerKind::StopLoss) => {
+                Ok(price >= self.trigger_price)
+            }
+            (Side::Long, TriggerKind::StopLoss) | (Side::Short, TriggerKind::TakeProfit) => {
+                Ok(price <= self.trigger_price)
+            }
+            (Side::None, _) => Err(PerpsError::InvalidPositionData),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct LimitOrder {
+    pub owner: Pubkey, // Trader the position opens for, receives the collateral and rent on cancel
+    pub pool: Pubkey,  // Pool the position opens in
+    pub custody: Pubkey, // Market custody the position opens in
+    pub order_id: u64, // Id chosen by the owner, seeds the order PDA
+    pub side: Side,    // Long or short
+    pub collateral_amount: u64, // Collateral held in the order's escrow, in tokens
+    pub size_usd: u64, // Notional size, scaled by PRICE_SCALE
+    pub limit_price: u64, // Worst entry price: a maximum for longs, a minimum for shorts
+    pub bump: u8,      // Bump of the order PDA, seeded by the owner and order id
+    pub escrow_bump: u8, // Bump of the escrow token account PDA, seeded by the order
+}
+
+impl LimitOrder {
+    pub const LEN: usize = 32 * 3 + 8 + 1 + 8 * 3 + 1 + 1;
+
+    /*
+    @name seeds
+    @description Signer seeds of a limit order PDA, which is the authority of its escrow.
+    @param owner - The order owner.
+    @param order_id - Slice holding the little-endian order id, kept alive by the caller.
+    @param bump - Slice holding the order bump, kept alive by the caller.
+    */
+    pub fn seeds<'a>(owner: &'a Pubkey, order_id: &'a [u8], bump: &'a [u8]) -> [&'a [u8]; 4] {
+        [LIMIT_ORDER_SEED, owner.as_ref(), order_id, bump]
+    }
+
+    /*
+    @name load
+    @description Loads a limit order account, checking its owner and address.
+    @param program_id - The perpetuals program.
+    @param order_account - The limit order PDA.
+    */
+    pub fn load(program_id: &Pubkey, order_account: &AccountInfo) -> Result<Self, ProgramError> {
+        if order_account.owner != program_id || order_account.data_is_empty() {
+            return Err(PerpsError::InvalidOrderData.into());
+        }
+
+        let data = order_account.try_borrow_data()?;
+        let order =
+            LimitOrder::deserialize(&mut &data[..]).map_err(|_| PerpsError::InvalidOrderData)?;
+
+        let order_pda = Pubkey::create_program_address(
+            &Self::seeds(&order.owner, &order.order_id.to_le_bytes(), &[order.bump]),
+            program_id,
+        )
+        .map_err(|_| PerpsError::InvalidPda)?;
+        if order_account.key != &order_pda {
+            return Err(PerpsError::InvalidPda.into());
+        }
+
+        Ok(order)
+    }
+
+    /*
+    @name store
+    @description Writes the order into its account.
+    @param order_account - The limit order PDA.
+    */
+    pub fn store(&self, order_account: &AccountInfo) -> ProgramResult {
+        let mut data = order_account.try_borrow_mut_data()?;
+        self.serialize(&mut &mut data[..])?;
+        Ok(())
+    }
+
+    /*
+    @name check_escrow
+    @description Verifies the escrow token account PDA of the order.
+    @param program_id - The perpetuals program.
+    @param order_account - The limit order PDA.
+    @param escrow_account - The escrow token account.
+    */
+    pub fn check_escrow(
+        &self,
+        program_id: &Pubkey,
+        order_account: &AccountInfo,
+        escrow_account: &AccountInfo,
+    ) -> ProgramResult {
+        let escrow_pda = Pubkey::create_program_address(
+            &[
+                LIMIT_ORDER_ESCROW_SEED,
+                order_account.key.as_ref(),
+                &[self.escrow_bump],
+            ],
+            program_id,
+        )
+        .map_err(|_| PerpsError::InvalidPda)?;
+        if escrow_account.key != &escrow_pda {
+            return Err(PerpsError::InvalidPda.into());
+        }
+        Ok(())
+    }
+
+    /*
+    @name is_fillable
+    @description Whether the price is at or better than the limit: at most the limit for longs, at least the limit for shorts.
+    @param price - Current oracle price, scaled by PRICE_SCALE.
+    */
+    pub fn is_fillable(&self, price: u64) -> Result<bool, PerpsError> {
+        match self.side {
+            Side::Long => Ok(price <= self.limit_price),
+            Side::Short => Ok(price >= self.limit_price),
+            Side::None => Err(PerpsError::InvalidOrderData),
+        }
+    }
+}
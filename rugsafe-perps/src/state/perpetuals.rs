@@ -1,21 +1,17 @@
+use crate::error::PerpsError;
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::pubkey::Pubkey;
 
 pub const MAX_POSITIONS: usize = 10; // Max number of positions per user
 
-#[derive(Copy, Clone, PartialEq, Debug, BorshSerialize, BorshDeserialize)]
+#[derive(Copy, Clone, PartialEq, Debug, Default, BorshSerialize, BorshDeserialize)]
 pub enum Side {
+    #[default]
     None,
     Long,
     Short,
 }
 
-impl Default for Side {
-    fn default() -> Self {
-        Self::None
-    }
-}
-
 #[derive(Debug, Clone, BorshSerialize, BorshDeserialize, Default)]
 pub struct Position {
     pub owner: Pubkey, // The public key of the user who owns this position. It uniquely identifies the user.
@@ -49,18 +45,67 @@ pub struct Position {
     pub locked_amount: u64, // The amount of collateral or liquidity locked in the position. This portion of collateral is inaccessible until the position is closed.
 
     pub collateral_amount: u64, // The total amount of collateral provided for the position. It includes both locked collateral and any excess collateral that can be withdrawn or adjusted.
+
+    pub cumulative_funding_snapshot: i128, // The market's funding index for this side when funding was last settled. Funding owed or received is the difference to the current index.
 }
 
 impl Position {
-    pub const LEN: usize = 32 * 4 + 8 * 11 + 16 + 1; // Adjusted size calculation
+    pub const LEN: usize = 32 * 4 + 8 * 2 + 1 + 8 * 8 + 16 * 2; // Pubkeys, timestamps, side, amounts, interest and funding snapshots
 }
 
 #[derive(Default, Debug, BorshSerialize, BorshDeserialize)]
 pub struct UserPositions {
-    pub owner: Pubkey,          // Owner's public key, no change
-    pub next_position_idx: u64, // Pointer to the next position index
+    pub owner: Pubkey,              // Owner's public key, no change
+    pub next_position_idx: u64,     // Pointer to the next position index
+    pub active_positions: Vec<u64>, // Ids of the user's open positions, at most MAX_POSITIONS
 }
 
 impl UserPositions {
-    pub const LEN: usize = 32 + 8; // 32 bytes for owner, 8 bytes for position index
+    pub const LEN: usize = 32 + 8 + 4 + 8 * MAX_POSITIONS; // Owner, position index and the open position ids
+
+    /*
+    @name add
+    @description Records a newly opened position, up to MAX_POSITIONS.
+    @param position_id - Id the position was opened under.
+    */
+    pub fn add(&mut self, position_id: u64) -> Result<(), PerpsError> {
+        if self.active_positions.len() >= MAX_POSITIONS {
+            return Err(PerpsError::MaxPositionsReached);
+        }
+        self.active_positions.push(position_id);
+        Ok(())
+    }
+
+    /*
+    @name remove
+    @description Frees the slot of a closed or liquidated position.
+    @param position_id - Id of the position.
+    */
+    pub fn remove(&mut self, position_id: u64) -> Result<(), PerpsError> {
+        let index = self
+            .active_positions
+            .iter()
+            .position(|&id| id == position_id)
+            .ok_or(PerpsError::PositionNotFound)?;
+        self.active_positions.remove(index);
+        Ok(())
+    }
+
+    /*
+    @name position_keys
+    @description Addresses of the user's open position PDAs, in the order they were opened.
+    @param program_id - The perpetuals program.
+    */
+    pub fn position_keys(&self, program_id: &Pubkey) -> Vec<Pubkey> {
+        self.active_positions
+            .iter()
+            .map(|id| {
+                Pubkey::find_program_address(
+                    &[b"position", self.owner.as_ref(), &id.to_le_bytes()],
+                    program_id,
+                )
+                .0
+            })
+            .collect()
+    }
 }
@@ -0,0 +1,668 @@
+use crate::error::PerpsError;
+use crate::math::{self, BPS_POWER};
+use crate::oracle::OracleParams;
+use crate::state::perpetuals::Side;
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{
+    account_info::AccountInfo, entrypoint::ProgramResult, program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+pub const POOL_SEED: &[u8] = b"pool";
+pub const CUSTODY_SEED: &[u8] = b"custody";
+pub const CUSTODY_TOKEN_ACCOUNT_SEED: &[u8] = b"custody_token_account";
+pub const LP_MINT_SEED: &[u8] = b"lp_mint";
+
+pub const LP_DECIMALS: u8 = 6; // One LP token is worth $1 when the pool is seeded
+
+pub const MAX_CUSTODIES: usize = 8; // Max number of markets per pool
+
+pub const DEFAULT_MAX_LEVERAGE_BPS: u64 = 100_000; // 10x, the initial margin a position must keep in a market
+pub const DEFAULT_MAINTENANCE_MARGIN_BPS: u64 = 500; // Positions below 5% equity can be liquidated
+pub const DEFAULT_LIQUIDATION_FEE_BPS: u64 = 50; // 0.5% of size paid to the liquidator
+pub const DEFAULT_MAX_FUNDING_RATE_BPS: u64 = 10; // 0.1% of size per hour when open interest is one-sided
+pub const DEFAULT_KEEPER_FEE_BPS: u64 = 10; // 0.1% of the executed size paid to the keeper
+
+pub const DEFAULT_BASE_RATE_BPS: u64 = 100; // 1% a year on borrowed size at zero utilization
+pub const DEFAULT_SLOPE1_BPS: u64 = 800; // Up to 9% a year at the optimal utilization
+pub const DEFAULT_SLOPE2_BPS: u64 = 10_000; // Up to 109% a year when fully utilized
+pub const DEFAULT_OPTIMAL_UTILIZATION_BPS: u64 = 8_000; // 80%
+
+pub const DEFAULT_OPEN_POSITION_FEE_BPS: u64 = 10; // 0.1% of size charged on open
+pub const DEFAULT_CLOSE_POSITION_FEE_BPS: u64 = 10; // 0.1% of the closed size charged on close
+pub const DEFAULT_PROTOCOL_SHARE_BPS: u64 = 2_000; // 20% of fees go to the treasury, the rest to LPs
+
+pub const DEFAULT_MAX_OPEN_INTEREST_USD: u64 = u64::MAX; // No cap on either side's open interest
+pub const DEFAULT_MAX_SKEW_BPS: u64 = 10_000; // The market may be entirely one-sided
+
+#[derive(Clone, Copy, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct PoolParams {
+    pub maintenance_margin_bps: u64, // Equity share of size below which a position can be liquidated
+    pub liquidation_fee_bps: u64,    // Share of size paid to the liquidator
+    pub max_funding_rate_bps: u64,   // Hourly funding rate paid by the heavier side at full skew
+    pub keeper_fee_bps: u64,         // Share of executed size paid to the keeper of an order
+}
+
+impl PoolParams {
+    pub const LEN: usize = 8 * 4;
+
+    /*
+    @name is_valid
+    @description The maintenance margin must leave room for the liquidation fee, and funding and keeper fees are at most 100%.
+    */
+    pub fn is_valid(&self) -> bool {
+        self.maintenance_margin_bps > 0
+            && self.maintenance_margin_bps < BPS_POWER
+            && self.liquidation_fee_bps <= self.maintenance_margin_bps
+            && self.max_funding_rate_bps <= BPS_POWER
+            && self.keeper_fee_bps <= BPS_POWER
+    }
+
+    /*
+    @name is_valid_max_leverage
+    @description A market's max leverage must be at least 1x, and its initial margin above the maintenance margin, or new positions open liquidatable.
+    @param max_leverage_bps - The market's maximum size / collateral ratio.
+    */
+    pub fn is_valid_max_leverage(&self, max_leverage_bps: u64) -> bool {
+        max_leverage_bps >= BPS_POWER
+            && (max_leverage_bps as u128) * (self.maintenance_margin_bps as u128)
+                < (BPS_POWER as u128) * (BPS_POWER as u128)
+    }
+}
+
+impl Default for PoolParams {
+    fn default() -> Self {
+        Self {
+            maintenance_margin_bps: DEFAULT_MAINTENANCE_MARGIN_BPS,
+            liquidation_fee_bps: DEFAULT_LIQUIDATION_FEE_BPS,
+            max_funding_rate_bps: DEFAULT_MAX_FUNDING_RATE_BPS,
+            keeper_fee_bps: DEFAULT_KEEPER_FEE_BPS,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct BorrowRateParams {
+    pub base_rate_bps: u64, // Yearly rate on borrowed size at zero utilization
+    pub slope1_bps: u64,    // Rate added as utilization rises to the optimum
+    pub slope2_bps: u64,    // Rate added as utilization rises from the optimum to 100%
+    pub optimal_utilization_bps: u64, // Utilization at which the steeper slope takes over
+}
+
+impl BorrowRateParams {
+    pub const LEN: usize = 8 * 4;
+
+    /*
+    @name is_valid
+    @description The optimal utilization is at most 100% and the rate at full utilization fits in a u64.
+    */
+    pub fn is_valid(&self) -> bool {
+        self.optimal_utilization_bps <= BPS_POWER && self.borrow_rate_bps(BPS_POWER).is_some()
+    }
+
+    /*
+    @name borrow_rate_bps
+    @description Yearly borrow rate at the given utilization.
+    @param utilization_bps - Share of the market's tokens locked by positions.
+    */
+    pub fn borrow_rate_bps(&self, utilization_bps: u64) -> Option<u64> {
+        math::borrow_rate_bps(
+            self.base_rate_bps,
+            self.slope1_bps,
+            self.slope2_bps,
+            self.optimal_utilization_bps,
+            utilization_bps,
+        )
+    }
+}
+
+impl Default for BorrowRateParams {
+    fn default() -> Self {
+        Self {
+            base_rate_bps: DEFAULT_BASE_RATE_BPS,
+            slope1_bps: DEFAULT_SLOPE1_BPS,
+            slope2_bps: DEFAULT_SLOPE2_BPS,
+            optimal_utilization_bps: DEFAULT_OPTIMAL_UTILIZATION_BPS,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct FeeParams {
+    pub open_position_bps: u64,  // Share of size charged when a position opens
+    pub close_position_bps: u64, // Share of the closed size charged when a position decreases
+    pub protocol_share_bps: u64, // Share of the fees owed to the treasury, the rest stays with LPs
+}
+
+impl FeeParams {
+    pub const LEN: usize = 8 * 3;
+
+    /*
+    @name is_valid
+    @description Fees and the protocol share are at most 100%.
+    */
+    pub fn is_valid(&self) -> bool {
+        self.open_position_bps <= BPS_POWER
+            && self.close_position_bps <= BPS_POWER
+            && self.protocol_share_bps <= BPS_POWER
+    }
+}
+
+impl Default for FeeParams {
+    fn default() -> Self {
+        Self {
+            open_position_bps: DEFAULT_OPEN_POSITION_FEE_BPS,
+            close_position_bps: DEFAULT_CLOSE_POSITION_FEE_BPS,
+            protocol_share_bps: DEFAULT_PROTOCOL_SHARE_BPS,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct OpenInterestParams {
+    pub max_open_interest_usd: u64, // Largest open interest either side of the market may reach
+    pub max_skew_bps: u64, // Largest difference between long and short open interest, as a share of their total
+}
+
+impl OpenInterestParams {
+    pub const LEN: usize = 8 * 2;
+
+    /*
+    @name is_valid
+    @description The open interest cap is positive and the skew limit is at most 100%.
+    */
+    pub fn is_valid(&self) -> bool {
+        self.max_open_interest_usd > 0 && self.max_skew_bps <= BPS_POWER
+    }
+}
+
+impl Default for OpenInterestParams {
+    fn default() -> Self {
+        Self {
+            max_open_interest_usd: DEFAULT_MAX_OPEN_INTEREST_USD,
+            max_skew_bps: DEFAULT_MAX_SKEW_BPS,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct Pool {
+    pub admin: Pubkey,                // Authority allowed to add markets
+    pub bump: u8,                     // Bump of the pool PDA, seeded by the admin
+    pub params: PoolParams,           // Margin, liquidation, funding and keeper settings
+    pub long_open_interest_usd: u64,  // Total size of open longs
+    pub short_open_interest_usd: u64, // Total size of open shorts
+    pub lp_mint: Pubkey,              // Mint of the LP token, whose authority is the pool PDA
+    pub lp_mint_bump: u8,             // Bump of the LP mint PDA, seeded by the pool
+    pub treasury: Pubkey,             // Owner of the token accounts protocol fees are swept to
+    pub custodies: Vec<Pubkey>,       // Custody accounts, one per market
+}
+
+impl Pool {
+    pub const LEN: usize = 32 + 1 + PoolParams::LEN + 8 + 8 + 32 + 1 + 32 + 4 + 32 * MAX_CUSTODIES;
+
+    /*
+    @name load
+    @description Loads a pool account, checking its owner and address.
+    @param program_id - The perpetuals program.
+    @param pool_account - The pool PDA.
+    */
+    pub fn load(program_id: &Pubkey, pool_account: &AccountInfo) -> Result<Self, ProgramError> {
+        if pool_account.owner != program_id || pool_account.data_is_empty() {
+            return Err(PerpsError::InvalidPoolData.into());
+        }
+
+        let data = pool_account.try_borrow_data()?;
+        let pool = Pool::deserialize(&mut &data[..]).map_err(|_| PerpsError::InvalidPoolData)?;
+
+        let pool_pda = Pubkey::create_program_address(
+            &[POOL_SEED, pool.admin.as_ref(), &[pool.bump]],
+            program_id,
+        )
+        .map_err(|_| PerpsError::InvalidPda)?;
+        if pool_account.key != &pool_pda {
+            return Err(PerpsError::InvalidPda.into());
+        }
+
+        Ok(pool)
+    }
+
+    /*
+    @name seeds
+    @description Signer seeds of the pool PDA, which is the LP mint authority.
+    @param bump - Slice holding the pool bump, kept alive by the caller.
+    */
+    pub fn seeds<'a>(&'a self, bump: &'a [u8]) -> [&'a [u8]; 3] {
+        [POOL_SEED, self.admin.as_ref(), bump]
+    }
+
+    /*
+    @name store
+    @description Writes the pool back into its account.
+    @param pool_account - The pool PDA.
+    */
+    pub fn store(&self, pool_account: &AccountInfo) -> ProgramResult {
+        let mut data = pool_account.try_borrow_mut_data()?;
+        self.serialize(&mut &mut data[..])?;
+        Ok(())
+    }
+
+    /*
+    @name add_open_interest
+    @description Adds a position's size to the open interest of its side.
+    @param side - The side of the position.
+    @param size_usd - The size to add.
+    */
+    pub fn add_open_interest(&mut self, side: Side, size_usd: u64) -> Result<(), PerpsError> {
+        let open_interest = self.open_interest_mut(side)?;
+        *open_interest = open_interest
+            .checked_add(size_usd)
+            .ok_or(PerpsError::MathOverflow)?;
+        Ok(())
+    }
+
+    /*
+    @name remove_open_interest
+    @description Removes a position's size from the open interest of its side.
+    @param side - The side of the position.
+    @param size_usd - The size to remove.
+    */
+    pub fn remove_open_interest(&mut self, side: Side, size_usd: u64) -> Result<(), PerpsError> {
+        let open_interest = self.open_interest_mut(side)?;
+        *open_interest = open_interest.saturating_sub(size_usd);
+        Ok(())
+    }
+
+    fn open_interest_mut(&mut self, side: Side) -> Result<&mut u64, PerpsError> {
+        match side {
+            Side::Long => Ok(&mut self.long_open_interest_usd),
+            Side::Short => Ok(&mut self.short_open_interest_usd),
+            Side::None => Err(PerpsError::InvalidPositionData),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct Custody {
+    pub pool: Pubkey,                      // Pool this market belongs to
+    pub mint: Pubkey,                      // Token traded and posted as collateral in this market
+    pub token_account: Pubkey, // Token account holding the market's collateral, owned by the custody PDA
+    pub oracle: OracleParams,  // Price oracle for the mint and its staleness and confidence limits
+    pub decimals: u8,          // Decimals of the mint
+    pub bump: u8,              // Bump of the custody PDA, seeded by pool and mint
+    pub token_account_bump: u8, // Bump of the custody token account PDA
+    pub long_open_interest_usd: u64, // Size of open longs in this market
+    pub short_open_interest_usd: u64, // Size of open shorts in this market
+    pub cumulative_funding_long: i128, // Funding owed per unit of long size since the market opened, scaled by FUNDING_PRECISION
+    pub cumulative_funding_short: i128, // Funding owed per unit of short size since the market opened, scaled by FUNDING_PRECISION
+    pub last_funding_update: i64,       // Unix timestamp funding was last accrued at
+    pub borrow_rate: BorrowRateParams, // Utilization-based borrow rate charged on positions' borrowed size
+    pub locked_amount: u64,            // Tokens reserved for the size of open positions
+    pub cumulative_interest: u128, // Interest owed per unit of borrowed size since the market opened, scaled by INTEREST_PRECISION
+    pub last_interest_update: i64, // Unix timestamp interest was last accrued at
+    pub collateral_amount: u64,    // Tokens posted by traders as collateral, not owned by the pool
+    pub long_locked_amount: u64,   // Share of the locked tokens reserved for longs
+    pub short_locked_amount: u64,  // Share of the locked tokens reserved for shorts
+    pub fees: FeeParams,           // Open and close fees and the protocol's share of them
+    pub collected_fee_amount: u64, // Fees charged in this market since it opened, in tokens
+    pub protocol_fee_amount: u64, // Protocol fees held in the custody until they are swept to the treasury
+    pub open_interest: OpenInterestParams, // Caps on each side's open interest and on the skew between them
+    pub max_leverage_bps: u64, // Maximum size / collateral ratio on open, collateral removal and decrease
+}
+
+impl Custody {
+    pub const LEN: usize = 32 * 3
+        + OracleParams::LEN
+        + 3
+        + 8 * 2
+        + 16 * 2
+        + 8
+        + BorrowRateParams::LEN
+        + 8
+        + 16
+        + 8
+        + 8 * 3
+        + FeeParams::LEN
+        + 8 * 2
+        + OpenInterestParams::LEN
+        + 8;
+
+    /*
+    @name load
+    @description Loads a custody account, checking its owner, address and pool.
+    @param program_id - The perpetuals program.
+    @param pool_key - The pool the custody must belong to.
+    @param custody_account - The custody PDA.
+    */
+    pub fn load(
+        program_id: &Pubkey,
+        pool_key: &Pubkey,
+        custody_account: &AccountInfo,
+    ) -> Result<Self, ProgramError> {
+        if custody_account.owner != program_id || custody_account.data_is_empty() {
+            return Err(PerpsError::InvalidCustodyData.into());
+        }
+
+        let data = custody_account.try_borrow_data()?;
+        let custody =
+            Custody::deserialize(&mut &data[..]).map_err(|_| PerpsError::InvalidCustodyData)?;
+
+        if custody.pool != *pool_key {
+            return Err(PerpsError::CustodyMismatch.into());
+        }
+
+        let custody_pda =
+            Pubkey::create_program_address(&custody.seeds(&[custody.bump]), program_id)
+                .map_err(|_| PerpsError::InvalidPda)?;
+        if custody_account.key != &custody_pda {
+            return Err(PerpsError::InvalidPda.into());
+        }
+
+        Ok(custody)
+    }
+
+    /*
+    @name seeds
+    @description Signer seeds of the custody PDA, which owns the custody token account.
+    @param bump - Slice holding the custody bump, kept alive by the caller.
+    */
+    pub fn seeds<'a>(&'a self, bump: &'a [u8]) -> [&'a [u8]; 4] {
+        [CUSTODY_SEED, self.pool.as_ref(), self.mint.as_ref(), bump]
+    }
+
+    /*
+    @name store
+    @description Writes the custody back into its account.
+    @param custody_account - The custody PDA.
+    */
+    pub fn store(&self, custody_account: &AccountInfo) -> ProgramResult {
+        let mut data = custody_account.try_borrow_mut_data()?;
+        self.serialize(&mut &mut data[..])?;
+        Ok(())
+    }
+
+    /*
+    @name update_funding
+    @description Accrues funding from the last update until now, based on the market's open-interest skew.
+    @param max_funding_rate_bps - Hourly rate paid by the heavier side at full skew.
+    @param now - Current unix timestamp.
+    */
+    pub fn update_funding(
+        &mut self,
+        max_funding_rate_bps: u64,
+        now: i64,
+    ) -> Result<(), PerpsError> {
+        let elapsed_sec = now.saturating_sub(self.last_funding_update);
+        if elapsed_sec <= 0 {
+            return Ok(());
+        }
+
+        let (long_delta, short_delta) = math::funding_index_deltas(
+            self.long_open_interest_usd,
+            self.short_open_interest_usd,
+            max_funding_rate_bps,
+            elapsed_sec as u64,
+        )
+        .ok_or(PerpsError::MathOverflow)?;
+        self.cumulative_funding_long = self
+            .cumulative_funding_long
+            .checked_add(long_delta)
+            .ok_or(PerpsError::MathOverflow)?;
+        self.cumulative_funding_short = self
+            .cumulative_funding_short
+            .checked_add(short_delta)
+            .ok_or(PerpsError::MathOverflow)?;
+        self.last_funding_update = now;
+        Ok(())
+    }
+
+    /*
+    @name funding_index
+    @description Current cumulative funding index of a side.
+    @param side - The side of the position.
+    */
+    pub fn funding_index(&self, side: Side) -> Result<i128, PerpsError> {
+        match side {
+            Side::Long => Ok(self.cumulative_funding_long),
+            Side::Short => Ok(self.cumulative_funding_short),
+            Side::None => Err(PerpsError::InvalidPositionData),
+        }
+    }
+
+    /*
+    @name update_interest
+    @description Accrues borrow interest from the last update until now, at the rate for the market's current utilization.
+    @param owned_amount - Tokens held by the custody token account.
+    @param now - Current unix timestamp.
+    */
+    pub fn update_interest(&mut self, owned_amount: u64, now: i64) -> Result<(), PerpsError> {
+        let elapsed_sec = now.saturating_sub(self.last_interest_update);
+        if elapsed_sec <= 0 {
+            return Ok(());
+        }
+
+        let utilization_bps = math::utilization_bps(self.locked_amount, owned_amount);
+        let rate_bps = self
+            .borrow_rate
+            .borrow_rate_bps(utilization_bps)
+            .ok_or(PerpsError::MathOverflow)?;
+        let delta = math::interest_index_delta(rate_bps, elapsed_sec as u64)
+            .ok_or(PerpsError::MathOverflow)?;
+        self.cumulative_interest = self
+            .cumulative_interest
+            .checked_add(delta)
+            .ok_or(PerpsError::MathOverflow)?;
+        self.last_interest_update = now;
+        Ok(())
+    }
+
+    /*
+    @name lock
+    @description Reserves tokens for an opened position's size.
+    @param side - The side of the position.
+    @param amount - The position's locked amount.
+    */
+    pub fn lock(&mut self, side: Side, amount: u64) -> Result<(), PerpsError> {
+        self.locked_amount = self
+            .locked_amount
+            .checked_add(amount)
+            .ok_or(PerpsError::MathOverflow)?;
+        let side_locked = self.locked_amount_mut(side)?;
+        *side_locked = side_locked
+            .checked_add(amount)
+            .ok_or(PerpsError::MathOverflow)?;
+        Ok(())
+    }
+
+    /*
+    @name unlock
+    @description Releases the tokens reserved for a closed position's size.
+    @param side - The side of the position.
+    @param amount - The position's locked amount.
+    */
+    pub fn unlock(&mut self, side: Side, amount: u64) -> Result<(), PerpsError> {
+        self.locked_amount = self.locked_amount.saturating_sub(amount);
+        let side_locked = self.locked_amount_mut(side)?;
+        *side_locked = side_locked.saturating_sub(amount);
+        Ok(())
+    }
+
+    fn locked_amount_mut(&mut self, side: Side) -> Result<&mut u64, PerpsError> {
+        match side {
+            Side::Long => Ok(&mut self.long_locked_amount),
+            Side::Short => Ok(&mut self.short_locked_amount),
+            Side::None => Err(PerpsError::InvalidPositionData),
+        }
+    }
+
+    /*
+    @name update_collateral
+    @description Tracks a change of a position's collateral, so traders' tokens are told apart from the pool's.
+    @param before - The position's collateral before the change.
+    @param after - The position's collateral after the change.
+    */
+    pub fn update_collateral(&mut self, before: u64, after: u64) -> Result<(), PerpsError> {
+        self.collateral_amount = self
+            .collateral_amount
+            .saturating_sub(before)
+            .checked_add(after)
+            .ok_or(PerpsError::MathOverflow)?;
+        Ok(())
+    }
+
+    /*
+    @name charge_fee
+    @description Books a fee kept in the custody, setting aside the protocol's share for the treasury.
+    @param fee_amount - The fee, in tokens.
+    */
+    pub fn charge_fee(&mut self, fee_amount: u64) -> Result<(), PerpsError> {
+        let (protocol_fee, _) = math::split_fee(fee_amount, self.fees.protocol_share_bps)
+            .ok_or(PerpsError::MathOverflow)?;
+        self.collected_fee_amount = self
+            .collected_fee_amount
+            .checked_add(fee_amount)
+            .ok_or(PerpsError::MathOverflow)?;
+        self.protocol_fee_amount = self
+            .protocol_fee_amount
+            .checked_add(protocol_fee)
+            .ok_or(PerpsError::MathOverflow)?;
+        Ok(())
+    }
+
+    /*
+    @name owned_amount
+    @description Tokens of the custody that belong to the pool: the balance less traders' collateral and unswept protocol fees.
+    @param token_balance - Tokens held by the custody token account.
+    */
+    pub fn owned_amount(&self, token_balance: u64) -> u64 {
+        token_balance
+            .saturating_sub(self.collateral_amount)
+            .saturating_sub(self.protocol_fee_amount)
+    }
+
+    /*
+    @name assets_under_management_usd
+    @description Value of the tokens the pool owns in this market, net of traders' unrealized PnL, floored at zero.
+    @param token_balance - Tokens held by the custody token account.
+    @param price - Current oracle price, scaled by PRICE_SCALE.
+    */
+    pub fn assets_under_management_usd(
+        &self,
+        token_balance: u64,
+        price: u64,
+    ) -> Result<u64, PerpsError> {
+        let owned_amount = self.owned_amount(token_balance);
+        let owned_usd = math::token_to_usd(owned_amount, self.decimals, price)
+            .ok_or(PerpsError::MathOverflow)?;
+        let long_locked_usd = math::token_to_usd(self.long_locked_amount, self.decimals, price)
+            .ok_or(PerpsError::MathOverflow)?;
+        let short_locked_usd = math::token_to_usd(self.short_locked_amount, self.decimals, price)
+            .ok_or(PerpsError::MathOverflow)?;
+        let traders_pnl_usd = math::traders_pnl_usd(
+            self.long_open_interest_usd,
+            long_locked_usd,
+            self.short_open_interest_usd,
+            short_locked_usd,
+        );
+
+        let aum_usd = (owned_usd as i128).saturating_sub(traders_pnl_usd).max(0);
+        u64::try_from(aum_usd).map_err(|_| PerpsError::MathOverflow)
+    }
+
+    /*
+    @name add_open_interest
+    @description Adds a position's size to the market's open interest on its side.
+    @param side - The side of the position.
+    @param size_usd - The size to add.
+    */
+    pub fn add_open_interest(&mut self, side: Side, size_usd: u64) -> Result<(), PerpsError> {
+        let open_interest = self.open_interest_mut(side)?;
+        *open_interest = open_interest
+            .checked_add(size_usd)
+            .ok_or(PerpsError::MathOverflow)?;
+        Ok(())
+    }
+
+    /*
+    @name remove_open_interest
+    @description Removes a position's size from the market's open interest on its side.
+    @param side - The side of the position.
+    @param size_usd - The size to remove.
+    */
+    pub fn remove_open_interest(&mut self, side: Side, size_usd: u64) -> Result<(), PerpsError> {
+        let open_interest = self.open_interest_mut(side)?;
+        *open_interest = open_interest.saturating_sub(size_usd);
+        Ok(())
+    }
+
+    /*
+    @name check_liquidity
+    @description Fails if locking more tokens would reserve more than the pool owns in this market, so every open position's profit is backed by LP liquidity.
+    @param token_balance - Tokens held by the custody token account, before the new collateral arrives.
+    @param amount - Tokens the new position locks.
+    */
+    pub fn check_liquidity(&self, token_balance: u64, amount: u64) -> Result<(), PerpsError> {
+        let locked_amount = self
+            .locked_amount
+            .checked_add(amount)
+            .ok_or(PerpsError::MathOverflow)?;
+        if locked_amount > self.owned_amount(token_balance) {
+            return Err(PerpsError::InsufficientLiquidity);
+        }
+        Ok(())
+    }
+
+    /*
+    @name check_open_interest
+    @description Fails if adding a position's size would take its side over the open interest cap, or grow the skew past its share of the open interest.
+    @param side - The side of the position.
+    @param size_usd - The size to add.
+    */
+    pub fn check_open_interest(&self, side: Side, size_usd: u64) -> Result<(), PerpsError> {
+        let (long_usd, short_usd) = match side {
+            Side::Long => (
+                self.long_open_interest_usd
+                    .checked_add(size_usd)
+                    .ok_or(PerpsError::MathOverflow)?,
+                self.short_open_interest_usd,
+            ),
+            Side::Short => (
+                self.long_open_interest_usd,
+                self.short_open_interest_usd
+                    .checked_add(size_usd)
+                    .ok_or(PerpsError::MathOverflow)?,
+            ),
+            Side::None => return Err(PerpsError::InvalidPositionData),
+        };
+        if long_usd.max(short_usd) > self.open_interest.max_open_interest_usd {
+            return Err(PerpsError::MaxOpenInterestExceeded);
+        }
+
+        // Orders on the lighter side shrink the skew and are always let through. The first
+        // position of an empty market is all skew, so only the open interest cap bounds it.
+        let skew_before_usd = self
+            .long_open_interest_usd
+            .abs_diff(self.short_open_interest_usd);
+        let skew_usd = long_usd.abs_diff(short_usd);
+        let is_first = self.long_open_interest_usd == 0 && self.short_open_interest_usd == 0;
+        if !is_first
+            && skew_usd > skew_before_usd
+            && math::exceeds_skew(
+                skew_usd,
+                long_usd.saturating_add(short_usd),
+                self.open_interest.max_skew_bps,
+            )
+        {
+            return Err(PerpsError::MaxSkewExceeded);
+        }
+        Ok(())
+    }
+
+    fn open_interest_mut(&mut self, side: Side) -> Result<&mut u64, PerpsError> {
+        match side {
+            Side::Long => Ok(&mut self.long_open_interest_usd),
+            Side::Short => Ok(&mut self.short_open_interest_usd),
+            Side::None => Err(PerpsError::InvalidPositionData),
+        }
+    }
+}
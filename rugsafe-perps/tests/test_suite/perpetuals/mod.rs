@@ -1,3 +1,14 @@
+pub mod test_decrease_position;
+pub mod test_error;
+pub mod test_fees;
+pub mod test_funding;
+pub mod test_interest;
+pub mod test_limit_orders;
+pub mod test_liquidity;
+pub mod test_math;
+pub mod test_open_interest;
+pub mod test_oracle;
 pub mod test_perpetuals;
-
-pub use test_perpetuals::*;
+pub mod test_pool;
+pub mod test_trigger_orders;
+pub mod test_user_positions;
@@ -0,0 +1,235 @@
+use solana_program::instruction::AccountMeta;
+use solana_program_test::*;
+use solana_sdk::{pubkey::Pubkey, signature::Keypair, signature::Signer};
+
+use rugsafe_perps::error::PerpsError;
+use rugsafe_perps::state::perpetuals::Side;
+
+use super::test_perpetuals::{
+    assert_perps_error, decrease_position_instruction, fetch_custody, fetch_pool, fetch_position,
+    open_position, position_pda, send, set_oracle_price, setup_positions, token_balance, Market,
+};
+
+// Position 0 is a 1x long of 500 tokens that keeps the custody liquid
+const ID: u64 = 1;
+
+/// Opens a $200 long on 100 tokens at $1.00 as position 1 and moves the price to `price`.
+async fn setup_long(price: u64) -> (ProgramTestContext, Pubkey, Market, Pubkey) {
+    let (mut context, program_id, market, user_collateral_account) =
+        setup_positions(1, 500_000_000).await;
+    open_position(
+        &mut context,
+        &program_id,
+        &market,
+        &user_collateral_account,
+        ID,
+        Side::Long,
+        100_000_000,
+        200_000_000,
+    )
+    .await;
+    set_oracle_price(&mut context, &program_id, &market.oracle, price).await;
+    (context, program_id, market, user_collateral_account)
+}
+
+#[tokio::test]
+async fn test_decrease_position_realizes_profit() {
+    let (mut context, program_id, market, user_collateral_account) = setup_long(1_500_000).await;
+    let owner = context.payer.pubkey();
+    let position_key = position_pda(&program_id, &owner, ID);
+    let decrease = |size_usd, withdraw_collateral| {
+        decrease_position_instruction(
+            &program_id,
+            &owner,
+            ID,
+            &user_collateral_account,
+            &market,
+            size_usd,
+            withdraw_collateral,
+        )
+    };
+
+    // Half of the $100 profit and half of the collateral are paid out at $1.50
+    let balance = token_balance(&mut context.banks_client, user_collateral_account).await;
+    send(&mut context, decrease(100_000_000, true), &[])
+        .await
+        .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, user_collateral_account).await,
+        balance + 50_000_000 + 33_333_333
+    );
+    let position = fetch_position(&mut context.banks_client, position_key).await;
+    assert_eq!(position.size_usd, 100_000_000);
+    assert_eq!(position.collateral_amount, 50_000_000);
+    assert_eq!(position.collateral_usd, 75_000_000);
+    assert_eq!(position.borrow_size_usd, 25_000_000);
+    assert_eq!(position.locked_amount, 100_000_000);
+    assert_eq!(position.price, 1_000_000);
+    assert_eq!(
+        fetch_pool(&mut context.banks_client, market.pool)
+            .await
+            .long_open_interest_usd,
+        600_000_000
+    );
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    assert_eq!(custody.long_open_interest_usd, 600_000_000);
+    assert_eq!(custody.locked_amount, 600_000_000);
+
+    // Keeping the collateral only pays out the realized profit
+    let balance = token_balance(&mut context.banks_client, user_collateral_account).await;
+    send(&mut context, decrease(50_000_000, false), &[])
+        .await
+        .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, user_collateral_account).await,
+        balance + 16_666_666
+    );
+    let position = fetch_position(&mut context.banks_client, position_key).await;
+    assert_eq!(position.size_usd, 50_000_000);
+    assert_eq!(position.collateral_amount, 50_000_000);
+    assert_eq!(position.locked_amount, 50_000_000);
+
+    // Decreasing by the remaining size closes the position
+    let balance = token_balance(&mut context.banks_client, user_collateral_account).await;
+    send(&mut context, decrease(50_000_000, false), &[])
+        .await
+        .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, user_collateral_account).await,
+        balance + 50_000_000 + 16_666_666
+    );
+    assert!(context
+        .banks_client
+        .get_account(position_key)
+        .await
+        .unwrap()
+        .is_none());
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    assert_eq!(custody.long_open_interest_usd, 500_000_000);
+    assert_eq!(custody.locked_amount, 500_000_000);
+}
+
+#[tokio::test]
+async fn test_decrease_position_realizes_loss() {
+    let (mut context, program_id, market, user_collateral_account) = setup_long(900_000).await;
+    let owner = context.payer.pubkey();
+    let position_key = position_pda(&program_id, &owner, ID);
+    let decrease = |size_usd, withdraw_collateral| {
+        decrease_position_instruction(
+            &program_id,
+            &owner,
+            ID,
+            &user_collateral_account,
+            &market,
+            size_usd,
+            withdraw_collateral,
+        )
+    };
+
+    // Half of the $20 loss comes out of the kept collateral, rounded up
+    let balance = token_balance(&mut context.banks_client, user_collateral_account).await;
+    send(&mut context, decrease(100_000_000, false), &[])
+        .await
+        .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, user_collateral_account).await,
+        balance
+    );
+    let position = fetch_position(&mut context.banks_client, position_key).await;
+    assert_eq!(position.size_usd, 100_000_000);
+    assert_eq!(position.collateral_amount, 88_888_888);
+
+    // Released collateral pays the realized loss before it is withdrawn
+    send(&mut context, decrease(50_000_000, true), &[])
+        .await
+        .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, user_collateral_account).await,
+        balance + 44_444_444 - 5_555_556
+    );
+    let position = fetch_position(&mut context.banks_client, position_key).await;
+    assert_eq!(position.size_usd, 50_000_000);
+    assert_eq!(position.collateral_amount, 44_444_444);
+}
+
+#[tokio::test]
+async fn test_decrease_position_checks_remaining_leverage() {
+    let (mut context, program_id, market, user_collateral_account) =
+        setup_positions(1, 500_000_000).await;
+    let owner = context.payer.pubkey();
+    let position_key = position_pda(&program_id, &owner, ID);
+    // A $500 long at the 10x limit, which a 1% drop takes to about 11.2x
+    open_position(
+        &mut context,
+        &program_id,
+        &market,
+        &user_collateral_account,
+        ID,
+        Side::Long,
+        50_000_000,
+        500_000_000,
+    )
+    .await;
+    set_oracle_price(&mut context, &program_id, &market.oracle, 990_000).await;
+    let decrease = |size_usd, withdraw_collateral| {
+        decrease_position_instruction(
+            &program_id,
+            &owner,
+            ID,
+            &user_collateral_account,
+            &market,
+            size_usd,
+            withdraw_collateral,
+        )
+    };
+
+    // Withdrawing half the collateral with half the size leaves the rest over max leverage
+    let result = send(&mut context, decrease(250_000_000, true), &[]).await;
+    assert_perps_error(result, 0, PerpsError::InsufficientMargin);
+    let position = fetch_position(&mut context.banks_client, position_key).await;
+    assert_eq!(position.size_usd, 500_000_000);
+
+    // Keeping the collateral deleverages the rest
+    send(&mut context, decrease(250_000_000, false), &[])
+        .await
+        .unwrap();
+    let position = fetch_position(&mut context.banks_client, position_key).await;
+    assert_eq!(position.size_usd, 250_000_000);
+}
+
+#[tokio::test]
+async fn test_decrease_position_rejects_invalid_requests() {
+    let (mut context, program_id, market, user_collateral_account) = setup_long(1_000_000).await;
+    let owner = context.payer.pubkey();
+
+    let result = send(
+        &mut context,
+        decrease_position_instruction(
+            &program_id,
+            &owner,
+            ID,
+            &user_collateral_account,
+            &market,
+            0,
+            true,
+        ),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::ZeroAmount);
+
+    // Another signer cannot decrease the owner's position
+    let stranger = Keypair::new();
+    let mut instruction = decrease_position_instruction(
+        &program_id,
+        &owner,
+        ID,
+        &user_collateral_account,
+        &market,
+        100_000_000,
+        true,
+    );
+    instruction.accounts[0] = AccountMeta::new(stranger.pubkey(), true);
+    let result = send(&mut context, instruction, &[&stranger]).await;
+    assert_perps_error(result, 0, PerpsError::InvalidPda);
+}
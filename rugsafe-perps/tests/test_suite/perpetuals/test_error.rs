@@ -0,0 +1,22 @@
+use rugsafe_perps::error::PerpsError;
+use solana_program::decode_error::DecodeError;
+
+#[test]
+fn test_perps_error_codes() {
+    // Keepers and frontends retry or surface these by code
+    for (error, code) in [
+        (PerpsError::InsufficientMargin, 7),
+        (PerpsError::StaleOracle, 10),
+        (PerpsError::NotLiquidatable, 12),
+        (PerpsError::SlippageExceeded, 16),
+        (PerpsError::TriggerNotReached, 27),
+        (PerpsError::LimitPriceNotReached, 28),
+        (PerpsError::InsufficientLiquidity, 29),
+    ] {
+        assert_eq!(error as u32, code, "{error:?}");
+        assert_eq!(
+            <PerpsError as DecodeError<PerpsError>>::decode_custom_error_to_enum(code),
+            Some(error)
+        );
+    }
+}
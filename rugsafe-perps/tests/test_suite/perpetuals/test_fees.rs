@@ -0,0 +1,191 @@
+use solana_sdk::{pubkey::Pubkey, signature::Signer};
+
+use rugsafe_perps::error::PerpsError;
+use rugsafe_perps::oracle::OracleType;
+use rugsafe_perps::state::perpetuals::Side;
+use rugsafe_perps::state::pool::{
+    BorrowRateParams, FeeParams, OpenInterestParams, DEFAULT_MAX_LEVERAGE_BPS,
+};
+
+use super::test_perpetuals::{
+    add_market_with_params_instruction, assert_perps_error, close_position_instruction,
+    collect_fees_instruction, create_token_account, fetch_custody, fetch_position, open_position,
+    position_pda, send, setup_market_with_params, token_balance, Market, TEST_LIQUIDITY,
+    TEST_MAX_PRICE_AGE_SEC, TEST_MAX_PRICE_ERROR_BPS,
+};
+
+// 0.1% to open and to close, a fifth of which goes to the protocol
+const TEST_FEES: FeeParams = FeeParams {
+    open_position_bps: 10,
+    close_position_bps: 10,
+    protocol_share_bps: 2_000,
+};
+
+#[tokio::test]
+async fn test_add_market_checks_fees() {
+    let (mut context, program_id, market, _) = setup_market_with_params(
+        &BorrowRateParams::default(),
+        &TEST_FEES,
+        &OpenInterestParams::default(),
+    )
+    .await;
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    assert_eq!(custody.fees, TEST_FEES);
+    assert_eq!(custody.collected_fee_amount, 0);
+    assert_eq!(custody.protocol_fee_amount, 0);
+
+    let admin = context.payer.pubkey();
+    let other_mint = Pubkey::new_unique();
+    let other_market = Market::new(&program_id, &market.pool, &other_mint);
+    for fees in [
+        FeeParams {
+            open_position_bps: 10_001,
+            ..TEST_FEES
+        },
+        FeeParams {
+            protocol_share_bps: 10_001,
+            ..TEST_FEES
+        },
+    ] {
+        let result = send(
+            &mut context,
+            add_market_with_params_instruction(
+                &program_id,
+                &admin,
+                &other_market,
+                OracleType::Test,
+                TEST_MAX_PRICE_AGE_SEC,
+                TEST_MAX_PRICE_ERROR_BPS,
+                &BorrowRateParams::default(),
+                &fees,
+                &OpenInterestParams::default(),
+                DEFAULT_MAX_LEVERAGE_BPS,
+            ),
+            &[],
+        )
+        .await;
+        assert_perps_error(result, 0, PerpsError::InvalidFeeParams);
+    }
+}
+
+#[tokio::test]
+async fn test_open_and_close_fees() {
+    let (mut context, program_id, market, user_collateral_account) = setup_market_with_params(
+        &BorrowRateParams::default(),
+        &TEST_FEES,
+        &OpenInterestParams::default(),
+    )
+    .await;
+    let owner = context.payer.pubkey();
+    let balance = token_balance(&mut context.banks_client, user_collateral_account).await;
+
+    // Opening $200 at $1 costs 0.2 tokens out of the 100 posted
+    open_position(
+        &mut context,
+        &program_id,
+        &market,
+        &user_collateral_account,
+        0,
+        Side::Long,
+        100_000_000,
+        200_000_000,
+    )
+    .await;
+    let position = fetch_position(
+        &mut context.banks_client,
+        position_pda(&program_id, &owner, 0),
+    )
+    .await;
+    assert_eq!(position.collateral_amount, 99_800_000);
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    assert_eq!(custody.collateral_amount, 99_800_000);
+    assert_eq!(custody.collected_fee_amount, 200_000);
+    assert_eq!(custody.protocol_fee_amount, 40_000);
+
+    // Closing flat pays the collateral back less another 0.2 tokens
+    send(
+        &mut context,
+        close_position_instruction(&program_id, &owner, 0, &user_collateral_account, &market),
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, user_collateral_account).await,
+        balance - 400_000
+    );
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    assert_eq!(custody.collateral_amount, 0);
+    assert_eq!(custody.collected_fee_amount, 400_000);
+    assert_eq!(custody.protocol_fee_amount, 80_000);
+    assert_eq!(
+        token_balance(&mut context.banks_client, market.custody_token_account).await,
+        TEST_LIQUIDITY + 400_000
+    );
+}
+
+#[tokio::test]
+async fn test_collect_fees() {
+    let (mut context, program_id, market, user_collateral_account) = setup_market_with_params(
+        &BorrowRateParams::default(),
+        &TEST_FEES,
+        &OpenInterestParams::default(),
+    )
+    .await;
+    let admin = context.payer.pubkey();
+    open_position(
+        &mut context,
+        &program_id,
+        &market,
+        &user_collateral_account,
+        0,
+        Side::Long,
+        100_000_000,
+        200_000_000,
+    )
+    .await;
+
+    // Only a token account owned by the treasury can receive the fees
+    let stranger_token_account =
+        create_token_account(&mut context, &market.mint, &Pubkey::new_unique()).await;
+    let result = send(
+        &mut context,
+        collect_fees_instruction(&program_id, &market, &stranger_token_account),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::TokenAccountMismatch);
+
+    let treasury_token_account = create_token_account(&mut context, &market.mint, &admin).await;
+    send(
+        &mut context,
+        collect_fees_instruction(&program_id, &market, &treasury_token_account),
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, treasury_token_account).await,
+        40_000
+    );
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    assert_eq!(custody.protocol_fee_amount, 0);
+    assert_eq!(custody.collected_fee_amount, 200_000);
+    assert_eq!(
+        token_balance(&mut context.banks_client, market.custody_token_account).await,
+        TEST_LIQUIDITY + 100_000_000 - 40_000
+    );
+
+    // Sweeping again with nothing owed is a no-op
+    send(
+        &mut context,
+        collect_fees_instruction(&program_id, &market, &treasury_token_account),
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, treasury_token_account).await,
+        40_000
+    );
+}
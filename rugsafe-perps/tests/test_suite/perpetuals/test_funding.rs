@@ -0,0 +1,202 @@
+use solana_program::instruction::{AccountMeta, Instruction};
+use solana_program_test::*;
+use solana_sdk::{pubkey::Pubkey, signature::Keypair, signature::Signer};
+
+use rugsafe_perps::error::PerpsError;
+use rugsafe_perps::math::{self, FUNDING_PRECISION};
+use rugsafe_perps::state::perpetuals::Side;
+
+use super::test_perpetuals::{
+    add_collateral_instruction, advance_clock, assert_perps_error, close_position_instruction,
+    create_token_account, fetch_custody, fetch_position, liquidate_position_instruction,
+    open_position, position_pda, send, setup_positions, token_balance, Market, TEST_LIQUIDITY,
+};
+
+fn update_funding_instruction(program_id: &Pubkey, market: &Market) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(market.pool, false),
+            AccountMeta::new(market.custody, false),
+        ],
+        data: vec![1, 3],
+    }
+}
+
+#[tokio::test]
+async fn test_funding_settles_between_longs_and_shorts() {
+    let (mut context, program_id, market, user_collateral_account) = setup_positions(0, 0).await;
+    let owner = context.payer.pubkey();
+
+    // $300 long and $100 short, each on 100 tokens at $1.00
+    open_position(
+        &mut context,
+        &program_id,
+        &market,
+        &user_collateral_account,
+        0,
+        Side::Long,
+        100_000_000,
+        300_000_000,
+    )
+    .await;
+    open_position(
+        &mut context,
+        &program_id,
+        &market,
+        &user_collateral_account,
+        1,
+        Side::Short,
+        100_000_000,
+        100_000_000,
+    )
+    .await;
+    let long = fetch_position(
+        &mut context.banks_client,
+        position_pda(&program_id, &owner, 0),
+    )
+    .await;
+    assert_eq!(long.cumulative_funding_snapshot, 0);
+
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    assert_eq!(custody.long_open_interest_usd, 300_000_000);
+    assert_eq!(custody.short_open_interest_usd, 100_000_000);
+
+    // An hour at 50% skew: longs pay 5 bps, the same $0.15 is 15 bps of the shorts
+    advance_clock(&mut context, &program_id, &market, 3_600, 1_000_000).await;
+    send(
+        &mut context,
+        update_funding_instruction(&program_id, &market),
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    let long_index = 5 * FUNDING_PRECISION as i128 / 10_000;
+    assert_eq!(custody.cumulative_funding_long, long_index);
+    assert_eq!(custody.cumulative_funding_short, -3 * long_index);
+
+    // Adding collateral settles the long's funding into its collateral, along with the
+    // borrow interest on its $200 not backed by collateral
+    send(
+        &mut context,
+        add_collateral_instruction(
+            &program_id,
+            &owner,
+            0,
+            &user_collateral_account,
+            &market,
+            10_000_000,
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+    let long = fetch_position(
+        &mut context.banks_client,
+        position_pda(&program_id, &owner, 0),
+    )
+    .await;
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    let interest = math::interest_usd(200_000_000, 0, custody.cumulative_interest).unwrap();
+    assert!(interest > 0);
+    assert_eq!(long.collateral_amount, 109_850_000 - interest);
+    assert_eq!(long.cumulative_funding_snapshot, long_index);
+
+    // The short receives the funding on close, and the long has nothing left to pay
+    let balance = token_balance(&mut context.banks_client, user_collateral_account).await;
+    send(
+        &mut context,
+        close_position_instruction(&program_id, &owner, 1, &user_collateral_account, &market),
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, user_collateral_account).await,
+        balance + 100_150_000
+    );
+    send(
+        &mut context,
+        close_position_instruction(&program_id, &owner, 0, &user_collateral_account, &market),
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, user_collateral_account).await,
+        balance + 100_150_000 + 109_850_000 - interest
+    );
+    assert_eq!(
+        token_balance(&mut context.banks_client, market.custody_token_account).await,
+        TEST_LIQUIDITY + interest
+    );
+
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    assert_eq!(custody.long_open_interest_usd, 0);
+    assert_eq!(custody.short_open_interest_usd, 0);
+}
+
+#[tokio::test]
+async fn test_funding_counts_towards_liquidation() {
+    let (mut context, program_id, market, user_collateral_account) = setup_positions(0, 0).await;
+    let owner = context.payer.pubkey();
+
+    // $1,000 long on 100 tokens (10x) against a $100 short
+    open_position(
+        &mut context,
+        &program_id,
+        &market,
+        &user_collateral_account,
+        0,
+        Side::Long,
+        100_000_000,
+        1_000_000_000,
+    )
+    .await;
+    open_position(
+        &mut context,
+        &program_id,
+        &market,
+        &user_collateral_account,
+        1,
+        Side::Short,
+        100_000_000,
+        100_000_000,
+    )
+    .await;
+
+    let liquidator = Keypair::new();
+    let liquidator_collateral_account =
+        create_token_account(&mut context, &market.mint, &liquidator.pubkey()).await;
+    let liquidate = |context: &mut ProgramTestContext| {
+        liquidate_position_instruction(
+            &program_id,
+            &liquidator.pubkey(),
+            &context.payer.pubkey(),
+            0,
+            &liquidator_collateral_account,
+            &market,
+        )
+    };
+
+    // Price has not moved, so the long only becomes liquidatable through funding:
+    // 100 hours at 8.18 bps costs it $81.81 of its $100 collateral, on top of $11.20
+    // of borrow interest
+    let instruction = liquidate(&mut context);
+    let result = send(&mut context, instruction, &[&liquidator]).await;
+    assert_perps_error(result, 0, PerpsError::NotLiquidatable);
+
+    advance_clock(&mut context, &program_id, &market, 100 * 3_600, 1_000_000).await;
+    let instruction = liquidate(&mut context);
+    send(&mut context, instruction, &[&liquidator])
+        .await
+        .unwrap();
+    assert!(context
+        .banks_client
+        .get_account(position_pda(&program_id, &owner, 0))
+        .await
+        .unwrap()
+        .is_none());
+}
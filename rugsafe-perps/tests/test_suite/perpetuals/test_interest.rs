@@ -0,0 +1,309 @@
+use solana_program_test::*;
+use solana_sdk::{pubkey::Pubkey, signature::Keypair, signature::Signer};
+
+use rugsafe_perps::error::PerpsError;
+use rugsafe_perps::instructions::processor::Processor;
+use rugsafe_perps::math::{INTEREST_PRECISION, SECONDS_PER_YEAR};
+use rugsafe_perps::oracle::OracleType;
+use rugsafe_perps::state::perpetuals::Side;
+use rugsafe_perps::state::pool::{
+    BorrowRateParams, OpenInterestParams, PoolParams, DEFAULT_MAX_LEVERAGE_BPS,
+};
+
+use super::test_perpetuals::{
+    add_collateral_instruction, add_market_with_params_instruction, advance_clock,
+    assert_perps_error, close_position_instruction, create_token_account, fetch_custody,
+    fetch_position, init_pool_instruction, liquidate_position_instruction, open_position, pool_pda,
+    position_pda, send, setup_collateral, setup_market_with_params, token_balance, Market, NO_FEES,
+    TEST_LIQUIDITY, TEST_MAX_PRICE_AGE_SEC, TEST_MAX_PRICE_ERROR_BPS,
+};
+
+// A flat 10% a year, whatever the utilization
+const FLAT_RATE: BorrowRateParams = BorrowRateParams {
+    base_rate_bps: 1_000,
+    slope1_bps: 0,
+    slope2_bps: 0,
+    optimal_utilization_bps: 8_000,
+};
+
+#[tokio::test]
+async fn test_add_market_checks_borrow_rate() {
+    let program_id = Pubkey::new_unique();
+    let program_test =
+        ProgramTest::new("rugsafe_perps", program_id, processor!(Processor::process));
+    let mut context = program_test.start_with_context().await;
+    let admin = context.payer.pubkey();
+    let (mint, _) = setup_collateral(
+        &mut context.banks_client,
+        &context.payer,
+        context.last_blockhash,
+        0,
+    )
+    .await;
+    send(
+        &mut context,
+        init_pool_instruction(&program_id, &admin, &PoolParams::default()),
+        &[],
+    )
+    .await
+    .unwrap();
+    let market = Market::new(&program_id, &pool_pda(&program_id, &admin), &mint);
+
+    for borrow_rate in [
+        BorrowRateParams {
+            optimal_utilization_bps: 10_001,
+            ..BorrowRateParams::default()
+        },
+        BorrowRateParams {
+            base_rate_bps: u64::MAX,
+            ..BorrowRateParams::default()
+        },
+    ] {
+        let result = send(
+            &mut context,
+            add_market_with_params_instruction(
+                &program_id,
+                &admin,
+                &market,
+                OracleType::Test,
+                TEST_MAX_PRICE_AGE_SEC,
+                TEST_MAX_PRICE_ERROR_BPS,
+                &borrow_rate,
+                &NO_FEES,
+                &OpenInterestParams::default(),
+                DEFAULT_MAX_LEVERAGE_BPS,
+            ),
+            &[],
+        )
+        .await;
+        assert_perps_error(result, 0, PerpsError::InvalidBorrowRateParams);
+    }
+
+    send(
+        &mut context,
+        add_market_with_params_instruction(
+            &program_id,
+            &admin,
+            &market,
+            OracleType::Test,
+            TEST_MAX_PRICE_AGE_SEC,
+            TEST_MAX_PRICE_ERROR_BPS,
+            &FLAT_RATE,
+            &NO_FEES,
+            &OpenInterestParams::default(),
+            DEFAULT_MAX_LEVERAGE_BPS,
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    assert_eq!(custody.borrow_rate, FLAT_RATE);
+    assert_eq!(custody.cumulative_interest, 0);
+}
+
+#[tokio::test]
+async fn test_interest_charged_on_borrowed_size() {
+    let (mut context, program_id, market, user_collateral_account) =
+        setup_market_with_params(&FLAT_RATE, &NO_FEES, &OpenInterestParams::default()).await;
+    let owner = context.payer.pubkey();
+    let position_key = position_pda(&program_id, &owner, 0);
+
+    // $200 long on 100 tokens at $1.00 borrows $100 and locks 200 tokens
+    open_position(
+        &mut context,
+        &program_id,
+        &market,
+        &user_collateral_account,
+        0,
+        Side::Long,
+        100_000_000,
+        200_000_000,
+    )
+    .await;
+    let position = fetch_position(&mut context.banks_client, position_key).await;
+    assert_eq!(position.borrow_size_usd, 100_000_000);
+    assert_eq!(position.cumulative_interest_snapshot, 0);
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    assert_eq!(custody.locked_amount, 200_000_000);
+
+    // A year at 10% costs $10 of collateral when collateral changes
+    advance_clock(
+        &mut context,
+        &program_id,
+        &market,
+        SECONDS_PER_YEAR as i64,
+        1_000_000,
+    )
+    .await;
+    send(
+        &mut context,
+        add_collateral_instruction(
+            &program_id,
+            &owner,
+            0,
+            &user_collateral_account,
+            &market,
+            10_000_000,
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    assert_eq!(custody.cumulative_interest, INTEREST_PRECISION / 10);
+    let position = fetch_position(&mut context.banks_client, position_key).await;
+    assert_eq!(position.collateral_amount, 100_000_000);
+    assert_eq!(
+        position.cumulative_interest_snapshot,
+        INTEREST_PRECISION / 10
+    );
+    assert_eq!(position.borrow_size_usd, 100_000_000);
+
+    // Another year is charged on close
+    advance_clock(
+        &mut context,
+        &program_id,
+        &market,
+        SECONDS_PER_YEAR as i64,
+        1_000_000,
+    )
+    .await;
+    let balance = token_balance(&mut context.banks_client, user_collateral_account).await;
+    send(
+        &mut context,
+        close_position_instruction(&program_id, &owner, 0, &user_collateral_account, &market),
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, user_collateral_account).await,
+        balance + 90_000_000
+    );
+
+    // The interest stays with the market
+    assert_eq!(
+        token_balance(&mut context.banks_client, market.custody_token_account).await,
+        TEST_LIQUIDITY + 20_000_000
+    );
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    assert_eq!(custody.locked_amount, 0);
+}
+
+#[tokio::test]
+async fn test_interest_rate_follows_pool_utilization() {
+    // 10% a year at full utilization
+    let borrow_rate = BorrowRateParams {
+        base_rate_bps: 0,
+        slope1_bps: 1_000,
+        slope2_bps: 0,
+        optimal_utilization_bps: 10_000,
+    };
+    let (mut context, program_id, market, user_collateral_account) =
+        setup_market_with_params(&borrow_rate, &NO_FEES, &OpenInterestParams::default()).await;
+    let owner = context.payer.pubkey();
+
+    // $1,000 long on 500 tokens locks 1,000 of the 10,000 LP tokens
+    open_position(
+        &mut context,
+        &program_id,
+        &market,
+        &user_collateral_account,
+        0,
+        Side::Long,
+        500_000_000,
+        1_000_000_000,
+    )
+    .await;
+
+    // The collateral is not lent out, so utilization is 10% and the rate 1%
+    advance_clock(
+        &mut context,
+        &program_id,
+        &market,
+        SECONDS_PER_YEAR as i64,
+        1_000_000,
+    )
+    .await;
+    send(
+        &mut context,
+        add_collateral_instruction(
+            &program_id,
+            &owner,
+            0,
+            &user_collateral_account,
+            &market,
+            10_000_000,
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    assert_eq!(custody.cumulative_interest, INTEREST_PRECISION / 100);
+}
+
+#[tokio::test]
+async fn test_interest_counts_towards_liquidation() {
+    let (mut context, program_id, market, user_collateral_account) =
+        setup_market_with_params(&FLAT_RATE, &NO_FEES, &OpenInterestParams::default()).await;
+    let owner = context.payer.pubkey();
+
+    // $1,000 long on 100 tokens (10x) borrows $900
+    open_position(
+        &mut context,
+        &program_id,
+        &market,
+        &user_collateral_account,
+        0,
+        Side::Long,
+        100_000_000,
+        1_000_000_000,
+    )
+    .await;
+
+    let liquidator = Keypair::new();
+    let liquidator_collateral_account =
+        create_token_account(&mut context, &market.mint, &liquidator.pubkey()).await;
+    let liquidate = |context: &mut ProgramTestContext| {
+        liquidate_position_instruction(
+            &program_id,
+            &liquidator.pubkey(),
+            &context.payer.pubkey(),
+            0,
+            &liquidator_collateral_account,
+            &market,
+        )
+    };
+
+    let instruction = liquidate(&mut context);
+    let result = send(&mut context, instruction, &[&liquidator]).await;
+    assert_perps_error(result, 0, PerpsError::NotLiquidatable);
+
+    // A year of interest leaves $10 of equity, below the $50 maintenance margin
+    advance_clock(
+        &mut context,
+        &program_id,
+        &market,
+        SECONDS_PER_YEAR as i64,
+        1_000_000,
+    )
+    .await;
+    let instruction = liquidate(&mut context);
+    send(&mut context, instruction, &[&liquidator])
+        .await
+        .unwrap();
+    assert!(context
+        .banks_client
+        .get_account(position_pda(&program_id, &owner, 0))
+        .await
+        .unwrap()
+        .is_none());
+
+    // The liquidator's $5 reward comes out of the remaining equity
+    assert_eq!(
+        token_balance(&mut context.banks_client, liquidator_collateral_account).await,
+        5_000_000
+    );
+}
@@ -0,0 +1,265 @@
+use borsh::BorshDeserialize;
+use solana_program::instruction::AccountMeta;
+use solana_program_test::*;
+use solana_sdk::{
+    account::AccountSharedData, instruction::InstructionError, pubkey::Pubkey, signature::Keypair,
+    signature::Signer, transaction::TransactionError,
+};
+
+use rugsafe_perps::error::PerpsError;
+use rugsafe_perps::state::order::LimitOrder;
+use rugsafe_perps::state::perpetuals::{Side, UserPositions};
+
+use super::test_perpetuals::{
+    assert_perps_error, cancel_limit_order_instruction, execute_limit_order_instruction,
+    fetch_custody, fetch_position, limit_order_escrow_pda, limit_order_pda,
+    place_limit_order_instruction, position_pda, send, set_oracle_price, setup_positions,
+    token_balance, user_positions_pda, TEST_LIQUIDITY,
+};
+
+const ORDER_ID: u64 = 7;
+
+/// A funded keeper, which pays for the positions it fills.
+fn keeper(context: &mut ProgramTestContext) -> Keypair {
+    let keeper = Keypair::new();
+    context.set_account(
+        &keeper.pubkey(),
+        &AccountSharedData::new(1_000_000_000, 0, &solana_program::system_program::id()),
+    );
+    keeper
+}
+
+async fn account_exists(context: &mut ProgramTestContext, key: Pubkey) -> bool {
+    context
+        .banks_client
+        .get_account(key)
+        .await
+        .unwrap()
+        .is_some()
+}
+
+#[tokio::test]
+async fn test_place_and_cancel_limit_order() {
+    let (mut context, program_id, market, user_collateral_account) = setup_positions(0, 0).await;
+    let owner = context.payer.pubkey();
+    let order_key = limit_order_pda(&program_id, &owner, ORDER_ID);
+    let escrow_key = limit_order_escrow_pda(&program_id, &order_key);
+
+    let balance = token_balance(&mut context.banks_client, user_collateral_account).await;
+    let place = place_limit_order_instruction(
+        &program_id,
+        &owner,
+        ORDER_ID,
+        &user_collateral_account,
+        &market,
+        Side::Long,
+        100_000_000,
+        200_000_000,
+        900_000,
+    );
+    send(&mut context, place.clone(), &[]).await.unwrap();
+
+    // The collateral waits in the order's escrow
+    assert_eq!(
+        token_balance(&mut context.banks_client, user_collateral_account).await,
+        balance - 100_000_000
+    );
+    assert_eq!(
+        token_balance(&mut context.banks_client, escrow_key).await,
+        100_000_000
+    );
+    let account = context
+        .banks_client
+        .get_account(order_key)
+        .await
+        .unwrap()
+        .unwrap();
+    let order = LimitOrder::deserialize(&mut &account.data[..]).unwrap();
+    assert_eq!(order.owner, owner);
+    assert_eq!(order.custody, market.custody);
+    assert_eq!(order.side, Side::Long);
+    assert_eq!(order.collateral_amount, 100_000_000);
+    assert_eq!(order.size_usd, 200_000_000);
+    assert_eq!(order.limit_price, 900_000);
+
+    let result = send(&mut context, place, &[]).await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::AccountAlreadyInitialized)
+    );
+
+    // Only the owner can cancel
+    let stranger = Keypair::new();
+    let mut cancel =
+        cancel_limit_order_instruction(&program_id, &owner, ORDER_ID, &user_collateral_account);
+    cancel.accounts[0] = AccountMeta::new(stranger.pubkey(), true);
+    let result = send(&mut context, cancel, &[&stranger]).await;
+    assert_perps_error(result, 0, PerpsError::Unauthorized);
+
+    send(
+        &mut context,
+        cancel_limit_order_instruction(&program_id, &owner, ORDER_ID, &user_collateral_account),
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, user_collateral_account).await,
+        balance
+    );
+    assert!(!account_exists(&mut context, order_key).await);
+    assert!(!account_exists(&mut context, escrow_key).await);
+}
+
+#[tokio::test]
+async fn test_keeper_fills_long_limit_order() {
+    let (mut context, program_id, market, user_collateral_account) = setup_positions(0, 0).await;
+    let owner = context.payer.pubkey();
+    let keeper = keeper(&mut context);
+    let order_key = limit_order_pda(&program_id, &owner, ORDER_ID);
+
+    send(
+        &mut context,
+        place_limit_order_instruction(
+            &program_id,
+            &owner,
+            ORDER_ID,
+            &user_collateral_account,
+            &market,
+            Side::Long,
+            100_000_000,
+            200_000_000,
+            900_000,
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let execute = execute_limit_order_instruction(
+        &program_id,
+        &keeper.pubkey(),
+        &owner,
+        ORDER_ID,
+        0,
+        &market,
+    );
+    let result = send(&mut context, execute.clone(), &[&keeper]).await;
+    assert_perps_error(result, 0, PerpsError::LimitPriceNotReached);
+
+    // The first position of the owner opens at the oracle price, creating their UserPositions
+    set_oracle_price(&mut context, &program_id, &market.oracle, 850_000).await;
+    send(&mut context, execute, &[&keeper]).await.unwrap();
+
+    let position = fetch_position(
+        &mut context.banks_client,
+        position_pda(&program_id, &owner, 0),
+    )
+    .await;
+    assert_eq!(position.owner, owner);
+    assert_eq!(position.side, Side::Long);
+    assert_eq!(position.price, 850_000);
+    assert_eq!(position.size_usd, 200_000_000);
+    assert_eq!(position.collateral_amount, 100_000_000);
+    assert_eq!(position.collateral_usd, 85_000_000);
+
+    let account = context
+        .banks_client
+        .get_account(user_positions_pda(&program_id, &owner))
+        .await
+        .unwrap()
+        .unwrap();
+    let user_positions = UserPositions::deserialize(&mut &account.data[..]).unwrap();
+    assert_eq!(user_positions.next_position_idx, 1);
+    assert_eq!(user_positions.active_positions, vec![0]);
+
+    assert_eq!(
+        token_balance(&mut context.banks_client, market.custody_token_account).await,
+        TEST_LIQUIDITY + 100_000_000
+    );
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    assert_eq!(custody.long_open_interest_usd, 200_000_000);
+    assert!(!account_exists(&mut context, order_key).await);
+    assert!(
+        !account_exists(
+            &mut context,
+            limit_order_escrow_pda(&program_id, &order_key)
+        )
+        .await
+    );
+}
+
+#[tokio::test]
+async fn test_keeper_fills_short_limit_order() {
+    let (mut context, program_id, market, user_collateral_account) =
+        setup_positions(1, 100_000_000).await;
+    let owner = context.payer.pubkey();
+    let keeper = keeper(&mut context);
+
+    send(
+        &mut context,
+        place_limit_order_instruction(
+            &program_id,
+            &owner,
+            ORDER_ID,
+            &user_collateral_account,
+            &market,
+            Side::Short,
+            100_000_000,
+            100_000_000,
+            1_100_000,
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let execute = execute_limit_order_instruction(
+        &program_id,
+        &keeper.pubkey(),
+        &owner,
+        ORDER_ID,
+        1,
+        &market,
+    );
+    set_oracle_price(&mut context, &program_id, &market.oracle, 1_050_000).await;
+    let result = send(&mut context, execute.clone(), &[&keeper]).await;
+    assert_perps_error(result, 0, PerpsError::LimitPriceNotReached);
+
+    // Filled as the owner's next position
+    set_oracle_price(&mut context, &program_id, &market.oracle, 1_200_000).await;
+    send(&mut context, execute, &[&keeper]).await.unwrap();
+    let position = fetch_position(
+        &mut context.banks_client,
+        position_pda(&program_id, &owner, 1),
+    )
+    .await;
+    assert_eq!(position.side, Side::Short);
+    assert_eq!(position.price, 1_200_000);
+    assert_eq!(
+        fetch_custody(&mut context.banks_client, market.custody)
+            .await
+            .short_open_interest_usd,
+        100_000_000
+    );
+}
+
+#[test]
+fn test_limit_order_fill_direction() {
+    let order = |side| LimitOrder {
+        side,
+        limit_price: 1_000_000,
+        ..LimitOrder::default()
+    };
+
+    assert!(order(Side::Long).is_fillable(1_000_000).unwrap());
+    assert!(order(Side::Long).is_fillable(999_999).unwrap());
+    assert!(!order(Side::Long).is_fillable(1_000_001).unwrap());
+    assert!(order(Side::Short).is_fillable(1_000_000).unwrap());
+    assert!(order(Side::Short).is_fillable(1_000_001).unwrap());
+    assert!(!order(Side::Short).is_fillable(999_999).unwrap());
+    assert_eq!(
+        order(Side::None).is_fillable(1_000_000),
+        Err(PerpsError::InvalidOrderData)
+    );
+}
@@ -0,0 +1,298 @@
+use solana_program_test::*;
+use solana_sdk::{pubkey::Pubkey, signature::Signer};
+
+use rugsafe_perps::error::PerpsError;
+use rugsafe_perps::instructions::processor::Processor;
+use rugsafe_perps::state::perpetuals::Side;
+
+use super::test_perpetuals::{
+    add_liquidity_instruction, assert_perps_error, close_position_instruction,
+    create_token_account, fetch_custody, fetch_position, lp_mint_pda, open_position,
+    open_position_instruction, position_pda, remove_liquidity_instruction, send, set_oracle_price,
+    setup_collateral, setup_market, token_balance, Market,
+};
+
+/// Starts a $1 market with 1000 tokens in the payer's account, and an empty LP token account for the payer.
+async fn setup_provider() -> (ProgramTestContext, Pubkey, Market, Pubkey, Pubkey) {
+    let program_id = Pubkey::new_unique();
+    let program_test =
+        ProgramTest::new("rugsafe_perps", program_id, processor!(Processor::process));
+    let mut context = program_test.start_with_context().await;
+    let (mint, token_account) = setup_collateral(
+        &mut context.banks_client,
+        &context.payer,
+        context.last_blockhash,
+        1_000_000_000,
+    )
+    .await;
+    let market = setup_market(&mut context, &program_id, &mint).await;
+    set_oracle_price(&mut context, &program_id, &market.oracle, 1_000_000).await;
+    let owner = context.payer.pubkey();
+    let lp_account = create_token_account(
+        &mut context,
+        &lp_mint_pda(&program_id, &market.pool),
+        &owner,
+    )
+    .await;
+    (context, program_id, market, token_account, lp_account)
+}
+
+#[tokio::test]
+async fn test_add_and_remove_liquidity() {
+    let (mut context, program_id, market, token_account, lp_account) = setup_provider().await;
+    let provider = context.payer.pubkey();
+    let add = |amount, min_lp_amount| {
+        add_liquidity_instruction(
+            &program_id,
+            &provider,
+            &token_account,
+            &lp_account,
+            &market,
+            &[market],
+            amount,
+            min_lp_amount,
+        )
+    };
+    let remove = |lp_amount, min_amount_out| {
+        remove_liquidity_instruction(
+            &program_id,
+            &provider,
+            &token_account,
+            &lp_account,
+            &market,
+            &[market],
+            lp_amount,
+            min_amount_out,
+        )
+    };
+
+    let result = send(&mut context, add(500_000_000, 500_000_001), &[]).await;
+    assert_perps_error(result, 0, PerpsError::SlippageExceeded);
+
+    // The first deposit mints one LP token per dollar
+    send(&mut context, add(500_000_000, 500_000_000), &[])
+        .await
+        .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, lp_account).await,
+        500_000_000
+    );
+    assert_eq!(
+        token_balance(&mut context.banks_client, market.custody_token_account).await,
+        500_000_000
+    );
+
+    // At $2 the pool is worth twice as much per LP token
+    set_oracle_price(&mut context, &program_id, &market.oracle, 2_000_000).await;
+    send(&mut context, add(100_000_000, 0), &[]).await.unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, lp_account).await,
+        600_000_000
+    );
+
+    let result = send(&mut context, remove(200_000_000, 200_000_001), &[]).await;
+    assert_perps_error(result, 0, PerpsError::SlippageExceeded);
+    send(&mut context, remove(200_000_000, 200_000_000), &[])
+        .await
+        .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, token_account).await,
+        600_000_000
+    );
+    assert_eq!(
+        token_balance(&mut context.banks_client, lp_account).await,
+        400_000_000
+    );
+
+    // Every market of the pool must be passed to value it
+    let mut instruction = remove(100_000_000, 0);
+    instruction.accounts.truncate(8);
+    let result = send(&mut context, instruction, &[]).await;
+    assert_perps_error(result, 0, PerpsError::CustodyMismatch);
+}
+
+#[tokio::test]
+async fn test_liquidity_backs_trader_profits() {
+    let (mut context, program_id, market, token_account, lp_account) = setup_provider().await;
+    let provider = context.payer.pubkey();
+    let remove = |lp_amount| {
+        remove_liquidity_instruction(
+            &program_id,
+            &provider,
+            &token_account,
+            &lp_account,
+            &market,
+            &[market],
+            lp_amount,
+            0,
+        )
+    };
+
+    send(
+        &mut context,
+        add_liquidity_instruction(
+            &program_id,
+            &provider,
+            &token_account,
+            &lp_account,
+            &market,
+            &[market],
+            500_000_000,
+            0,
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+    open_position(
+        &mut context,
+        &program_id,
+        &market,
+        &token_account,
+        0,
+        Side::Long,
+        100_000_000,
+        200_000_000,
+    )
+    .await;
+
+    // At $1.50 the pool owns $750 but owes the long $100 of profit: a fifth of the LP redeems $130
+    set_oracle_price(&mut context, &program_id, &market.oracle, 1_500_000).await;
+    let balance = token_balance(&mut context.banks_client, token_account).await;
+    send(&mut context, remove(100_000_000), &[]).await.unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, token_account).await,
+        balance + 86_666_666
+    );
+
+    // Tokens locked for the position and its collateral cannot be withdrawn
+    let result = send(&mut context, remove(400_000_000), &[]).await;
+    assert_perps_error(result, 0, PerpsError::InsufficientLiquidity);
+
+    // The profit is paid out of the pool's tokens
+    send(
+        &mut context,
+        close_position_instruction(&program_id, &provider, 0, &token_account, &market),
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, token_account).await,
+        balance + 86_666_666 + 100_000_000 + 66_666_666
+    );
+
+    // The remaining LP tokens redeem everything left in the custody
+    send(&mut context, remove(400_000_000), &[]).await.unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, market.custody_token_account).await,
+        0
+    );
+}
+
+#[tokio::test]
+async fn test_position_cannot_lock_more_than_liquidity() {
+    let (mut context, program_id, market, token_account, lp_account) = setup_provider().await;
+    let owner = context.payer.pubkey();
+    let open = |position_id, size_usd| {
+        open_position_instruction(
+            &program_id,
+            &owner,
+            &token_account,
+            &market,
+            &position_pda(&program_id, &owner, position_id),
+            Side::Long,
+            100_000_000,
+            size_usd,
+            u64::MAX,
+        )
+    };
+
+    // Without LP deposits no profit is backed
+    let result = send(&mut context, open(0, 100_000_000), &[]).await;
+    assert_perps_error(result, 0, PerpsError::InsufficientLiquidity);
+
+    send(
+        &mut context,
+        add_liquidity_instruction(
+            &program_id,
+            &owner,
+            &token_account,
+            &lp_account,
+            &market,
+            &[market],
+            150_000_000,
+            0,
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+
+    // Traders' collateral is not pool liquidity: $200 locks more than the $150 deposited
+    let result = send(&mut context, open(0, 200_000_000), &[]).await;
+    assert_perps_error(result, 0, PerpsError::InsufficientLiquidity);
+
+    send(&mut context, open(0, 100_000_000), &[]).await.unwrap();
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    assert_eq!(custody.locked_amount, 100_000_000);
+
+    // Only $50 is left to lock
+    let result = send(&mut context, open(1, 100_000_000), &[]).await;
+    assert_perps_error(result, 0, PerpsError::InsufficientLiquidity);
+    send(&mut context, open(1, 50_000_000), &[]).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_payout_is_backed_by_the_position() {
+    let (mut context, program_id, market, token_account, lp_account) = setup_provider().await;
+    let owner = context.payer.pubkey();
+    send(
+        &mut context,
+        add_liquidity_instruction(
+            &program_id,
+            &owner,
+            &token_account,
+            &lp_account,
+            &market,
+            &[market],
+            500_000_000,
+            0,
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+    open_position(
+        &mut context,
+        &program_id,
+        &market,
+        &token_account,
+        0,
+        Side::Short,
+        100_000_000,
+        200_000_000,
+    )
+    .await;
+    let close = close_position_instruction(&program_id, &owner, 0, &token_account, &market);
+
+    // At $0.30 the $140 profit is 466 tokens, more than the 100 collateral and 200 locked
+    set_oracle_price(&mut context, &program_id, &market.oracle, 300_000).await;
+    let result = send(&mut context, close.clone(), &[]).await;
+    assert_perps_error(result, 0, PerpsError::InsufficientLiquidity);
+    let position = fetch_position(
+        &mut context.banks_client,
+        position_pda(&program_id, &owner, 0),
+    )
+    .await;
+    assert_eq!(position.size_usd, 200_000_000);
+
+    // At $0.60 the $80 profit is 133 tokens
+    set_oracle_price(&mut context, &program_id, &market.oracle, 600_000).await;
+    let balance = token_balance(&mut context.banks_client, token_account).await;
+    send(&mut context, close, &[]).await.unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, token_account).await,
+        balance + 100_000_000 + 133_333_333
+    );
+}
@@ -0,0 +1,302 @@
+use rugsafe_perps::math::{
+    apply_funding, borrow_rate_bps, bps_of, calculate_pnl, equity_usd, exceeds_leverage,
+    exceeds_skew, fee_amount, funding_index_deltas, funding_usd, interest_index_delta,
+    interest_usd, is_liquidatable, lp_amount_for_deposit, lp_redemption_usd, pro_rata,
+    pro_rata_ceil, split_fee, token_to_usd, traders_pnl_usd, usd_to_token, usd_to_token_ceil,
+    utilization_bps, BPS_POWER, FUNDING_PRECISION, INTEREST_PRECISION, PRICE_SCALE,
+    SECONDS_PER_HOUR, SECONDS_PER_YEAR,
+};
+use rugsafe_perps::state::perpetuals::Side;
+use rugsafe_perps::state::pool::BorrowRateParams;
+
+#[test]
+fn test_token_usd_conversions() {
+    // 2.5 tokens (6 decimals) at $40 is $100
+    assert_eq!(
+        token_to_usd(2_500_000, 6, 40 * PRICE_SCALE),
+        Some(100 * PRICE_SCALE)
+    );
+    assert_eq!(
+        usd_to_token(100 * PRICE_SCALE, 6, 40 * PRICE_SCALE),
+        Some(2_500_000)
+    );
+
+    // 9-decimal mints scale the same way
+    assert_eq!(
+        token_to_usd(1_000_000_000, 9, 150 * PRICE_SCALE),
+        Some(150 * PRICE_SCALE)
+    );
+
+    // $100 at $0.90 is 111.111111... tokens, rounded down or up
+    assert_eq!(
+        usd_to_token(100 * PRICE_SCALE, 6, 900_000),
+        Some(111_111_111)
+    );
+    assert_eq!(
+        usd_to_token_ceil(100 * PRICE_SCALE, 6, 900_000),
+        Some(111_111_112)
+    );
+    assert_eq!(
+        usd_to_token_ceil(100 * PRICE_SCALE, 6, 1_000_000),
+        Some(100_000_000)
+    );
+
+    assert_eq!(usd_to_token(1, 6, 0), None);
+    assert_eq!(token_to_usd(u64::MAX, 0, u64::MAX), None);
+}
+
+#[test]
+fn test_calculate_pnl() {
+    let size = 1_000 * PRICE_SCALE;
+
+    assert_eq!(
+        calculate_pnl(Side::Long, size, PRICE_SCALE, 1_100_000),
+        Some((100 * PRICE_SCALE, 0))
+    );
+    assert_eq!(
+        calculate_pnl(Side::Long, size, PRICE_SCALE, 900_000),
+        Some((0, 100 * PRICE_SCALE))
+    );
+    assert_eq!(
+        calculate_pnl(Side::Short, size, PRICE_SCALE, 1_100_000),
+        Some((0, 100 * PRICE_SCALE))
+    );
+    assert_eq!(
+        calculate_pnl(Side::Short, size, PRICE_SCALE, 900_000),
+        Some((100 * PRICE_SCALE, 0))
+    );
+    assert_eq!(
+        calculate_pnl(Side::Long, size, PRICE_SCALE, PRICE_SCALE),
+        Some((0, 0))
+    );
+
+    assert_eq!(
+        calculate_pnl(Side::None, size, PRICE_SCALE, PRICE_SCALE),
+        None
+    );
+    assert_eq!(calculate_pnl(Side::Long, size, 0, PRICE_SCALE), None);
+}
+
+#[test]
+fn test_leverage_limits() {
+    assert_eq!(equity_usd(500, 100, 0), 600);
+    assert_eq!(equity_usd(500, 0, 100), 400);
+    assert_eq!(equity_usd(500, 0, 600), 0);
+
+    let max_leverage = 10 * BPS_POWER;
+    assert!(!exceeds_leverage(2_000, 200, max_leverage));
+    assert!(exceeds_leverage(2_000, 199, max_leverage));
+    assert!(exceeds_leverage(1, 0, max_leverage));
+    assert!(!exceeds_leverage(0, 0, max_leverage));
+}
+
+#[test]
+fn test_maintenance_margin() {
+    assert_eq!(bps_of(2_000 * PRICE_SCALE, 50), Some(10 * PRICE_SCALE));
+    assert_eq!(bps_of(u64::MAX, 2 * BPS_POWER), None);
+
+    // 5% of $2,000 is $100
+    assert!(!is_liquidatable(2_000, 100, 500));
+    assert!(is_liquidatable(2_000, 99, 500));
+    assert!(is_liquidatable(2_000, 0, 500));
+    assert!(!is_liquidatable(0, 0, 500));
+}
+
+#[test]
+fn test_funding_index_deltas() {
+    let hour = SECONDS_PER_HOUR;
+
+    // $300 long against $100 short is a 50% skew: longs pay half of 10 bps per hour,
+    // and the $0.15 they pay is shared across the $100 of shorts
+    let (long_delta, short_delta) =
+        funding_index_deltas(300 * PRICE_SCALE, 100 * PRICE_SCALE, 10, hour).unwrap();
+    assert_eq!(long_delta, 5 * FUNDING_PRECISION as i128 / 10_000);
+    assert_eq!(short_delta, -15 * FUNDING_PRECISION as i128 / 10_000);
+
+    // Mirrored when shorts are heavier
+    assert_eq!(
+        funding_index_deltas(100 * PRICE_SCALE, 300 * PRICE_SCALE, 10, hour),
+        Some((short_delta, long_delta))
+    );
+
+    // Accrual is linear in time
+    let (half_hour, _) =
+        funding_index_deltas(300 * PRICE_SCALE, 100 * PRICE_SCALE, 10, hour / 2).unwrap();
+    assert_eq!(half_hour * 2, long_delta);
+
+    // Balanced or one-sided markets accrue nothing
+    assert_eq!(
+        funding_index_deltas(100 * PRICE_SCALE, 100 * PRICE_SCALE, 10, hour),
+        Some((0, 0))
+    );
+    assert_eq!(
+        funding_index_deltas(100 * PRICE_SCALE, 0, 10, hour),
+        Some((0, 0))
+    );
+    assert_eq!(
+        funding_index_deltas(0, 100 * PRICE_SCALE, 10, hour),
+        Some((0, 0))
+    );
+}
+
+#[test]
+fn test_funding_settlement() {
+    let index = 5 * FUNDING_PRECISION as i128 / 10_000;
+
+    // 5 bps of $300 is owed, 15 bps of $100 is received
+    assert_eq!(funding_usd(300 * PRICE_SCALE, 0, index), Some(150_000));
+    assert_eq!(
+        funding_usd(100 * PRICE_SCALE, 0, -3 * index),
+        Some(-150_000)
+    );
+    assert_eq!(funding_usd(100 * PRICE_SCALE, index, index), Some(0));
+
+    // Amounts owed round up, amounts received round down
+    assert_eq!(funding_usd(1, 0, 1), Some(1));
+    assert_eq!(funding_usd(1, 0, -1), Some(0));
+
+    assert_eq!(apply_funding(1_000, 0, 300), Some((700, 0)));
+    assert_eq!(apply_funding(1_000, 0, 1_300), Some((0, 300)));
+    assert_eq!(apply_funding(0, 1_000, -300), Some((0, 700)));
+    assert_eq!(apply_funding(0, 1_000, -1_300), Some((300, 0)));
+    assert_eq!(apply_funding(0, 0, 0), Some((0, 0)));
+}
+
+#[test]
+fn test_utilization() {
+    assert_eq!(utilization_bps(0, 0), 0);
+    assert_eq!(utilization_bps(0, 1_000), 0);
+    assert_eq!(utilization_bps(250, 1_000), 2_500);
+    assert_eq!(utilization_bps(1_000, 1_000), BPS_POWER);
+
+    // Leveraged positions can lock more than the custody holds
+    assert_eq!(utilization_bps(3_000, 1_000), BPS_POWER);
+    assert_eq!(utilization_bps(1, 0), BPS_POWER);
+}
+
+#[test]
+fn test_borrow_rate() {
+    // 1% base, +8% up to 80% utilization, +100% from there to 100%
+    let rate = |utilization| borrow_rate_bps(100, 800, 10_000, 8_000, utilization);
+    assert_eq!(rate(0), Some(100));
+    assert_eq!(rate(4_000), Some(500));
+    assert_eq!(rate(8_000), Some(900));
+    assert_eq!(rate(9_000), Some(5_900));
+    assert_eq!(rate(BPS_POWER), Some(10_900));
+
+    // Either slope alone covers the whole range
+    assert_eq!(
+        borrow_rate_bps(0, 1_000, 5_000, BPS_POWER, BPS_POWER),
+        Some(1_000)
+    );
+    assert_eq!(borrow_rate_bps(0, 1_000, 5_000, 0, 0), Some(0));
+    assert_eq!(borrow_rate_bps(0, 1_000, 5_000, 0, 5_000), Some(3_500));
+
+    assert_eq!(borrow_rate_bps(u64::MAX, 1, 0, BPS_POWER, BPS_POWER), None);
+    assert!(BorrowRateParams::default().is_valid());
+    assert!(!BorrowRateParams {
+        optimal_utilization_bps: BPS_POWER + 1,
+        ..BorrowRateParams::default()
+    }
+    .is_valid());
+}
+
+#[test]
+fn test_interest_accrual() {
+    // 10% a year is 0.1 per unit borrowed after a year
+    assert_eq!(
+        interest_index_delta(1_000, SECONDS_PER_YEAR),
+        Some(INTEREST_PRECISION / 10)
+    );
+    assert_eq!(interest_index_delta(1_000, 0), Some(0));
+    assert_eq!(interest_index_delta(0, SECONDS_PER_YEAR), Some(0));
+
+    let index = INTEREST_PRECISION / 10;
+    assert_eq!(
+        interest_usd(100 * PRICE_SCALE, 0, index),
+        Some(10 * PRICE_SCALE)
+    );
+    assert_eq!(interest_usd(100 * PRICE_SCALE, index, index), Some(0));
+    assert_eq!(interest_usd(0, 0, index), Some(0));
+
+    // Interest owed rounds up
+    assert_eq!(interest_usd(1, 0, 1), Some(1));
+
+    // The index never moves backwards
+    assert_eq!(interest_usd(1, index, 0), None);
+}
+
+#[test]
+fn test_pro_rata() {
+    assert_eq!(pro_rata(100, 1, 4), Some(25));
+    assert_eq!(pro_rata(100, 1, 3), Some(33));
+    assert_eq!(pro_rata_ceil(100, 1, 3), Some(34));
+    assert_eq!(pro_rata_ceil(100, 1, 4), Some(25));
+    assert_eq!(pro_rata(u64::MAX, u64::MAX, u64::MAX), Some(u64::MAX));
+    assert_eq!(pro_rata(u64::MAX, 2, 1), None);
+    assert_eq!(pro_rata(100, 1, 0), None);
+    assert_eq!(pro_rata_ceil(100, 1, 0), None);
+}
+
+#[test]
+fn test_traders_pnl() {
+    // Longs of $200 on 200 tokens at $1.50 are $100 up, shorts of $100 on 100 tokens $50 down
+    assert_eq!(
+        traders_pnl_usd(200_000_000, 300_000_000, 100_000_000, 150_000_000),
+        50_000_000
+    );
+    assert_eq!(traders_pnl_usd(200_000_000, 150_000_000, 0, 0), -50_000_000);
+    assert_eq!(traders_pnl_usd(0, 0, 0, 0), 0);
+}
+
+#[test]
+fn test_lp_amounts() {
+    // The first deposit mints one LP unit per micro-dollar
+    assert_eq!(lp_amount_for_deposit(500_000_000, 0, 0), Some(500_000_000));
+    assert_eq!(
+        lp_amount_for_deposit(500_000_000, 1_000, 0),
+        Some(500_000_000)
+    );
+    // Later deposits mint their share of the AUM
+    assert_eq!(
+        lp_amount_for_deposit(100_000_000, 400_000_000, 200_000_000),
+        Some(50_000_000)
+    );
+    assert_eq!(lp_amount_for_deposit(1, 3, 1), Some(0));
+    // Outstanding LP tokens with nothing behind them must not be topped up at 1:1
+    assert_eq!(lp_amount_for_deposit(500_000_000, 0, 200_000_000), None);
+
+    assert_eq!(
+        lp_redemption_usd(50_000_000, 400_000_000, 200_000_000),
+        Some(100_000_000)
+    );
+    assert_eq!(lp_redemption_usd(1, 2, 3), Some(0));
+    assert_eq!(lp_redemption_usd(1, 2, 0), None);
+}
+
+#[test]
+fn test_fees() {
+    // 10 bps of a $200 position at $2 is 0.1 tokens
+    assert_eq!(
+        fee_amount(200 * PRICE_SCALE, 10, 6, 2 * PRICE_SCALE),
+        Some(100_000)
+    );
+    // Fees round up in the pool's favour
+    assert_eq!(fee_amount(1_000_000, 10, 6, 3 * PRICE_SCALE), Some(334));
+    assert_eq!(fee_amount(200 * PRICE_SCALE, 0, 6, PRICE_SCALE), Some(0));
+
+    assert_eq!(split_fee(100_000, 2_000), Some((20_000, 80_000)));
+    assert_eq!(split_fee(9, 2_000), Some((1, 8)));
+    assert_eq!(split_fee(100_000, 0), Some((0, 100_000)));
+    assert_eq!(split_fee(100_000, BPS_POWER), Some((100_000, 0)));
+}
+
+#[test]
+fn test_exceeds_skew() {
+    // Half of $300 of open interest allows $150 of skew
+    assert!(!exceeds_skew(150_000_000, 300_000_000, 5_000));
+    assert!(exceeds_skew(150_000_001, 300_000_000, 5_000));
+    assert!(!exceeds_skew(0, 300_000_000, 0));
+    assert!(exceeds_skew(1, 300_000_000, 0));
+    assert!(!exceeds_skew(u64::MAX, u64::MAX, BPS_POWER));
+}
@@ -0,0 +1,236 @@
+use solana_sdk::{pubkey::Pubkey, signature::Signer};
+
+use rugsafe_perps::error::PerpsError;
+use rugsafe_perps::oracle::OracleType;
+use rugsafe_perps::state::perpetuals::Side;
+use rugsafe_perps::state::pool::{BorrowRateParams, OpenInterestParams, DEFAULT_MAX_LEVERAGE_BPS};
+
+use super::test_perpetuals::{
+    add_market_with_params_instruction, assert_perps_error, close_position_instruction,
+    fetch_custody, open_position, open_position_instruction, position_pda, send,
+    setup_market_with_params, Market, NO_FEES, TEST_MAX_PRICE_AGE_SEC, TEST_MAX_PRICE_ERROR_BPS,
+};
+
+// $300 of open interest a side, and the sides may differ by at most 20% of the total
+const TEST_OPEN_INTEREST: OpenInterestParams = OpenInterestParams {
+    max_open_interest_usd: 300_000_000,
+    max_skew_bps: 2_000,
+};
+
+#[tokio::test]
+async fn test_add_market_checks_open_interest() {
+    let (mut context, program_id, market, _) =
+        setup_market_with_params(&BorrowRateParams::default(), &NO_FEES, &TEST_OPEN_INTEREST).await;
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    assert_eq!(custody.open_interest, TEST_OPEN_INTEREST);
+
+    let admin = context.payer.pubkey();
+    let other_market = Market::new(&program_id, &market.pool, &Pubkey::new_unique());
+    for open_interest in [
+        OpenInterestParams {
+            max_open_interest_usd: 0,
+            ..TEST_OPEN_INTEREST
+        },
+        OpenInterestParams {
+            max_skew_bps: 10_001,
+            ..TEST_OPEN_INTEREST
+        },
+    ] {
+        let result = send(
+            &mut context,
+            add_market_with_params_instruction(
+                &program_id,
+                &admin,
+                &other_market,
+                OracleType::Test,
+                TEST_MAX_PRICE_AGE_SEC,
+                TEST_MAX_PRICE_ERROR_BPS,
+                &BorrowRateParams::default(),
+                &NO_FEES,
+                &open_interest,
+                DEFAULT_MAX_LEVERAGE_BPS,
+            ),
+            &[],
+        )
+        .await;
+        assert_perps_error(result, 0, PerpsError::InvalidOpenInterestParams);
+    }
+}
+
+#[tokio::test]
+async fn test_open_interest_caps() {
+    let (mut context, program_id, market, user_collateral_account) =
+        setup_market_with_params(&BorrowRateParams::default(), &NO_FEES, &TEST_OPEN_INTEREST).await;
+    let owner = context.payer.pubkey();
+    let open = |position_id, side, size_usd| {
+        open_position_instruction(
+            &program_id,
+            &owner,
+            &user_collateral_account,
+            &market,
+            &position_pda(&program_id, &owner, position_id),
+            side,
+            size_usd,
+            size_usd,
+            match side {
+                Side::Long => u64::MAX,
+                _ => 0,
+            },
+        )
+    };
+
+    // The first position of an empty market is bounded by the open interest cap only
+    let result = send(&mut context, open(0, Side::Long, 350_000_000), &[]).await;
+    assert_perps_error(result, 0, PerpsError::MaxOpenInterestExceeded);
+    open_position(
+        &mut context,
+        &program_id,
+        &market,
+        &user_collateral_account,
+        0,
+        Side::Long,
+        100_000_000,
+        100_000_000,
+    )
+    .await;
+
+    // Adding to a one-sided market keeps the skew at 100%
+    let result = send(&mut context, open(1, Side::Long, 100_000_000), &[]).await;
+    assert_perps_error(result, 0, PerpsError::MaxSkewExceeded);
+
+    // Flipping the skew to $150 of $350 the other way is no better
+    let result = send(&mut context, open(1, Side::Short, 250_000_000), &[]).await;
+    assert_perps_error(result, 0, PerpsError::MaxSkewExceeded);
+    open_position(
+        &mut context,
+        &program_id,
+        &market,
+        &user_collateral_account,
+        1,
+        Side::Short,
+        200_000_000,
+        200_000_000,
+    )
+    .await;
+
+    // Longs stop at the $300 cap even when they narrow the skew
+    let result = send(&mut context, open(2, Side::Long, 250_000_000), &[]).await;
+    assert_perps_error(result, 0, PerpsError::MaxOpenInterestExceeded);
+    open_position(
+        &mut context,
+        &program_id,
+        &market,
+        &user_collateral_account,
+        2,
+        Side::Long,
+        150_000_000,
+        150_000_000,
+    )
+    .await;
+
+    // $100 of skew on $500 is right at the 20% limit
+    open_position(
+        &mut context,
+        &program_id,
+        &market,
+        &user_collateral_account,
+        3,
+        Side::Long,
+        50_000_000,
+        50_000_000,
+    )
+    .await;
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    assert_eq!(custody.long_open_interest_usd, 300_000_000);
+    assert_eq!(custody.short_open_interest_usd, 200_000_000);
+
+    // Closing the short leaves the market one-sided, which only shorts may now narrow
+    send(
+        &mut context,
+        close_position_instruction(&program_id, &owner, 1, &user_collateral_account, &market),
+        &[],
+    )
+    .await
+    .unwrap();
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    assert_eq!(custody.long_open_interest_usd, 300_000_000);
+    assert_eq!(custody.short_open_interest_usd, 0);
+    let result = send(&mut context, open(4, Side::Long, 1_000_000), &[]).await;
+    assert_perps_error(result, 0, PerpsError::MaxOpenInterestExceeded);
+    open_position(
+        &mut context,
+        &program_id,
+        &market,
+        &user_collateral_account,
+        4,
+        Side::Short,
+        100_000_000,
+        100_000_000,
+    )
+    .await;
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    assert_eq!(custody.short_open_interest_usd, 100_000_000);
+}
+
+#[tokio::test]
+async fn test_skew_ratio_without_open_interest_cap() {
+    // Nothing caps either side, but the sides may differ by at most half the open interest
+    let (mut context, program_id, market, user_collateral_account) = setup_market_with_params(
+        &BorrowRateParams::default(),
+        &NO_FEES,
+        &OpenInterestParams {
+            max_skew_bps: 5_000,
+            ..OpenInterestParams::default()
+        },
+    )
+    .await;
+    let owner = context.payer.pubkey();
+    let open_long = |position_id, size_usd| {
+        open_position_instruction(
+            &program_id,
+            &owner,
+            &user_collateral_account,
+            &market,
+            &position_pda(&program_id, &owner, position_id),
+            Side::Long,
+            size_usd,
+            size_usd,
+            u64::MAX,
+        )
+    };
+
+    open_position(
+        &mut context,
+        &program_id,
+        &market,
+        &user_collateral_account,
+        0,
+        Side::Long,
+        100_000_000,
+        100_000_000,
+    )
+    .await;
+    let result = send(&mut context, open_long(1, 100_000_000), &[]).await;
+    assert_perps_error(result, 0, PerpsError::MaxSkewExceeded);
+
+    // A $50 short lets longs grow the skew back to half of the larger total
+    open_position(
+        &mut context,
+        &program_id,
+        &market,
+        &user_collateral_account,
+        1,
+        Side::Short,
+        50_000_000,
+        50_000_000,
+    )
+    .await;
+    let result = send(&mut context, open_long(2, 51_000_000), &[]).await;
+    assert_perps_error(result, 0, PerpsError::MaxSkewExceeded);
+    send(&mut context, open_long(2, 50_000_000), &[])
+        .await
+        .unwrap();
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    assert_eq!(custody.long_open_interest_usd, 150_000_000);
+    assert_eq!(custody.short_open_interest_usd, 50_000_000);
+}
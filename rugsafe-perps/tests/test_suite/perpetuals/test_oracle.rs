@@ -0,0 +1,403 @@
+use solana_program_test::*;
+use solana_sdk::{
+    account::AccountSharedData,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    signature::{Keypair, Signer},
+};
+
+use borsh::BorshDeserialize;
+use rugsafe_perps::error::PerpsError;
+use rugsafe_perps::instructions::processor::Processor;
+use rugsafe_perps::oracle::{
+    OracleParams, OraclePrice, OracleType, TestOracle, PYTH_PRICE_ACCOUNT_LEN,
+};
+use rugsafe_perps::state::perpetuals::{Position, Side};
+
+use super::test_perpetuals::{
+    add_market_with_oracle_instruction, assert_perps_error, open_position_instruction, pool_pda,
+    provide_liquidity, send, set_test_oracle, set_test_oracle_instruction, setup_collateral,
+    setup_market, unix_timestamp, Market, TEST_LIQUIDITY, TEST_MAX_PRICE_AGE_SEC,
+    TEST_MAX_PRICE_ERROR_BPS,
+};
+
+const NOW: i64 = 1_700_000_000;
+
+fn oracle_params() -> OracleParams {
+    OracleParams {
+        oracle_account: Pubkey::new_unique(),
+        oracle_type: OracleType::Pyth,
+        max_price_age_sec: 60,
+        max_price_error_bps: 100,
+    }
+}
+
+/// Builds a Pyth v2 price account with the given aggregate price.
+fn pyth_price_account(price: i64, expo: i32, conf: u64, publish_time: i64, status: u32) -> Vec<u8> {
+    let mut data = vec![0; PYTH_PRICE_ACCOUNT_LEN];
+    data[0..4].copy_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+    data[4..8].copy_from_slice(&2u32.to_le_bytes());
+    data[8..12].copy_from_slice(&3u32.to_le_bytes());
+    data[20..24].copy_from_slice(&expo.to_le_bytes());
+    data[96..104].copy_from_slice(&publish_time.to_le_bytes());
+    data[208..216].copy_from_slice(&price.to_le_bytes());
+    data[216..224].copy_from_slice(&conf.to_le_bytes());
+    data[224..228].copy_from_slice(&status.to_le_bytes());
+    data
+}
+
+fn price(price: i64, expo: i32, conf: u64, publish_time: i64) -> OraclePrice {
+    OraclePrice {
+        price,
+        expo,
+        conf,
+        publish_time,
+    }
+}
+
+#[test]
+fn test_parse_pyth_price() {
+    let data = pyth_price_account(6_512_345_678, -8, 1_234_567, NOW, 1);
+    assert_eq!(
+        OraclePrice::from_pyth(&data).unwrap(),
+        price(6_512_345_678, -8, 1_234_567, NOW)
+    );
+
+    let invalid: ProgramError = PerpsError::InvalidOraclePrice.into();
+    assert_eq!(
+        OraclePrice::from_pyth(&data[..PYTH_PRICE_ACCOUNT_LEN - 1]).unwrap_err(),
+        invalid
+    );
+
+    let mut bad_magic = data.clone();
+    bad_magic[0] = 0;
+    assert_eq!(OraclePrice::from_pyth(&bad_magic).unwrap_err(), invalid);
+
+    // Product and mapping accounts share the magic but not the account type
+    let mut product = data.clone();
+    product[8..12].copy_from_slice(&2u32.to_le_bytes());
+    assert_eq!(OraclePrice::from_pyth(&product).unwrap_err(), invalid);
+
+    // Halted or unknown aggregates are not prices
+    let halted = pyth_price_account(6_512_345_678, -8, 1_234_567, NOW, 2);
+    assert_eq!(OraclePrice::from_pyth(&halted).unwrap_err(), invalid);
+}
+
+#[test]
+fn test_checked_price() {
+    let params = oracle_params();
+
+    // Scaled to 6 decimals from any exponent
+    assert_eq!(
+        price(6_512_345_678, -8, 0, NOW).checked_price(&params, NOW),
+        Ok(65_123_456)
+    );
+    assert_eq!(
+        price(150, -2, 0, NOW).checked_price(&params, NOW),
+        Ok(1_500_000)
+    );
+    assert_eq!(
+        price(3, 0, 0, NOW).checked_price(&params, NOW),
+        Ok(3_000_000)
+    );
+    assert_eq!(
+        price(1_000_000, -6, 0, NOW).checked_price(&params, NOW),
+        Ok(1_000_000)
+    );
+
+    // Staleness is measured against the publish time
+    assert_eq!(
+        price(1_000_000, -6, 0, NOW - 60).checked_price(&params, NOW),
+        Ok(1_000_000)
+    );
+    assert_eq!(
+        price(1_000_000, -6, 0, NOW - 61).checked_price(&params, NOW),
+        Err(PerpsError::StaleOracle.into())
+    );
+
+    // Confidence may be at most 1% of the price
+    assert_eq!(
+        price(1_000_000, -6, 10_000, NOW).checked_price(&params, NOW),
+        Ok(1_000_000)
+    );
+    assert_eq!(
+        price(1_000_000, -6, 10_001, NOW).checked_price(&params, NOW),
+        Err(PerpsError::OracleConfidenceTooWide.into())
+    );
+
+    // Non-positive prices and prices below PRICE_SCALE precision are rejected
+    for invalid in [
+        price(0, -6, 0, NOW),
+        price(-1_000_000, -6, 0, NOW),
+        price(1, -10, 0, NOW),
+    ] {
+        assert_eq!(
+            invalid.checked_price(&params, NOW),
+            Err(PerpsError::InvalidOraclePrice.into())
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_set_test_oracle() {
+    let program_id = Pubkey::new_unique();
+    let program_test =
+        ProgramTest::new("rugsafe_perps", program_id, processor!(Processor::process));
+    let mut context = program_test.start_with_context().await;
+    let admin = context.payer.pubkey();
+    let (mint, _) = setup_collateral(
+        &mut context.banks_client,
+        &context.payer,
+        context.last_blockhash,
+        0,
+    )
+    .await;
+    let market = setup_market(&mut context, &program_id, &mint).await;
+
+    let oracle = TestOracle {
+        price: 2_500_000,
+        expo: -6,
+        conf: 1_000,
+        publish_time: NOW,
+    };
+
+    // Only the pool admin sets prices
+    let stranger = Keypair::new();
+    let result = send(
+        &mut context,
+        set_test_oracle_instruction(&program_id, &stranger.pubkey(), &market, &oracle),
+        &[&stranger],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::Unauthorized);
+
+    // The first update creates the oracle account, later ones overwrite it
+    for oracle in [
+        oracle,
+        TestOracle {
+            price: 2_600_000,
+            publish_time: NOW + 1,
+            ..oracle
+        },
+    ] {
+        send(
+            &mut context,
+            set_test_oracle_instruction(&program_id, &admin, &market, &oracle),
+            &[],
+        )
+        .await
+        .unwrap();
+        let account = context
+            .banks_client
+            .get_account(market.oracle)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(account.owner, program_id);
+        assert_eq!(TestOracle::try_from_slice(&account.data).unwrap(), oracle);
+    }
+
+    // Each market only accepts its own oracle account
+    let result = send(
+        &mut context,
+        set_test_oracle_instruction(
+            &program_id,
+            &admin,
+            &Market {
+                oracle: Pubkey::new_unique(),
+                ..market
+            },
+            &oracle,
+        ),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::OracleMismatch);
+}
+
+#[tokio::test]
+async fn test_add_market_checks_oracle() {
+    let program_id = Pubkey::new_unique();
+    let program_test =
+        ProgramTest::new("rugsafe_perps", program_id, processor!(Processor::process));
+    let mut context = program_test.start_with_context().await;
+    let admin = context.payer.pubkey();
+    let (mint, _) = setup_collateral(
+        &mut context.banks_client,
+        &context.payer,
+        context.last_blockhash,
+        0,
+    )
+    .await;
+    let market = setup_market(&mut context, &program_id, &mint).await;
+    let (other_mint, _) = setup_collateral(
+        &mut context.banks_client,
+        &context.payer,
+        context.last_blockhash,
+        0,
+    )
+    .await;
+    let other_market = Market::new(&program_id, &pool_pda(&program_id, &admin), &other_mint);
+
+    // Test markets are priced by their test oracle PDA
+    let result = send(
+        &mut context,
+        add_market_with_oracle_instruction(
+            &program_id,
+            &admin,
+            &Market {
+                oracle: market.oracle,
+                ..other_market
+            },
+            OracleType::Test,
+            TEST_MAX_PRICE_AGE_SEC,
+            TEST_MAX_PRICE_ERROR_BPS,
+        ),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::InvalidPda);
+
+    for (max_price_age_sec, max_price_error_bps) in [(0, 100), (60, 0), (60, 10_001)] {
+        let result = send(
+            &mut context,
+            add_market_with_oracle_instruction(
+                &program_id,
+                &admin,
+                &other_market,
+                OracleType::Pyth,
+                max_price_age_sec,
+                max_price_error_bps,
+            ),
+            &[],
+        )
+        .await;
+        assert_perps_error(result, 0, PerpsError::InvalidOracleParams);
+    }
+}
+
+#[tokio::test]
+async fn test_open_position_checks_oracle() {
+    let program_id = Pubkey::new_unique();
+    let program_test =
+        ProgramTest::new("rugsafe_perps", program_id, processor!(Processor::process));
+    let mut context = program_test.start_with_context().await;
+    let owner = context.payer.pubkey();
+    let (mint, user_collateral_account) = setup_collateral(
+        &mut context.banks_client,
+        &context.payer,
+        context.last_blockhash,
+        1_000_000_000,
+    )
+    .await;
+    let market = setup_market(&mut context, &program_id, &mint).await;
+    let position = Pubkey::find_program_address(
+        &[b"position", owner.as_ref(), &0u64.to_le_bytes()],
+        &program_id,
+    )
+    .0;
+    let open = |market: &Market, user_collateral_account: &Pubkey| {
+        open_position_instruction(
+            &program_id,
+            &owner,
+            user_collateral_account,
+            market,
+            &position,
+            Side::Long,
+            100_000_000,
+            100_000_000,
+            u64::MAX,
+        )
+    };
+
+    let now = unix_timestamp(&mut context).await;
+    let oracle = TestOracle {
+        price: 100_000_000,
+        expo: -8,
+        conf: 0,
+        publish_time: now - TEST_MAX_PRICE_AGE_SEC as i64 - 1,
+    };
+    set_test_oracle(&mut context, &program_id, &market.oracle, &oracle);
+    let result = send(&mut context, open(&market, &user_collateral_account), &[]).await;
+    assert_perps_error(result, 0, PerpsError::StaleOracle);
+
+    set_test_oracle(
+        &mut context,
+        &program_id,
+        &market.oracle,
+        &TestOracle {
+            conf: 1_000_001,
+            publish_time: now,
+            ..oracle
+        },
+    );
+    let result = send(&mut context, open(&market, &user_collateral_account), &[]).await;
+    assert_perps_error(result, 0, PerpsError::OracleConfidenceTooWide);
+
+    // A Pyth-priced market in the same pool, owned by an outside program
+    let (pyth_mint, pyth_collateral_account) = setup_collateral(
+        &mut context.banks_client,
+        &context.payer,
+        context.last_blockhash,
+        1_000_000_000,
+    )
+    .await;
+    let pyth_market = Market {
+        oracle: Pubkey::new_unique(),
+        ..Market::new(&program_id, &market.pool, &pyth_mint)
+    };
+    send(
+        &mut context,
+        add_market_with_oracle_instruction(
+            &program_id,
+            &owner,
+            &pyth_market,
+            OracleType::Pyth,
+            TEST_MAX_PRICE_AGE_SEC,
+            TEST_MAX_PRICE_ERROR_BPS,
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let data = pyth_price_account(99_950_000, -8, 25_000, now, 1);
+    let mut account = AccountSharedData::new(1_000_000_000, data.len(), &Pubkey::new_unique());
+    account.set_data_from_slice(&data);
+    context.set_account(&pyth_market.oracle, &account);
+    // Pricing the deposit values every market of the pool
+    set_test_oracle(
+        &mut context,
+        &program_id,
+        &market.oracle,
+        &TestOracle {
+            publish_time: now,
+            ..oracle
+        },
+    );
+    provide_liquidity(
+        &mut context,
+        &program_id,
+        &pyth_market,
+        &[market, pyth_market],
+        TEST_LIQUIDITY,
+    )
+    .await;
+
+    send(
+        &mut context,
+        open(&pyth_market, &pyth_collateral_account),
+        &[],
+    )
+    .await
+    .unwrap();
+    let account = context
+        .banks_client
+        .get_account(position)
+        .await
+        .unwrap()
+        .unwrap();
+    let opened = Position::deserialize(&mut &account.data[..]).unwrap();
+    assert_eq!(opened.price, 999_500);
+    assert_eq!(opened.custody, pyth_market.custody);
+}
@@ -1,22 +1,1194 @@
 // Import necessary dependencies
 use solana_program::instruction::{AccountMeta, Instruction};
-use solana_program::{system_instruction, sysvar};
+use solana_program::{clock::Clock, system_instruction, sysvar};
 use solana_program_test::*;
 
 use solana_sdk::{
+    account::{AccountSharedData, ReadableAccount},
+    instruction::InstructionError,
     program_pack::Pack,
     pubkey::Pubkey,
     signature::{Keypair, Signer},
-    transaction::Transaction,
+    transaction::{Transaction, TransactionError},
 };
 
 use spl_token::state::Account as TokenAccount;
 
 use borsh::{BorshDeserialize, BorshSerialize};
+use rugsafe_perps::error::PerpsError;
 use rugsafe_perps::instructions::processor::Processor;
+use rugsafe_perps::oracle::{OracleType, TestOracle, TEST_ORACLE_SEED};
 
+use rugsafe_perps::state::order::{
+    TriggerKind, LIMIT_ORDER_ESCROW_SEED, LIMIT_ORDER_SEED, TRIGGER_ORDER_SEED,
+};
 use rugsafe_perps::state::perpetuals::{Position, Side, UserPositions};
-use spl_associated_token_account::get_associated_token_address;
+use rugsafe_perps::state::pool::{
+    BorrowRateParams, Custody, FeeParams, OpenInterestParams, Pool, PoolParams, CUSTODY_SEED,
+    CUSTODY_TOKEN_ACCOUNT_SEED, DEFAULT_MAX_LEVERAGE_BPS, LP_MINT_SEED, POOL_SEED,
+};
+
+pub fn assert_perps_error(result: Result<(), BanksClientError>, index: u8, expected: PerpsError) {
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(index, InstructionError::Custom(expected as u32))
+    );
+}
+
+pub fn user_positions_pda(program_id: &Pubkey, owner: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"user_positions", owner.as_ref()], program_id).0
+}
+
+pub fn position_pda(program_id: &Pubkey, owner: &Pubkey, position_id: u64) -> Pubkey {
+    Pubkey::find_program_address(
+        &[b"position", owner.as_ref(), &position_id.to_le_bytes()],
+        program_id,
+    )
+    .0
+}
+
+pub fn pool_pda(program_id: &Pubkey, admin: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[POOL_SEED, admin.as_ref()], program_id).0
+}
+
+pub fn lp_mint_pda(program_id: &Pubkey, pool: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[LP_MINT_SEED, pool.as_ref()], program_id).0
+}
+
+pub const TEST_MAX_PRICE_AGE_SEC: u64 = 60;
+pub const TEST_MAX_PRICE_ERROR_BPS: u64 = 100;
+
+// Markets are fee-free unless a test is about fees, so payouts stay easy to follow
+pub const NO_FEES: FeeParams = FeeParams {
+    open_position_bps: 0,
+    close_position_bps: 0,
+    protocol_share_bps: 0,
+};
+
+/// Addresses of a market: the pool, its custody for `mint` and the oracle registered with it.
+#[derive(Clone, Copy, Debug)]
+pub struct Market {
+    pub pool: Pubkey,
+    pub custody: Pubkey,
+    pub custody_token_account: Pubkey,
+    pub mint: Pubkey,
+    pub oracle: Pubkey,
+}
+
+impl Market {
+    /// A market priced by its test oracle.
+    pub fn new(program_id: &Pubkey, pool: &Pubkey, mint: &Pubkey) -> Self {
+        Self {
+            pool: *pool,
+            custody: Pubkey::find_program_address(
+                &[CUSTODY_SEED, pool.as_ref(), mint.as_ref()],
+                program_id,
+            )
+            .0,
+            custody_token_account: Pubkey::find_program_address(
+                &[CUSTODY_TOKEN_ACCOUNT_SEED, pool.as_ref(), mint.as_ref()],
+                program_id,
+            )
+            .0,
+            mint: *mint,
+            oracle: Pubkey::find_program_address(
+                &[TEST_ORACLE_SEED, pool.as_ref(), mint.as_ref()],
+                program_id,
+            )
+            .0,
+        }
+    }
+
+    /// Pool, custody and custody token account, in the order the perpetuals instructions take them.
+    fn accounts(&self) -> [AccountMeta; 3] {
+        [
+            AccountMeta::new(self.pool, false),
+            AccountMeta::new(self.custody, false),
+            AccountMeta::new(self.custody_token_account, false),
+        ]
+    }
+}
+
+pub fn init_pool_instruction(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    params: &PoolParams,
+) -> Instruction {
+    let mut data = vec![1, 0];
+    data.extend_from_slice(&params.maintenance_margin_bps.to_le_bytes());
+    data.extend_from_slice(&params.liquidation_fee_bps.to_le_bytes());
+    data.extend_from_slice(&params.max_funding_rate_bps.to_le_bytes());
+    data.extend_from_slice(&params.keeper_fee_bps.to_le_bytes());
+
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*admin, true),
+            AccountMeta::new(pool_pda(program_id, admin), false),
+            AccountMeta::new(lp_mint_pda(program_id, &pool_pda(program_id, admin)), false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(solana_program::system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data,
+    }
+}
+
+pub fn add_market_instruction(program_id: &Pubkey, admin: &Pubkey, market: &Market) -> Instruction {
+    add_market_with_oracle_instruction(
+        program_id,
+        admin,
+        market,
+        OracleType::Test,
+        TEST_MAX_PRICE_AGE_SEC,
+        TEST_MAX_PRICE_ERROR_BPS,
+    )
+}
+
+pub fn add_market_with_oracle_instruction(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    market: &Market,
+    oracle_type: OracleType,
+    max_price_age_sec: u64,
+    max_price_error_bps: u64,
+) -> Instruction {
+    add_market_with_params_instruction(
+        program_id,
+        admin,
+        market,
+        oracle_type,
+        max_price_age_sec,
+        max_price_error_bps,
+        &BorrowRateParams::default(),
+        &NO_FEES,
+        &OpenInterestParams::default(),
+        DEFAULT_MAX_LEVERAGE_BPS,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn add_market_with_params_instruction(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    market: &Market,
+    oracle_type: OracleType,
+    max_price_age_sec: u64,
+    max_price_error_bps: u64,
+    borrow_rate: &BorrowRateParams,
+    fees: &FeeParams,
+    open_interest: &OpenInterestParams,
+    max_leverage_bps: u64,
+) -> Instruction {
+    let mut data = vec![1, 1, oracle_type as u8];
+    data.extend_from_slice(&max_price_age_sec.to_le_bytes());
+    data.extend_from_slice(&max_price_error_bps.to_le_bytes());
+    data.extend_from_slice(&borrow_rate.base_rate_bps.to_le_bytes());
+    data.extend_from_slice(&borrow_rate.slope1_bps.to_le_bytes());
+    data.extend_from_slice(&borrow_rate.slope2_bps.to_le_bytes());
+    data.extend_from_slice(&borrow_rate.optimal_utilization_bps.to_le_bytes());
+    data.extend_from_slice(&fees.open_position_bps.to_le_bytes());
+    data.extend_from_slice(&fees.close_position_bps.to_le_bytes());
+    data.extend_from_slice(&fees.protocol_share_bps.to_le_bytes());
+    data.extend_from_slice(&open_interest.max_open_interest_usd.to_le_bytes());
+    data.extend_from_slice(&open_interest.max_skew_bps.to_le_bytes());
+    data.extend_from_slice(&max_leverage_bps.to_le_bytes());
+
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*admin, true),
+            AccountMeta::new(market.pool, false),
+            AccountMeta::new(market.custody, false),
+            AccountMeta::new(market.custody_token_account, false),
+            AccountMeta::new_readonly(market.mint, false),
+            AccountMeta::new_readonly(market.oracle, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(solana_program::system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data,
+    }
+}
+
+pub fn set_test_oracle_instruction(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    market: &Market,
+    oracle: &TestOracle,
+) -> Instruction {
+    let mut data = vec![1, 2];
+    data.extend_from_slice(&borsh::to_vec(oracle).unwrap());
+
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*admin, true),
+            AccountMeta::new_readonly(market.pool, false),
+            AccountMeta::new_readonly(market.custody, false),
+            AccountMeta::new(market.oracle, false),
+            AccountMeta::new_readonly(solana_program::system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data,
+    }
+}
+
+/// Creates a 6-decimal collateral mint and a payer-owned token account holding `amount`.
+pub async fn setup_collateral(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    recent_blockhash: solana_sdk::hash::Hash,
+    amount: u64,
+) -> (Pubkey, Pubkey) {
+    let collateral_mint = Keypair::new();
+    let user_collateral_account = Keypair::new();
+    let rent = banks_client.get_rent().await.unwrap();
+
+    let transaction = Transaction::new_signed_with_payer(
+        &[
+            system_instruction::create_account(
+                &payer.pubkey(),
+                &collateral_mint.pubkey(),
+                rent.minimum_balance(spl_token::state::Mint::LEN),
+                spl_token::state::Mint::LEN as u64,
+                &spl_token::id(),
+            ),
+            spl_token::instruction::initialize_mint(
+                &spl_token::id(),
+                &collateral_mint.pubkey(),
+                &payer.pubkey(),
+                None,
+                6,
+            )
+            .unwrap(),
+            system_instruction::create_account(
+                &payer.pubkey(),
+                &user_collateral_account.pubkey(),
+                rent.minimum_balance(TokenAccount::LEN),
+                TokenAccount::LEN as u64,
+                &spl_token::id(),
+            ),
+            spl_token::instruction::initialize_account(
+                &spl_token::id(),
+                &user_collateral_account.pubkey(),
+                &collateral_mint.pubkey(),
+                &payer.pubkey(),
+            )
+            .unwrap(),
+            spl_token::instruction::mint_to(
+                &spl_token::id(),
+                &collateral_mint.pubkey(),
+                &user_collateral_account.pubkey(),
+                &payer.pubkey(),
+                &[],
+                amount,
+            )
+            .unwrap(),
+        ],
+        Some(&payer.pubkey()),
+        &[payer, &collateral_mint, &user_collateral_account],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    (collateral_mint.pubkey(), user_collateral_account.pubkey())
+}
+
+pub async fn unix_timestamp(context: &mut ProgramTestContext) -> i64 {
+    context
+        .banks_client
+        .get_sysvar::<Clock>()
+        .await
+        .unwrap()
+        .unix_timestamp
+}
+
+/// Writes `oracle` into a program-owned test oracle account.
+pub fn set_test_oracle(
+    context: &mut ProgramTestContext,
+    program_id: &Pubkey,
+    oracle_key: &Pubkey,
+    oracle: &TestOracle,
+) {
+    let data = borsh::to_vec(oracle).unwrap();
+    let mut account = AccountSharedData::new(1_000_000_000, data.len(), program_id);
+    account.set_data_from_slice(&data);
+    context.set_account(oracle_key, &account);
+}
+
+/// Sets a test oracle to `price`, scaled by PRICE_SCALE and published now.
+pub async fn set_oracle_price(
+    context: &mut ProgramTestContext,
+    program_id: &Pubkey,
+    oracle: &Pubkey,
+    price: u64,
+) {
+    let publish_time = unix_timestamp(context).await;
+    set_test_oracle(
+        context,
+        program_id,
+        oracle,
+        &TestOracle {
+            price: price as i64,
+            expo: -6,
+            conf: 0,
+            publish_time,
+        },
+    );
+}
+
+pub async fn fetch_pool(banks_client: &mut BanksClient, pool: Pubkey) -> Pool {
+    let account = banks_client.get_account(pool).await.unwrap().unwrap();
+    Pool::deserialize(&mut &account.data[..]).unwrap()
+}
+
+pub async fn fetch_custody(banks_client: &mut BanksClient, custody: Pubkey) -> Custody {
+    let account = banks_client.get_account(custody).await.unwrap().unwrap();
+    Custody::deserialize(&mut &account.data[..]).unwrap()
+}
+
+/// Moves the clock forward and republishes the oracle at `price`.
+pub async fn advance_clock(
+    context: &mut ProgramTestContext,
+    program_id: &Pubkey,
+    market: &Market,
+    seconds: i64,
+    price: u64,
+) {
+    let mut clock = context.banks_client.get_sysvar::<Clock>().await.unwrap();
+    clock.unix_timestamp += seconds;
+    context.set_sysvar(&clock);
+    set_oracle_price(context, program_id, &market.oracle, price).await;
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn open_position(
+    context: &mut ProgramTestContext,
+    program_id: &Pubkey,
+    market: &Market,
+    user_collateral_account: &Pubkey,
+    position_id: u64,
+    side: Side,
+    amount: u64,
+    size_usd: u64,
+) {
+    let owner = context.payer.pubkey();
+    let acceptable_price = match side {
+        Side::Long => u64::MAX,
+        _ => 0,
+    };
+    send(
+        context,
+        open_position_instruction(
+            program_id,
+            &owner,
+            user_collateral_account,
+            market,
+            &position_pda(program_id, &owner, position_id),
+            side,
+            amount,
+            size_usd,
+            acceptable_price,
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+}
+
+pub async fn fetch_position(banks_client: &mut BanksClient, position: Pubkey) -> Position {
+    let account = banks_client.get_account(position).await.unwrap().unwrap();
+    Position::deserialize(&mut &account.data[..]).unwrap()
+}
+
+/// Overwrites a stored position, e.g. to give it an entry price.
+async fn store_position(
+    context: &mut ProgramTestContext,
+    position_key: Pubkey,
+    position: &Position,
+) {
+    let mut account: AccountSharedData = context
+        .banks_client
+        .get_account(position_key)
+        .await
+        .unwrap()
+        .unwrap()
+        .into();
+    let mut data = vec![0; account.data().len()];
+    position.serialize(&mut &mut data[..]).unwrap();
+    account.set_data_from_slice(&data);
+    context.set_account(&position_key, &account);
+}
+
+pub async fn token_balance(banks_client: &mut BanksClient, token_account: Pubkey) -> u64 {
+    let account = banks_client
+        .get_account(token_account)
+        .await
+        .unwrap()
+        .unwrap();
+    TokenAccount::unpack(&account.data).unwrap().amount
+}
+
+pub async fn send(
+    context: &mut ProgramTestContext,
+    instruction: Instruction,
+    signers: &[&Keypair],
+) -> Result<(), BanksClientError> {
+    let recent_blockhash = context
+        .banks_client
+        .get_new_latest_blockhash(&context.last_blockhash)
+        .await
+        .unwrap();
+    context.last_blockhash = recent_blockhash;
+    let mut all_signers = vec![&context.payer];
+    all_signers.extend_from_slice(signers);
+    let transaction = Transaction::new_signed_with_payer(
+        &[instruction],
+        Some(&context.payer.pubkey()),
+        &all_signers,
+        recent_blockhash,
+    );
+    context.banks_client.process_transaction(transaction).await
+}
+
+/// Accounts shared by the instructions acting on an owner's position.
+fn position_accounts(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    position_id: u64,
+    user_collateral_account: &Pubkey,
+    market: &Market,
+) -> Vec<AccountMeta> {
+    let mut accounts = vec![
+        AccountMeta::new(*owner, true),
+        AccountMeta::new(position_pda(program_id, owner, position_id), false),
+        AccountMeta::new(*user_collateral_account, false),
+    ];
+    accounts.extend(market.accounts());
+    accounts.extend([
+        AccountMeta::new_readonly(market.oracle, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+    ]);
+    accounts
+}
+
+pub fn close_position_instruction(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    position_id: u64,
+    user_collateral_account: &Pubkey,
+    market: &Market,
+) -> Instruction {
+    let mut data = vec![0, 1];
+    data.extend_from_slice(&position_id.to_le_bytes());
+
+    let mut accounts = position_accounts(
+        program_id,
+        owner,
+        position_id,
+        user_collateral_account,
+        market,
+    );
+    accounts.push(AccountMeta::new(
+        user_positions_pda(program_id, owner),
+        false,
+    ));
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    }
+}
+
+pub fn decrease_position_instruction(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    position_id: u64,
+    user_collateral_account: &Pubkey,
+    market: &Market,
+    size_usd: u64,
+    withdraw_collateral: bool,
+) -> Instruction {
+    let mut instruction = close_position_instruction(
+        program_id,
+        owner,
+        position_id,
+        user_collateral_account,
+        market,
+    );
+    let mut data = vec![0, 6];
+    data.extend_from_slice(&position_id.to_le_bytes());
+    data.extend_from_slice(&size_usd.to_le_bytes());
+    data.push(withdraw_collateral as u8);
+    instruction.data = data;
+    instruction
+}
+
+pub fn add_collateral_instruction(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    position_id: u64,
+    user_collateral_account: &Pubkey,
+    market: &Market,
+    amount: u64,
+) -> Instruction {
+    let mut data = vec![0, 2];
+    data.extend_from_slice(&position_id.to_le_bytes());
+    data.extend_from_slice(&amount.to_le_bytes());
+
+    Instruction {
+        program_id: *program_id,
+        accounts: position_accounts(
+            program_id,
+            owner,
+            position_id,
+            user_collateral_account,
+            market,
+        ),
+        data,
+    }
+}
+
+fn remove_collateral_instruction(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    position_id: u64,
+    user_collateral_account: &Pubkey,
+    market: &Market,
+    amount: u64,
+) -> Instruction {
+    let mut instruction = add_collateral_instruction(
+        program_id,
+        owner,
+        position_id,
+        user_collateral_account,
+        market,
+        amount,
+    );
+    instruction.data[1] = 3;
+    instruction
+}
+
+fn insurance_fund_pda(program_id: &Pubkey, mint: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"insurance_fund", mint.as_ref()], program_id).0
+}
+
+fn init_insurance_fund_instruction(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    collateral_mint: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*payer, true),
+            AccountMeta::new(insurance_fund_pda(program_id, collateral_mint), false),
+            AccountMeta::new_readonly(*collateral_mint, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(solana_program::system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data: vec![0, 5],
+    }
+}
+
+pub fn liquidate_position_instruction(
+    program_id: &Pubkey,
+    liquidator: &Pubkey,
+    owner: &Pubkey,
+    position_id: u64,
+    liquidator_collateral_account: &Pubkey,
+    market: &Market,
+) -> Instruction {
+    let mut data = vec![0, 4];
+    data.extend_from_slice(&position_id.to_le_bytes());
+
+    let mut accounts = vec![
+        AccountMeta::new(*liquidator, true),
+        AccountMeta::new(*owner, false),
+        AccountMeta::new(position_pda(program_id, owner, position_id), false),
+        AccountMeta::new(*liquidator_collateral_account, false),
+    ];
+    accounts.extend(market.accounts());
+    accounts.extend([
+        AccountMeta::new(insurance_fund_pda(program_id, &market.mint), false),
+        AccountMeta::new_readonly(market.oracle, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new(user_positions_pda(program_id, owner), false),
+    ]);
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    }
+}
+
+pub fn trigger_order_pda(program_id: &Pubkey, position: &Pubkey, kind: TriggerKind) -> Pubkey {
+    Pubkey::find_program_address(
+        &[TRIGGER_ORDER_SEED, position.as_ref(), &[kind as u8]],
+        program_id,
+    )
+    .0
+}
+
+pub fn place_trigger_order_instruction(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    position_id: u64,
+    kind: TriggerKind,
+    trigger_price: u64,
+    size_usd: u64,
+) -> Instruction {
+    let position = position_pda(program_id, owner, position_id);
+    let mut data = vec![0, 7];
+    data.extend_from_slice(&position_id.to_le_bytes());
+    data.push(kind as u8);
+    data.extend_from_slice(&trigger_price.to_le_bytes());
+    data.extend_from_slice(&size_usd.to_le_bytes());
+
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*owner, true),
+            AccountMeta::new_readonly(position, false),
+            AccountMeta::new(trigger_order_pda(program_id, &position, kind), false),
+            AccountMeta::new_readonly(solana_program::system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data,
+    }
+}
+
+pub fn cancel_trigger_order_instruction(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    position_id: u64,
+    kind: TriggerKind,
+) -> Instruction {
+    let position = position_pda(program_id, owner, position_id);
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*owner, true),
+            AccountMeta::new(trigger_order_pda(program_id, &position, kind), false),
+        ],
+        data: vec![0, 8],
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn execute_trigger_order_instruction(
+    program_id: &Pubkey,
+    keeper: &Pubkey,
+    keeper_collateral_account: &Pubkey,
+    owner: &Pubkey,
+    position_id: u64,
+    kind: TriggerKind,
+    user_collateral_account: &Pubkey,
+    market: &Market,
+) -> Instruction {
+    let position = position_pda(program_id, owner, position_id);
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*keeper, true),
+        AccountMeta::new(*keeper_collateral_account, false),
+        AccountMeta::new(*owner, false),
+        AccountMeta::new(trigger_order_pda(program_id, &position, kind), false),
+        AccountMeta::new(position, false),
+        AccountMeta::new(*user_collateral_account, false),
+    ];
+    accounts.extend(market.accounts());
+    accounts.extend([
+        AccountMeta::new_readonly(market.oracle, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new(user_positions_pda(program_id, owner), false),
+    ]);
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: vec![0, 9],
+    }
+}
+
+pub fn limit_order_pda(program_id: &Pubkey, owner: &Pubkey, order_id: u64) -> Pubkey {
+    Pubkey::find_program_address(
+        &[LIMIT_ORDER_SEED, owner.as_ref(), &order_id.to_le_bytes()],
+        program_id,
+    )
+    .0
+}
+
+pub fn limit_order_escrow_pda(program_id: &Pubkey, order: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[LIMIT_ORDER_ESCROW_SEED, order.as_ref()], program_id).0
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn place_limit_order_instruction(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    order_id: u64,
+    user_collateral_account: &Pubkey,
+    market: &Market,
+    side: Side,
+    amount: u64,
+    size_usd: u64,
+    limit_price: u64,
+) -> Instruction {
+    let order = limit_order_pda(program_id, owner, order_id);
+    let side_byte = match side {
+        Side::Long => 1,
+        Side::Short => 2,
+        Side::None => 0,
+    };
+    let mut data = vec![0, 10];
+    data.extend_from_slice(&order_id.to_le_bytes());
+    data.push(side_byte);
+    data.extend_from_slice(&amount.to_le_bytes());
+    data.extend_from_slice(&size_usd.to_le_bytes());
+    data.extend_from_slice(&limit_price.to_le_bytes());
+
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*owner, true),
+            AccountMeta::new(order, false),
+            AccountMeta::new(limit_order_escrow_pda(program_id, &order), false),
+            AccountMeta::new(*user_collateral_account, false),
+            AccountMeta::new_readonly(market.pool, false),
+            AccountMeta::new_readonly(market.custody, false),
+            AccountMeta::new_readonly(market.mint, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(solana_program::system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data,
+    }
+}
+
+pub fn cancel_limit_order_instruction(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    order_id: u64,
+    user_collateral_account: &Pubkey,
+) -> Instruction {
+    let order = limit_order_pda(program_id, owner, order_id);
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*owner, true),
+            AccountMeta::new(order, false),
+            AccountMeta::new(limit_order_escrow_pda(program_id, &order), false),
+            AccountMeta::new(*user_collateral_account, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ],
+        data: vec![0, 11],
+    }
+}
+
+pub fn execute_limit_order_instruction(
+    program_id: &Pubkey,
+    keeper: &Pubkey,
+    owner: &Pubkey,
+    order_id: u64,
+    position_id: u64,
+    market: &Market,
+) -> Instruction {
+    let order = limit_order_pda(program_id, owner, order_id);
+    let mut accounts = vec![
+        AccountMeta::new(*keeper, true),
+        AccountMeta::new_readonly(*owner, false),
+        AccountMeta::new(order, false),
+        AccountMeta::new(limit_order_escrow_pda(program_id, &order), false),
+        AccountMeta::new(user_positions_pda(program_id, owner), false),
+        AccountMeta::new(position_pda(program_id, owner, position_id), false),
+    ];
+    accounts.extend(market.accounts());
+    accounts.extend([
+        AccountMeta::new_readonly(market.oracle, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(solana_program::system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+    ]);
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: vec![0, 12],
+    }
+}
+
+/// Liquidity instruction accounts, followed by every market of the pool for the AUM.
+fn liquidity_accounts(
+    provider: &Pubkey,
+    provider_token_account: &Pubkey,
+    provider_lp_account: &Pubkey,
+    program_id: &Pubkey,
+    market: &Market,
+    pool_markets: &[Market],
+) -> Vec<AccountMeta> {
+    let mut accounts = vec![
+        AccountMeta::new(*provider, true),
+        AccountMeta::new(*provider_token_account, false),
+        AccountMeta::new(*provider_lp_account, false),
+    ];
+    accounts.extend(market.accounts());
+    accounts.extend([
+        AccountMeta::new(lp_mint_pda(program_id, &market.pool), false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+    ]);
+    for pool_market in pool_markets {
+        accounts.extend([
+            AccountMeta::new_readonly(pool_market.custody, false),
+            AccountMeta::new_readonly(pool_market.custody_token_account, false),
+            AccountMeta::new_readonly(pool_market.oracle, false),
+        ]);
+    }
+    accounts
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn add_liquidity_instruction(
+    program_id: &Pubkey,
+    provider: &Pubkey,
+    provider_token_account: &Pubkey,
+    provider_lp_account: &Pubkey,
+    market: &Market,
+    pool_markets: &[Market],
+    amount: u64,
+    min_lp_amount: u64,
+) -> Instruction {
+    let mut data = vec![1, 4];
+    data.extend_from_slice(&amount.to_le_bytes());
+    data.extend_from_slice(&min_lp_amount.to_le_bytes());
+
+    Instruction {
+        program_id: *program_id,
+        accounts: liquidity_accounts(
+            provider,
+            provider_token_account,
+            provider_lp_account,
+            program_id,
+            market,
+            pool_markets,
+        ),
+        data,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn remove_liquidity_instruction(
+    program_id: &Pubkey,
+    provider: &Pubkey,
+    provider_token_account: &Pubkey,
+    provider_lp_account: &Pubkey,
+    market: &Market,
+    pool_markets: &[Market],
+    lp_amount: u64,
+    min_amount_out: u64,
+) -> Instruction {
+    let mut data = vec![1, 5];
+    data.extend_from_slice(&lp_amount.to_le_bytes());
+    data.extend_from_slice(&min_amount_out.to_le_bytes());
+
+    Instruction {
+        program_id: *program_id,
+        accounts: liquidity_accounts(
+            provider,
+            provider_token_account,
+            provider_lp_account,
+            program_id,
+            market,
+            pool_markets,
+        ),
+        data,
+    }
+}
+
+pub fn collect_fees_instruction(
+    program_id: &Pubkey,
+    market: &Market,
+    treasury_token_account: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(market.pool, false),
+            AccountMeta::new(market.custody, false),
+            AccountMeta::new(market.custody_token_account, false),
+            AccountMeta::new(*treasury_token_account, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ],
+        data: vec![1, 6],
+    }
+}
+
+/// Creates an empty token account for `mint` owned by `owner`.
+pub async fn create_token_account(
+    context: &mut ProgramTestContext,
+    mint: &Pubkey,
+    owner: &Pubkey,
+) -> Pubkey {
+    let token_account = Keypair::new();
+    let rent = context.banks_client.get_rent().await.unwrap();
+    let transaction = Transaction::new_signed_with_payer(
+        &[
+            system_instruction::create_account(
+                &context.payer.pubkey(),
+                &token_account.pubkey(),
+                rent.minimum_balance(TokenAccount::LEN),
+                TokenAccount::LEN as u64,
+                &spl_token::id(),
+            ),
+            spl_token::instruction::initialize_account(
+                &spl_token::id(),
+                &token_account.pubkey(),
+                mint,
+                owner,
+            )
+            .unwrap(),
+        ],
+        Some(&context.payer.pubkey()),
+        &[&context.payer, &token_account],
+        context.last_blockhash,
+    );
+    context
+        .banks_client
+        .process_transaction(transaction)
+        .await
+        .unwrap();
+    token_account.pubkey()
+}
+
+// Tokens LPs deposit into fixture markets, so positions have liquidity to lock
+pub const TEST_LIQUIDITY: u64 = 10_000_000_000;
+
+/// Mints `amount` of the market's token to the payer and deposits it as pool liquidity. Every market of the pool needs a price.
+pub async fn provide_liquidity(
+    context: &mut ProgramTestContext,
+    program_id: &Pubkey,
+    market: &Market,
+    pool_markets: &[Market],
+    amount: u64,
+) {
+    let provider = context.payer.pubkey();
+    let token_account = create_token_account(context, &market.mint, &provider).await;
+    let lp_account =
+        create_token_account(context, &lp_mint_pda(program_id, &market.pool), &provider).await;
+    send(
+        context,
+        spl_token::instruction::mint_to(
+            &spl_token::id(),
+            &market.mint,
+            &token_account,
+            &provider,
+            &[],
+            amount,
+        )
+        .unwrap(),
+        &[],
+    )
+    .await
+    .unwrap();
+    send(
+        context,
+        add_liquidity_instruction(
+            program_id,
+            &provider,
+            &token_account,
+            &lp_account,
+            market,
+            pool_markets,
+            amount,
+            0,
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+}
+
+/// Creates a pool administered by the payer with a test-oracle market for `mint`.
+pub async fn setup_market(
+    context: &mut ProgramTestContext,
+    program_id: &Pubkey,
+    mint: &Pubkey,
+) -> Market {
+    let admin = context.payer.pubkey();
+    send(
+        context,
+        init_pool_instruction(program_id, &admin, &PoolParams::default()),
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let market = Market::new(program_id, &pool_pda(program_id, &admin), mint);
+    send(
+        context,
+        add_market_instruction(program_id, &admin, &market),
+        &[],
+    )
+    .await
+    .unwrap();
+    market
+}
+
+/// Starts a context with a funded collateral account and a market priced at $1 with the given parameters and TEST_LIQUIDITY.
+pub async fn setup_market_with_params(
+    borrow_rate: &BorrowRateParams,
+    fees: &FeeParams,
+    open_interest: &OpenInterestParams,
+) -> (ProgramTestContext, Pubkey, Market, Pubkey) {
+    let program_id = Pubkey::new_unique();
+    let program_test =
+        ProgramTest::new("rugsafe_perps", program_id, processor!(Processor::process));
+    let mut context = program_test.start_with_context().await;
+    let admin = context.payer.pubkey();
+
+    let (mint, user_collateral_account) = setup_collateral(
+        &mut context.banks_client,
+        &context.payer,
+        context.last_blockhash,
+        1_000_000_000,
+    )
+    .await;
+    send(
+        &mut context,
+        init_pool_instruction(&program_id, &admin, &PoolParams::default()),
+        &[],
+    )
+    .await
+    .unwrap();
+    let market = Market::new(&program_id, &pool_pda(&program_id, &admin), &mint);
+    send(
+        &mut context,
+        add_market_with_params_instruction(
+            &program_id,
+            &admin,
+            &market,
+            OracleType::Test,
+            TEST_MAX_PRICE_AGE_SEC,
+            TEST_MAX_PRICE_ERROR_BPS,
+            borrow_rate,
+            fees,
+            open_interest,
+            DEFAULT_MAX_LEVERAGE_BPS,
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+    set_oracle_price(&mut context, &program_id, &market.oracle, 1_000_000).await;
+    provide_liquidity(
+        &mut context,
+        &program_id,
+        &market,
+        &[market],
+        TEST_LIQUIDITY,
+    )
+    .await;
+
+    (context, program_id, market, user_collateral_account)
+}
+
+/// Starts a context with a funded collateral account, a market priced at $1 with TEST_LIQUIDITY and `opens` 1x longs of `amount`.
+pub async fn setup_positions(
+    opens: u64,
+    amount: u64,
+) -> (ProgramTestContext, Pubkey, Market, Pubkey) {
+    let program_id = Pubkey::new_unique();
+    let program_test =
+        ProgramTest::new("rugsafe_perps", program_id, processor!(Processor::process));
+    let mut context = program_test.start_with_context().await;
+
+    let (collateral_mint, user_collateral_account) = setup_collateral(
+        &mut context.banks_client,
+        &context.payer,
+        context.last_blockhash,
+        1_000_000_000 * opens.max(1),
+    )
+    .await;
+    let market = setup_market(&mut context, &program_id, &collateral_mint).await;
+    set_oracle_price(&mut context, &program_id, &market.oracle, 1_000_000).await;
+    provide_liquidity(
+        &mut context,
+        &program_id,
+        &market,
+        &[market],
+        TEST_LIQUIDITY,
+    )
+    .await;
+
+    let owner = context.payer.pubkey();
+    for position_id in 0..opens {
+        send(
+            &mut context,
+            open_position_instruction(
+                &program_id,
+                &owner,
+                &user_collateral_account,
+                &market,
+                &position_pda(&program_id, &owner, position_id),
+                Side::Long,
+                amount,
+                amount,
+                u64::MAX,
+            ),
+            &[],
+        )
+        .await
+        .unwrap();
+    }
+
+    (context, program_id, market, user_collateral_account)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn open_position_instruction(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    user_collateral_account: &Pubkey,
+    market: &Market,
+    position_account: &Pubkey,
+    side: Side,
+    amount: u64,
+    size_usd: u64,
+    acceptable_price: u64,
+) -> Instruction {
+    let side_byte = match side {
+        Side::Long => 1,
+        Side::Short => 2,
+        Side::None => 0,
+    };
+
+    let mut data = vec![0, 0, side_byte];
+    data.extend_from_slice(&amount.to_le_bytes());
+    data.extend_from_slice(&size_usd.to_le_bytes());
+    data.extend_from_slice(&acceptable_price.to_le_bytes());
+
+    let mut accounts = vec![
+        AccountMeta::new(*owner, true),
+        AccountMeta::new(user_positions_pda(program_id, owner), false),
+        AccountMeta::new(*user_collateral_account, false),
+    ];
+    accounts.extend(market.accounts());
+    accounts.extend([
+        AccountMeta::new(*position_account, false),
+        AccountMeta::new_readonly(market.oracle, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(solana_program::system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+    ]);
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    }
+}
 
 #[tokio::test]
 async fn test_open_position() {
@@ -127,17 +1299,98 @@ async fn test_open_position() {
     // println!("Processing user token minting transaction...");
     banks_client.process_transaction(transaction).await.unwrap();
 
-    // **Step 4: Derive the custody associated token account**
-    // println!("Deriving custody associated token account...");
-    // let custody_account = spl_associated_token_account::get_associated_token_address(
-    //     &program_id,               // Custody is owned by the program
-    //     &collateral_mint.pubkey(), // Associated with collateral mint
-    // );
-    let (custody_pda, _) =
-        Pubkey::find_program_address(&[b"custody", payer.pubkey().as_ref()], &program_id);
-    // Use the custody PDA as the custody account
-    let custody_account = custody_pda;
-    // println!("Custody account: {:?}", custody_account);
+    // **Step 4: Create the pool and a market for the collateral mint, priced at $1.00**
+    // println!("Creating pool and market...");
+    let pool = pool_pda(&program_id, &payer.pubkey());
+    let market = Market::new(&program_id, &pool, &collateral_mint.pubkey());
+    let oracle = market.oracle;
+    let now = banks_client
+        .get_sysvar::<Clock>()
+        .await
+        .unwrap()
+        .unix_timestamp;
+    let transaction = Transaction::new_signed_with_payer(
+        &[
+            init_pool_instruction(&program_id, &payer.pubkey(), &PoolParams::default()),
+            add_market_instruction(&program_id, &payer.pubkey(), &market),
+            set_test_oracle_instruction(
+                &program_id,
+                &payer.pubkey(),
+                &market,
+                &TestOracle {
+                    price: 100_000_000,
+                    expo: -8,
+                    conf: 10_000,
+                    publish_time: now,
+                },
+            ),
+        ],
+        Some(&payer.pubkey()),
+        &[&payer],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await.unwrap();
+    let custody_account = market.custody_token_account;
+
+    // **Step 4b: Deposit pool liquidity for the position to lock**
+    let provider_token_account = Keypair::new();
+    let provider_lp_account = Keypair::new();
+    let rent = banks_client.get_rent().await.unwrap();
+    let transaction = Transaction::new_signed_with_payer(
+        &[
+            system_instruction::create_account(
+                &payer.pubkey(),
+                &provider_token_account.pubkey(),
+                rent.minimum_balance(TokenAccount::LEN),
+                TokenAccount::LEN as u64,
+                &spl_token::id(),
+            ),
+            spl_token::instruction::initialize_account(
+                &spl_token::id(),
+                &provider_token_account.pubkey(),
+                &collateral_mint.pubkey(),
+                &payer.pubkey(),
+            )
+            .unwrap(),
+            system_instruction::create_account(
+                &payer.pubkey(),
+                &provider_lp_account.pubkey(),
+                rent.minimum_balance(TokenAccount::LEN),
+                TokenAccount::LEN as u64,
+                &spl_token::id(),
+            ),
+            spl_token::instruction::initialize_account(
+                &spl_token::id(),
+                &provider_lp_account.pubkey(),
+                &lp_mint_pda(&program_id, &pool),
+                &payer.pubkey(),
+            )
+            .unwrap(),
+            spl_token::instruction::mint_to(
+                &spl_token::id(),
+                &collateral_mint.pubkey(),
+                &provider_token_account.pubkey(),
+                &payer.pubkey(),
+                &[],
+                TEST_LIQUIDITY,
+            )
+            .unwrap(),
+            add_liquidity_instruction(
+                &program_id,
+                &payer.pubkey(),
+                &provider_token_account.pubkey(),
+                &provider_lp_account.pubkey(),
+                &market,
+                &[market],
+                TEST_LIQUIDITY,
+                0,
+            ),
+        ],
+        Some(&payer.pubkey()),
+        &[&payer, &provider_token_account, &provider_lp_account],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await.unwrap();
 
     // **Step 5: Derive the user positions PDA**
     // println!("Deriving user positions PDA...");
@@ -159,11 +1412,13 @@ async fn test_open_position() {
         _ => panic!("Invalid side"), // Handle any unexpected values
     };
 
-    let mut instruction_data = Vec::with_capacity(11);
+    let mut instruction_data = Vec::with_capacity(27);
     instruction_data.push(module_tag); // Module tag for Perpetuals
     instruction_data.push(instruction_tag); // Instruction tag for OpenPosition
     instruction_data.push(side_byte); // Side byte
     instruction_data.extend_from_slice(&amount.to_le_bytes()); // Amount as u64 in little-endian
+    instruction_data.extend_from_slice(&amount.to_le_bytes()); // Size in USD, 1x at $1.00
+    instruction_data.extend_from_slice(&1_010_000u64.to_le_bytes()); // Acceptable price, 1% slippage
     println!("Instruction data: {:?}", instruction_data);
 
     // **Derive the position PDA based on the next position index, assuming it's 0 for now**
@@ -185,14 +1440,14 @@ async fn test_open_position() {
             AccountMeta::new(payer.pubkey(), true),      // Payer (signer)
             AccountMeta::new(user_positions_pda, false), // UserPositions account (PDA, writable)
             AccountMeta::new(user_collateral_account.pubkey(), false), // User's collateral token account (writable)
-            AccountMeta::new(collateral_mint.pubkey(), false), // Collateral mint account (readonly)
-            AccountMeta::new(custody_account, false), // Custody associated token account (writable)
-            AccountMeta::new_readonly(spl_token::id(), false), // SPL Token Program
+            AccountMeta::new(market.pool, false),                      // Pool account (writable)
+            AccountMeta::new(market.custody, false), // Market custody account (writable)
+            AccountMeta::new(market.custody_token_account, false), // Custody token account (writable)
+            AccountMeta::new(position_pda, false),                 // Position PDA (writable)
+            AccountMeta::new_readonly(oracle, false),              // Price oracle
+            AccountMeta::new_readonly(spl_token::id(), false),     // SPL Token Program
             AccountMeta::new_readonly(solana_program::system_program::id(), false), // System program
             AccountMeta::new_readonly(solana_program::sysvar::rent::id(), false),   // Rent sysvar
-            AccountMeta::new_readonly(spl_associated_token_account::id(), false), // Associated token program
-            AccountMeta::new_readonly(program_id, false),                         // Program account
-            AccountMeta::new(position_pda, false), // Add the position PDA here (writable)
         ],
         data: instruction_data,
     };
@@ -256,6 +1511,10 @@ async fn test_open_position() {
     assert_eq!(position.owner, payer.pubkey());
     assert_eq!(position.side, Side::Long);
     assert_eq!(position.size_usd, amount);
+    assert_eq!(position.price, 1_000_000);
+    assert_eq!(position.collateral_amount, amount);
+    assert_eq!(position.collateral_usd, amount);
+    assert_eq!(position.borrow_size_usd, 0);
 
     // **Check token balances**
     // println!("Checking token balances...");
@@ -279,7 +1538,729 @@ async fn test_open_position() {
         .unwrap();
     let custody_token_account = TokenAccount::unpack(&custody_account_data.data).unwrap();
 
-    assert_eq!(custody_token_account.amount, 500_000_000);
+    assert_eq!(custody_token_account.amount, TEST_LIQUIDITY + 500_000_000);
 
     // println!("Test passed: Position opened successfully.");
 }
+
+#[tokio::test]
+async fn test_open_position_rejects_wrong_pdas() {
+    let (mut context, program_id, market, user_collateral_account) = setup_positions(0, 0).await;
+    let owner = context.payer.pubkey();
+
+    // A custody that was never added to the pool
+    let result = send(
+        &mut context,
+        open_position_instruction(
+            &program_id,
+            &owner,
+            &user_collateral_account,
+            &Market {
+                custody: Pubkey::new_unique(),
+                ..market
+            },
+            &position_pda(&program_id, &owner, 0),
+            Side::Long,
+            100,
+            100,
+            u64::MAX,
+        ),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::InvalidCustodyData);
+
+    // Position PDA for an index other than next_position_idx
+    let result = send(
+        &mut context,
+        open_position_instruction(
+            &program_id,
+            &owner,
+            &user_collateral_account,
+            &market,
+            &position_pda(&program_id, &owner, 1),
+            Side::Long,
+            100,
+            100,
+            u64::MAX,
+        ),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::InvalidPda);
+
+    // Zero-sized positions are rejected before any account is touched
+    let result = send(
+        &mut context,
+        open_position_instruction(
+            &program_id,
+            &owner,
+            &user_collateral_account,
+            &market,
+            &position_pda(&program_id, &owner, 0),
+            Side::Long,
+            0,
+            100,
+            u64::MAX,
+        ),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::ZeroAmount);
+}
+
+#[tokio::test]
+async fn test_close_position_returns_collateral() {
+    let (mut context, program_id, market, user_collateral_account) =
+        setup_positions(1, 500_000_000).await;
+    let owner = context.payer.pubkey();
+
+    assert_eq!(
+        token_balance(&mut context.banks_client, user_collateral_account).await,
+        500_000_000
+    );
+
+    send(
+        &mut context,
+        close_position_instruction(&program_id, &owner, 0, &user_collateral_account, &market),
+        &[],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        token_balance(&mut context.banks_client, user_collateral_account).await,
+        1_000_000_000
+    );
+    assert_eq!(
+        token_balance(&mut context.banks_client, market.custody_token_account).await,
+        TEST_LIQUIDITY
+    );
+
+    // The position account is closed and its rent refunded
+    assert!(context
+        .banks_client
+        .get_account(position_pda(&program_id, &owner, 0))
+        .await
+        .unwrap()
+        .is_none());
+
+    // Closing again finds nothing
+    let result = send(
+        &mut context,
+        close_position_instruction(&program_id, &owner, 0, &user_collateral_account, &market),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::PositionNotFound);
+}
+
+#[tokio::test]
+async fn test_close_position_settles_pnl() {
+    let (mut context, program_id, market, user_collateral_account) =
+        setup_positions(2, 500_000_000).await;
+    let owner = context.payer.pubkey();
+    let custody = market.custody_token_account;
+    let starting_balance = token_balance(&mut context.banks_client, user_collateral_account).await;
+
+    // Both positions: $1,000 long entered at $1.00 on 500 tokens of collateral
+    for position_id in 0..2 {
+        let position_key = position_pda(&program_id, &owner, position_id);
+        let mut position = fetch_position(&mut context.banks_client, position_key).await;
+        position.price = 1_000_000;
+        position.size_usd = 1_000_000_000;
+        store_position(&mut context, position_key, &position).await;
+    }
+
+    // +10%: $100 profit paid at $1.10, 90.909090 tokens on top of the collateral
+    set_oracle_price(&mut context, &program_id, &market.oracle, 1_100_000).await;
+    send(
+        &mut context,
+        close_position_instruction(&program_id, &owner, 0, &user_collateral_account, &market),
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, user_collateral_account).await,
+        starting_balance + 590_909_090
+    );
+
+    // -10%: $100 loss taken at $0.90, 111.111112 tokens (rounded up) out of the collateral
+    set_oracle_price(&mut context, &program_id, &market.oracle, 900_000).await;
+    send(
+        &mut context,
+        close_position_instruction(&program_id, &owner, 1, &user_collateral_account, &market),
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, user_collateral_account).await,
+        starting_balance + 590_909_090 + 388_888_888
+    );
+    assert_eq!(
+        token_balance(&mut context.banks_client, custody).await,
+        TEST_LIQUIDITY + 1_000_000_000 - 590_909_090 - 388_888_888
+    );
+}
+
+#[tokio::test]
+async fn test_close_position_rejects_invalid_accounts() {
+    let (mut context, program_id, market, user_collateral_account) =
+        setup_positions(1, 500_000_000).await;
+    let owner = context.payer.pubkey();
+
+    // Another signer cannot close the owner's position
+    let stranger = Keypair::new();
+    let mut instruction =
+        close_position_instruction(&program_id, &owner, 0, &user_collateral_account, &market);
+    instruction.accounts[0] = AccountMeta::new(stranger.pubkey(), true);
+    let result = send(&mut context, instruction, &[&stranger]).await;
+    assert_perps_error(result, 0, PerpsError::InvalidPda);
+
+    // Only the oracle registered for the market is accepted
+    let fake_oracle = Pubkey::new_unique();
+    set_oracle_price(&mut context, &program_id, &fake_oracle, 2_000_000).await;
+    let result = send(
+        &mut context,
+        close_position_instruction(
+            &program_id,
+            &owner,
+            0,
+            &user_collateral_account,
+            &Market {
+                oracle: fake_oracle,
+                ..market
+            },
+        ),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::OracleMismatch);
+
+    // Oracle accounts must be owned by the program
+    let data = borsh::to_vec(&TestOracle {
+        price: 2_000_000,
+        expo: -6,
+        conf: 0,
+        publish_time: unix_timestamp(&mut context).await,
+    })
+    .unwrap();
+    let mut account = AccountSharedData::new(1_000_000_000, data.len(), &Pubkey::new_unique());
+    account.set_data_from_slice(&data);
+    context.set_account(&market.oracle, &account);
+    let result = send(
+        &mut context,
+        close_position_instruction(&program_id, &owner, 0, &user_collateral_account, &market),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::InvalidOraclePrice);
+
+    assert_eq!(
+        fetch_position(
+            &mut context.banks_client,
+            position_pda(&program_id, &owner, 0)
+        )
+        .await
+        .collateral_amount,
+        500_000_000
+    );
+}
+
+#[tokio::test]
+async fn test_add_collateral() {
+    let (mut context, program_id, market, user_collateral_account) =
+        setup_positions(1, 500_000_000).await;
+    let owner = context.payer.pubkey();
+    let position_key = position_pda(&program_id, &owner, 0);
+    let opened = fetch_position(&mut context.banks_client, position_key).await;
+
+    // 250 tokens at $2.00: collateral is revalued at the oracle price
+    set_oracle_price(&mut context, &program_id, &market.oracle, 2_000_000).await;
+    send(
+        &mut context,
+        add_collateral_instruction(
+            &program_id,
+            &owner,
+            0,
+            &user_collateral_account,
+            &market,
+            250_000_000,
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let position = fetch_position(&mut context.banks_client, position_key).await;
+    assert_eq!(position.collateral_amount, 750_000_000);
+    assert_eq!(position.collateral_usd, 1_500_000_000);
+    assert_eq!(position.size_usd, opened.size_usd);
+    assert!(position.update_time >= opened.update_time);
+    assert_eq!(
+        token_balance(&mut context.banks_client, user_collateral_account).await,
+        250_000_000
+    );
+    assert_eq!(
+        token_balance(&mut context.banks_client, market.custody_token_account).await,
+        TEST_LIQUIDITY + 750_000_000
+    );
+
+    let result = send(
+        &mut context,
+        add_collateral_instruction(&program_id, &owner, 0, &user_collateral_account, &market, 0),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::ZeroAmount);
+}
+
+#[tokio::test]
+async fn test_add_collateral_rejects_closed_and_foreign_positions() {
+    let (mut context, program_id, market, user_collateral_account) =
+        setup_positions(1, 500_000_000).await;
+    let owner = context.payer.pubkey();
+
+    // Someone else's position
+    let stranger = Keypair::new();
+    let mut instruction = add_collateral_instruction(
+        &program_id,
+        &owner,
+        0,
+        &user_collateral_account,
+        &market,
+        100,
+    );
+    instruction.accounts[0] = AccountMeta::new(stranger.pubkey(), true);
+    let result = send(&mut context, instruction, &[&stranger]).await;
+    assert_perps_error(result, 0, PerpsError::InvalidPda);
+
+    // A closed position
+    send(
+        &mut context,
+        close_position_instruction(&program_id, &owner, 0, &user_collateral_account, &market),
+        &[],
+    )
+    .await
+    .unwrap();
+    let result = send(
+        &mut context,
+        add_collateral_instruction(
+            &program_id,
+            &owner,
+            0,
+            &user_collateral_account,
+            &market,
+            100,
+        ),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::PositionNotFound);
+}
+
+#[tokio::test]
+async fn test_remove_collateral_keeps_initial_margin() {
+    let (mut context, program_id, market, user_collateral_account) =
+        setup_positions(1, 500_000_000).await;
+    let owner = context.payer.pubkey();
+    let position_key = position_pda(&program_id, &owner, 0);
+
+    // $2,000 long at $1.00 on $500 of collateral (4x); 10x needs at least $200 of equity
+    let mut position = fetch_position(&mut context.banks_client, position_key).await;
+    position.price = 1_000_000;
+    position.size_usd = 2_000_000_000;
+    store_position(&mut context, position_key, &position).await;
+
+    let result = send(
+        &mut context,
+        remove_collateral_instruction(
+            &program_id,
+            &owner,
+            0,
+            &user_collateral_account,
+            &market,
+            500_000_001,
+        ),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::InsufficientCollateral);
+
+    send(
+        &mut context,
+        remove_collateral_instruction(
+            &program_id,
+            &owner,
+            0,
+            &user_collateral_account,
+            &market,
+            300_000_000,
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let position = fetch_position(&mut context.banks_client, position_key).await;
+    assert_eq!(position.collateral_amount, 200_000_000);
+    assert_eq!(position.collateral_usd, 200_000_000);
+    assert_eq!(
+        token_balance(&mut context.banks_client, user_collateral_account).await,
+        800_000_000
+    );
+    assert_eq!(
+        token_balance(&mut context.banks_client, market.custody_token_account).await,
+        TEST_LIQUIDITY + 200_000_000
+    );
+
+    // Exactly at 10x, so no more can be withdrawn
+    let result = send(
+        &mut context,
+        remove_collateral_instruction(&program_id, &owner, 0, &user_collateral_account, &market, 1),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::InsufficientMargin);
+
+    // Unrealized profit counts towards equity: +5% on $2,000 is $100
+    set_oracle_price(&mut context, &program_id, &market.oracle, 1_050_000).await;
+    send(
+        &mut context,
+        remove_collateral_instruction(
+            &program_id,
+            &owner,
+            0,
+            &user_collateral_account,
+            &market,
+            50_000_000,
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        fetch_position(&mut context.banks_client, position_key)
+            .await
+            .collateral_amount,
+        150_000_000
+    );
+}
+
+#[tokio::test]
+async fn test_liquidate_position() {
+    let (mut context, program_id, market, _user_collateral_account) =
+        setup_positions(2, 500_000_000).await;
+    let owner = context.payer.pubkey();
+    let custody = market.custody_token_account;
+
+    // Both positions: $2,000 long entered at $1.00 on 500 tokens of collateral.
+    // Collateral is the traded token, so equity is 2,500 * price - 2,000 and the
+    // 5% maintenance margin ($100) is crossed below $0.84.
+    for position_id in 0..2 {
+        let position_key = position_pda(&program_id, &owner, position_id);
+        let mut position = fetch_position(&mut context.banks_client, position_key).await;
+        position.price = 1_000_000;
+        position.size_usd = 2_000_000_000;
+        store_position(&mut context, position_key, &position).await;
+    }
+
+    let liquidator = Keypair::new();
+    let liquidator_collateral_account =
+        create_token_account(&mut context, &market.mint, &liquidator.pubkey()).await;
+
+    send(
+        &mut context,
+        init_insurance_fund_instruction(&program_id, &owner, &market.mint),
+        &[],
+    )
+    .await
+    .unwrap();
+    let insurance_fund = insurance_fund_pda(&program_id, &market.mint);
+    send(
+        &mut context,
+        spl_token::instruction::mint_to(
+            &spl_token::id(),
+            &market.mint,
+            &insurance_fund,
+            &owner,
+            &[],
+            100_000_000,
+        )
+        .unwrap(),
+        &[],
+    )
+    .await
+    .unwrap();
+
+    // $0.90: equity $250, healthy
+    set_oracle_price(&mut context, &program_id, &market.oracle, 900_000).await;
+    let result = send(
+        &mut context,
+        liquidate_position_instruction(
+            &program_id,
+            &liquidator.pubkey(),
+            &owner,
+            0,
+            &liquidator_collateral_account,
+            &market,
+        ),
+        &[&liquidator],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::NotLiquidatable);
+
+    // $0.83: equity $75. The liquidator earns 0.5% of size, $10 = 12.048192 tokens
+    set_oracle_price(&mut context, &program_id, &market.oracle, 830_000).await;
+    send(
+        &mut context,
+        liquidate_position_instruction(
+            &program_id,
+            &liquidator.pubkey(),
+            &owner,
+            0,
+            &liquidator_collateral_account,
+            &market,
+        ),
+        &[&liquidator],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, liquidator_collateral_account).await,
+        12_048_192
+    );
+    assert_eq!(
+        token_balance(&mut context.banks_client, custody).await,
+        TEST_LIQUIDITY + 1_000_000_000 - 12_048_192
+    );
+    assert!(context
+        .banks_client
+        .get_account(position_pda(&program_id, &owner, 0))
+        .await
+        .unwrap()
+        .is_none());
+
+    // $0.70: equity is -$250, so no reward and a 357.142858 token shortfall,
+    // of which the insurance fund covers its full 100 tokens
+    set_oracle_price(&mut context, &program_id, &market.oracle, 700_000).await;
+    send(
+        &mut context,
+        liquidate_position_instruction(
+            &program_id,
+            &liquidator.pubkey(),
+            &owner,
+            1,
+            &liquidator_collateral_account,
+            &market,
+        ),
+        &[&liquidator],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, liquidator_collateral_account).await,
+        12_048_192
+    );
+    assert_eq!(
+        token_balance(&mut context.banks_client, insurance_fund).await,
+        0
+    );
+    assert_eq!(
+        token_balance(&mut context.banks_client, custody).await,
+        TEST_LIQUIDITY + 1_000_000_000 - 12_048_192 + 100_000_000
+    );
+}
+
+#[tokio::test]
+async fn test_liquidate_position_rejects_invalid_accounts() {
+    let (mut context, program_id, market, user_collateral_account) =
+        setup_positions(1, 500_000_000).await;
+    let owner = context.payer.pubkey();
+
+    let position_key = position_pda(&program_id, &owner, 0);
+    let mut position = fetch_position(&mut context.banks_client, position_key).await;
+    position.price = 1_000_000;
+    position.size_usd = 2_000_000_000;
+    store_position(&mut context, position_key, &position).await;
+    set_oracle_price(&mut context, &program_id, &market.oracle, 700_000).await;
+
+    // The reward account must belong to the signing liquidator
+    let liquidator = Keypair::new();
+    let result = send(
+        &mut context,
+        liquidate_position_instruction(
+            &program_id,
+            &liquidator.pubkey(),
+            &owner,
+            0,
+            &user_collateral_account,
+            &market,
+        ),
+        &[&liquidator],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::TokenAccountMismatch);
+
+    // The insurance fund must be the PDA for the collateral mint
+    let liquidator_collateral_account =
+        create_token_account(&mut context, &market.mint, &liquidator.pubkey()).await;
+    let mut instruction = liquidate_position_instruction(
+        &program_id,
+        &liquidator.pubkey(),
+        &owner,
+        0,
+        &liquidator_collateral_account,
+        &market,
+    );
+    instruction.accounts[7] = AccountMeta::new(Pubkey::new_unique(), false);
+    let result = send(&mut context, instruction, &[&liquidator]).await;
+    assert_perps_error(result, 0, PerpsError::InvalidPda);
+
+    // Without an insurance fund the whole shortfall is left as bad debt
+    send(
+        &mut context,
+        liquidate_position_instruction(
+            &program_id,
+            &liquidator.pubkey(),
+            &owner,
+            0,
+            &liquidator_collateral_account,
+            &market,
+        ),
+        &[&liquidator],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, market.custody_token_account).await,
+        TEST_LIQUIDITY + 500_000_000
+    );
+}
+
+#[tokio::test]
+async fn test_open_position_with_leverage() {
+    let (mut context, program_id, market, user_collateral_account) = setup_positions(0, 0).await;
+    let owner = context.payer.pubkey();
+    let custody = market.custody_token_account;
+    set_oracle_price(&mut context, &program_id, &market.oracle, 2_000_000).await;
+
+    // 100 tokens at $2.00 ($200) backing a $1,000 long is 5x
+    send(
+        &mut context,
+        open_position_instruction(
+            &program_id,
+            &owner,
+            &user_collateral_account,
+            &market,
+            &position_pda(&program_id, &owner, 0),
+            Side::Long,
+            100_000_000,
+            1_000_000_000,
+            2_010_000,
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let position = fetch_position(
+        &mut context.banks_client,
+        position_pda(&program_id, &owner, 0),
+    )
+    .await;
+    assert_eq!(position.side, Side::Long);
+    assert_eq!(position.price, 2_000_000);
+    assert_eq!(position.size_usd, 1_000_000_000);
+    assert_eq!(position.collateral_amount, 100_000_000);
+    assert_eq!(position.collateral_usd, 200_000_000);
+    assert_eq!(position.borrow_size_usd, 800_000_000);
+    assert_eq!(position.locked_amount, 500_000_000);
+    assert_eq!(
+        token_balance(&mut context.banks_client, custody).await,
+        TEST_LIQUIDITY + 100_000_000
+    );
+
+    // $2,001 on $200 is just over 10x
+    let result = send(
+        &mut context,
+        open_position_instruction(
+            &program_id,
+            &owner,
+            &user_collateral_account,
+            &market,
+            &position_pda(&program_id, &owner, 1),
+            Side::Short,
+            100_000_000,
+            2_001_000_000,
+            0,
+        ),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::MaxLeverageExceeded);
+
+    // Longs reject prices above the limit, shorts prices below it
+    let result = send(
+        &mut context,
+        open_position_instruction(
+            &program_id,
+            &owner,
+            &user_collateral_account,
+            &market,
+            &position_pda(&program_id, &owner, 1),
+            Side::Long,
+            100_000_000,
+            1_000_000_000,
+            1_990_000,
+        ),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::SlippageExceeded);
+
+    let result = send(
+        &mut context,
+        open_position_instruction(
+            &program_id,
+            &owner,
+            &user_collateral_account,
+            &market,
+            &position_pda(&program_id, &owner, 1),
+            Side::Short,
+            100_000_000,
+            1_000_000_000,
+            2_010_000,
+        ),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::SlippageExceeded);
+
+    send(
+        &mut context,
+        open_position_instruction(
+            &program_id,
+            &owner,
+            &user_collateral_account,
+            &market,
+            &position_pda(&program_id, &owner, 1),
+            Side::Short,
+            100_000_000,
+            2_000_000_000,
+            1_990_000,
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+    let position = fetch_position(
+        &mut context.banks_client,
+        position_pda(&program_id, &owner, 1),
+    )
+    .await;
+    assert_eq!(position.side, Side::Short);
+    assert_eq!(position.borrow_size_usd, 1_800_000_000);
+}
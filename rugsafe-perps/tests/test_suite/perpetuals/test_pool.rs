@@ -0,0 +1,370 @@
+use solana_program_test::*;
+use solana_sdk::{
+    instruction::InstructionError,
+    pubkey::Pubkey,
+    signature::{Keypair, Signer},
+    transaction::TransactionError,
+};
+
+use rugsafe_perps::error::PerpsError;
+use rugsafe_perps::instructions::processor::Processor;
+use rugsafe_perps::oracle::OracleType;
+use rugsafe_perps::state::perpetuals::Side;
+use rugsafe_perps::state::pool::{BorrowRateParams, OpenInterestParams, PoolParams, MAX_CUSTODIES};
+
+use super::test_perpetuals::{
+    add_market_instruction, add_market_with_params_instruction, assert_perps_error, fetch_custody,
+    fetch_pool, init_pool_instruction, open_position_instruction, pool_pda, position_pda,
+    provide_liquidity, send, set_oracle_price, setup_collateral, setup_market, Market, NO_FEES,
+    TEST_LIQUIDITY, TEST_MAX_PRICE_AGE_SEC, TEST_MAX_PRICE_ERROR_BPS,
+};
+
+async fn start() -> (ProgramTestContext, Pubkey) {
+    let program_id = Pubkey::new_unique();
+    let program_test =
+        ProgramTest::new("rugsafe_perps", program_id, processor!(Processor::process));
+    (program_test.start_with_context().await, program_id)
+}
+
+#[tokio::test]
+async fn test_init_pool() {
+    let (mut context, program_id) = start().await;
+    let admin = context.payer.pubkey();
+
+    let result = send(
+        &mut context,
+        init_pool_instruction(
+            &program_id,
+            &admin,
+            &PoolParams {
+                liquidation_fee_bps: 600,
+                ..PoolParams::default()
+            },
+        ),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::InvalidPoolParams);
+
+    let params = PoolParams {
+        maintenance_margin_bps: 1_000,
+        liquidation_fee_bps: 100,
+        max_funding_rate_bps: 25,
+        keeper_fee_bps: 20,
+    };
+    send(
+        &mut context,
+        init_pool_instruction(&program_id, &admin, &params),
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let pool = fetch_pool(&mut context.banks_client, pool_pda(&program_id, &admin)).await;
+    assert_eq!(pool.admin, admin);
+    assert_eq!(pool.params, params);
+    assert!(pool.custodies.is_empty());
+
+    let result = send(
+        &mut context,
+        init_pool_instruction(&program_id, &admin, &params),
+        &[],
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::AccountAlreadyInitialized)
+    );
+}
+
+#[tokio::test]
+async fn test_add_market() {
+    let (mut context, program_id) = start().await;
+    let admin = context.payer.pubkey();
+    let (mint, _) = setup_collateral(
+        &mut context.banks_client,
+        &context.payer,
+        context.last_blockhash,
+        0,
+    )
+    .await;
+    let market = setup_market(&mut context, &program_id, &mint).await;
+    let pool = fetch_pool(&mut context.banks_client, market.pool).await;
+    assert_eq!(pool.custodies, vec![market.custody]);
+
+    // One market per mint
+    let result = send(
+        &mut context,
+        add_market_instruction(&program_id, &admin, &market),
+        &[],
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::AccountAlreadyInitialized)
+    );
+
+    // Only the admin adds markets
+    let stranger = Keypair::new();
+    let (other_mint, _) = setup_collateral(
+        &mut context.banks_client,
+        &context.payer,
+        context.last_blockhash,
+        0,
+    )
+    .await;
+    let other_market = Market::new(&program_id, &market.pool, &other_mint);
+    let result = send(
+        &mut context,
+        add_market_instruction(&program_id, &stranger.pubkey(), &other_market),
+        &[&stranger],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::Unauthorized);
+
+    // Custody addresses are derived from the pool and mint
+    let result = send(
+        &mut context,
+        add_market_instruction(
+            &program_id,
+            &admin,
+            &Market {
+                custody: market.custody,
+                ..other_market
+            },
+        ),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::InvalidPda);
+
+    for _ in 1..MAX_CUSTODIES {
+        let (mint, _) = setup_collateral(
+            &mut context.banks_client,
+            &context.payer,
+            context.last_blockhash,
+            0,
+        )
+        .await;
+        send(
+            &mut context,
+            add_market_instruction(
+                &program_id,
+                &admin,
+                &Market::new(&program_id, &market.pool, &mint),
+            ),
+            &[],
+        )
+        .await
+        .unwrap();
+    }
+    let result = send(
+        &mut context,
+        add_market_instruction(&program_id, &admin, &other_market),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::PoolFull);
+}
+
+#[tokio::test]
+async fn test_open_position_checks_market() {
+    let (mut context, program_id) = start().await;
+    let owner = context.payer.pubkey();
+    let (mint, user_collateral_account) = setup_collateral(
+        &mut context.banks_client,
+        &context.payer,
+        context.last_blockhash,
+        1_000_000_000,
+    )
+    .await;
+    let market = setup_market(&mut context, &program_id, &mint).await;
+    set_oracle_price(&mut context, &program_id, &market.oracle, 1_000_000).await;
+    provide_liquidity(
+        &mut context,
+        &program_id,
+        &market,
+        &[market],
+        TEST_LIQUIDITY,
+    )
+    .await;
+
+    // A market of another pool
+    let admin = Keypair::new();
+    send(
+        &mut context,
+        solana_sdk::system_instruction::transfer(&owner, &admin.pubkey(), 1_000_000_000),
+        &[],
+    )
+    .await
+    .unwrap();
+    send(
+        &mut context,
+        init_pool_instruction(&program_id, &admin.pubkey(), &PoolParams::default()),
+        &[&admin],
+    )
+    .await
+    .unwrap();
+    let foreign_market = Market::new(&program_id, &pool_pda(&program_id, &admin.pubkey()), &mint);
+    send(
+        &mut context,
+        add_market_instruction(&program_id, &admin.pubkey(), &foreign_market),
+        &[&admin],
+    )
+    .await
+    .unwrap();
+
+    let open = |market: &Market| {
+        open_position_instruction(
+            &program_id,
+            &owner,
+            &user_collateral_account,
+            market,
+            &position_pda(&program_id, &owner, 0),
+            Side::Long,
+            100_000_000,
+            200_000_000,
+            u64::MAX,
+        )
+    };
+
+    let result = send(
+        &mut context,
+        open(&Market {
+            custody: foreign_market.custody,
+            ..market
+        }),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::CustodyMismatch);
+
+    let result = send(
+        &mut context,
+        open(&Market {
+            custody_token_account: foreign_market.custody_token_account,
+            ..market
+        }),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::CustodyMismatch);
+
+    set_oracle_price(&mut context, &program_id, &foreign_market.oracle, 1_000_000).await;
+    let result = send(
+        &mut context,
+        open(&Market {
+            oracle: foreign_market.oracle,
+            ..market
+        }),
+        &[],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::OracleMismatch);
+
+    // Open interest is tracked per side on the pool
+    send(&mut context, open(&market), &[]).await.unwrap();
+    let pool = fetch_pool(&mut context.banks_client, market.pool).await;
+    assert_eq!(pool.long_open_interest_usd, 200_000_000);
+    assert_eq!(pool.short_open_interest_usd, 0);
+    let foreign_pool = fetch_pool(&mut context.banks_client, foreign_market.pool).await;
+    assert_eq!(foreign_pool.long_open_interest_usd, 0);
+}
+
+#[tokio::test]
+async fn test_max_leverage_is_per_market() {
+    let (mut context, program_id) = start().await;
+    let owner = context.payer.pubkey();
+    let (mint, user_collateral_account) = setup_collateral(
+        &mut context.banks_client,
+        &context.payer,
+        context.last_blockhash,
+        1_000_000_000,
+    )
+    .await;
+    send(
+        &mut context,
+        init_pool_instruction(&program_id, &owner, &PoolParams::default()),
+        &[],
+    )
+    .await
+    .unwrap();
+    let market = Market::new(&program_id, &pool_pda(&program_id, &owner), &mint);
+    let add_market = |max_leverage_bps: u64| {
+        add_market_with_params_instruction(
+            &program_id,
+            &owner,
+            &market,
+            OracleType::Test,
+            TEST_MAX_PRICE_AGE_SEC,
+            TEST_MAX_PRICE_ERROR_BPS,
+            &BorrowRateParams::default(),
+            &NO_FEES,
+            &OpenInterestParams::default(),
+            max_leverage_bps,
+        )
+    };
+
+    // Below 1x, or an initial margin at or below the 5% maintenance margin, is rejected
+    let result = send(&mut context, add_market(9_999), &[]).await;
+    assert_perps_error(result, 0, PerpsError::InvalidMaxLeverage);
+    let result = send(&mut context, add_market(200_000), &[]).await;
+    assert_perps_error(result, 0, PerpsError::InvalidMaxLeverage);
+
+    send(&mut context, add_market(20_000), &[]).await.unwrap();
+    set_oracle_price(&mut context, &program_id, &market.oracle, 1_000_000).await;
+    let custody = fetch_custody(&mut context.banks_client, market.custody).await;
+    assert_eq!(custody.max_leverage_bps, 20_000);
+
+    // A default 10x market of another pool on the same mint
+    let admin = Keypair::new();
+    send(
+        &mut context,
+        solana_sdk::system_instruction::transfer(&owner, &admin.pubkey(), 1_000_000_000),
+        &[],
+    )
+    .await
+    .unwrap();
+    send(
+        &mut context,
+        init_pool_instruction(&program_id, &admin.pubkey(), &PoolParams::default()),
+        &[&admin],
+    )
+    .await
+    .unwrap();
+    let other_market = Market::new(&program_id, &pool_pda(&program_id, &admin.pubkey()), &mint);
+    send(
+        &mut context,
+        add_market_instruction(&program_id, &admin.pubkey(), &other_market),
+        &[&admin],
+    )
+    .await
+    .unwrap();
+    set_oracle_price(&mut context, &program_id, &other_market.oracle, 1_000_000).await;
+    provide_liquidity(
+        &mut context,
+        &program_id,
+        &other_market,
+        &[other_market],
+        TEST_LIQUIDITY,
+    )
+    .await;
+
+    // $300 on $100 is over the first market's 2x but within the other's 10x
+    let open = |market: &Market| {
+        open_position_instruction(
+            &program_id,
+            &owner,
+            &user_collateral_account,
+            market,
+            &position_pda(&program_id, &owner, 0),
+            Side::Long,
+            100_000_000,
+            300_000_000,
+            u64::MAX,
+        )
+    };
+    let result = send(&mut context, open(&market), &[]).await;
+    assert_perps_error(result, 0, PerpsError::MaxLeverageExceeded);
+    send(&mut context, open(&other_market), &[]).await.unwrap();
+}
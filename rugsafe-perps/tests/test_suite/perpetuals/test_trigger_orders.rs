@@ -0,0 +1,318 @@
+use borsh::BorshDeserialize;
+use solana_program::instruction::AccountMeta;
+use solana_program_test::*;
+use solana_sdk::{
+    instruction::InstructionError, pubkey::Pubkey, signature::Keypair, signature::Signer,
+    transaction::TransactionError,
+};
+
+use rugsafe_perps::error::PerpsError;
+use rugsafe_perps::state::order::{TriggerKind, TriggerOrder};
+use rugsafe_perps::state::perpetuals::{Side, UserPositions};
+
+use super::test_perpetuals::{
+    assert_perps_error, cancel_trigger_order_instruction, create_token_account,
+    execute_trigger_order_instruction, fetch_position, open_position,
+    place_trigger_order_instruction, position_pda, send, set_oracle_price, setup_positions,
+    token_balance, trigger_order_pda, user_positions_pda, Market,
+};
+
+// Position 0 is a 1x long of 500 tokens that keeps the custody liquid
+const ID: u64 = 1;
+
+/// Opens a $200 long on 100 tokens at $1.00 as position 1, and a collateral account for a keeper.
+async fn setup_long() -> (ProgramTestContext, Pubkey, Market, Pubkey, Keypair, Pubkey) {
+    let (mut context, program_id, market, user_collateral_account) =
+        setup_positions(1, 500_000_000).await;
+    open_position(
+        &mut context,
+        &program_id,
+        &market,
+        &user_collateral_account,
+        ID,
+        Side::Long,
+        100_000_000,
+        200_000_000,
+    )
+    .await;
+    let keeper = Keypair::new();
+    let keeper_collateral_account =
+        create_token_account(&mut context, &market.mint, &keeper.pubkey()).await;
+    (
+        context,
+        program_id,
+        market,
+        user_collateral_account,
+        keeper,
+        keeper_collateral_account,
+    )
+}
+
+async fn fetch_order(context: &mut ProgramTestContext, order: Pubkey) -> Option<TriggerOrder> {
+    context
+        .banks_client
+        .get_account(order)
+        .await
+        .unwrap()
+        .map(|account| TriggerOrder::deserialize(&mut &account.data[..]).unwrap())
+}
+
+#[tokio::test]
+async fn test_place_and_cancel_trigger_order() {
+    let (mut context, program_id, _, _, _, _) = setup_long().await;
+    let owner = context.payer.pubkey();
+    let position_key = position_pda(&program_id, &owner, ID);
+    let order_key = trigger_order_pda(&program_id, &position_key, TriggerKind::StopLoss);
+
+    let place = place_trigger_order_instruction(
+        &program_id,
+        &owner,
+        ID,
+        TriggerKind::StopLoss,
+        900_000,
+        200_000_000,
+    );
+    send(&mut context, place.clone(), &[]).await.unwrap();
+    let order = fetch_order(&mut context, order_key).await.unwrap();
+    assert_eq!(order.owner, owner);
+    assert_eq!(order.position, position_key);
+    assert_eq!(order.position_id, ID);
+    assert_eq!(order.kind, TriggerKind::StopLoss);
+    assert_eq!(order.trigger_price, 900_000);
+    assert_eq!(order.size_usd, 200_000_000);
+
+    // One order of each kind per position
+    let result = send(&mut context, place, &[]).await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::AccountAlreadyInitialized)
+    );
+
+    // Only the owner can cancel
+    let stranger = Keypair::new();
+    let mut cancel =
+        cancel_trigger_order_instruction(&program_id, &owner, ID, TriggerKind::StopLoss);
+    cancel.accounts[0] = AccountMeta::new(stranger.pubkey(), true);
+    let result = send(&mut context, cancel, &[&stranger]).await;
+    assert_perps_error(result, 0, PerpsError::Unauthorized);
+
+    send(
+        &mut context,
+        cancel_trigger_order_instruction(&program_id, &owner, ID, TriggerKind::StopLoss),
+        &[],
+    )
+    .await
+    .unwrap();
+    assert!(fetch_order(&mut context, order_key).await.is_none());
+}
+
+#[tokio::test]
+async fn test_trigger_order_waits_for_trigger_price() {
+    let (
+        mut context,
+        program_id,
+        market,
+        user_collateral_account,
+        keeper,
+        keeper_collateral_account,
+    ) = setup_long().await;
+    let owner = context.payer.pubkey();
+
+    send(
+        &mut context,
+        place_trigger_order_instruction(
+            &program_id,
+            &owner,
+            ID,
+            TriggerKind::TakeProfit,
+            1_500_000,
+            100_000_000,
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+
+    set_oracle_price(&mut context, &program_id, &market.oracle, 1_400_000).await;
+    let result = send(
+        &mut context,
+        execute_trigger_order_instruction(
+            &program_id,
+            &keeper.pubkey(),
+            &keeper_collateral_account,
+            &owner,
+            ID,
+            TriggerKind::TakeProfit,
+            &user_collateral_account,
+            &market,
+        ),
+        &[&keeper],
+    )
+    .await;
+    assert_perps_error(result, 0, PerpsError::TriggerNotReached);
+}
+
+#[tokio::test]
+async fn test_take_profit_partially_closes_and_pays_keeper() {
+    let (
+        mut context,
+        program_id,
+        market,
+        user_collateral_account,
+        keeper,
+        keeper_collateral_account,
+    ) = setup_long().await;
+    let owner = context.payer.pubkey();
+    let position_key = position_pda(&program_id, &owner, ID);
+
+    send(
+        &mut context,
+        place_trigger_order_instruction(
+            &program_id,
+            &owner,
+            ID,
+            TriggerKind::TakeProfit,
+            1_500_000,
+            100_000_000,
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+
+    set_oracle_price(&mut context, &program_id, &market.oracle, 1_500_000).await;
+    let balance = token_balance(&mut context.banks_client, user_collateral_account).await;
+    send(
+        &mut context,
+        execute_trigger_order_instruction(
+            &program_id,
+            &keeper.pubkey(),
+            &keeper_collateral_account,
+            &owner,
+            ID,
+            TriggerKind::TakeProfit,
+            &user_collateral_account,
+            &market,
+        ),
+        &[&keeper],
+    )
+    .await
+    .unwrap();
+
+    // Half the collateral and half the $100 profit, less 0.1% of the $100 closed for the keeper
+    let keeper_fee = 66_666;
+    assert_eq!(
+        token_balance(&mut context.banks_client, keeper_collateral_account).await,
+        keeper_fee
+    );
+    assert_eq!(
+        token_balance(&mut context.banks_client, user_collateral_account).await,
+        balance + 50_000_000 + 33_333_333 - keeper_fee
+    );
+    let position = fetch_position(&mut context.banks_client, position_key).await;
+    assert_eq!(position.size_usd, 100_000_000);
+    assert_eq!(position.collateral_amount, 50_000_000);
+    assert!(fetch_order(
+        &mut context,
+        trigger_order_pda(&program_id, &position_key, TriggerKind::TakeProfit)
+    )
+    .await
+    .is_none());
+}
+
+#[tokio::test]
+async fn test_stop_loss_closes_position() {
+    let (
+        mut context,
+        program_id,
+        market,
+        user_collateral_account,
+        keeper,
+        keeper_collateral_account,
+    ) = setup_long().await;
+    let owner = context.payer.pubkey();
+    let position_key = position_pda(&program_id, &owner, ID);
+
+    send(
+        &mut context,
+        place_trigger_order_instruction(
+            &program_id,
+            &owner,
+            ID,
+            TriggerKind::StopLoss,
+            900_000,
+            u64::MAX,
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+
+    set_oracle_price(&mut context, &program_id, &market.oracle, 850_000).await;
+    let balance = token_balance(&mut context.banks_client, user_collateral_account).await;
+    send(
+        &mut context,
+        execute_trigger_order_instruction(
+            &program_id,
+            &keeper.pubkey(),
+            &keeper_collateral_account,
+            &owner,
+            ID,
+            TriggerKind::StopLoss,
+            &user_collateral_account,
+            &market,
+        ),
+        &[&keeper],
+    )
+    .await
+    .unwrap();
+
+    // The $30 loss comes out of the collateral, and the keeper takes 0.1% of the $200 closed
+    let keeper_fee = 235_294;
+    assert_eq!(
+        token_balance(&mut context.banks_client, keeper_collateral_account).await,
+        keeper_fee
+    );
+    assert_eq!(
+        token_balance(&mut context.banks_client, user_collateral_account).await,
+        balance + 100_000_000 - 35_294_118 - keeper_fee
+    );
+    assert!(context
+        .banks_client
+        .get_account(position_key)
+        .await
+        .unwrap()
+        .is_none());
+    let user_positions = context
+        .banks_client
+        .get_account(user_positions_pda(&program_id, &owner))
+        .await
+        .unwrap()
+        .unwrap();
+    let user_positions = UserPositions::deserialize(&mut &user_positions.data[..]).unwrap();
+    assert_eq!(user_positions.active_positions, vec![0]);
+}
+
+#[test]
+fn test_trigger_directions() {
+    let order = |kind| TriggerOrder {
+        kind,
+        trigger_price: 1_000_000,
+        ..TriggerOrder::default()
+    };
+    let take_profit = order(TriggerKind::TakeProfit);
+    let stop_loss = order(TriggerKind::StopLoss);
+
+    assert!(take_profit.is_triggered(Side::Long, 1_000_000).unwrap());
+    assert!(!take_profit.is_triggered(Side::Long, 999_999).unwrap());
+    assert!(take_profit.is_triggered(Side::Short, 999_999).unwrap());
+    assert!(!take_profit.is_triggered(Side::Short, 1_000_001).unwrap());
+    assert!(stop_loss.is_triggered(Side::Long, 999_999).unwrap());
+    assert!(!stop_loss.is_triggered(Side::Long, 1_000_001).unwrap());
+    assert!(stop_loss.is_triggered(Side::Short, 1_000_001).unwrap());
+    assert!(!stop_loss.is_triggered(Side::Short, 999_999).unwrap());
+    assert_eq!(
+        take_profit.is_triggered(Side::None, 1_000_000),
+        Err(PerpsError::InvalidPositionData)
+    );
+}
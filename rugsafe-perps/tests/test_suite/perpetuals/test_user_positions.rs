@@ -0,0 +1,120 @@
+use solana_program::instruction::AccountMeta;
+use solana_program_test::*;
+use solana_sdk::{pubkey::Pubkey, signature::Keypair, signature::Signer};
+
+use borsh::BorshDeserialize;
+use rugsafe_perps::error::PerpsError;
+use rugsafe_perps::state::perpetuals::{Side, UserPositions, MAX_POSITIONS};
+
+use super::test_perpetuals::{
+    assert_perps_error, close_position_instruction, create_token_account,
+    liquidate_position_instruction, open_position_instruction, position_pda, send,
+    set_oracle_price, setup_positions, user_positions_pda,
+};
+
+async fn fetch_user_positions(
+    banks_client: &mut BanksClient,
+    program_id: &Pubkey,
+    owner: &Pubkey,
+) -> UserPositions {
+    let account = banks_client
+        .get_account(user_positions_pda(program_id, owner))
+        .await
+        .unwrap()
+        .unwrap();
+    UserPositions::deserialize(&mut &account.data[..]).unwrap()
+}
+
+#[tokio::test]
+async fn test_user_positions_track_open_positions() {
+    let (mut context, program_id, market, user_collateral_account) =
+        setup_positions(MAX_POSITIONS as u64, 100_000_000).await;
+    let owner = context.payer.pubkey();
+
+    let user_positions = fetch_user_positions(&mut context.banks_client, &program_id, &owner).await;
+    assert_eq!(
+        user_positions.active_positions,
+        (0..MAX_POSITIONS as u64).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        user_positions.position_keys(&program_id),
+        (0..MAX_POSITIONS as u64)
+            .map(|id| position_pda(&program_id, &owner, id))
+            .collect::<Vec<_>>()
+    );
+
+    // No more positions while every slot is taken
+    let next_id = MAX_POSITIONS as u64;
+    let open = |context: &mut ProgramTestContext, position_id, size_usd| {
+        open_position_instruction(
+            &program_id,
+            &context.payer.pubkey(),
+            &user_collateral_account,
+            &market,
+            &position_pda(&program_id, &context.payer.pubkey(), position_id),
+            Side::Long,
+            100_000_000,
+            size_usd,
+            u64::MAX,
+        )
+    };
+    let instruction = open(&mut context, next_id, 100_000_000);
+    let result = send(&mut context, instruction, &[]).await;
+    assert_perps_error(result, 0, PerpsError::MaxPositionsReached);
+
+    // Closing frees a slot, and only the owner's UserPositions account is accepted
+    let mut instruction =
+        close_position_instruction(&program_id, &owner, 3, &user_collateral_account, &market);
+    instruction.accounts[8] = AccountMeta::new(Pubkey::new_unique(), false);
+    let result = send(&mut context, instruction, &[]).await;
+    assert_perps_error(result, 0, PerpsError::InvalidPda);
+
+    send(
+        &mut context,
+        close_position_instruction(&program_id, &owner, 3, &user_collateral_account, &market),
+        &[],
+    )
+    .await
+    .unwrap();
+    let user_positions = fetch_user_positions(&mut context.banks_client, &program_id, &owner).await;
+    assert_eq!(
+        user_positions.active_positions,
+        vec![0, 1, 2, 4, 5, 6, 7, 8, 9]
+    );
+
+    // Liquidation frees a slot too: at $0.04 the 1x longs have lost all their equity
+    set_oracle_price(&mut context, &program_id, &market.oracle, 40_000).await;
+    let liquidator = Keypair::new();
+    let liquidator_collateral_account =
+        create_token_account(&mut context, &market.mint, &liquidator.pubkey()).await;
+    send(
+        &mut context,
+        liquidate_position_instruction(
+            &program_id,
+            &liquidator.pubkey(),
+            &owner,
+            0,
+            &liquidator_collateral_account,
+            &market,
+        ),
+        &[&liquidator],
+    )
+    .await
+    .unwrap();
+
+    // New positions keep taking fresh ids
+    let instruction = open(&mut context, next_id, 1_000_000);
+    send(&mut context, instruction, &[]).await.unwrap();
+    let instruction = open(&mut context, next_id + 1, 1_000_000);
+    send(&mut context, instruction, &[]).await.unwrap();
+    let instruction = open(&mut context, next_id + 2, 1_000_000);
+    let result = send(&mut context, instruction, &[]).await;
+    assert_perps_error(result, 0, PerpsError::MaxPositionsReached);
+
+    let user_positions = fetch_user_positions(&mut context.banks_client, &program_id, &owner).await;
+    assert_eq!(user_positions.next_position_idx, next_id + 2);
+    assert_eq!(
+        user_positions.active_positions,
+        vec![1, 2, 4, 5, 6, 7, 8, 9, 10, 11]
+    );
+}
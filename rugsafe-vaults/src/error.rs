@@ -0,0 +1,66 @@
+use num_derive::FromPrimitive;
+use solana_program::{
+    decode_error::DecodeError,
+    msg,
+    program_error::{PrintProgramError, ProgramError},
+};
+use thiserror::Error;
+
+#[derive(Clone, Copy, Debug, Eq, Error, FromPrimitive, PartialEq)]
+pub enum VaultError {
+    #[error("A vault already exists for this mint")]
+    VaultAlreadyExists,
+    #[error("Vault registry is at capacity")]
+    RegistryFull,
+    #[error("Vault registry data is invalid")]
+    InvalidRegistryData,
+    #[error("Vault state data is invalid")]
+    InvalidVaultData,
+    #[error("No vault is registered for this account")]
+    VaultNotFound,
+    #[error("Vault is not active")]
+    VaultNotActive,
+    #[error("Account does not match its derived address")]
+    InvalidPda,
+    #[error("Mint does not match the vault")]
+    MintMismatch,
+    #[error("Token account does not belong to the vault or signer")]
+    TokenAccountMismatch,
+    #[error("Signer is not authorized for this vault")]
+    Unauthorized,
+    #[error("Insufficient balance")]
+    InsufficientBalance,
+    #[error("Amount must be greater than zero")]
+    ZeroAmount,
+    #[error("Price must be greater than zero")]
+    InvalidPrice,
+    #[error("Anti-coin amount is below the requested minimum")]
+    SlippageExceeded,
+    #[error("Arithmetic overflow")]
+    MathOverflow,
+}
+
+impl From<VaultError> for ProgramError {
+    fn from(e: VaultError) -> Self {
+        ProgramError::Custom(e as u32)
+    }
+}
+
+impl<T> DecodeError<T> for VaultError {
+    fn type_of() -> &'static str {
+        "VaultError"
+    }
+}
+
+impl PrintProgramError for VaultError {
+    fn print<E>(&self)
+    where
+        E: 'static
+            + std::error::Error
+            + DecodeError<E>
+            + PrintProgramError
+            + num_traits::FromPrimitive,
+    {
+        msg!("VaultError: {}", self);
+    }
+}
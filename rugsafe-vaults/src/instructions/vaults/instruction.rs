@@ -4,11 +4,13 @@ use solana_program::program_error::ProgramError;
 #[repr(C)]
 #[derive(Clone, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
 pub enum VaultInstruction {
-    CreateVault,
-    Deposit { amount: u64 },
+    CreateVault { peg_price: u64 },
+    Deposit { amount: u64, min_anti_amount: u64 },
     Withdraw { amount: u64 },
     BurnRToken { amount: u64 },
     Faucet { amount: u64 },
+    SetReferencePrice { price: u64 },
+    IndexVault,
 }
 
 impl VaultInstruction {
@@ -22,11 +24,18 @@ impl VaultInstruction {
         msg!("Rest: {:?}", hex::encode(rest));
 
         Ok(match tag {
-            0 => Self::CreateVault,
+            0 => {
+                let peg_price = Self::unpack_amount(rest)?;
+                Self::CreateVault { peg_price }
+            }
             1 => {
                 let amount = Self::unpack_amount(rest)?;
                 msg!("amount from inside unpack 1: {}", &amount.to_string());
-                Self::Deposit { amount }
+                let min_anti_amount = Self::unpack_amount(&rest[8..])?;
+                Self::Deposit {
+                    amount,
+                    min_anti_amount,
+                }
             }
             2 => {
                 let amount = Self::unpack_amount(rest)?;
@@ -41,6 +50,11 @@ impl VaultInstruction {
                 msg!("amount from inside unpack 2: {}", &amount.to_string());
                 Self::Faucet { amount }
             }
+            5 => {
+                let price = Self::unpack_amount(rest)?;
+                Self::SetReferencePrice { price }
+            }
+            6 => Self::IndexVault,
             _ => return Err(ProgramError::InvalidInstructionData),
         })
     }
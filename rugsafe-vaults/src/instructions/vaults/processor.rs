@@ -1,5 +1,9 @@
 // use crate::instructions::VaultInstruction;
+use crate::error::VaultError;
 use crate::instructions::vaults::VaultInstruction;
+use crate::pricing;
+use solana_program::bpf_loader_upgradeable::{self, UpgradeableLoaderState};
+use solana_program::program_utils::limited_deserialize;
 use solana_program::sysvar::Sysvar;
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
@@ -10,23 +14,23 @@ use solana_program::{
     program_error::ProgramError,
     program_pack::Pack,
     pubkey::Pubkey,
-    sysvar,
     sysvar::rent::Rent,
 };
 use spl_associated_token_account::get_associated_token_address;
 // use spl_associated_token_account::instruction::create_associated_token_account;
-use spl_associated_token_account::{self, instruction};
+use spl_associated_token_account;
 
 // use solana_sdk::program::invoke_signed;
 
-use spl_token::instruction::{burn, initialize_mint, initialize_mint2, mint_to};
+use spl_token::instruction::{burn, initialize_mint, mint_to};
 use spl_token::state::Account as TokenAccount;
 use spl_token::state::Mint;
 
 // storage
-use crate::state::vaults::{Vault, VaultRegistry};
-use borsh::{BorshDeserialize, BorshSerialize};
-use std::io::Cursor;
+use crate::state::vaults::{
+    Vault, VaultEntry, VaultRegistry, VaultStatus, ANTI_MINT_SEED, MINT_AUTHORITY_SEED,
+    VAULT_AUTHORITY_SEED, VAULT_SEED,
+};
 
 pub struct Processor;
 
@@ -46,10 +50,13 @@ impl Processor {
         let instruction = VaultInstruction::unpack(instruction_data)?;
 
         match instruction {
-            VaultInstruction::CreateVault => Self::process_create_vault(program_id, accounts),
-            VaultInstruction::Deposit { amount } => {
-                Self::process_deposit(program_id, accounts, amount)
+            VaultInstruction::CreateVault { peg_price } => {
+                Self::process_create_vault(program_id, accounts, peg_price)
             }
+            VaultInstruction::Deposit {
+                amount,
+                min_anti_amount,
+            } => Self::process_deposit(program_id, accounts, amount, min_anti_amount),
             VaultInstruction::Withdraw { amount } => {
                 Self::process_withdraw(program_id, accounts, amount)
             }
@@ -59,16 +66,30 @@ impl Processor {
             VaultInstruction::Faucet { amount } => {
                 Self::process_faucet(program_id, accounts, amount)
             }
+            VaultInstruction::SetReferencePrice { price } => {
+                Self::process_set_reference_price(program_id, accounts, price)
+            }
+            VaultInstruction::IndexVault => Self::process_index_vault(program_id, accounts),
         }
     }
     /*
     @name process_create_vault
-    @description Handles the creation of a new vault, including initializing the mint and vault accounts, and setting up the state account for the vault registry.
+    @description Handles the creation of a new vault, including initializing the mint and vault accounts and the vault's state PDA. The vault starts Uninitialized and only accepts deposits once process_index_vault has added it to the registry.
     @param program_id - The ID of the currently executing program.
     @param accounts - The accounts involved in the transaction.
-    @ note - creates a MINT, a VAULT, and a STATE account is they arent already made
+    @param peg_price - The peg price of Token A, scaled by PRICE_SCALE. Also the initial reference price.
+    @ note - creates a MINT and a VAULT account if they arent already made, and the vault state PDA
     */
-    fn process_create_vault(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    fn process_create_vault(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        peg_price: u64,
+    ) -> ProgramResult {
+        if peg_price == 0 {
+            msg!("Error: Peg price must be greater than zero");
+            return Err(VaultError::InvalidPrice.into());
+        }
+
         let account_info_iter = &mut accounts.iter();
         let payer_account = next_account_info(account_info_iter)?;
         // mint for anticoins
@@ -81,23 +102,67 @@ impl Processor {
         let spl_account = next_account_info(account_info_iter)?;
 
         let system_program = next_account_info(account_info_iter)?;
-        // let state_account = next_account_info(account_info_iter)?; // Add this line
+        let associated_token_program = next_account_info(account_info_iter)?;
+        let user_token_a_account = next_account_info(account_info_iter)?;
+        // PDA that owns the vault token account and signs transfers out of it
+        let vault_authority_account = next_account_info(account_info_iter)?;
+        // PDA that is the mint authority of every anti-token mint
+        let mint_authority_account = next_account_info(account_info_iter)?;
+        // PDA holding this vault's state, seeded by its Token A mint
+        let vault_state_account = next_account_info(account_info_iter)?;
+
+        // The anti-token mint is a PDA seeded by the underlying Token A mint
+        let (anti_mint_pda, anti_mint_bump) = Pubkey::find_program_address(
+            &[ANTI_MINT_SEED, mint_account_token_a.key.as_ref()],
+            program_id,
+        );
+        if mint_account_a_token_a.key != &anti_mint_pda {
+            msg!("Error: AToken A mint does not match the derived PDA");
+            return Err(VaultError::InvalidPda.into());
+        }
 
-        // Derive the state account PDA
-        let (state_account_pda, bump_seed) =
-            Pubkey::find_program_address(&[b"vault_registry"], program_id);
+        let (mint_authority_pda, _mint_authority_bump) =
+            Pubkey::find_program_address(&[MINT_AUTHORITY_SEED], program_id);
+        if mint_authority_account.key != &mint_authority_pda {
+            msg!("Error: Mint authority does not match the derived PDA");
+            return Err(VaultError::InvalidPda.into());
+        }
 
-        let state_account = next_account_info(account_info_iter)?;
+        // Each vault has its own authority PDA, seeded by its Token A mint
+        let (vault_authority_pda, vault_authority_bump) = Pubkey::find_program_address(
+            &[VAULT_AUTHORITY_SEED, mint_account_token_a.key.as_ref()],
+            program_id,
+        );
+        if vault_authority_account.key != &vault_authority_pda {
+            msg!("Error: Vault authority does not match the derived PDA");
+            return Err(VaultError::InvalidPda.into());
+        }
 
-        // Ensure the state account matches the derived PDA
-        if state_account.key != &state_account_pda {
-            return Err(ProgramError::InvalidAccountData);
+        let (vault_state_pda, vault_state_bump) = Pubkey::find_program_address(
+            &[VAULT_SEED, mint_account_token_a.key.as_ref()],
+            program_id,
+        );
+        if vault_state_account.key != &vault_state_pda {
+            msg!("Error: Vault state account does not match the derived PDA");
+            return Err(VaultError::InvalidPda.into());
         }
 
-        let associated_token_program = next_account_info(account_info_iter)?;
-        let user_token_a_account = next_account_info(account_info_iter)?;
-        let program_id_account = next_account_info(account_info_iter)?;
+        // The vault must be the associated token account of the vault authority for Token A
+        if *vault_account.key
+            != get_associated_token_address(&vault_authority_pda, mint_account_token_a.key)
+        {
+            msg!("Error: Vault account is not the vault authority's Token A account");
+            return Err(VaultError::InvalidPda.into());
+        }
 
+        // Only one vault may exist per Token A mint
+        if !vault_state_account.data_is_empty() {
+            msg!(
+                "Error: A vault already exists for Token A mint {}",
+                mint_account_token_a.key
+            );
+            return Err(VaultError::VaultAlreadyExists.into());
+        }
         // msg!("Creating vault...");
         msg!("payer account key: {:?}", payer_account.key);
         msg!("Mint account Token A key: {:?}", mint_account_token_a.key);
@@ -107,7 +172,6 @@ impl Processor {
         );
         msg!("Vault account key: {:?}", vault_account.key);
         msg!("Rent account key: {:?}", rent_account.key);
-        msg!("State account key: {:?}", state_account.key);
         msg!("SPL: {}", spl_account.key);
         msg!("user_token_a_account: {}", user_token_a_account.key);
 
@@ -135,8 +199,6 @@ impl Processor {
             user_token_a_account.lamports()
         );
 
-        msg!("state account balance: {:?}", state_account.lamports());
-
         // Ensure accounts are rent-exempt
         if !payer_account.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
@@ -166,8 +228,11 @@ impl Processor {
 
         // NOTE: check if ATokenA mint is empty, since we need to mint atokens
         if mint_account_a_token_a.data_is_empty() {
+            // Anti-tokens use the same precision as the token they hedge
+            let token_a_decimals = Mint::unpack(&mint_account_token_a.try_borrow_data()?)?.decimals;
+
             msg!("create mint accct");
-            invoke(
+            invoke_signed(
                 &solana_program::system_instruction::create_account(
                     payer_account.key,
                     mint_account_a_token_a.key,
@@ -180,24 +245,24 @@ impl Processor {
                     mint_account_a_token_a.clone(),
                     system_program.clone(),
                 ],
+                &[&[
+                    ANTI_MINT_SEED,
+                    mint_account_token_a.key.as_ref(),
+                    &[anti_mint_bump],
+                ]],
             )?;
-            // }
 
             msg!("after create mint accct, now init mint");
-            // Initialize the mint account
+            // Initialize the mint account, only the program's mint authority can mint
             match invoke(
                 &initialize_mint(
                     &spl_token::id(),
-                    &mint_account_a_token_a.key,
-                    &payer_account.key,
-                    Some(&payer_account.key),
-                    0,
+                    mint_account_a_token_a.key,
+                    mint_authority_account.key,
+                    None,
+                    token_a_decimals,
                 )?,
-                &[
-                    mint_account_a_token_a.clone(),
-                    rent_account.clone(),
-                    payer_account.clone(),
-                ],
+                &[mint_account_a_token_a.clone(), rent_account.clone()],
             ) {
                 Ok(_) => msg!("Mint account initialized successfully"),
                 Err(e) => {
@@ -218,49 +283,112 @@ impl Processor {
             invoke(
                 &spl_associated_token_account::instruction::create_associated_token_account(
                     payer_account.key,
-                    program_id_account.key,
+                    vault_authority_account.key,
                     mint_account_token_a.key, // the mint this associated account should be for is the token a mint
-                    // NOTE:
-                    spl_account.key, // SPL Token program ID is needed here
-                                     // associated_token_program.key,
+                    spl_account.key,          // SPL Token program ID is needed here
                 ),
                 &[
-                    payer_account.clone(),        // Funding account
-                    vault_account.clone(),        // Associated token account
-                    payer_account.clone(),        // Wallet address
-                    mint_account_token_a.clone(), // Token mint address
-                    system_program.clone(),       // System program
-                    // NOTE: spl or associated
-                    spl_account.clone(), // SPL Token program
-
-                                         // associated_token_program.clone(), // associated_token_program.clone(),
+                    payer_account.clone(),           // Funding account
+                    vault_account.clone(),           // Associated token account
+                    vault_authority_account.clone(), // Wallet address
+                    mint_account_token_a.clone(),    // Token mint address
+                    system_program.clone(),          // System program
+                    spl_account.clone(),             // SPL Token program
                 ],
             )?;
         } else {
             msg!("Associated Token vault account is NOT EMPTY");
         }
 
-        msg!("about to check if state_account is empty");
-        //////////////////////////////////////////
-        /// ////////////////////////////////////////////
-        /// ////////////////////////////////////
-        // Check if state account is empty and initialize it
-        if state_account.data_is_empty() {
-            // Correctly calculate the required size of the state account
-            let mut vault_registry = VaultRegistry::new();
-            let state_account_size = vault_registry.len();
-            let state_account_required_lamports = rent.minimum_balance(state_account_size);
+        // Create the vault's own state account
+        msg!("Creating vault state account {}", vault_state_account.key);
+        invoke_signed(
+            &solana_program::system_instruction::create_account(
+                payer_account.key,
+                vault_state_account.key,
+                rent.minimum_balance(Vault::LEN),
+                Vault::LEN as u64,
+                program_id,
+            ),
+            &[
+                payer_account.clone(),
+                vault_state_account.clone(),
+                system_program.clone(),
+            ],
+            &[&[
+                VAULT_SEED,
+                mint_account_token_a.key.as_ref(),
+                &[vault_state_bump],
+            ]],
+        )?;
 
-            // Create the state account
-            msg!("about to create state cuz its empty");
+        let vault = Vault {
+            vault_account: *vault_account.key,
+            mint_token_a: *mint_account_token_a.key,
+            mint_a_token_a: *mint_account_a_token_a.key,
+            owner: *payer_account.key,
+            vault_authority: vault_authority_pda,
+            vault_authority_bump,
+            peg_price,
+            reference_price: peg_price,
+            bump: vault_state_bump,
+            status: VaultStatus::Uninitialized,
+            total_deposited: 0,
+            total_withdrawn: 0,
+        };
+        vault_state_account.try_borrow_mut_data()?[..Vault::LEN]
+            .copy_from_slice(&vault.serialize());
 
-            // invoke(
+        // msg!("Vault created successfully");
+        Ok(())
+    }
+
+    /*
+    @name process_index_vault
+    @description Adds a newly created vault to the vault registry, growing the registry account when it is full, and activates the vault. Permissionless, and required before the vault accepts deposits.
+    @param program_id - The ID of the currently executing program.
+    @param accounts - The accounts involved in the transaction.
+    */
+    fn process_index_vault(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let payer_account = next_account_info(account_info_iter)?;
+        let vault_state_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?; // Vault registry PDA
+        let system_program = next_account_info(account_info_iter)?;
+        let rent_account = next_account_info(account_info_iter)?;
+
+        if !payer_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let (state_account_pda, bump_seed) =
+            Pubkey::find_program_address(&[b"vault_registry"], program_id);
+        if state_account.key != &state_account_pda {
+            return Err(VaultError::InvalidPda.into());
+        }
+
+        // Only vaults created by this program can be indexed, and only once
+        let mut vault = Self::load_vault_state(program_id, vault_state_account)?;
+        if vault.status != VaultStatus::Uninitialized {
+            msg!("Error: Vault is already indexed");
+            return Err(VaultError::VaultAlreadyExists.into());
+        }
+        let new_entry = VaultEntry {
+            vault: *vault_state_account.key,
+            mint_token_a: vault.mint_token_a,
+            mint_a_token_a: vault.mint_a_token_a,
+        };
+
+        let rent = &Rent::from_account_info(rent_account)?;
+        let mut vault_registry = if state_account.data_is_empty() {
+            let vault_registry = VaultRegistry::new();
+            msg!("Creating vault registry {}", state_account.key);
             invoke_signed(
                 &solana_program::system_instruction::create_account(
                     payer_account.key,
                     state_account.key,
-                    state_account_required_lamports,
-                    state_account_size as u64,
+                    rent.minimum_balance(vault_registry.len()),
+                    vault_registry.len() as u64,
                     program_id,
                 ),
                 &[
@@ -268,174 +396,123 @@ impl Processor {
                     state_account.clone(),
                     system_program.clone(),
                 ],
-                // &[&[b"vault_registry", &[bump_seed]]],
                 &[&[b"vault_registry".as_ref(), &[bump_seed]]],
             )?;
-
-            // Initialize VaultRegistry and serialize it into the state account's data
-            // let mut vault_registry = VaultRegistry { vaults: Vec::new() };
-
-            let new_vault = Vault {
-                vault_account: *vault_account.key,
-                mint_token_a: *mint_account_token_a.key,
-                mint_a_token_a: *mint_account_a_token_a.key,
-                owner: *payer_account.key,
-            };
-
-            // Use the add_vault method
-            if let Err(e) = vault_registry.add_vault(new_vault) {
-                msg!("Failed to add vault: {}", e);
-                return Err(ProgramError::Custom(0)); // Use appropriate error code
-            }
-
-            // Log the number of vaults after successful addition
-            let number_of_vaults = vault_registry.vault_count();
-            msg!("Number of vaults after addition: {}", number_of_vaults);
-
-            // Debug: Print the vault registry state before serialization
-            // msg!("VaultRegistry before serialization: {:?}", vault_registry);
-
-            let mut state_data = state_account.data.borrow_mut();
-
-            // Perform serialization with error handling
-            let serialized_data = vault_registry.serialize();
-
-            if serialized_data.len() != vault_registry.len() {
-                msg!(
-                    "Serialized length mismatch: expected {}, got {}",
-                    vault_registry.len(),
-                    serialized_data.len()
-                );
-                return Err(ProgramError::Custom(1));
-            }
-
-            // Verify length of serialized data
-            let serialized_length = state_data.len();
-            let expected_length = vault_registry.len();
-
-            // Log the serialized and expected length
-
-            let actual_serialized_length = 4 + vault_registry.vault_count() * Vault::LEN;
-            msg!("Serialized length: {}", serialized_length);
-            msg!("Expected length: {}", expected_length);
-            msg!("actual_serialized_length: {}", actual_serialized_length);
-
-            if serialized_data.len() != vault_registry.len() {
-                msg!(
-                    "Serialized length mismatch: expected {}, got {}",
-                    vault_registry.len(),
-                    serialized_data.len()
-                );
-                return Err(ProgramError::Custom(1));
-            }
-
-            state_data[..serialized_data.len()].copy_from_slice(&serialized_data);
-
-            // Log the serialized data for verification
-            msg!(
-                "Serialized state data (first 64 bytes): {:?}",
-                &state_data[..64]
-            );
-
-            state_data[..vault_registry.len()].copy_from_slice(&serialized_data);
-
-            let deserialized_vault_registry = VaultRegistry::deserialize(&state_data[..]);
-
-            // Log the number of vaults after deserialization
-            if deserialized_vault_registry.is_ok() {
-                let number_of_vaults_after_deserialization =
-                    deserialized_vault_registry.unwrap().vault_count();
-                msg!(
-                    "Number of vaults after deserialization: {}",
-                    number_of_vaults_after_deserialization
-                );
-            } else {
-                return Err(ProgramError::Custom(2));
-            }
-
-            // msg!("State account initialized successfully");
+            vault_registry
         } else {
-            msg!("State account is already initialized");
-
-            // Step 1: Deserialize the existing vault registry
-            let mut state_data = state_account.try_borrow_mut_data()?;
-            let mut vault_registry = match VaultRegistry::deserialize(&mut state_data.as_ref()) {
-                Ok(vr) => vr,
-                Err(_) => {
-                    msg!("Failed to deserialize existing VaultRegistry");
-                    return Err(ProgramError::InvalidAccountData);
-                }
-            };
-
-            // Step 2: Add the new vault to the registry
-            let new_vault = Vault {
-                vault_account: *vault_account.key,
-                mint_token_a: *mint_account_token_a.key,
-                mint_a_token_a: *mint_account_a_token_a.key,
-                owner: *payer_account.key,
-            };
-
-            if let Err(e) = vault_registry.add_vault(new_vault) {
-                msg!("Failed to add vault: {}", e);
-                return Err(ProgramError::Custom(0)); // Use appropriate error code
+            if state_account.owner != program_id {
+                msg!("Error: Vault registry is not owned by the program");
+                return Err(VaultError::InvalidRegistryData.into());
             }
-
-            // Step 3: Serialize the updated vault registry back into the state account
-            let serialized_data = vault_registry.serialize();
-            if serialized_data.len() != vault_registry.len() {
-                msg!(
-                    "Serialized length mismatch: expected {}, got {}",
-                    vault_registry.len(),
-                    serialized_data.len()
-                );
-                return Err(ProgramError::Custom(1));
-            }
-
-            state_data[..serialized_data.len()].copy_from_slice(&serialized_data);
-
-            // Optional: Log the serialized data for verification
+            VaultRegistry::deserialize(&state_account.try_borrow_data()?).map_err(|e| {
+                msg!("Failed to deserialize VaultRegistry: {}", e);
+                e
+            })?
+        };
+
+        // Grow the registry account when every slot is taken
+        if vault_registry.is_full() {
+            vault_registry.grow();
+            Self::realloc_state_account(
+                payer_account,
+                state_account,
+                system_program,
+                rent,
+                vault_registry.len(),
+            )?;
             msg!(
-                "Updated serialized state data (first 64 bytes): {:?}",
-                &state_data[..64]
+                "Vault registry grown to capacity {}",
+                vault_registry.capacity
             );
+        }
 
-            // msg!("VaultRegistry updated successfully");
-
-            // Log the number of vaults in the registry after the update
-            let vault_count = vault_registry.vault_count();
-            msg!("Number of vaults after update: {}", vault_count);
+        if let Err(e) = vault_registry.add_vault(new_entry) {
+            msg!("Failed to add vault: {}", e);
+            return Err(e.into());
+        }
 
-            // Optional: Log the serialized data for verification
+        let serialized_data = vault_registry.serialize();
+        if serialized_data.len() != state_account.data_len() {
             msg!(
-                "Updated serialized state data (first 64 bytes): {:?}",
-                &state_data[..64]
+                "Serialized length mismatch: expected {}, got {}",
+                state_account.data_len(),
+                serialized_data.len()
             );
+            return Err(VaultError::InvalidRegistryData.into());
         }
+        state_account
+            .try_borrow_mut_data()?
+            .copy_from_slice(&serialized_data);
 
-        ////////////////////////////////////
-        /// ////////////////////////////////
-        /// //////////////////////////////
-        // msg!("Vault created successfully");
+        vault.status = VaultStatus::Active;
+        Self::store_vault(vault_state_account, &vault)?;
+
+        msg!(
+            "Indexed vault {}, registry holds {} vaults",
+            vault_state_account.key,
+            vault_registry.vault_count()
+        );
         Ok(())
     }
 
-    ////////////////////////////////////////
-    ///
-    ///
-    ///
+    /*
+    @name realloc_state_account
+    @description Resizes the vault registry account, topping up its lamports from the payer so it stays rent-exempt.
+    @param payer_account - The account paying for the extra rent.
+    @param state_account - The vault registry account to resize.
+    @param system_program - The system program, used for the rent transfer.
+    @param rent - The rent sysvar.
+    @param new_len - The new size of the registry account in bytes.
+    */
+    fn realloc_state_account<'a>(
+        payer_account: &AccountInfo<'a>,
+        state_account: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        rent: &Rent,
+        new_len: usize,
+    ) -> ProgramResult {
+        let required_lamports = rent.minimum_balance(new_len);
+        let top_up = required_lamports.saturating_sub(state_account.lamports());
+        msg!(
+            "Reallocating vault registry from {} to {} bytes, rent top up {}",
+            state_account.data_len(),
+            new_len,
+            top_up
+        );
+
+        if top_up > 0 {
+            invoke(
+                &solana_program::system_instruction::transfer(
+                    payer_account.key,
+                    state_account.key,
+                    top_up,
+                ),
+                &[
+                    payer_account.clone(),
+                    state_account.clone(),
+                    system_program.clone(),
+                ],
+            )?;
+        }
+
+        state_account.realloc(new_len, true)
+    }
 
     /*
     @name process_deposit
-    @description Handles the deposit of tokens into a vault, including transferring the user's tokens to the vault and minting the corresponding amount of aTokens.
-    @param _program_id - The ID of the currently executing program.
+    @description Handles the deposit of tokens into a vault, including transferring the user's tokens to the vault and minting the corresponding amount of aTokens, signed by the program's mint authority PDA so any user can deposit.
+    @param program_id - The ID of the currently executing program.
     @param accounts - The accounts involved in the transaction.
     @param amount - The amount of tokens to be deposited.
+    @param min_anti_amount - The minimum amount of aTokens the depositor accepts, guarding against reference price moves.
     @note creates user_token_a and user_aatoken_a
+    @note aTokens are minted on the inverse log curve, see pricing::anti_coin_amount
     */
     fn process_deposit(
-        _program_id: &Pubkey,
+        program_id: &Pubkey,
         accounts: &[AccountInfo],
         amount: u64, // Pass the amount directly
+        min_anti_amount: u64,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
 
@@ -474,12 +551,12 @@ impl Processor {
         // SAYING: is the users account mint, is not
         if user_token_account_info.mint != *mint_token_a_account.key {
             msg!("Error: The mint associated with the user's TokenA account does not match the expected mint.");
-            return Err(ProgramError::InvalidAccountData);
+            return Err(VaultError::MintMismatch.into());
         }
 
         if user_token_account_info.owner != *payer_account.key {
             msg!("Error: Payer account does not own the user TokenA account.");
-            return Err(ProgramError::IllegalOwner);
+            return Err(VaultError::TokenAccountMismatch.into());
         }
 
         let user_atoken_account = next_account_info(account_info_iter)?; // User's aTokenA account
@@ -511,6 +588,76 @@ impl Processor {
             associated_token_program.key
         );
 
+        let mint_authority_account = next_account_info(account_info_iter)?; // Anti-token mint authority PDA
+        msg!("Mint authority account: {}", mint_authority_account.key);
+
+        let vault_state_account = next_account_info(account_info_iter)?; // Vault state PDA
+        msg!("Vault state account: {}", vault_state_account.key);
+
+        // The anti-token mint must be the one derived for this Token A mint
+        let (anti_mint_pda, _) = Pubkey::find_program_address(
+            &[ANTI_MINT_SEED, mint_token_a_account.key.as_ref()],
+            program_id,
+        );
+        if mint_atoken_a_account.key != &anti_mint_pda {
+            msg!("Error: ATokenA mint does not match the derived PDA for this TokenA mint");
+            return Err(VaultError::InvalidPda.into());
+        }
+
+        let (mint_authority_pda, mint_authority_bump) =
+            Pubkey::find_program_address(&[MINT_AUTHORITY_SEED], program_id);
+        if mint_authority_account.key != &mint_authority_pda {
+            msg!("Error: Mint authority does not match the derived PDA");
+            return Err(VaultError::InvalidPda.into());
+        }
+
+        // Deposits may only go into the vault's own token account
+        let (vault_authority_pda, _) = Pubkey::find_program_address(
+            &[VAULT_AUTHORITY_SEED, mint_token_a_account.key.as_ref()],
+            program_id,
+        );
+        if *vault_account.key
+            != get_associated_token_address(&vault_authority_pda, mint_token_a_account.key)
+        {
+            msg!("Error: Vault account is not the vault authority's TokenA account");
+            return Err(VaultError::InvalidPda.into());
+        }
+
+        // The vault state holds the peg and reference prices
+        let mut vault = Self::load_vault(program_id, vault_state_account)?;
+        if vault.mint_token_a != *mint_token_a_account.key
+            || vault.vault_account != *vault_account.key
+        {
+            msg!("Error: Vault state does not belong to this TokenA vault");
+            return Err(VaultError::MintMismatch.into());
+        }
+
+        let anti_amount = pricing::anti_coin_amount(amount, vault.reference_price, vault.peg_price)
+            .ok_or_else(|| {
+                msg!("Error: Could not price anti-coins for this deposit");
+                VaultError::MathOverflow
+            })?;
+        msg!(
+            "Anti-coins for deposit: {} (reference price {}, peg price {})",
+            anti_amount,
+            vault.reference_price,
+            vault.peg_price
+        );
+
+        if anti_amount == 0 {
+            msg!("Error: Deposit is too small to mint any aTokenA");
+            return Err(VaultError::ZeroAmount.into());
+        }
+
+        if anti_amount < min_anti_amount {
+            msg!(
+                "Error: Slippage exceeded, would mint {} aTokenA but at least {} required",
+                anti_amount,
+                min_anti_amount
+            );
+            return Err(VaultError::SlippageExceeded.into());
+        }
+
         // NOTE: if the users ATokenA account doesnt exist, then create one
         msg!(
             "user_atoken_account.lamports(): {}",
@@ -543,9 +690,6 @@ impl Processor {
             )?;
         }
 
-        /////////////////////////
-        /// //////////WORKED!!
-        /// ////////////////////////////
         // Log balances before transfer
         // msg!("Log balances before transfer");
         let user_token_a_balance_before =
@@ -601,27 +745,35 @@ impl Processor {
             vault_token_a_balance_after
         );
 
-        // Mint aTokenA equivalent to the amount of TokenA deposited
-        msg!("Minting -- {} aTokenA to user's aTokenA account", amount);
-        invoke(
+        // Mint aTokenA priced on the inverse log curve
+        msg!(
+            "Minting -- {} aTokenA to user's aTokenA account",
+            anti_amount
+        );
+        invoke_signed(
             &spl_token::instruction::mint_to(
-                // &spl_token::id(),
                 &spl_token::id(),
-                // &spl_associated_token_account::id(),
                 mint_atoken_a_account.key,
                 user_atoken_account.key,
-                payer_account.key,
+                mint_authority_account.key,
                 &[], // No multisig signing required
-                amount,
+                anti_amount,
             )?,
             &[
                 mint_atoken_a_account.clone(),
                 user_atoken_account.clone(),
-                payer_account.clone(),
+                mint_authority_account.clone(),
             ],
+            &[&[MINT_AUTHORITY_SEED, &[mint_authority_bump]]],
         )?;
         // msg!("Minting completed");
 
+        vault.total_deposited = vault
+            .total_deposited
+            .checked_add(amount)
+            .ok_or(VaultError::MathOverflow)?;
+        Self::store_vault(vault_state_account, &vault)?;
+
         // Log balances after minting
         let user_atoken_balance_after =
             TokenAccount::unpack(&user_atoken_account.try_borrow_data()?)?.amount;
@@ -636,48 +788,164 @@ impl Processor {
 
     /*
     @name process_withdraw
-    @description Handles the withdrawal of tokens from a vault, including burning the corresponding rTokens and transferring the requested amount to the user's account.
-    @param _program_id - The ID of the currently executing program.
+    @description Handles the withdrawal of tokens from a vault, burning the user's anti-tokens and transferring the matching amount of Token A out of the vault's token account, signed by the vault authority PDA.
+    @param program_id - The ID of the currently executing program.
     @param accounts - The accounts involved in the transaction.
-    @param amount - The amount of tokens to be withdrawn.
+    @param amount - The amount of anti-tokens to burn. The Token A returned is their pro-rata share of the vault.
     */
     fn process_withdraw(
-        _program_id: &Pubkey,
+        program_id: &Pubkey,
         accounts: &[AccountInfo],
         amount: u64,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
-        let vault_account = next_account_info(account_info_iter)?;
         let user_account = next_account_info(account_info_iter)?;
-        let user_rtoken_account = next_account_info(account_info_iter)?;
-        let mint_account = next_account_info(account_info_iter)?;
-        msg!("Withdrawing {} lamports", amount);
+        let mint_token_a_account = next_account_info(account_info_iter)?;
+        let mint_atoken_a_account = next_account_info(account_info_iter)?;
+        let vault_account = next_account_info(account_info_iter)?;
+        let vault_authority_account = next_account_info(account_info_iter)?;
+        let user_token_a_account = next_account_info(account_info_iter)?;
+        let user_atoken_a_account = next_account_info(account_info_iter)?;
+        let vault_state_account = next_account_info(account_info_iter)?;
+        let spl_account = next_account_info(account_info_iter)?;
 
+        msg!(
+            "Withdrawing {} TokenA from vault {}",
+            amount,
+            vault_account.key
+        );
+
+        if !user_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if amount == 0 {
+            msg!("Error: Withdraw amount must be greater than zero");
+            return Err(VaultError::ZeroAmount.into());
+        }
+
+        if *spl_account.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        // The vault and mint pair must match the vault's state
+        let mut vault = Self::load_vault(program_id, vault_state_account)?;
+        if vault.vault_account != *vault_account.key {
+            msg!("Error: Vault state does not belong to this vault account");
+            return Err(VaultError::TokenAccountMismatch.into());
+        }
+
+        if vault.mint_token_a != *mint_token_a_account.key
+            || vault.mint_a_token_a != *mint_atoken_a_account.key
+        {
+            msg!("Error: Mint accounts do not match the registered vault");
+            return Err(VaultError::MintMismatch.into());
+        }
+
+        // The vault authority is recorded with the vault at creation
+        if *vault_authority_account.key != vault.vault_authority {
+            msg!("Error: Vault authority does not match the registered vault");
+            return Err(VaultError::InvalidPda.into());
+        }
+
+        // The user must own both token accounts, and they must hold the vault's mints
+        let user_token_a_info = TokenAccount::unpack(&user_token_a_account.try_borrow_data()?)?;
+        if user_token_a_info.mint != vault.mint_token_a {
+            msg!("Error: The user's TokenA account does not hold the vault's Token A mint");
+            return Err(VaultError::MintMismatch.into());
+        }
+        if user_token_a_info.owner != *user_account.key {
+            msg!("Error: User does not own the TokenA account");
+            return Err(VaultError::TokenAccountMismatch.into());
+        }
+
+        let user_atoken_a_info = TokenAccount::unpack(&user_atoken_a_account.try_borrow_data()?)?;
+        if user_atoken_a_info.mint != vault.mint_a_token_a {
+            msg!("Error: The user's aTokenA account does not hold the vault's anti-token mint");
+            return Err(VaultError::MintMismatch.into());
+        }
+        if user_atoken_a_info.owner != *user_account.key {
+            msg!("Error: User does not own the aTokenA account");
+            return Err(VaultError::TokenAccountMismatch.into());
+        }
+        if user_atoken_a_info.amount < amount {
+            msg!(
+                "Error: Insufficient aTokenA balance: have {}, need {}",
+                user_atoken_a_info.amount,
+                amount
+            );
+            return Err(VaultError::InsufficientBalance.into());
+        }
+
+        let vault_info = TokenAccount::unpack(&vault_account.try_borrow_data()?)?;
+        if vault_info.owner != vault.vault_authority {
+            msg!("Error: Vault account is not owned by the vault authority");
+            return Err(VaultError::TokenAccountMismatch.into());
+        }
+
+        // Anti-coins redeem a pro-rata share of the vault's Token A
+        let anti_supply = Mint::unpack(&mint_atoken_a_account.try_borrow_data()?)?.supply;
+        let token_a_amount =
+            pricing::underlying_for_anti_coins(amount, vault_info.amount, anti_supply).ok_or_else(
+                || {
+                    msg!("Error: Could not price the withdrawal against the anti-coin supply");
+                    VaultError::InsufficientBalance
+                },
+            )?;
+        msg!(
+            "Redeeming {} aTokenA for {} TokenA (vault balance {}, supply {})",
+            amount,
+            token_a_amount,
+            vault_info.amount,
+            anti_supply
+        );
+
+        // Burn the user's anti-tokens
         invoke(
             &burn(
-                &spl_token::id(),
-                user_rtoken_account.key,
-                mint_account.key,
+                spl_account.key,
+                user_atoken_a_account.key,
+                mint_atoken_a_account.key,
                 user_account.key,
                 &[],
                 amount,
             )?,
             &[
-                user_rtoken_account.clone(),
-                mint_account.clone(),
+                user_atoken_a_account.clone(),
+                mint_atoken_a_account.clone(),
                 user_account.clone(),
             ],
         )?;
 
-        invoke(
-            &solana_program::system_instruction::transfer(
+        // Transfer Token A out of the vault, signed by the vault authority PDA
+        invoke_signed(
+            &spl_token::instruction::transfer(
+                spl_account.key,
                 vault_account.key,
-                user_account.key,
-                amount,
-            ),
-            &[vault_account.clone(), user_account.clone()],
+                user_token_a_account.key,
+                vault_authority_account.key,
+                &[],
+                token_a_amount,
+            )?,
+            &[
+                vault_account.clone(),
+                user_token_a_account.clone(),
+                vault_authority_account.clone(),
+            ],
+            &[&vault.authority_seeds(&[vault.vault_authority_bump])],
         )?;
 
+        vault.total_withdrawn = vault
+            .total_withdrawn
+            .checked_add(token_a_amount)
+            .ok_or(VaultError::MathOverflow)?;
+        Self::store_vault(vault_state_account, &vault)?;
+
+        msg!(
+            "Withdraw completed: {} TokenA returned to {}",
+            token_a_amount,
+            user_token_a_account.key
+        );
         Ok(())
     }
 
@@ -748,10 +1016,7 @@ impl Processor {
         let rent_account = next_account_info(account_info_iter)?;
         let system_program = next_account_info(account_info_iter)?;
 
-        let associated_token_program = next_account_info(account_info_iter)?; // System program account
-
-        let expected_user_token_pubkey =
-            get_associated_token_address(payer_account.key, mint_account.key);
+        let _associated_token_program = next_account_info(account_info_iter)?; // Associated token program account
 
         // Derive the PDA for the mint account
         let (expected_mint_pubkey, bump_seed) =
@@ -857,7 +1122,7 @@ impl Processor {
             &mint_to(
                 &spl_token::id(),
                 mint_account.key,
-                &user_token_account.key,
+                user_token_account.key,
                 payer_account.key,
                 &[],
                 amount,
@@ -877,4 +1142,148 @@ impl Processor {
 
         Ok(())
     }
+
+    /*
+    @name load_vault
+    @description Reads an active vault from its state PDA, see load_vault_state.
+    @param program_id - The ID of the currently executing program.
+    @param vault_state_account - The vault state PDA.
+    */
+    fn load_vault(
+        program_id: &Pubkey,
+        vault_state_account: &AccountInfo,
+    ) -> Result<Vault, ProgramError> {
+        let vault = Self::load_vault_state(program_id, vault_state_account)?;
+        if vault.status != VaultStatus::Active {
+            msg!("Error: Vault is not active");
+            return Err(VaultError::VaultNotActive.into());
+        }
+        Ok(vault)
+    }
+
+    /*
+    @name load_vault_state
+    @description Reads a vault from its state PDA, checking the account is owned by the program and sits at the address derived from its Token A mint. The vault may not be active yet.
+    @param program_id - The ID of the currently executing program.
+    @param vault_state_account - The vault state PDA.
+    */
+    fn load_vault_state(
+        program_id: &Pubkey,
+        vault_state_account: &AccountInfo,
+    ) -> Result<Vault, ProgramError> {
+        if vault_state_account.owner != program_id {
+            msg!("Error: Vault state account is not owned by the program");
+            return Err(VaultError::InvalidVaultData.into());
+        }
+
+        let vault = Vault::deserialize(&vault_state_account.try_borrow_data()?).map_err(|e| {
+            msg!("Failed to deserialize Vault: {}", e);
+            e
+        })?;
+
+        let vault_state_pda =
+            Pubkey::create_program_address(&vault.seeds(&[vault.bump]), program_id)?;
+        if *vault_state_account.key != vault_state_pda {
+            msg!("Error: Vault state account does not match the derived PDA");
+            return Err(VaultError::InvalidPda.into());
+        }
+
+        Ok(vault)
+    }
+
+    /*
+    @name store_vault
+    @description Writes a vault back into its state PDA.
+    @param vault_state_account - The vault state PDA, already checked by load_vault.
+    @param vault - The vault to write.
+    */
+    fn store_vault(vault_state_account: &AccountInfo, vault: &Vault) -> ProgramResult {
+        vault_state_account.try_borrow_mut_data()?[..Vault::LEN]
+            .copy_from_slice(&vault.serialize());
+        Ok(())
+    }
+
+    /*
+    @name check_program_admin
+    @description Checks the signer is the program's upgrade authority, read from the program's ProgramData account.
+    @param program_id - The ID of the currently executing program.
+    @param admin_account - The signer claiming to be the upgrade authority.
+    @param program_data_account - The program's ProgramData account, owned by the upgradeable loader.
+    */
+    fn check_program_admin(
+        program_id: &Pubkey,
+        admin_account: &AccountInfo,
+        program_data_account: &AccountInfo,
+    ) -> ProgramResult {
+        let (program_data_pda, _) =
+            Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+        if *program_data_account.key != program_data_pda
+            || *program_data_account.owner != bpf_loader_upgradeable::id()
+        {
+            msg!("Error: Program data account does not belong to this program");
+            return Err(VaultError::InvalidPda.into());
+        }
+
+        let metadata_len = UpgradeableLoaderState::size_of_programdata_metadata();
+        let data = program_data_account.try_borrow_data()?;
+        let metadata = data
+            .get(..metadata_len)
+            .ok_or(ProgramError::InvalidAccountData)?;
+        let upgrade_authority = match limited_deserialize(metadata, metadata_len as u64) {
+            Ok(UpgradeableLoaderState::ProgramData {
+                upgrade_authority_address,
+                ..
+            }) => upgrade_authority_address,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+
+        if upgrade_authority != Some(*admin_account.key) {
+            msg!("Error: Signer is not the program's upgrade authority");
+            return Err(VaultError::Unauthorized.into());
+        }
+        Ok(())
+    }
+
+    /*
+    @name process_set_reference_price
+    @description Updates the reference price of Token A that deposits are priced against. Only the program's upgrade authority may set it, never the vault's creator.
+    @param program_id - The ID of the currently executing program.
+    @param accounts - The accounts involved in the transaction.
+    @param price - The new reference price, scaled by PRICE_SCALE.
+    */
+    fn process_set_reference_price(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        price: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let admin_account = next_account_info(account_info_iter)?;
+        let vault_state_account = next_account_info(account_info_iter)?;
+        let program_data_account = next_account_info(account_info_iter)?; // ProgramData of this program, names the admin
+
+        if !admin_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if price == 0 {
+            msg!("Error: Reference price must be greater than zero");
+            return Err(VaultError::InvalidPrice.into());
+        }
+
+        // Anyone can create a vault, so its creator must not be able to price other users' deposits
+        Self::check_program_admin(program_id, admin_account, program_data_account)?;
+
+        let mut vault = Self::load_vault(program_id, vault_state_account)?;
+
+        msg!(
+            "Reference price for vault {}: {} -> {}",
+            vault.vault_account,
+            vault.reference_price,
+            price
+        );
+        vault.reference_price = price;
+        Self::store_vault(vault_state_account, &vault)?;
+
+        Ok(())
+    }
 }
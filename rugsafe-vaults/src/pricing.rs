@@ -0,0 +1,112 @@
+// Anti-coin pricing: inverse logarithmic peg between a rugged token and its anti-coin.
+//
+// With r = reference_price / peg_price (capped at 1), an anti-coin is priced at
+// peg_price * (1 - ln r), so it appreciates logarithmically as the token falls below its peg.
+// A deposit keeps value parity: amount * reference_price = minted * anti_coin_price, i.e.
+// minted = amount * r / (1 - ln r). At the peg this is exactly 1:1.
+//
+// All math is unsigned/signed 128-bit fixed point so it is deterministic on-chain.
+
+pub const PRICE_SCALE: u64 = 1_000_000; // Prices carry 6 decimals
+
+const FIXED_ONE: i128 = 1_000_000_000_000; // Internal precision, 12 decimals
+const LN_2: i128 = 693_147_180_560; // ln(2) * FIXED_ONE
+const LN_SERIES_TERMS: i128 = 16; // atanh series terms, error < 1e-12 for z <= 1/3
+
+/*
+@name ln_fixed
+@description Natural logarithm of a positive FIXED_ONE-scaled value, returned FIXED_ONE-scaled.
+@param x - The value to take the logarithm of, scaled by FIXED_ONE. Must be greater than zero.
+*/
+fn ln_fixed(x: i128) -> Option<i128> {
+    if x <= 0 {
+        return None;
+    }
+
+    // Range reduction: x = m * 2^k with m in [1, 2)
+    let mut m = x;
+    let mut k: i128 = 0;
+    while m >= 2 * FIXED_ONE {
+        m /= 2;
+        k += 1;
+    }
+    while m < FIXED_ONE {
+        m *= 2;
+        k -= 1;
+    }
+
+    // ln(m) = 2 * atanh(z) = 2 * (z + z^3/3 + z^5/5 + ...), z = (m - 1) / (m + 1)
+    let z = (m - FIXED_ONE) * FIXED_ONE / (m + FIXED_ONE);
+    let z_squared = z * z / FIXED_ONE;
+    let mut term = z;
+    let mut sum = 0;
+    for n in 0..LN_SERIES_TERMS {
+        sum += term / (2 * n + 1);
+        term = term * z_squared / FIXED_ONE;
+    }
+
+    Some(k * LN_2 + 2 * sum)
+}
+
+/*
+@name price_ratio
+@description Reference price relative to the peg, capped at 1 and scaled by FIXED_ONE.
+@param reference_price - Current reference price of the underlying token, scaled by PRICE_SCALE.
+@param peg_price - Peg price recorded for the vault, scaled by PRICE_SCALE.
+*/
+fn price_ratio(reference_price: u64, peg_price: u64) -> Option<i128> {
+    if reference_price == 0 || peg_price == 0 {
+        return None;
+    }
+    let ratio = (reference_price as i128) * FIXED_ONE / (peg_price as i128);
+    Some(ratio.min(FIXED_ONE))
+}
+
+/*
+@name anti_coin_price
+@description Price of one anti-coin under the inverse log peg, peg_price * (1 - ln r).
+@param reference_price - Current reference price of the underlying token, scaled by PRICE_SCALE.
+@param peg_price - Peg price recorded for the vault, scaled by PRICE_SCALE.
+@return The anti-coin price scaled by PRICE_SCALE, or None for a zero or overflowing price.
+*/
+pub fn anti_coin_price(reference_price: u64, peg_price: u64) -> Option<u64> {
+    let ratio = price_ratio(reference_price, peg_price)?;
+    let factor = FIXED_ONE.checked_sub(ln_fixed(ratio)?)?;
+    let price = (peg_price as i128).checked_mul(factor)? / FIXED_ONE;
+    u64::try_from(price).ok()
+}
+
+/*
+@name anti_coin_amount
+@description Number of anti-coins minted for a deposit of the underlying token, amount * r / (1 - ln r).
+@param amount - The amount of the underlying token deposited, in base units.
+@param reference_price - Current reference price of the underlying token, scaled by PRICE_SCALE.
+@param peg_price - Peg price recorded for the vault, scaled by PRICE_SCALE.
+@return The anti-coin amount in base units (rounded down), or None for a zero or overflowing price.
+*/
+pub fn anti_coin_amount(amount: u64, reference_price: u64, peg_price: u64) -> Option<u64> {
+    let ratio = price_ratio(reference_price, peg_price)?;
+    let factor = FIXED_ONE.checked_sub(ln_fixed(ratio)?)?;
+    let minted = (amount as i128).checked_mul(ratio)? / factor;
+    u64::try_from(minted).ok()
+}
+
+/*
+@name underlying_for_anti_coins
+@description Pro-rata share of the vault's underlying tokens redeemed by burning anti-coins.
+@param anti_amount - The amount of anti-coins burned.
+@param vault_balance - The vault's current underlying token balance.
+@param anti_supply - The anti-coin supply before burning.
+@return The underlying amount (rounded down), or None if there is no supply.
+*/
+pub fn underlying_for_anti_coins(
+    anti_amount: u64,
+    vault_balance: u64,
+    anti_supply: u64,
+) -> Option<u64> {
+    if anti_supply == 0 || anti_amount > anti_supply {
+        return None;
+    }
+    let amount = (anti_amount as u128) * (vault_balance as u128) / (anti_supply as u128);
+    u64::try_from(amount).ok()
+}
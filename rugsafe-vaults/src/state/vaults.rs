@@ -1,21 +1,63 @@
+use crate::error::VaultError;
+use solana_program::entrypoint::MAX_PERMITTED_DATA_INCREASE;
 use solana_program::pubkey::Pubkey;
 
+pub const VAULT_AUTHORITY_SEED: &[u8] = b"vault_authority"; // Seed, with the Token A mint, for the PDA that owns a vault's token account
+pub const ANTI_MINT_SEED: &[u8] = b"anti_mint"; // Seed, with the Token A mint, for the vault's anti-token mint
+pub const MINT_AUTHORITY_SEED: &[u8] = b"mint_authority"; // Seed for the PDA allowed to mint anti-tokens
+pub const VAULT_SEED: &[u8] = b"vault"; // Seed, with the Token A mint, for the PDA holding a vault's state
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VaultStatus {
+    Uninitialized = 0, // Created but not yet added to the registry by IndexVault
+    Active = 1,
+}
+
+impl TryFrom<u8> for VaultStatus {
+    type Error = VaultError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(VaultStatus::Uninitialized),
+            1 => Ok(VaultStatus::Active),
+            _ => Err(VaultError::InvalidVaultData),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Vault {
     pub vault_account: Pubkey,
     pub mint_token_a: Pubkey,
     pub mint_a_token_a: Pubkey,
     pub owner: Pubkey,
+    pub vault_authority: Pubkey, // PDA owning vault_account, seeded by VAULT_AUTHORITY_SEED and mint_token_a
+    pub vault_authority_bump: u8, // Bump seed of vault_authority, used to sign transfers out of the vault
+    pub peg_price: u64, // Price of Token A the anti-coin is pegged against, scaled by pricing::PRICE_SCALE
+    pub reference_price: u64, // Latest reference price of Token A set by the program's upgrade authority, scaled by pricing::PRICE_SCALE
+    pub bump: u8, // Bump seed of the vault state PDA, seeded by VAULT_SEED and mint_token_a
+    pub status: VaultStatus,
+    pub total_deposited: u64, // Token A deposited into the vault over its lifetime
+    pub total_withdrawn: u64, // Token A withdrawn from the vault over its lifetime
+}
+
+// Registry index entry; the vault itself lives in its own PDA at `vault`
+#[derive(Debug, PartialEq)]
+pub struct VaultEntry {
+    pub vault: Pubkey,
+    pub mint_token_a: Pubkey,
+    pub mint_a_token_a: Pubkey,
 }
 
 #[derive(Debug, PartialEq)]
 pub struct VaultRegistry {
-    pub vaults: Vec<Vault>,
+    pub vaults: Vec<VaultEntry>,
     pub capacity: usize,
 }
 
 impl Vault {
-    pub const LEN: usize = 32 * 4; // 4 Pubkeys, each 32 bytes
+    pub const LEN: usize = 32 * 5 + 1 + 8 * 2 + 1 + 1 + 8 * 2; // 5 Pubkeys, the authority bump, 2 prices, the state bump, status and 2 totals
 
     pub fn serialize(&self) -> Vec<u8> {
         let mut data = Vec::with_capacity(Self::LEN);
@@ -23,26 +65,92 @@ impl Vault {
         data.extend_from_slice(self.mint_token_a.as_ref());
         data.extend_from_slice(self.mint_a_token_a.as_ref());
         data.extend_from_slice(self.owner.as_ref());
+        data.extend_from_slice(self.vault_authority.as_ref());
+        data.push(self.vault_authority_bump);
+        data.extend_from_slice(&self.peg_price.to_le_bytes());
+        data.extend_from_slice(&self.reference_price.to_le_bytes());
+        data.push(self.bump);
+        data.push(self.status as u8);
+        data.extend_from_slice(&self.total_deposited.to_le_bytes());
+        data.extend_from_slice(&self.total_withdrawn.to_le_bytes());
         data
     }
 
-    pub fn deserialize(input: &[u8]) -> Self {
+    pub fn deserialize(input: &[u8]) -> Result<Self, VaultError> {
+        if input.len() < Self::LEN {
+            return Err(VaultError::InvalidVaultData);
+        }
+
         let vault_account = Pubkey::new_from_array(input[0..32].try_into().unwrap());
         let mint_token_a = Pubkey::new_from_array(input[32..64].try_into().unwrap());
         let mint_a_token_a = Pubkey::new_from_array(input[64..96].try_into().unwrap());
         let owner = Pubkey::new_from_array(input[96..128].try_into().unwrap());
-
-        Vault {
+        let vault_authority = Pubkey::new_from_array(input[128..160].try_into().unwrap());
+        let vault_authority_bump = input[160];
+        let peg_price = u64::from_le_bytes(input[161..169].try_into().unwrap());
+        let reference_price = u64::from_le_bytes(input[169..177].try_into().unwrap());
+        let bump = input[177];
+        let status = VaultStatus::try_from(input[178])?;
+        let total_deposited = u64::from_le_bytes(input[179..187].try_into().unwrap());
+        let total_withdrawn = u64::from_le_bytes(input[187..195].try_into().unwrap());
+
+        Ok(Vault {
             vault_account,
             mint_token_a,
             mint_a_token_a,
             owner,
+            vault_authority,
+            vault_authority_bump,
+            peg_price,
+            reference_price,
+            bump,
+            status,
+            total_deposited,
+            total_withdrawn,
+        })
+    }
+
+    // Seeds the vault state PDA signs with; `bump` must hold `self.bump`
+    pub fn seeds<'a>(&'a self, bump: &'a [u8; 1]) -> [&'a [u8]; 3] {
+        [VAULT_SEED, self.mint_token_a.as_ref(), bump]
+    }
+
+    // Seeds the vault authority PDA signs with; `bump` must hold `vault_authority_bump`
+    pub fn authority_seeds<'a>(&'a self, bump: &'a [u8; 1]) -> [&'a [u8]; 3] {
+        [VAULT_AUTHORITY_SEED, self.mint_token_a.as_ref(), bump]
+    }
+}
+
+impl VaultEntry {
+    pub const LEN: usize = 32 * 3; // 3 Pubkeys, each 32 bytes
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(Self::LEN);
+        data.extend_from_slice(self.vault.as_ref());
+        data.extend_from_slice(self.mint_token_a.as_ref());
+        data.extend_from_slice(self.mint_a_token_a.as_ref());
+        data
+    }
+
+    pub fn deserialize(input: &[u8]) -> Self {
+        VaultEntry {
+            vault: Pubkey::new_from_array(input[0..32].try_into().unwrap()),
+            mint_token_a: Pubkey::new_from_array(input[32..64].try_into().unwrap()),
+            mint_a_token_a: Pubkey::new_from_array(input[64..96].try_into().unwrap()),
         }
     }
 }
 
+impl Default for VaultRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(clippy::len_without_is_empty)] // `len` is the serialized size in bytes, not the vault count
 impl VaultRegistry {
     pub const INITIAL_CAPACITY: usize = 10;
+    pub const MAX_GROWTH: usize = MAX_PERMITTED_DATA_INCREASE / VaultEntry::LEN; // Most vault slots one realloc can add
 
     pub fn new() -> Self {
         VaultRegistry {
@@ -52,7 +160,7 @@ impl VaultRegistry {
     }
 
     pub fn len(&self) -> usize {
-        8 + 8 + (Vault::LEN * self.capacity) // 8 bytes for vec length, 8 bytes for capacity
+        8 + 8 + (VaultEntry::LEN * self.capacity) // 8 bytes for vec length, 8 bytes for capacity
     }
 
     pub fn serialize(&self) -> Vec<u8> {
@@ -61,8 +169,8 @@ impl VaultRegistry {
         data.extend_from_slice(&vaults_len.to_le_bytes());
         data.extend_from_slice(&(self.capacity as u64).to_le_bytes());
 
-        for vault in &self.vaults {
-            data.extend_from_slice(&vault.serialize());
+        for entry in &self.vaults {
+            data.extend_from_slice(&entry.serialize());
         }
 
         // Pad the remaining space with zeros
@@ -71,9 +179,9 @@ impl VaultRegistry {
         data
     }
 
-    pub fn deserialize(input: &[u8]) -> Result<Self, &'static str> {
+    pub fn deserialize(input: &[u8]) -> Result<Self, VaultError> {
         if input.len() < 16 {
-            return Err("Input data is too short for deserialization");
+            return Err(VaultError::InvalidRegistryData);
         }
 
         let (len_bytes, rest) = input.split_at(8);
@@ -82,41 +190,54 @@ impl VaultRegistry {
         let (capacity_bytes, mut rest) = rest.split_at(8);
         let capacity = u64::from_le_bytes(capacity_bytes.try_into().unwrap()) as usize;
 
-        if vaults_len > capacity {
-            return Err("Invalid data: vault count exceeds capacity");
+        if vaults_len > capacity || rest.len() < vaults_len * VaultEntry::LEN {
+            return Err(VaultError::InvalidRegistryData);
         }
 
         let mut vaults = Vec::with_capacity(vaults_len);
         for _ in 0..vaults_len {
-            let (vault_bytes, remaining) = rest.split_at(Vault::LEN);
-            vaults.push(Vault::deserialize(vault_bytes));
+            let (entry_bytes, remaining) = rest.split_at(VaultEntry::LEN);
+            vaults.push(VaultEntry::deserialize(entry_bytes));
             rest = remaining;
         }
 
         Ok(VaultRegistry { vaults, capacity })
     }
 
-    pub fn add_vault(&mut self, vault: Vault) -> Result<(), &'static str> {
-        if self.vaults.len() >= self.capacity {
-            return Err("Max capacity reached. Reallocation needed.");
+    pub fn add_vault(&mut self, entry: VaultEntry) -> Result<(), VaultError> {
+        // The anti-token mint is derived from the Token A mint, so one check covers both
+        if self.find_by_mint(&entry.mint_token_a).is_some() {
+            return Err(VaultError::VaultAlreadyExists);
         }
-        self.vaults.push(vault);
+        if self.is_full() {
+            return Err(VaultError::RegistryFull);
+        }
+        self.vaults.push(entry);
         Ok(())
     }
 
-    pub fn remove_vault(&mut self, index: usize) -> Result<(), &'static str> {
+    pub fn remove_vault(&mut self, index: usize) -> Result<(), VaultError> {
         if index >= self.vaults.len() {
-            return Err("Index out of bounds");
+            return Err(VaultError::VaultNotFound);
         }
         self.vaults.remove(index);
         Ok(())
     }
 
+    pub fn find_by_mint(&self, mint_token_a: &Pubkey) -> Option<&VaultEntry> {
+        self.vaults.iter().find(|v| v.mint_token_a == *mint_token_a)
+    }
+
     pub fn vault_count(&self) -> usize {
         self.vaults.len()
     }
 
+    pub fn is_full(&self) -> bool {
+        self.vaults.len() >= self.capacity
+    }
+
+    // Doubles the capacity, bounded by how much an account can grow in one instruction
     pub fn grow(&mut self) {
-        self.capacity *= 2;
+        self.capacity += self.capacity.clamp(1, Self::MAX_GROWTH);
     }
 }
@@ -1,3 +1,3 @@
+pub mod test_error;
+pub mod test_pricing;
 pub mod test_vaults;
-
-pub use {test_vaults::*};
@@ -0,0 +1,23 @@
+use rugsafe_vaults::error::VaultError;
+use solana_program::program_error::ProgramError;
+
+#[test]
+fn test_vault_error_codes() {
+    // Clients match on these codes to tell a user why a deposit or withdraw failed
+    assert_eq!(
+        ProgramError::from(VaultError::VaultAlreadyExists),
+        ProgramError::Custom(0)
+    );
+    assert_eq!(
+        ProgramError::from(VaultError::VaultNotActive),
+        ProgramError::Custom(5)
+    );
+    assert_eq!(
+        ProgramError::from(VaultError::InsufficientBalance),
+        ProgramError::Custom(10)
+    );
+    assert_eq!(
+        ProgramError::from(VaultError::SlippageExceeded),
+        ProgramError::Custom(13)
+    );
+}
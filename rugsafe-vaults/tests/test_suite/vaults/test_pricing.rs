@@ -0,0 +1,76 @@
+use rugsafe_vaults::pricing::{
+    anti_coin_amount, anti_coin_price, underlying_for_anti_coins, PRICE_SCALE,
+};
+
+#[test]
+fn test_anti_coin_price_at_peg() {
+    assert_eq!(anti_coin_price(PRICE_SCALE, PRICE_SCALE), Some(PRICE_SCALE));
+    // Above the peg the ratio is capped, so the anti-coin never drops below the peg
+    assert_eq!(
+        anti_coin_price(2 * PRICE_SCALE, PRICE_SCALE),
+        Some(PRICE_SCALE)
+    );
+}
+
+#[test]
+fn test_anti_coin_price_below_peg() {
+    // r = 0.5: 1 - ln(0.5) = 1.693147...
+    assert_eq!(
+        anti_coin_price(PRICE_SCALE / 2, PRICE_SCALE),
+        Some(1_693_147)
+    );
+    // r = 0.1: 1 - ln(0.1) = 3.302585...
+    assert_eq!(
+        anti_coin_price(PRICE_SCALE / 10, PRICE_SCALE),
+        Some(3_302_585)
+    );
+
+    // The anti-coin price rises as the reference price falls
+    let mut last = 0;
+    for price in [900_000, 500_000, 100_000, 10_000, 1] {
+        let anti_price = anti_coin_price(price, PRICE_SCALE).unwrap();
+        assert!(anti_price > last);
+        last = anti_price;
+    }
+}
+
+#[test]
+fn test_anti_coin_amount() {
+    // 1:1 at the peg
+    assert_eq!(
+        anti_coin_amount(1_000, PRICE_SCALE, PRICE_SCALE),
+        Some(1_000)
+    );
+    assert_eq!(
+        anti_coin_amount(1_000, 3 * PRICE_SCALE, PRICE_SCALE),
+        Some(1_000)
+    );
+
+    // r = 0.5: 1_000_000 * 0.5 / 1.693147 = 295_308.05...
+    assert_eq!(
+        anti_coin_amount(1_000_000, PRICE_SCALE / 2, PRICE_SCALE),
+        Some(295_308)
+    );
+
+    // Value parity: minted anti-coins at the anti-coin price match the deposit's value
+    let amount = 1_000_000_000;
+    let reference_price = PRICE_SCALE / 4;
+    let minted = anti_coin_amount(amount, reference_price, PRICE_SCALE).unwrap();
+    let anti_price = anti_coin_price(reference_price, PRICE_SCALE).unwrap();
+    let deposit_value = amount as u128 * reference_price as u128;
+    let minted_value = minted as u128 * anti_price as u128;
+    assert!(deposit_value.abs_diff(minted_value) * 1_000_000 < deposit_value);
+
+    // Zero or missing prices cannot be priced
+    assert_eq!(anti_coin_amount(1_000, 0, PRICE_SCALE), None);
+    assert_eq!(anti_coin_amount(1_000, PRICE_SCALE, 0), None);
+}
+
+#[test]
+fn test_underlying_for_anti_coins() {
+    assert_eq!(underlying_for_anti_coins(60, 101, 101), Some(60));
+    assert_eq!(underlying_for_anti_coins(50, 300, 100), Some(150));
+    assert_eq!(underlying_for_anti_coins(1, 2, 3), Some(0));
+    assert_eq!(underlying_for_anti_coins(10, 100, 0), None);
+    assert_eq!(underlying_for_anti_coins(11, 100, 10), None);
+}
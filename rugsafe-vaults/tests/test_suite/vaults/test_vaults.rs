@@ -1,35 +1,44 @@
 // use borsh::de::BorshDeserialize;
 // use borsh::{BorshDeserialize, BorshSerialize};
-use hex;
-use rugsafe_vaults::instructions::vaults::VaultInstruction;
+use rugsafe_vaults::error::VaultError;
 use rugsafe_vaults::process_instruction;
 // use rugsafe::processor::Processor;
 use rugsafe_vaults::instructions::processor::Processor;
-use rugsafe_vaults::state::vaults::{Vault, VaultRegistry};
+use rugsafe_vaults::state::vaults::{
+    Vault, VaultEntry, VaultRegistry, VaultStatus, ANTI_MINT_SEED, MINT_AUTHORITY_SEED,
+    VAULT_AUTHORITY_SEED, VAULT_SEED,
+};
+use solana_program::bpf_loader_upgradeable;
 use solana_program::hash::Hash;
+use solana_program::program_option::COption;
 use solana_program::program_pack::Pack;
-use solana_program::rent::Rent;
 use solana_program::system_instruction;
 use solana_program::{
     instruction::{AccountMeta, Instruction},
-    msg,
     program_error::ProgramError,
     sysvar,
-    sysvar::rent::ID as RentSysvarId,
 };
 use solana_program_test::*;
 use solana_sdk::{
     account::Account,
+    instruction::InstructionError,
     pubkey::Pubkey,
     signature::{Keypair, Signer},
-    transaction::Transaction,
+    transaction::{Transaction, TransactionError},
     transport::TransportError,
 };
 use spl_associated_token_account::get_associated_token_address;
-use spl_token::instruction::initialize_mint;
 use spl_token::state::Account as TokenAccount;
 use spl_token::state::Mint;
-use std::iter;
+
+const TEST_PEG_PRICE: u64 = 1_000_000; // $1.00 with 6 decimals
+
+fn assert_vault_error(result: Result<(), BanksClientError>, index: u8, expected: VaultError) {
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(index, InstructionError::Custom(expected as u32))
+    );
+}
 
 fn program_error_to_banks_client_error(e: ProgramError) -> BanksClientError {
     BanksClientError::ClientError(Box::leak(Box::new(e.to_string())))
@@ -114,23 +123,22 @@ async fn test_create_vault() -> Result<(), TransportError> {
     let rent_account = banks_client.get_account(rent_key).await?;
     assert!(rent_account.is_some(), "Rent account not found");
 
-    ////////////////////////
-    /// ///////////////// TODO
-    /// /////////////////////
     // Create token mint
     let mint_tokena_keypair =
-        create_token_mint(banks_client, &payer, recent_blockhash, &payer.pubkey()).await?;
+        create_token_mint(banks_client, payer, recent_blockhash, &payer.pubkey()).await?;
     let mint_tokena_key = mint_tokena_keypair.pubkey();
 
-    // NOTE: SHOULD THE A TOKEN MINT BE A NEW KEYPAIR?
-    let mint_atokena_keypair = Keypair::new(); // Mint account
-    let mint_atokena_key = mint_atokena_keypair.pubkey();
+    // The AToken A mint is a PDA derived from the Token A mint
+    let mint_atokena_key = anti_mint_pda(&program_id, &mint_tokena_key);
 
     // NOTE vault key should be associated token account for token a's mint
     // let vault_keypair = Keypair::new(); // Vault account
     // let vault_key = vault_keypair.pubkey();
     // let vault_key: Pubkey = get_associated_token_address(&payer.pubkey(), &mint_tokena_key);
-    let vault_key: Pubkey = get_associated_token_address(&program_id, &mint_tokena_key); //associated address for programid and tokenamint
+    let vault_key: Pubkey = get_associated_token_address(
+        &vault_authority_pda(&program_id, &mint_tokena_key),
+        &mint_tokena_key,
+    ); //associated address for the vault authority and tokenamint
     let user_token_a_key: Pubkey = get_associated_token_address(&payer.pubkey(), &mint_tokena_key);
 
     // Call the function to create the vault instruction
@@ -141,7 +149,6 @@ async fn test_create_vault() -> Result<(), TransportError> {
         &mint_tokena_key,
         &mint_atokena_key,
         &payer.pubkey(),
-        &state_key,
         &associated_token_program,
         &user_token_a_key,
         // &[&payer.pubkey(), &mint_key, &vault_key, &state_key],
@@ -164,8 +171,7 @@ async fn test_create_vault() -> Result<(), TransportError> {
     transaction.sign(
         // &[&payer, &mint_keypair, &vault_keypair, &state_keypair],
         // &[&payer, &mint_keypair, &vault_keypair],
-        // &[&payer, &mint_tokena_keypair, &mint_atokena_keypair],
-        &[&payer, &mint_atokena_keypair],
+        &[payer],
         recent_blockhash,
     );
 
@@ -188,6 +194,22 @@ async fn test_create_vault() -> Result<(), TransportError> {
     let vault_account = banks_client.get_account(vault_key).await?;
     assert!(vault_account.is_some(), "Vault account not created");
 
+    // CreateVault only writes the vault's own PDA, which stays inactive until indexed
+    assert!(banks_client.get_account(state_key).await?.is_none());
+    let vault = fetch_vault(banks_client, &program_id, &mint_tokena_key).await?;
+    assert_eq!(vault.status, VaultStatus::Uninitialized);
+    let transaction = Transaction::new_signed_with_payer(
+        &[index_vault_instruction(
+            &program_id,
+            &payer.pubkey(),
+            &mint_tokena_key,
+        )],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await?;
+
     // assertions
     let state_account = banks_client.get_account(state_key).await?;
     assert!(state_account.is_some(), "State account not found");
@@ -204,7 +226,23 @@ async fn test_create_vault() -> Result<(), TransportError> {
         "Vault registry should contain exactly one vault"
     );
 
-    let first_vault = &vault_registry.vaults[0];
+    // Only the program's mint authority PDA can mint anti-tokens
+    let anti_mint = Mint::unpack(&mint_account.unwrap().data).unwrap();
+    assert_eq!(
+        anti_mint.mint_authority,
+        COption::Some(mint_authority_pda(&program_id)),
+        "Anti-token mint authority should be the program PDA"
+    );
+    assert_eq!(anti_mint.freeze_authority, COption::None);
+
+    // The registry only indexes the vault; its state lives in the vault's own PDA
+    assert_eq!(
+        vault_registry.vaults[0].vault,
+        vault_state_pda(&program_id, &mint_tokena_key)
+    );
+    let first_vault = &fetch_vault(banks_client, &program_id, &mint_tokena_key).await?;
+    assert_eq!(first_vault.status, VaultStatus::Active);
+    assert_eq!(first_vault.total_deposited, 0);
     assert_eq!(
         first_vault.vault_account, vault_key,
         "First vault account mismatch"
@@ -223,6 +261,16 @@ async fn test_create_vault() -> Result<(), TransportError> {
         "First vault owner mismatch"
     );
 
+    // The vault records the PDA that owns its token account
+    let (vault_authority, vault_authority_bump) = Pubkey::find_program_address(
+        &[VAULT_AUTHORITY_SEED, mint_tokena_key.as_ref()],
+        &program_id,
+    );
+    assert_eq!(first_vault.vault_authority, vault_authority);
+    assert_eq!(first_vault.vault_authority_bump, vault_authority_bump);
+    let vault_token_account = TokenAccount::unpack(&vault_account.unwrap().data).unwrap();
+    assert_eq!(vault_token_account.owner, vault_authority);
+
     println!("Test completed successfully.");
     Ok(())
 }
@@ -266,15 +314,17 @@ async fn test_create_two_vaults() -> Result<(), TransportError> {
 
     // Create the first token mint (Token A)
     let mint_tokena_keypair1 =
-        create_token_mint(banks_client, &payer, recent_blockhash, &payer.pubkey()).await?;
+        create_token_mint(banks_client, payer, recent_blockhash, &payer.pubkey()).await?;
     let mint_tokena_key1 = mint_tokena_keypair1.pubkey();
 
-    // Create a new keypair for AToken A
-    let mint_atokena_keypair1 = Keypair::new();
-    let mint_atokena_key1 = mint_atokena_keypair1.pubkey();
+    // Derive the AToken A mint for the first vault
+    let mint_atokena_key1 = anti_mint_pda(&program_id, &mint_tokena_key1);
 
     // Derive the associated token account for the first vault
-    let vault_key1: Pubkey = get_associated_token_address(&program_id, &mint_tokena_key1);
+    let vault_key1: Pubkey = get_associated_token_address(
+        &vault_authority_pda(&program_id, &mint_tokena_key1),
+        &mint_tokena_key1,
+    );
     let user_token_a: Pubkey = get_associated_token_address(&payer.pubkey(), &mint_tokena_key1);
 
     // Create the first vault instruction
@@ -285,14 +335,18 @@ async fn test_create_two_vaults() -> Result<(), TransportError> {
         &mint_tokena_key1,  // Token A mint
         &mint_atokena_key1, // AToken A mint
         &payer.pubkey(),
-        &state_account_pda,
         &associated_token_program,
         &user_token_a,
     );
 
-    let mut transaction1 =
-        Transaction::new_with_payer(&[create_vault_instruction1], Some(&payer.pubkey()));
-    transaction1.sign(&[&payer, &mint_atokena_keypair1], recent_blockhash);
+    let mut transaction1 = Transaction::new_with_payer(
+        &[
+            create_vault_instruction1,
+            index_vault_instruction(&program_id, &payer.pubkey(), &mint_tokena_key1),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction1.sign(&[payer], recent_blockhash);
     banks_client.process_transaction(transaction1).await?;
 
     // Verify first vault creation
@@ -301,15 +355,17 @@ async fn test_create_two_vaults() -> Result<(), TransportError> {
 
     // Create the second token mint (Token A for the second vault)
     let mint_tokena_keypair2 =
-        create_token_mint(banks_client, &payer, recent_blockhash, &payer.pubkey()).await?;
+        create_token_mint(banks_client, payer, recent_blockhash, &payer.pubkey()).await?;
     let mint_tokena_key2 = mint_tokena_keypair2.pubkey();
 
-    // Create a new keypair for AToken A for the second vault
-    let mint_atokena_keypair2 = Keypair::new();
-    let mint_atokena_key2 = mint_atokena_keypair2.pubkey();
+    // Derive the AToken A mint for the second vault
+    let mint_atokena_key2 = anti_mint_pda(&program_id, &mint_tokena_key2);
 
     // Derive the associated token account for the second vault
-    let vault_key2: Pubkey = get_associated_token_address(&program_id, &mint_tokena_key2);
+    let vault_key2: Pubkey = get_associated_token_address(
+        &vault_authority_pda(&program_id, &mint_tokena_key2),
+        &mint_tokena_key2,
+    );
 
     // Create the second vault instruction
     println!("Creating second vault instruction...");
@@ -319,14 +375,18 @@ async fn test_create_two_vaults() -> Result<(), TransportError> {
         &mint_tokena_key2,  // Token A mint
         &mint_atokena_key2, // AToken A mint
         &payer.pubkey(),
-        &state_account_pda,
         &associated_token_program,
         &user_token_a,
     );
 
-    let mut transaction2 =
-        Transaction::new_with_payer(&[create_vault_instruction2], Some(&payer.pubkey()));
-    transaction2.sign(&[&payer, &mint_atokena_keypair2], recent_blockhash);
+    let mut transaction2 = Transaction::new_with_payer(
+        &[
+            create_vault_instruction2,
+            index_vault_instruction(&program_id, &payer.pubkey(), &mint_tokena_key2),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction2.sign(&[payer], recent_blockhash);
     banks_client.process_transaction(transaction2).await?;
 
     // Verify second vault creation
@@ -352,7 +412,15 @@ async fn test_create_two_vaults() -> Result<(), TransportError> {
     );
 
     // Verify the first vault's details
-    let first_vault = &vault_registry.vaults[0];
+    assert_eq!(
+        vault_registry.vaults[0].vault,
+        vault_state_pda(&program_id, &mint_tokena_key1)
+    );
+    assert_eq!(
+        vault_registry.vaults[1].vault,
+        vault_state_pda(&program_id, &mint_tokena_key2)
+    );
+    let first_vault = &fetch_vault(banks_client, &program_id, &mint_tokena_key1).await?;
     assert_eq!(
         first_vault.vault_account, vault_key1,
         "First vault account mismatch"
@@ -372,7 +440,7 @@ async fn test_create_two_vaults() -> Result<(), TransportError> {
     );
 
     // Verify the second vault's details
-    let second_vault = &vault_registry.vaults[1];
+    let second_vault = &fetch_vault(banks_client, &program_id, &mint_tokena_key2).await?;
     assert_eq!(
         second_vault.vault_account, vault_key2,
         "Second vault account mismatch"
@@ -395,6 +463,122 @@ async fn test_create_two_vaults() -> Result<(), TransportError> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_create_duplicate_vault_rejected() -> Result<(), BanksClientError> {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new(
+        "rugsafe_vaults",
+        program_id,
+        processor!(process_instruction),
+    );
+    program_test.add_program(
+        "spl_token",
+        spl_token::id(),
+        processor!(spl_token::processor::Processor::process),
+    );
+
+    let mut context = program_test.start_with_context().await;
+    let banks_client = &mut context.banks_client;
+    let payer = &context.payer;
+    let recent_blockhash = banks_client.get_latest_blockhash().await?;
+
+    let mint_tokena_key = create_token_mint(banks_client, payer, recent_blockhash, &payer.pubkey())
+        .await?
+        .pubkey();
+    let create_instruction = create_vault_instruction(
+        &program_id,
+        &get_associated_token_address(
+            &vault_authority_pda(&program_id, &mint_tokena_key),
+            &mint_tokena_key,
+        ),
+        &mint_tokena_key,
+        &anti_mint_pda(&program_id, &mint_tokena_key),
+        &payer.pubkey(),
+        &spl_associated_token_account::id(),
+        &get_associated_token_address(&payer.pubkey(), &mint_tokena_key),
+    );
+
+    let index_instruction = index_vault_instruction(&program_id, &payer.pubkey(), &mint_tokena_key);
+    let transaction = Transaction::new_signed_with_payer(
+        &[create_instruction.clone(), index_instruction.clone()],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await?;
+
+    // A second vault for the same Token A mint is rejected
+    let recent_blockhash = banks_client
+        .get_new_latest_blockhash(&recent_blockhash)
+        .await?;
+    let transaction = Transaction::new_signed_with_payer(
+        &[create_instruction],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    assert_vault_error(
+        banks_client.process_transaction(transaction).await,
+        0,
+        VaultError::VaultAlreadyExists,
+    );
+
+    // and so is indexing the same vault twice
+    let transaction = Transaction::new_signed_with_payer(
+        &[index_instruction],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    assert_vault_error(
+        banks_client.process_transaction(transaction).await,
+        0,
+        VaultError::VaultAlreadyExists,
+    );
+
+    let state_account = banks_client
+        .get_account(registry_pda(&program_id))
+        .await?
+        .unwrap();
+    let vault_registry = VaultRegistry::deserialize(&state_account.data).unwrap();
+    assert_eq!(vault_registry.vault_count(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_registry_lookup_by_mint() {
+    let entry = VaultEntry {
+        vault: Pubkey::new_unique(),
+        mint_token_a: Pubkey::new_unique(),
+        mint_a_token_a: Pubkey::new_unique(),
+    };
+    let mint_token_a = entry.mint_token_a;
+    let mint_a_token_a = entry.mint_a_token_a;
+
+    let mut vault_registry = VaultRegistry::new();
+    vault_registry.add_vault(entry).unwrap();
+    assert_eq!(
+        vault_registry
+            .find_by_mint(&mint_token_a)
+            .map(|v| v.mint_a_token_a),
+        Some(mint_a_token_a)
+    );
+    assert!(vault_registry.find_by_mint(&mint_a_token_a).is_none());
+
+    // The underlying mint may not be registered twice
+    let duplicate_mint = VaultEntry {
+        vault: Pubkey::new_unique(),
+        mint_token_a,
+        mint_a_token_a: Pubkey::new_unique(),
+    };
+    assert_eq!(
+        vault_registry.add_vault(duplicate_mint),
+        Err(VaultError::VaultAlreadyExists)
+    );
+    assert_eq!(vault_registry.vault_count(), 1);
+}
+
 #[tokio::test]
 async fn test_deposit() -> Result<(), BanksClientError> {
     println!("Starting test_deposit");
@@ -429,23 +613,24 @@ async fn test_deposit() -> Result<(), BanksClientError> {
 
     // Step 1: Create TokenA mint
     let mint_tokena_keypair =
-        create_token_mint(banks_client, &payer, recent_blockhash, &payer.pubkey()).await?;
+        create_token_mint(banks_client, payer, recent_blockhash, &payer.pubkey()).await?;
     let mint_tokena_key = mint_tokena_keypair.pubkey();
 
-    // NOTE: generate the address for the ATokenA mint
-    let mint_atokena_keypair = Keypair::new(); // Mint account for TokenA
-    let mint_atokena_key = mint_atokena_keypair.pubkey();
+    // NOTE: derive the address for the ATokenA mint
+    let mint_atokena_key = anti_mint_pda(&program_id, &mint_tokena_key);
 
     println!("mint_atokena_key: {}", mint_atokena_key);
 
     // Step 2: Derive the associated token account for the vault
-    let vault_key: Pubkey = get_associated_token_address(&program_id, &mint_tokena_key);
+    let vault_key: Pubkey = get_associated_token_address(
+        &vault_authority_pda(&program_id, &mint_tokena_key),
+        &mint_tokena_key,
+    );
     let user_token_a_account: Pubkey =
         get_associated_token_address(&payer.pubkey(), &mint_tokena_key);
 
     // Step 3: Create the vault using the create_vault functionality
     println!("Creating vault...");
-    let (state_key, _bump_seed) = Pubkey::find_program_address(&[b"vault_registry"], &program_id);
 
     let create_vault_instruction = create_vault_instruction(
         &program_id,
@@ -453,16 +638,17 @@ async fn test_deposit() -> Result<(), BanksClientError> {
         &mint_tokena_key,
         &mint_atokena_key,
         &payer.pubkey(),
-        &state_key,
         &associated_token_program,
         &user_token_a_account,
     );
     let transaction = Transaction::new_signed_with_payer(
-        &[create_vault_instruction],
+        &[
+            create_vault_instruction,
+            index_vault_instruction(&program_id, &payer.pubkey(), &mint_tokena_key),
+        ],
         Some(&payer.pubkey()),
-        // &[&payer, &mint_tokena_keypair, &mint_atokena_keypair],
         // &[&payer, &mint_tokena_keypair],
-        &[&payer, &mint_atokena_keypair],
+        &[payer],
         recent_blockhash,
     );
     banks_client.process_transaction(transaction).await?;
@@ -530,6 +716,7 @@ async fn test_deposit() -> Result<(), BanksClientError> {
     let deposit_amount: u64 = 101;
     let mut deposit_instruction_data = vec![0, 1]; // Instruction ID for "Deposit"
     deposit_instruction_data.extend_from_slice(&deposit_amount.to_le_bytes());
+    deposit_instruction_data.extend_from_slice(&deposit_amount.to_le_bytes()); // At the peg anti-coins mint 1:1
 
     let deposit_instruction = Instruction {
         program_id,
@@ -544,6 +731,8 @@ async fn test_deposit() -> Result<(), BanksClientError> {
             AccountMeta::new_readonly(spl_key, false), // SPL Token Program Account
             AccountMeta::new_readonly(solana_program::system_program::id(), false), // System Program Account
             AccountMeta::new(associated_token_program, false),                      //was true
+            AccountMeta::new_readonly(mint_authority_pda(&program_id), false), // Anti-token mint authority
+            AccountMeta::new(vault_state_pda(&program_id, &mint_tokena_key), false), // Vault state
         ],
         data: deposit_instruction_data,
     };
@@ -588,213 +777,1174 @@ async fn test_deposit() -> Result<(), BanksClientError> {
     Ok(())
 }
 
-fn create_vault_instruction(
-    program_id: &Pubkey,
-    vault_key: &Pubkey,
-    mint_key_token_a: &Pubkey,   // Mint A for the incoming tokens
-    mint_key_a_token_a: &Pubkey, // Mint B for the aTokens
-    payer: &Pubkey,
-    state: &Pubkey,
-    associated_token: &Pubkey,
-    user_token_a: &Pubkey,
-    // signer_keys: &[&Pubkey],
-) -> Instruction {
-    let accounts = vec![
-        AccountMeta::new(*payer, true),
-        AccountMeta::new(*mint_key_token_a, false),
-        AccountMeta::new(*mint_key_a_token_a, true),
-        AccountMeta::new(*vault_key, false),
-        AccountMeta::new_readonly(sysvar::rent::id(), false),
-        AccountMeta::new_readonly(spl_token::id(), false),
-        AccountMeta::new_readonly(solana_program::system_program::id(), false),
-        AccountMeta::new(*state, false),            //was true
-        AccountMeta::new(*associated_token, false), //was true
-        AccountMeta::new(*user_token_a, false),
-        AccountMeta::new(*program_id, false),
-    ];
-    println!("Create Vault program_id: {:?}", program_id);
-
-    Instruction {
-        program_id: *program_id,
-        accounts,
-        data: vec![0, 0], // Add any additional data if needed
-    }
-}
-
-async fn create_token_mint(
-    banks_client: &mut BanksClient,
-    payer: &Keypair,
-    recent_blockhash: Hash,
-    mint_authority: &Pubkey,
-) -> Result<Keypair, BanksClientError> {
-    let mint_keypair = Keypair::new();
-    let mint_rent = banks_client
-        .get_rent()
-        .await?
-        .minimum_balance(spl_token::state::Mint::LEN);
-
-    let create_mint_account_ix = system_instruction::create_account(
-        &payer.pubkey(),
-        &mint_keypair.pubkey(),
-        mint_rent,
-        spl_token::state::Mint::LEN as u64,
-        &spl_token::id(),
-    );
-
-    let initialize_mint_ix = spl_token::instruction::initialize_mint(
-        &spl_token::id(),
-        &mint_keypair.pubkey(),
-        mint_authority,
-        None,
-        0,
-    )
-    .map_err(|e| {
-        BanksClientError::ClientError(Box::leak(Box::new(format!(
-            "Failed to create initialize_mint instruction: {}",
-            e
-        ))))
-    })?;
-
-    let transaction = Transaction::new_signed_with_payer(
-        &[create_mint_account_ix, initialize_mint_ix],
-        Some(&payer.pubkey()),
-        &[payer, &mint_keypair],
-        recent_blockhash,
-    );
-
-    banks_client.process_transaction(transaction).await?;
-
-    Ok(mint_keypair)
-}
-
-// Log serialized data at the bit level
-fn log_bits(bytes: &[u8]) {
-    for (i, byte) in bytes.iter().enumerate() {
-        println!("Byte {}: {:#010b}", i, byte);
-    }
-}
-fn manual_deserialize(state_data: &[u8]) -> Vec<Vault> {
-    let mut rest = state_data;
-
-    // First 4 bytes: length of the vaults vector
-    let (vaults_len_bytes, next) = rest.split_at(4);
-    let vaults_len = u32::from_le_bytes(vaults_len_bytes.try_into().unwrap());
-    println!("Vaults length (u32): {:?}", vaults_len);
-    rest = next;
-
-    // Each Vault is 160 bytes (5 Pubkeys of 32 bytes each)
-    let mut vaults = Vec::new();
-    for i in 0..vaults_len {
-        let (vault_bytes, next) = rest.split_at(Vault::LEN);
-        rest = next;
-
-        // Refactoring the Vault deserialization into its own function
-        let vault = deserialize_vault(vault_bytes);
-
-        println!("Vault {}: {:?}", i, vault);
-        vaults.push(vault);
-    }
-
-    println!("Manually deserialized vaults: {:?}", vaults);
-
-    // Return the deserialized vaults
-    vaults
-}
-
-// Refactored vault deserialization function
-// Refactored vault deserialization function
-fn deserialize_vault(vault_bytes: &[u8]) -> Vault {
-    let (vault_account_bytes, vault_bytes) = vault_bytes.split_at(32);
-    let vault_account = Pubkey::new_from_array(vault_account_bytes.try_into().unwrap());
-
-    let (mint_account_bytes, vault_bytes) = vault_bytes.split_at(32);
-    let mint_account = Pubkey::new_from_array(mint_account_bytes.try_into().unwrap());
-
-    let (mint_atoken_account_bytes, vault_bytes) = vault_bytes.split_at(32);
-    let mint_atoken_account = Pubkey::new_from_array(mint_atoken_account_bytes.try_into().unwrap());
-
-    let (owner_bytes, _vault_bytes) = vault_bytes.split_at(32);
-    let owner = Pubkey::new_from_array(owner_bytes.try_into().unwrap());
-
-    Vault {
-        vault_account: vault_account,
-        mint_token_a: mint_account,
-        mint_a_token_a: mint_atoken_account, // Include the new field
-        owner: owner,
-    }
-}
 #[tokio::test]
-async fn test_fetch_vault_from_registry() -> Result<(), TransportError> {
-    // Setup keys and program
+async fn test_withdraw() -> Result<(), BanksClientError> {
     let program_id = Pubkey::new_unique();
-    let mint_tokena_keypair = Keypair::new(); // Mint account for token A
-    let mint_tokena_key = mint_tokena_keypair.pubkey();
-    let mint_atokena_keypair = Keypair::new(); // Mint account for aToken A
-    let mint_atokena_key = mint_atokena_keypair.pubkey();
-
-    let rent_key = solana_program::sysvar::rent::ID;
-    let spl_key = spl_token::id();
-    let associated_token_program = spl_associated_token_account::id();
-
-    let (state_key, _bump_seed) = Pubkey::find_program_address(&[b"vault_registry"], &program_id);
-
     let mut program_test = ProgramTest::new(
         "rugsafe_vaults",
         program_id,
         processor!(process_instruction),
     );
-
-    // Add SPL Token program
     program_test.add_program(
         "spl_token",
-        spl_key,
+        spl_token::id(),
         processor!(spl_token::processor::Processor::process),
     );
 
-    // Start the context
     let mut context = program_test.start_with_context().await;
     let banks_client = &mut context.banks_client;
     let payer = &context.payer;
     let recent_blockhash = banks_client.get_latest_blockhash().await?;
 
-    // Create token mint for token A
+    // Create the vault for a fresh Token A mint
     let mint_tokena_keypair =
-        create_token_mint(banks_client, &payer, recent_blockhash, &payer.pubkey()).await?;
+        create_token_mint(banks_client, payer, recent_blockhash, &payer.pubkey()).await?;
     let mint_tokena_key = mint_tokena_keypair.pubkey();
-
-    // Vault key should be associated token account for token A's mint
-    let vault_key: Pubkey = get_associated_token_address(&program_id, &mint_tokena_key);
-    let user_token_a_account: Pubkey =
-        get_associated_token_address(&payer.pubkey(), &mint_tokena_key);
-
-    // Call the function to create the vault instruction
-    println!("Creating vault instruction...");
-    let create_vault_instruction = create_vault_instruction(
-        &program_id,
-        &vault_key,
+    let mint_atokena_key = anti_mint_pda(&program_id, &mint_tokena_key);
+    let vault_key = get_associated_token_address(
+        &vault_authority_pda(&program_id, &mint_tokena_key),
         &mint_tokena_key,
-        &mint_atokena_key,
-        &payer.pubkey(),
-        &state_key,
-        &associated_token_program,
-        &user_token_a_account,
     );
 
-    let mut transaction =
-        Transaction::new_with_payer(&[create_vault_instruction], Some(&payer.pubkey()));
-
-    println!("Signing the transaction...");
-    transaction.sign(
-        // &[&payer, &mint_tokena_keypair, &mint_atokena_keypair],
-        &[&payer, &mint_atokena_keypair],
+    let transaction = Transaction::new_signed_with_payer(
+        &[
+            create_vault_instruction(
+                &program_id,
+                &vault_key,
+                &mint_tokena_key,
+                &mint_atokena_key,
+                &payer.pubkey(),
+                &spl_associated_token_account::id(),
+                &get_associated_token_address(&payer.pubkey(), &mint_tokena_key),
+            ),
+            index_vault_instruction(&program_id, &payer.pubkey(), &mint_tokena_key),
+        ],
+        Some(&payer.pubkey()),
+        &[payer],
         recent_blockhash,
     );
-
-    // Process CreateVault transaction
-    println!("Processing CreateVault transaction...");
     banks_client.process_transaction(transaction).await?;
 
-    // Verify vault creation
+    // Fund the user with Token A and deposit all of it
+    let user_token_a_key = create_token_account(
+        banks_client,
+        payer,
+        recent_blockhash,
+        &mint_tokena_key,
+        &payer.pubkey(),
+    )
+    .await?;
+    let user_atoken_a_key = get_associated_token_address(&payer.pubkey(), &mint_atokena_key);
+
+    let mint_to_user_ix = spl_token::instruction::mint_to(
+        &spl_token::id(),
+        &mint_tokena_key,
+        &user_token_a_key,
+        &payer.pubkey(),
+        &[],
+        101,
+    )
+    .map_err(program_error_to_banks_client_error)?;
+    let transaction = Transaction::new_signed_with_payer(
+        &[
+            mint_to_user_ix,
+            deposit_instruction(
+                &program_id,
+                &payer.pubkey(),
+                &mint_tokena_key,
+                &mint_atokena_key,
+                &vault_key,
+                &user_token_a_key,
+                &user_atoken_a_key,
+                101,
+                101,
+            ),
+        ],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await?;
+
+    // Withdraw part of the deposit
+    let transaction = Transaction::new_signed_with_payer(
+        &[withdraw_instruction(
+            &program_id,
+            &payer.pubkey(),
+            &mint_tokena_key,
+            &mint_atokena_key,
+            &vault_key,
+            &user_token_a_key,
+            &user_atoken_a_key,
+            60,
+        )],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await?;
+
+    assert_eq!(token_balance(banks_client, &user_token_a_key).await?, 60);
+    assert_eq!(token_balance(banks_client, &vault_key).await?, 41);
+    assert_eq!(token_balance(banks_client, &user_atoken_a_key).await?, 41);
+
+    let anti_mint = banks_client.get_account(mint_atokena_key).await?.unwrap();
+    let anti_mint = Mint::unpack(&anti_mint.data).map_err(program_error_to_banks_client_error)?;
+    assert_eq!(
+        anti_mint.supply, 41,
+        "Burned anti-tokens should leave supply"
+    );
+
+    let vault = fetch_vault(banks_client, &program_id, &mint_tokena_key).await?;
+    assert_eq!(vault.total_deposited, 101);
+    assert_eq!(vault.total_withdrawn, 60);
+
+    // Withdrawing more than the remaining anti-token balance must fail
+    let transaction = Transaction::new_signed_with_payer(
+        &[withdraw_instruction(
+            &program_id,
+            &payer.pubkey(),
+            &mint_tokena_key,
+            &mint_atokena_key,
+            &vault_key,
+            &user_token_a_key,
+            &user_atoken_a_key,
+            42,
+        )],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    assert_vault_error(
+        banks_client.process_transaction(transaction).await,
+        0,
+        VaultError::InsufficientBalance,
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_deposit_by_non_creator() -> Result<(), BanksClientError> {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new(
+        "rugsafe_vaults",
+        program_id,
+        processor!(process_instruction),
+    );
+    program_test.add_program(
+        "spl_token",
+        spl_token::id(),
+        processor!(spl_token::processor::Processor::process),
+    );
+
+    let mut context = program_test.start_with_context().await;
+    let banks_client = &mut context.banks_client;
+    let payer = &context.payer;
+    let recent_blockhash = banks_client.get_latest_blockhash().await?;
+
+    // The payer creates the vault
+    let mint_tokena_keypair =
+        create_token_mint(banks_client, payer, recent_blockhash, &payer.pubkey()).await?;
+    let mint_tokena_key = mint_tokena_keypair.pubkey();
+    let mint_atokena_key = anti_mint_pda(&program_id, &mint_tokena_key);
+    let vault_key = get_associated_token_address(
+        &vault_authority_pda(&program_id, &mint_tokena_key),
+        &mint_tokena_key,
+    );
+
+    let transaction = Transaction::new_signed_with_payer(
+        &[
+            create_vault_instruction(
+                &program_id,
+                &vault_key,
+                &mint_tokena_key,
+                &mint_atokena_key,
+                &payer.pubkey(),
+                &spl_associated_token_account::id(),
+                &get_associated_token_address(&payer.pubkey(), &mint_tokena_key),
+            ),
+            index_vault_instruction(&program_id, &payer.pubkey(), &mint_tokena_key),
+        ],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await?;
+
+    // A different user holding Token A deposits into it
+    let depositor = Keypair::new();
+    let depositor_token_a_key = create_token_account(
+        banks_client,
+        payer,
+        recent_blockhash,
+        &mint_tokena_key,
+        &depositor.pubkey(),
+    )
+    .await?;
+    let depositor_atoken_a_key =
+        get_associated_token_address(&depositor.pubkey(), &mint_atokena_key);
+
+    let transaction = Transaction::new_signed_with_payer(
+        &[
+            system_instruction::transfer(&payer.pubkey(), &depositor.pubkey(), 1_000_000_000),
+            spl_token::instruction::mint_to(
+                &spl_token::id(),
+                &mint_tokena_key,
+                &depositor_token_a_key,
+                &payer.pubkey(),
+                &[],
+                50,
+            )
+            .map_err(program_error_to_banks_client_error)?,
+        ],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await?;
+
+    let transaction = Transaction::new_signed_with_payer(
+        &[deposit_instruction(
+            &program_id,
+            &depositor.pubkey(),
+            &mint_tokena_key,
+            &mint_atokena_key,
+            &vault_key,
+            &depositor_token_a_key,
+            &depositor_atoken_a_key,
+            50,
+            50,
+        )],
+        Some(&depositor.pubkey()),
+        &[&depositor],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await?;
+
+    assert_eq!(
+        token_balance(banks_client, &depositor_token_a_key).await?,
+        0
+    );
+    assert_eq!(token_balance(banks_client, &vault_key).await?, 50);
+    assert_eq!(
+        token_balance(banks_client, &depositor_atoken_a_key).await?,
+        50
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_deposit_requires_indexed_vault() -> Result<(), BanksClientError> {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new(
+        "rugsafe_vaults",
+        program_id,
+        processor!(process_instruction),
+    );
+    program_test.add_program(
+        "spl_token",
+        spl_token::id(),
+        processor!(spl_token::processor::Processor::process),
+    );
+
+    let mut context = program_test.start_with_context().await;
+    let banks_client = &mut context.banks_client;
+    let payer = &context.payer;
+    let recent_blockhash = banks_client.get_latest_blockhash().await?;
+
+    let mint_tokena_keypair =
+        create_token_mint(banks_client, payer, recent_blockhash, &payer.pubkey()).await?;
+    let mint_tokena_key = mint_tokena_keypair.pubkey();
+    let mint_atokena_key = anti_mint_pda(&program_id, &mint_tokena_key);
+    let vault_key = get_associated_token_address(
+        &vault_authority_pda(&program_id, &mint_tokena_key),
+        &mint_tokena_key,
+    );
+
+    // Create the vault without indexing it
+    let transaction = Transaction::new_signed_with_payer(
+        &[create_vault_instruction(
+            &program_id,
+            &vault_key,
+            &mint_tokena_key,
+            &mint_atokena_key,
+            &payer.pubkey(),
+            &spl_associated_token_account::id(),
+            &get_associated_token_address(&payer.pubkey(), &mint_tokena_key),
+        )],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await?;
+
+    let user_token_a_key = create_token_account(
+        banks_client,
+        payer,
+        recent_blockhash,
+        &mint_tokena_key,
+        &payer.pubkey(),
+    )
+    .await?;
+    let user_atoken_a_key = get_associated_token_address(&payer.pubkey(), &mint_atokena_key);
+    let transaction = Transaction::new_signed_with_payer(
+        &[spl_token::instruction::mint_to(
+            &spl_token::id(),
+            &mint_tokena_key,
+            &user_token_a_key,
+            &payer.pubkey(),
+            &[],
+            50,
+        )
+        .map_err(program_error_to_banks_client_error)?],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await?;
+
+    let deposit = deposit_instruction(
+        &program_id,
+        &payer.pubkey(),
+        &mint_tokena_key,
+        &mint_atokena_key,
+        &vault_key,
+        &user_token_a_key,
+        &user_atoken_a_key,
+        50,
+        50,
+    );
+
+    // An unindexed vault does not accept deposits
+    let transaction = Transaction::new_signed_with_payer(
+        std::slice::from_ref(&deposit),
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    assert_vault_error(
+        banks_client.process_transaction(transaction).await,
+        0,
+        VaultError::VaultNotActive,
+    );
+
+    // Indexing activates it
+    let transaction = Transaction::new_signed_with_payer(
+        &[
+            index_vault_instruction(&program_id, &payer.pubkey(), &mint_tokena_key),
+            deposit,
+        ],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await?;
+
+    let vault = fetch_vault(banks_client, &program_id, &mint_tokena_key).await?;
+    assert_eq!(vault.status, VaultStatus::Active);
+    assert_eq!(token_balance(banks_client, &vault_key).await?, 50);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_deposit_below_peg() -> Result<(), BanksClientError> {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new(
+        "rugsafe_vaults",
+        program_id,
+        processor!(process_instruction),
+    );
+    program_test.add_program(
+        "spl_token",
+        spl_token::id(),
+        processor!(spl_token::processor::Processor::process),
+    );
+    let admin = Keypair::new();
+    add_upgrade_authority(&mut program_test, &program_id, &admin.pubkey());
+
+    let mut context = program_test.start_with_context().await;
+    let banks_client = &mut context.banks_client;
+    let payer = &context.payer;
+    let recent_blockhash = banks_client.get_latest_blockhash().await?;
+
+    let mint_tokena_keypair =
+        create_token_mint(banks_client, payer, recent_blockhash, &payer.pubkey()).await?;
+    let mint_tokena_key = mint_tokena_keypair.pubkey();
+    let mint_atokena_key = anti_mint_pda(&program_id, &mint_tokena_key);
+    let vault_key = get_associated_token_address(
+        &vault_authority_pda(&program_id, &mint_tokena_key),
+        &mint_tokena_key,
+    );
+
+    let transaction = Transaction::new_signed_with_payer(
+        &[
+            create_vault_instruction(
+                &program_id,
+                &vault_key,
+                &mint_tokena_key,
+                &mint_atokena_key,
+                &payer.pubkey(),
+                &spl_associated_token_account::id(),
+                &get_associated_token_address(&payer.pubkey(), &mint_tokena_key),
+            ),
+            index_vault_instruction(&program_id, &payer.pubkey(), &mint_tokena_key),
+        ],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await?;
+
+    // Not even the vault's creator may move the reference price, only the program admin
+    let transaction = Transaction::new_signed_with_payer(
+        &[set_reference_price_instruction(
+            &program_id,
+            &payer.pubkey(),
+            &mint_tokena_key,
+            TEST_PEG_PRICE / 2,
+        )],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    assert_vault_error(
+        banks_client.process_transaction(transaction).await,
+        0,
+        VaultError::Unauthorized,
+    );
+
+    // Token A falls to half its peg
+    let transaction = Transaction::new_signed_with_payer(
+        &[set_reference_price_instruction(
+            &program_id,
+            &admin.pubkey(),
+            &mint_tokena_key,
+            TEST_PEG_PRICE / 2,
+        )],
+        Some(&payer.pubkey()),
+        &[payer, &admin],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await?;
+
+    let vault = fetch_vault(banks_client, &program_id, &mint_tokena_key).await?;
+    assert_eq!(vault.peg_price, TEST_PEG_PRICE);
+    assert_eq!(vault.reference_price, TEST_PEG_PRICE / 2);
+
+    let user_token_a_key = create_token_account(
+        banks_client,
+        payer,
+        recent_blockhash,
+        &mint_tokena_key,
+        &payer.pubkey(),
+    )
+    .await?;
+    let user_atoken_a_key = get_associated_token_address(&payer.pubkey(), &mint_atokena_key);
+    let transaction = Transaction::new_signed_with_payer(
+        &[spl_token::instruction::mint_to(
+            &spl_token::id(),
+            &mint_tokena_key,
+            &user_token_a_key,
+            &payer.pubkey(),
+            &[],
+            1_000_000,
+        )
+        .map_err(program_error_to_banks_client_error)?],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await?;
+
+    // 1_000_000 * 0.5 / (1 - ln 0.5) = 295_308 anti-coins, so asking for one more must fail
+    let transaction = Transaction::new_signed_with_payer(
+        &[deposit_instruction(
+            &program_id,
+            &payer.pubkey(),
+            &mint_tokena_key,
+            &mint_atokena_key,
+            &vault_key,
+            &user_token_a_key,
+            &user_atoken_a_key,
+            1_000_000,
+            295_309,
+        )],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    assert_vault_error(
+        banks_client.process_transaction(transaction).await,
+        0,
+        VaultError::SlippageExceeded,
+    );
+
+    let transaction = Transaction::new_signed_with_payer(
+        &[deposit_instruction(
+            &program_id,
+            &payer.pubkey(),
+            &mint_tokena_key,
+            &mint_atokena_key,
+            &vault_key,
+            &user_token_a_key,
+            &user_atoken_a_key,
+            1_000_000,
+            295_308,
+        )],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await?;
+
+    assert_eq!(token_balance(banks_client, &vault_key).await?, 1_000_000);
+    assert_eq!(
+        token_balance(banks_client, &user_atoken_a_key).await?,
+        295_308
+    );
+
+    // As the only holder, burning every anti-coin redeems the whole vault
+    let transaction = Transaction::new_signed_with_payer(
+        &[withdraw_instruction(
+            &program_id,
+            &payer.pubkey(),
+            &mint_tokena_key,
+            &mint_atokena_key,
+            &vault_key,
+            &user_token_a_key,
+            &user_atoken_a_key,
+            295_308,
+        )],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await?;
+
+    assert_eq!(
+        token_balance(banks_client, &user_token_a_key).await?,
+        1_000_000
+    );
+    assert_eq!(token_balance(banks_client, &vault_key).await?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_withdraw_rejects_mismatched_mint() -> Result<(), BanksClientError> {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new(
+        "rugsafe_vaults",
+        program_id,
+        processor!(process_instruction),
+    );
+    program_test.add_program(
+        "spl_token",
+        spl_token::id(),
+        processor!(spl_token::processor::Processor::process),
+    );
+
+    let mut context = program_test.start_with_context().await;
+    let banks_client = &mut context.banks_client;
+    let payer = &context.payer;
+    let recent_blockhash = banks_client.get_latest_blockhash().await?;
+
+    let mint_tokena_keypair =
+        create_token_mint(banks_client, payer, recent_blockhash, &payer.pubkey()).await?;
+    let mint_tokena_key = mint_tokena_keypair.pubkey();
+    let mint_atokena_key = anti_mint_pda(&program_id, &mint_tokena_key);
+    let vault_key = get_associated_token_address(
+        &vault_authority_pda(&program_id, &mint_tokena_key),
+        &mint_tokena_key,
+    );
+
+    let transaction = Transaction::new_signed_with_payer(
+        &[
+            create_vault_instruction(
+                &program_id,
+                &vault_key,
+                &mint_tokena_key,
+                &mint_atokena_key,
+                &payer.pubkey(),
+                &spl_associated_token_account::id(),
+                &get_associated_token_address(&payer.pubkey(), &mint_tokena_key),
+            ),
+            index_vault_instruction(&program_id, &payer.pubkey(), &mint_tokena_key),
+        ],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await?;
+
+    // A token the payer controls, but which is not the vault's anti-token mint
+    let fake_anti_mint =
+        create_token_mint(banks_client, payer, recent_blockhash, &payer.pubkey()).await?;
+    let user_token_a_key = create_token_account(
+        banks_client,
+        payer,
+        recent_blockhash,
+        &mint_tokena_key,
+        &payer.pubkey(),
+    )
+    .await?;
+    let user_fake_anti_key = create_token_account(
+        banks_client,
+        payer,
+        recent_blockhash,
+        &fake_anti_mint.pubkey(),
+        &payer.pubkey(),
+    )
+    .await?;
+
+    let transaction = Transaction::new_signed_with_payer(
+        &[
+            spl_token::instruction::mint_to(
+                &spl_token::id(),
+                &fake_anti_mint.pubkey(),
+                &user_fake_anti_key,
+                &payer.pubkey(),
+                &[],
+                10,
+            )
+            .map_err(program_error_to_banks_client_error)?,
+            withdraw_instruction(
+                &program_id,
+                &payer.pubkey(),
+                &mint_tokena_key,
+                &fake_anti_mint.pubkey(),
+                &vault_key,
+                &user_token_a_key,
+                &user_fake_anti_key,
+                10,
+            ),
+        ],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_blockhash,
+    );
+    assert_vault_error(
+        banks_client.process_transaction(transaction).await,
+        1,
+        VaultError::MintMismatch,
+    );
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn deposit_instruction(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    mint_key_token_a: &Pubkey,
+    mint_key_a_token_a: &Pubkey,
+    vault_key: &Pubkey,
+    user_token_a: &Pubkey,
+    user_a_token_a: &Pubkey,
+    amount: u64,
+    min_anti_amount: u64,
+) -> Instruction {
+    let mut data = vec![0, 1]; // Instruction ID for "Deposit"
+    data.extend_from_slice(&amount.to_le_bytes());
+    data.extend_from_slice(&min_anti_amount.to_le_bytes());
+
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*payer, true),
+            AccountMeta::new(*mint_key_token_a, false),
+            AccountMeta::new(*mint_key_a_token_a, false),
+            AccountMeta::new(*vault_key, false),
+            AccountMeta::new(*user_token_a, false),
+            AccountMeta::new(*user_a_token_a, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(solana_program::system_program::id(), false),
+            AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+            AccountMeta::new_readonly(mint_authority_pda(program_id), false),
+            AccountMeta::new(vault_state_pda(program_id, mint_key_token_a), false),
+        ],
+        data,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn withdraw_instruction(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    mint_key_token_a: &Pubkey,
+    mint_key_a_token_a: &Pubkey,
+    vault_key: &Pubkey,
+    user_token_a: &Pubkey,
+    user_a_token_a: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    let mut data = vec![0, 2]; // Instruction ID for "Withdraw"
+    data.extend_from_slice(&amount.to_le_bytes());
+
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*user, true),
+            AccountMeta::new_readonly(*mint_key_token_a, false),
+            AccountMeta::new(*mint_key_a_token_a, false),
+            AccountMeta::new(*vault_key, false),
+            AccountMeta::new_readonly(vault_authority_pda(program_id, mint_key_token_a), false),
+            AccountMeta::new(*user_token_a, false),
+            AccountMeta::new(*user_a_token_a, false),
+            AccountMeta::new(vault_state_pda(program_id, mint_key_token_a), false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ],
+        data,
+    }
+}
+
+async fn create_token_account(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    recent_blockhash: Hash,
+    mint: &Pubkey,
+    owner: &Pubkey,
+) -> Result<Pubkey, BanksClientError> {
+    let account_keypair = Keypair::new();
+    let rent = banks_client.get_rent().await?;
+
+    let transaction = Transaction::new_signed_with_payer(
+        &[
+            system_instruction::create_account(
+                &payer.pubkey(),
+                &account_keypair.pubkey(),
+                rent.minimum_balance(TokenAccount::LEN),
+                TokenAccount::LEN as u64,
+                &spl_token::id(),
+            ),
+            spl_token::instruction::initialize_account(
+                &spl_token::id(),
+                &account_keypair.pubkey(),
+                mint,
+                owner,
+            )
+            .map_err(program_error_to_banks_client_error)?,
+        ],
+        Some(&payer.pubkey()),
+        &[payer, &account_keypair],
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await?;
+
+    Ok(account_keypair.pubkey())
+}
+
+async fn token_balance(
+    banks_client: &mut BanksClient,
+    token_account: &Pubkey,
+) -> Result<u64, BanksClientError> {
+    let account = banks_client
+        .get_account(*token_account)
+        .await?
+        .expect("token account not found");
+    Ok(TokenAccount::unpack(&account.data)
+        .map_err(program_error_to_banks_client_error)?
+        .amount)
+}
+
+fn create_vault_instruction(
+    program_id: &Pubkey,
+    vault_key: &Pubkey,
+    mint_key_token_a: &Pubkey,   // Mint A for the incoming tokens
+    mint_key_a_token_a: &Pubkey, // Mint B for the aTokens
+    payer: &Pubkey,
+    associated_token: &Pubkey,
+    user_token_a: &Pubkey,
+    // signer_keys: &[&Pubkey],
+) -> Instruction {
+    let accounts = vec![
+        AccountMeta::new(*payer, true),
+        AccountMeta::new(*mint_key_token_a, false),
+        AccountMeta::new(*mint_key_a_token_a, false),
+        AccountMeta::new(*vault_key, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(solana_program::system_program::id(), false),
+        AccountMeta::new(*associated_token, false), //was true
+        AccountMeta::new(*user_token_a, false),
+        AccountMeta::new_readonly(vault_authority_pda(program_id, mint_key_token_a), false),
+        AccountMeta::new_readonly(mint_authority_pda(program_id), false),
+        AccountMeta::new(vault_state_pda(program_id, mint_key_token_a), false),
+    ];
+    println!("Create Vault program_id: {:?}", program_id);
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: [vec![0, 0], TEST_PEG_PRICE.to_le_bytes().to_vec()].concat(),
+    }
+}
+
+fn index_vault_instruction(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    mint_key_token_a: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(*payer, true),
+            AccountMeta::new(vault_state_pda(program_id, mint_key_token_a), false),
+            AccountMeta::new(registry_pda(program_id), false),
+            AccountMeta::new_readonly(solana_program::system_program::id(), false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data: vec![0, 6], // Instruction ID for "IndexVault"
+    }
+}
+
+fn set_reference_price_instruction(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    mint_key_token_a: &Pubkey,
+    price: u64,
+) -> Instruction {
+    let mut data = vec![0, 5]; // Instruction ID for "SetReferencePrice"
+    data.extend_from_slice(&price.to_le_bytes());
+
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*admin, true),
+            AccountMeta::new(vault_state_pda(program_id, mint_key_token_a), false),
+            AccountMeta::new_readonly(program_data_pda(program_id), false),
+        ],
+        data,
+    }
+}
+
+fn program_data_pda(program_id: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id()).0
+}
+
+// Gives the test program the ProgramData account an upgradeable deploy would, naming `admin` as upgrade authority
+fn add_upgrade_authority(program_test: &mut ProgramTest, program_id: &Pubkey, admin: &Pubkey) {
+    let mut data = 3u32.to_le_bytes().to_vec(); // UpgradeableLoaderState::ProgramData
+    data.extend_from_slice(&0u64.to_le_bytes()); // Deployment slot
+    data.push(1); // Some(upgrade authority)
+    data.extend_from_slice(admin.as_ref());
+    program_test.add_account(
+        program_data_pda(program_id),
+        Account {
+            lamports: 1_000_000_000,
+            data,
+            owner: bpf_loader_upgradeable::id(),
+            executable: false,
+            rent_epoch: 0,
+        },
+    );
+}
+
+fn registry_pda(program_id: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"vault_registry"], program_id).0
+}
+
+fn vault_state_pda(program_id: &Pubkey, mint_token_a: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[VAULT_SEED, mint_token_a.as_ref()], program_id).0
+}
+
+async fn fetch_vault(
+    banks_client: &mut BanksClient,
+    program_id: &Pubkey,
+    mint_token_a: &Pubkey,
+) -> Result<Vault, BanksClientError> {
+    let account = banks_client
+        .get_account(vault_state_pda(program_id, mint_token_a))
+        .await?
+        .expect("vault state account not found");
+    assert_eq!(account.owner, *program_id);
+    Ok(Vault::deserialize(&account.data).expect("Failed to deserialize Vault"))
+}
+
+fn vault_authority_pda(program_id: &Pubkey, mint_token_a: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[VAULT_AUTHORITY_SEED, mint_token_a.as_ref()], program_id).0
+}
+
+fn mint_authority_pda(program_id: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[MINT_AUTHORITY_SEED], program_id).0
+}
+
+fn anti_mint_pda(program_id: &Pubkey, mint_token_a: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[ANTI_MINT_SEED, mint_token_a.as_ref()], program_id).0
+}
+
+async fn create_token_mint(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    recent_blockhash: Hash,
+    mint_authority: &Pubkey,
+) -> Result<Keypair, BanksClientError> {
+    let mint_keypair = Keypair::new();
+    let mint_rent = banks_client
+        .get_rent()
+        .await?
+        .minimum_balance(spl_token::state::Mint::LEN);
+
+    let create_mint_account_ix = system_instruction::create_account(
+        &payer.pubkey(),
+        &mint_keypair.pubkey(),
+        mint_rent,
+        spl_token::state::Mint::LEN as u64,
+        &spl_token::id(),
+    );
+
+    let initialize_mint_ix = spl_token::instruction::initialize_mint(
+        &spl_token::id(),
+        &mint_keypair.pubkey(),
+        mint_authority,
+        None,
+        0,
+    )
+    .map_err(|e| {
+        BanksClientError::ClientError(Box::leak(Box::new(format!(
+            "Failed to create initialize_mint instruction: {}",
+            e
+        ))))
+    })?;
+
+    let transaction = Transaction::new_signed_with_payer(
+        &[create_mint_account_ix, initialize_mint_ix],
+        Some(&payer.pubkey()),
+        &[payer, &mint_keypair],
+        recent_blockhash,
+    );
+
+    banks_client.process_transaction(transaction).await?;
+
+    Ok(mint_keypair)
+}
+
+// Log serialized data at the bit level
+#[allow(dead_code)]
+fn log_bits(bytes: &[u8]) {
+    for (i, byte) in bytes.iter().enumerate() {
+        println!("Byte {}: {:#010b}", i, byte);
+    }
+}
+fn manual_deserialize(state_data: &[u8]) -> Vec<VaultEntry> {
+    let mut rest = state_data;
+
+    // First 8 bytes: number of vaults, next 8 bytes: capacity
+    let (vaults_len_bytes, next) = rest.split_at(8);
+    let vaults_len = u64::from_le_bytes(vaults_len_bytes.try_into().unwrap());
+    println!("Vaults length (u64): {:?}", vaults_len);
+    let (capacity_bytes, next) = next.split_at(8);
+    let capacity = u64::from_le_bytes(capacity_bytes.try_into().unwrap());
+    println!("Capacity (u64): {:?}", capacity);
+    rest = next;
+
+    // Each entry is VaultEntry::LEN bytes (the vault state PDA and both mints)
+    let mut entries = Vec::new();
+    for i in 0..vaults_len {
+        let (entry_bytes, next) = rest.split_at(VaultEntry::LEN);
+        rest = next;
+
+        let entry = deserialize_entry(entry_bytes);
+
+        println!("Vault entry {}: {:?}", i, entry);
+        entries.push(entry);
+    }
+
+    println!("Manually deserialized vault entries: {:?}", entries);
+
+    entries
+}
+
+fn deserialize_entry(entry_bytes: &[u8]) -> VaultEntry {
+    let (vault_bytes, entry_bytes) = entry_bytes.split_at(32);
+    let vault = Pubkey::new_from_array(vault_bytes.try_into().unwrap());
+
+    let (mint_account_bytes, entry_bytes) = entry_bytes.split_at(32);
+    let mint_account = Pubkey::new_from_array(mint_account_bytes.try_into().unwrap());
+
+    let (mint_atoken_account_bytes, _) = entry_bytes.split_at(32);
+    let mint_atoken_account = Pubkey::new_from_array(mint_atoken_account_bytes.try_into().unwrap());
+
+    VaultEntry {
+        vault,
+        mint_token_a: mint_account,
+        mint_a_token_a: mint_atoken_account,
+    }
+}
+
+#[tokio::test]
+async fn test_registry_grows_past_initial_capacity() -> Result<(), BanksClientError> {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new(
+        "rugsafe_vaults",
+        program_id,
+        processor!(process_instruction),
+    );
+    program_test.add_program(
+        "spl_token",
+        spl_token::id(),
+        processor!(spl_token::processor::Processor::process),
+    );
+
+    let mut context = program_test.start_with_context().await;
+    let banks_client = &mut context.banks_client;
+    let payer = &context.payer;
+    let recent_blockhash = banks_client.get_latest_blockhash().await?;
+    let rent = banks_client.get_rent().await?;
+    let (state_key, _) = Pubkey::find_program_address(&[b"vault_registry"], &program_id);
+
+    // Capacity doubles each time the registry fills: 10 -> 20 -> 40 -> 80
+    let mut mints = Vec::new();
+    for count in 1..=41 {
+        let mint_tokena_key =
+            create_token_mint(banks_client, payer, recent_blockhash, &payer.pubkey())
+                .await?
+                .pubkey();
+        let transaction = Transaction::new_signed_with_payer(
+            &[
+                create_vault_instruction(
+                    &program_id,
+                    &get_associated_token_address(
+                        &vault_authority_pda(&program_id, &mint_tokena_key),
+                        &mint_tokena_key,
+                    ),
+                    &mint_tokena_key,
+                    &anti_mint_pda(&program_id, &mint_tokena_key),
+                    &payer.pubkey(),
+                    &spl_associated_token_account::id(),
+                    &get_associated_token_address(&payer.pubkey(), &mint_tokena_key),
+                ),
+                index_vault_instruction(&program_id, &payer.pubkey(), &mint_tokena_key),
+            ],
+            Some(&payer.pubkey()),
+            &[payer],
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await?;
+        mints.push(mint_tokena_key);
+
+        let expected_capacity = match count {
+            1..=10 => 10,
+            11..=20 => 20,
+            21..=40 => 40,
+            _ => 80,
+        };
+        let state_account = banks_client.get_account(state_key).await?.unwrap();
+        let vault_registry = VaultRegistry::deserialize(&state_account.data).unwrap();
+        assert_eq!(vault_registry.vault_count(), count);
+        assert_eq!(vault_registry.capacity, expected_capacity);
+        assert_eq!(state_account.data.len(), vault_registry.len());
+        assert!(
+            rent.is_exempt(state_account.lamports, state_account.data.len()),
+            "Registry must stay rent-exempt after growing"
+        );
+    }
+
+    // Vaults written before each reallocation survive it
+    let state_account = banks_client.get_account(state_key).await?.unwrap();
+    let vault_registry = VaultRegistry::deserialize(&state_account.data).unwrap();
+    for (entry, mint) in vault_registry.vaults.iter().zip(&mints) {
+        assert_eq!(entry.mint_token_a, *mint);
+        assert_eq!(entry.mint_a_token_a, anti_mint_pda(&program_id, mint));
+        assert_eq!(entry.vault, vault_state_pda(&program_id, mint));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_registry_growth_is_bounded() {
+    let mut vault_registry = VaultRegistry::new();
+    vault_registry.grow();
+    assert_eq!(vault_registry.capacity, 20);
+
+    // A single realloc may only add MAX_PERMITTED_DATA_INCREASE bytes
+    vault_registry.capacity = 200;
+    let len_before = vault_registry.len();
+    vault_registry.grow();
+    assert_eq!(vault_registry.capacity, 200 + VaultRegistry::MAX_GROWTH);
+    assert!(
+        vault_registry.len() - len_before
+            <= solana_program::entrypoint::MAX_PERMITTED_DATA_INCREASE
+    );
+}
+
+#[tokio::test]
+async fn test_fetch_vault_from_registry() -> Result<(), TransportError> {
+    // Setup keys and program
+    let program_id = Pubkey::new_unique();
+
+    let _rent_key = solana_program::sysvar::rent::ID;
+    let spl_key = spl_token::id();
+    let associated_token_program = spl_associated_token_account::id();
+
+    let (state_key, _bump_seed) = Pubkey::find_program_address(&[b"vault_registry"], &program_id);
+
+    let mut program_test = ProgramTest::new(
+        "rugsafe_vaults",
+        program_id,
+        processor!(process_instruction),
+    );
+
+    // Add SPL Token program
+    program_test.add_program(
+        "spl_token",
+        spl_key,
+        processor!(spl_token::processor::Processor::process),
+    );
+
+    // Start the context
+    let mut context = program_test.start_with_context().await;
+    let banks_client = &mut context.banks_client;
+    let payer = &context.payer;
+    let recent_blockhash = banks_client.get_latest_blockhash().await?;
+
+    // Create token mint for token A
+    let mint_tokena_keypair =
+        create_token_mint(banks_client, payer, recent_blockhash, &payer.pubkey()).await?;
+    let mint_tokena_key = mint_tokena_keypair.pubkey();
+    let mint_atokena_key = anti_mint_pda(&program_id, &mint_tokena_key); // Mint account for aToken A
+
+    // Vault key should be associated token account for token A's mint
+    let vault_key: Pubkey = get_associated_token_address(
+        &vault_authority_pda(&program_id, &mint_tokena_key),
+        &mint_tokena_key,
+    );
+    let user_token_a_account: Pubkey =
+        get_associated_token_address(&payer.pubkey(), &mint_tokena_key);
+
+    // Call the function to create the vault instruction
+    println!("Creating vault instruction...");
+    let create_vault_instruction = create_vault_instruction(
+        &program_id,
+        &vault_key,
+        &mint_tokena_key,
+        &mint_atokena_key,
+        &payer.pubkey(),
+        &associated_token_program,
+        &user_token_a_account,
+    );
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            create_vault_instruction,
+            index_vault_instruction(&program_id, &payer.pubkey(), &mint_tokena_key),
+        ],
+        Some(&payer.pubkey()),
+    );
+
+    println!("Signing the transaction...");
+    transaction.sign(&[payer], recent_blockhash);
+
+    // Process CreateVault transaction
+    println!("Processing CreateVault transaction...");
+    banks_client.process_transaction(transaction).await?;
+
+    // Verify vault creation
     println!("Verifying vault creation...");
     let mint_account = banks_client.get_account(mint_atokena_key).await?;
     assert!(mint_account.is_some(), "Mint account not created");
@@ -814,9 +1964,11 @@ async fn test_fetch_vault_from_registry() -> Result<(), TransportError> {
         vault_registry
             .vaults
             .iter()
-            .any(|v| v.vault_account == vault_key),
+            .any(|v| v.vault == vault_state_pda(&program_id, &mint_tokena_key)),
         "Vault not found in registry"
     );
+    let vault = fetch_vault(banks_client, &program_id, &mint_tokena_key).await?;
+    assert_eq!(vault.vault_account, vault_key);
 
     Ok(())
 }
@@ -824,12 +1976,8 @@ async fn test_fetch_vault_from_registry() -> Result<(), TransportError> {
 async fn test_fetch_vault_with_data_from_registry() -> Result<(), TransportError> {
     // Setup keys and program
     let program_id = Pubkey::new_unique();
-    let mint_tokena_keypair = Keypair::new(); // Mint account for token A
-    let mint_tokena_key = mint_tokena_keypair.pubkey();
-    let mint_atokena_keypair = Keypair::new(); // Mint account for aToken A
-    let mint_atokena_key = mint_atokena_keypair.pubkey();
 
-    let rent_key = solana_program::sysvar::rent::ID;
+    let _rent_key = solana_program::sysvar::rent::ID;
     let spl_key = spl_token::id();
     let associated_token_program = spl_associated_token_account::id();
 
@@ -856,11 +2004,15 @@ async fn test_fetch_vault_with_data_from_registry() -> Result<(), TransportError
 
     // Create token mint for token A
     let mint_tokena_keypair =
-        create_token_mint(banks_client, &payer, recent_blockhash, &payer.pubkey()).await?;
+        create_token_mint(banks_client, payer, recent_blockhash, &payer.pubkey()).await?;
     let mint_tokena_key = mint_tokena_keypair.pubkey();
+    let mint_atokena_key = anti_mint_pda(&program_id, &mint_tokena_key); // Mint account for aToken A
 
     // Vault key should be associated token account for token A's mint
-    let vault_key: Pubkey = get_associated_token_address(&program_id, &mint_tokena_key);
+    let vault_key: Pubkey = get_associated_token_address(
+        &vault_authority_pda(&program_id, &mint_tokena_key),
+        &mint_tokena_key,
+    );
     let user_token_a_account: Pubkey =
         get_associated_token_address(&payer.pubkey(), &mint_tokena_key);
 
@@ -872,20 +2024,20 @@ async fn test_fetch_vault_with_data_from_registry() -> Result<(), TransportError
         &mint_tokena_key,
         &mint_atokena_key,
         &payer.pubkey(),
-        &state_key,
         &associated_token_program,
         &user_token_a_account,
     );
 
-    let mut transaction =
-        Transaction::new_with_payer(&[create_vault_instruction], Some(&payer.pubkey()));
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            create_vault_instruction,
+            index_vault_instruction(&program_id, &payer.pubkey(), &mint_tokena_key),
+        ],
+        Some(&payer.pubkey()),
+    );
 
     println!("Signing the transaction...");
-    transaction.sign(
-        // &[&payer, &mint_tokena_keypair, &mint_atokena_keypair],
-        &[&payer, &mint_atokena_keypair],
-        recent_blockhash,
-    );
+    transaction.sign(&[payer], recent_blockhash);
 
     // Process CreateVault transaction
     println!("Processing CreateVault transaction...");
@@ -915,19 +2067,23 @@ async fn test_fetch_vault_with_data_from_registry() -> Result<(), TransportError
         state_data.len()
     );
 
-    manual_deserialize(&state_data);
+    let entries = manual_deserialize(&state_data);
 
     let vault_registry =
         VaultRegistry::deserialize(&state_data).expect("Failed to deserialize VaultRegistry");
 
     println!("VaultRegistry contents: {:?}", vault_registry);
+    assert_eq!(entries, vault_registry.vaults);
+    assert_eq!(entries, vault_registry.vaults);
     assert!(
         vault_registry
             .vaults
             .iter()
-            .any(|v| v.vault_account == vault_key),
+            .any(|v| v.vault == vault_state_pda(&program_id, &mint_tokena_key)),
         "Vault not found in registry"
     );
+    let vault = fetch_vault(banks_client, &program_id, &mint_tokena_key).await?;
+    assert_eq!(vault.vault_account, vault_key);
 
     Ok(())
 }
@@ -962,7 +2118,7 @@ async fn test_faucet() -> Result<(), Box<dyn std::error::Error>> {
     // let user_token_keypair: Keypair = Keypair::new();
     let user_token_account = get_associated_token_address(&payer.pubkey(), &mint_pubkey);
 
-    let associated_token_program: Pubkey = spl_associated_token_account::id();
+    let _associated_token_program: Pubkey = spl_associated_token_account::id();
 
     // Prepare the faucet instruction
     let amount: u64 = 1000;
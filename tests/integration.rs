@@ -1,5 +1,8 @@
 use rugsafe_perps::instructions::processor::Processor as PerpsProcessor;
 use rugsafe_vaults::instructions::processor::Processor as VaultProcessor;
+use rugsafe_vaults::state::vaults::{
+    ANTI_MINT_SEED, MINT_AUTHORITY_SEED, VAULT_AUTHORITY_SEED, VAULT_SEED,
+};
 use solana_program::instruction::{AccountMeta, Instruction};
 // use solana_program::program_error::ProgramError;
 use solana_program::program_pack::Pack;
@@ -65,63 +68,45 @@ async fn test_integration_perps_vaults() -> Result<(), TransportError> {
     )
     .unwrap();
 
-    // Step 2: Create AToken A mint for the vault
-    let mint_atokena_keypair = Keypair::new();
-    let create_mint_atokena_ix = system_instruction::create_account(
-        &payer.pubkey(),
-        &mint_atokena_keypair.pubkey(),
-        mint_rent,
-        spl_token::state::Mint::LEN as u64,
-        &spl_token::id(),
+    // Step 2: Derive the AToken A mint for the vault, the vaults program creates it
+    let (mint_atokena_key, _mint_atokena_bump) = Pubkey::find_program_address(
+        &[ANTI_MINT_SEED, mint_tokena_keypair.pubkey().as_ref()],
+        &vaults_program_id,
     );
-    let initialize_mint_atokena_ix = spl_token::instruction::initialize_mint(
-        &spl_token::id(),
-        &mint_atokena_keypair.pubkey(),
-        &payer.pubkey(),
-        None,
-        6,
-    )
-    .unwrap();
 
     // Process mint transactions
     let transaction = Transaction::new_signed_with_payer(
-        &[
-            create_mint_tokena_ix,
-            initialize_mint_tokena_ix,
-            create_mint_atokena_ix,
-            initialize_mint_atokena_ix,
-        ],
+        &[create_mint_tokena_ix, initialize_mint_tokena_ix],
         Some(&payer.pubkey()),
-        &[&payer, &mint_tokena_keypair, &mint_atokena_keypair],
+        &[payer, &mint_tokena_keypair],
         recent_blockhash,
     );
     banks_client.process_transaction(transaction).await?;
 
-    // Step 3: Create the state_key using PDA for vault_registry
-    let (state_key, _bump_seed) =
-        Pubkey::find_program_address(&[b"vault_registry"], &vaults_program_id);
-
-    // Step 4: Create vault and associated token accounts
+    // Step 3: Create vault and associated token accounts
+    let (vault_authority, _vault_authority_bump) = Pubkey::find_program_address(
+        &[VAULT_AUTHORITY_SEED, mint_tokena_keypair.pubkey().as_ref()],
+        &vaults_program_id,
+    );
     let vault_key: Pubkey =
-        get_associated_token_address(&vaults_program_id, &mint_tokena_keypair.pubkey());
+        get_associated_token_address(&vault_authority, &mint_tokena_keypair.pubkey());
     let user_token_account =
         get_associated_token_address(&payer.pubkey(), &mint_tokena_keypair.pubkey());
 
     let create_vault_instruction = create_vault_instruction(
         &vaults_program_id,
         &vault_key,
-        &mint_tokena_keypair.pubkey(),  // Token A mint
-        &mint_atokena_keypair.pubkey(), // AToken A mint
+        &mint_tokena_keypair.pubkey(), // Token A mint
+        &mint_atokena_key,             // AToken A mint
         &payer.pubkey(),
-        &state_key,                          // State key
         &spl_associated_token_account::id(), // Associated token program
         &user_token_account,
     );
 
-    let mut transaction = Transaction::new_signed_with_payer(
+    let transaction = Transaction::new_signed_with_payer(
         &[create_vault_instruction],
         Some(&payer.pubkey()),
-        &[&payer, &mint_atokena_keypair], // Sign with both mint keypairs
+        &[payer],
         recent_blockhash,
     );
     banks_client.process_transaction(transaction).await?;
@@ -190,32 +175,46 @@ fn create_vault_instruction(
     mint_key_token_a: &Pubkey,   // Mint A for the incoming tokens
     mint_key_a_token_a: &Pubkey, // Mint B for the aTokens
     payer: &Pubkey,
-    state: &Pubkey,
     associated_token: &Pubkey,
     user_token_a: &Pubkey,
 ) -> Instruction {
     let accounts = vec![
         AccountMeta::new(*payer, true),
         AccountMeta::new(*mint_key_token_a, false),
-        AccountMeta::new(*mint_key_a_token_a, true),
+        AccountMeta::new(*mint_key_a_token_a, false),
         AccountMeta::new(*vault_key, false),
         AccountMeta::new_readonly(sysvar::rent::id(), false),
         AccountMeta::new_readonly(spl_token::id(), false),
         AccountMeta::new_readonly(solana_program::system_program::id(), false),
-        AccountMeta::new(*state, false),
         AccountMeta::new(*associated_token, false),
         AccountMeta::new(*user_token_a, false),
-        AccountMeta::new(*program_id, false),
+        AccountMeta::new_readonly(
+            Pubkey::find_program_address(
+                &[VAULT_AUTHORITY_SEED, mint_key_token_a.as_ref()],
+                program_id,
+            )
+            .0,
+            false,
+        ),
+        AccountMeta::new_readonly(
+            Pubkey::find_program_address(&[MINT_AUTHORITY_SEED], program_id).0,
+            false,
+        ),
+        AccountMeta::new(
+            Pubkey::find_program_address(&[VAULT_SEED, mint_key_token_a.as_ref()], program_id).0,
+            false,
+        ),
     ];
 
     Instruction {
         program_id: *program_id,
         accounts,
-        data: vec![0, 0], // Vault creation data
+        data: [vec![0, 0], 1_000_000u64.to_le_bytes().to_vec()].concat(), // Vault creation data, $1.00 peg
     }
 }
 
 // Helper function to create a deposit instruction
+#[allow(dead_code)]
 fn create_deposit_instruction(
     program_id: &Pubkey,
     vault_pda: &Pubkey,
@@ -234,6 +233,7 @@ fn create_deposit_instruction(
     ];
     let mut data = vec![0, 1]; // Instruction ID for deposit
     data.extend_from_slice(&amount.to_le_bytes());
+    data.extend_from_slice(&amount.to_le_bytes()); // Minimum anti-coins, 1:1 at the peg
     Instruction {
         program_id: *program_id,
         accounts,
@@ -242,6 +242,7 @@ fn create_deposit_instruction(
 }
 
 // Helper function to create an open position instruction
+#[allow(dead_code)]
 fn create_open_position_instruction(
     program_id: &Pubkey,
     user_positions_pda: &Pubkey,